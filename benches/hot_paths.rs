@@ -0,0 +1,130 @@
+//! Benchmarks for the hot paths, run with `cargo bench [FILTER]`.
+//!
+//! Importing PBF files needs `--features pbf`, and assembling multipolygons, which isn't reachable
+//! through the public API, needs `--features bench-internals`; `cargo bench --all-features` runs
+//! everything.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use renderer::draw::drawer::Drawer;
+use renderer::draw::fill::{fill_contour, Filler};
+use renderer::draw::point::Point;
+use renderer::draw::tile_pixels::TilePixels;
+use renderer::geodata::importer::{import, parse_input_with_progress, ImportStats, ProgressSink};
+use renderer::geodata::reader::GeodataReader;
+use renderer::mapcss::color::Color;
+use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::perf_stats::{self, PerfStats};
+use renderer::tile::Tile;
+use std::path::{Path, PathBuf};
+
+fn fixture_path(relative_path: &[&str]) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    for component in relative_path {
+        path.push(component);
+    }
+    path
+}
+
+struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&mut self, _: &ImportStats) {}
+}
+
+fn bench_parse_input(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_input");
+    group.sample_size(10);
+
+    let osm_file = fixture_path(&["osm", "nano_moscow.osm"]);
+    group.bench_function("nano_moscow", |b| {
+        b.iter(|| parse_input_with_progress(&osm_file, &mut NoProgress).unwrap())
+    });
+
+    #[cfg(feature = "pbf")]
+    {
+        let pbf_file = fixture_path(&["osm", "nano_moscow.osm.pbf"]);
+        group.bench_function("nano_moscow_pbf", |b| {
+            b.iter(|| parse_input_with_progress(&pbf_file, &mut NoProgress).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+#[cfg(feature = "bench-internals")]
+fn bench_find_polygons(c: &mut Criterion) {
+    use renderer::geodata::bench_support::{assemble_relation, synthetic_relation};
+
+    let relation = synthetic_relation(8, 500);
+    assert_eq!(assemble_relation(&relation, false), 8);
+
+    let mut group = c.benchmark_group("find_polygons");
+    group.bench_function("8x500", |b| b.iter(|| assemble_relation(&relation, false)));
+    group.bench_function("8x500/no_roles", |b| b.iter(|| assemble_relation(&relation, true)));
+    group.finish();
+}
+
+#[cfg(not(feature = "bench-internals"))]
+fn bench_find_polygons(_: &mut Criterion) {}
+
+fn bench_fill_contour(c: &mut Criterion) {
+    let star = (0..2000)
+        .map(|idx| {
+            let angle = 2.0 * std::f64::consts::PI * f64::from(idx) / 2000.0;
+            let radius = if idx % 2 == 0 { 1000.0 } else { 400.0 };
+            Point {
+                x: 128 + (radius * angle.cos()) as i32,
+                y: 128 + (radius * angle.sin()) as i32,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut pixels = TilePixels::new(1);
+    let color = Color { r: 0, g: 128, b: 0 };
+
+    c.bench_function("fill_contour/large_star", |b| {
+        b.iter(|| {
+            let contour = (0..star.len()).map(|idx| (star[idx].clone(), star[(idx + 1) % star.len()].clone()));
+            fill_contour(Box::new(contour), &Filler::Color(&color), 1.0, &mut pixels);
+            pixels.bump_generation();
+        })
+    });
+}
+
+fn bench_render_tile(c: &mut Criterion) {
+    let osm_file = fixture_path(&["osm", "nano_moscow.osm"]);
+    let bin_file = std::env::temp_dir().join("osm_renderer_bench_nano_moscow.bin");
+    import(osm_file.as_path(), bin_file.as_path()).unwrap();
+    let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+    let base_path = fixture_path(&["mapcss"]);
+    let styler = Styler::new(parse_file(&base_path, "mapnik.mapcss").unwrap(), &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new(&base_path));
+    let mut pixels = TilePixels::new(1);
+    let mut perf_stats = PerfStats::default();
+    let tile = Tile {
+        zoom: 15,
+        x: 19_807,
+        y: 10_244,
+    };
+
+    c.bench_function("render_tile/z15", |b| {
+        b.iter(|| {
+            // The drawer reports into the per-tile stats when built with `perf-stats`.
+            perf_stats::start_tile(tile.zoom);
+            let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+            let rendered = drawer.draw_tile(&entities, &tile, &mut pixels, 1, &styler).unwrap();
+            perf_stats::finish_tile(&mut perf_stats);
+            rendered
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_parse_input,
+    bench_find_polygons,
+    bench_fill_contour,
+    bench_render_tile
+);
+criterion_main!(benches);
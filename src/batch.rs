@@ -0,0 +1,417 @@
+use crate::coords::{tiles_for_bbox, BoundingBox};
+use crate::draw::drawer::{Drawer, TileRenderedPixels};
+use crate::draw::png_writer::rgb_triples_to_png;
+use crate::draw::tile_pixels::TilePixels;
+use crate::geodata::reader::GeodataReader;
+use crate::mapcss::styler::Styler;
+use crate::perf_stats::PerfStats;
+use crate::tile::{tile_to_ancestor, Tile, TileScheme, MAX_ZOOM};
+use anyhow::{anyhow, Context, Result};
+use std::collections::VecDeque;
+use std::fs;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant};
+
+/// How many zooms `BatchOptions::overzoom` goes above `max_zoom`. A tile that many zooms deeper is blown up
+/// from a single pixel of its ancestor, so deeper ones would only repeat it.
+pub const MAX_OVERZOOM_LEVELS: u8 = 8;
+
+pub struct BatchOptions {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Tiles above `max_zoom` are produced by upscaling the matching part of their `max_zoom` ancestor
+    /// instead of being skipped. Only up to `MAX_OVERZOOM_LEVELS` zooms above it, where a single pixel of
+    /// the ancestor already covers the whole tile.
+    pub overzoom: bool,
+    pub scale: usize,
+    /// How many worker threads `render_region_iter` renders the tiles with.
+    pub threads: usize,
+    /// Makes `render_to_dir` write a `{z}/{x}/{y}.json` file with the `RenderStats` next to every tile.
+    pub write_sidecars: bool,
+    /// How `render_to_dir` numbers the rows in the file names. The tiles that are passed in and out are
+    /// always XYZ.
+    pub tile_scheme: TileScheme,
+}
+
+impl Default for BatchOptions {
+    fn default() -> BatchOptions {
+        BatchOptions {
+            min_zoom: 0,
+            max_zoom: MAX_ZOOM,
+            overzoom: false,
+            scale: 1,
+            threads: thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1),
+            write_sidecars: false,
+            tile_scheme: TileScheme::Xyz,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BatchStats {
+    rasterized: AtomicUsize,
+    resampled: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl BatchStats {
+    /// Number of tiles that were drawn from the vector data.
+    pub fn rasterized(&self) -> usize {
+        self.rasterized.load(Ordering::Relaxed)
+    }
+
+    /// Number of overzoomed tiles that were produced from an already rasterized ancestor.
+    pub fn resampled(&self) -> usize {
+        self.resampled.load(Ordering::Relaxed)
+    }
+
+    /// Number of requested tiles that were outside of the zoom range or off the map.
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// What it took to render a single tile.
+#[derive(Clone, Debug)]
+pub struct RenderStats {
+    /// The number of nodes (untagged ones included), ways, multipolygons and routes the tile was drawn
+    /// from. Overzoomed tiles report the ones of their ancestor.
+    pub feature_count: usize,
+    /// Includes rasterizing the ancestor of an overzoomed tile, unless it was reused from the previous tile.
+    pub render_time: Duration,
+    pub is_blank: bool,
+}
+
+impl RenderStats {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"feature_count\":{},\"render_time_ms\":{:.3},\"is_blank\":{}}}",
+            self.feature_count,
+            self.render_time.as_secs_f64() * 1000.0,
+            self.is_blank
+        )
+    }
+}
+
+pub struct BatchRenderer<'a> {
+    reader: &'a GeodataReader<'a>,
+    styler: &'a Styler,
+    drawer: &'a Drawer,
+    options: BatchOptions,
+    stats: BatchStats,
+    perf_stats: Mutex<PerfStats>,
+}
+
+impl<'a> BatchRenderer<'a> {
+    pub fn new(
+        reader: &'a GeodataReader<'a>,
+        styler: &'a Styler,
+        drawer: &'a Drawer,
+        options: BatchOptions,
+    ) -> BatchRenderer<'a> {
+        BatchRenderer {
+            reader,
+            styler,
+            drawer,
+            options,
+            stats: BatchStats::default(),
+            perf_stats: Mutex::new(PerfStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> &BatchStats {
+        &self.stats
+    }
+
+    /// Hands over the perf stats of the tiles rasterized since the last call. They are only collected with the
+    /// `perf-stats` feature.
+    pub fn take_perf_stats(&self) -> PerfStats {
+        mem::take(&mut *self.perf_stats.lock().unwrap())
+    }
+
+    /// Returns `None` if the tile is outside of the configured zoom range or its row is off the map. Columns
+    /// past the antimeridian wrap around, see `Tile::normalized`.
+    pub fn render_tile(&self, tile: &Tile, pixels: &mut TilePixels) -> Option<TileRenderedPixels> {
+        match self.ancestor_to_rasterize(tile) {
+            Some(ancestor) if ancestor == *tile => Some(self.rasterize(tile, pixels).0),
+            Some(ancestor) => {
+                let (ancestor_pixels, _) = self.rasterize(&ancestor, pixels);
+                Some(self.resample(&ancestor_pixels, &ancestor, tile))
+            }
+            None => None,
+        }
+    }
+
+    /// Renders all tiles in order and hands them to `on_tile`, stopping at the first error it returns.
+    /// Consecutive overzoomed tiles that share an ancestor rasterize it only once.
+    pub fn render_tiles<I, F>(&self, tiles: I, mut on_tile: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Tile>,
+        F: FnMut(&Tile, TileRenderedPixels) -> Result<()>,
+    {
+        self.render_tiles_with_stats(tiles, |tile, rendered, _| on_tile(tile, rendered))
+    }
+
+    /// Same as `render_tiles`, but also reports the `RenderStats` of every tile.
+    pub fn render_tiles_with_stats<I, F>(&self, tiles: I, mut on_tile: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Tile>,
+        F: FnMut(&Tile, TileRenderedPixels, &RenderStats) -> Result<()>,
+    {
+        let mut pixels = TilePixels::new(self.options.scale);
+        let mut last_ancestor: Option<(Tile, TileRenderedPixels, usize)> = None;
+
+        for tile in tiles {
+            let started_at = Instant::now();
+            let (rendered, feature_count) = match self.ancestor_to_rasterize(&tile) {
+                Some(ancestor) if ancestor == tile => self.rasterize(&tile, &mut pixels),
+                Some(ancestor) => {
+                    if last_ancestor.as_ref().map(|x| &x.0) != Some(&ancestor) {
+                        let (ancestor_pixels, feature_count) = self.rasterize(&ancestor, &mut pixels);
+                        last_ancestor = Some((ancestor.clone(), ancestor_pixels, feature_count));
+                    }
+                    let (_, ancestor_pixels, feature_count) = last_ancestor.as_ref().unwrap();
+                    (self.resample(ancestor_pixels, &ancestor, &tile), *feature_count)
+                }
+                None => continue,
+            };
+            let stats = RenderStats {
+                feature_count,
+                render_time: started_at.elapsed(),
+                is_blank: rendered.is_blank,
+            };
+            on_tile(&tile, rendered, &stats)?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders all tiles into `{z}/{x}/{y}.png` files under `dir`, creating the directories as needed. `y` is
+    /// in `BatchOptions::tile_scheme`.
+    pub fn render_to_dir<I>(&self, tiles: I, dir: &Path) -> Result<()>
+    where
+        I: IntoIterator<Item = Tile>,
+    {
+        self.render_tiles_with_stats(tiles, |tile, rendered, stats| {
+            let tile_dir = dir.join(tile.zoom.to_string()).join(tile.x.to_string());
+            fs::create_dir_all(&tile_dir).context(format!("Failed to create {}", tile_dir.to_string_lossy()))?;
+
+            let png = rgb_triples_to_png(&rendered.triples, rendered.dimension, rendered.dimension)?;
+            let y = self.options.tile_scheme.flip_y(tile.y, tile.zoom)?;
+            let png_path = tile_dir.join(format!("{}.png", y));
+            fs::write(&png_path, png).context(format!("Failed to write {}", png_path.to_string_lossy()))?;
+
+            if self.options.write_sidecars {
+                let json_path = tile_dir.join(format!("{}.json", y));
+                fs::write(&json_path, stats.to_json())
+                    .context(format!("Failed to write {}", json_path.to_string_lossy()))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Lazily renders all tiles of `zoom` that intersect `bbox` into PNGs, column by column (so in
+    /// z/x/y order). `BatchOptions::threads` workers are spawned on `scope` and render the tiles ahead of
+    /// the caller, but only a few runs of tiles at a time, so the finished tiles that haven't been
+    /// consumed yet stay bounded. The workers stop once the iterator is dropped, which has to happen
+    /// before the scope ends, as the scope waits for them.
+    /// ```no_run
+    /// # fn example(batch: &renderer::batch::BatchRenderer, bbox: &renderer::coords::BoundingBox) {
+    /// std::thread::scope(|scope| {
+    ///     for rendered in batch.render_region_iter(scope, bbox, 18) {
+    ///         let (tile, png_bytes) = rendered.unwrap();
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    pub fn render_region_iter<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        bbox: &BoundingBox,
+        zoom: u8,
+    ) -> RegionTiles<'scope> {
+        let threads = self.options.threads.max(1);
+        let (job_sender, job_receiver) = channel::<RegionJob>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for _ in 0..threads {
+            let job_receiver = Arc::clone(&job_receiver);
+            scope.spawn(move || loop {
+                let job = job_receiver.lock().unwrap().recv();
+                let Ok((tiles, sender)) = job else {
+                    break;
+                };
+                let result = self.render_tiles(tiles, |tile, rendered| {
+                    let png = rgb_triples_to_png(&rendered.triples, rendered.dimension, rendered.dimension)?;
+                    sender
+                        .send(Ok((tile.clone(), png)))
+                        .map_err(|_| anyhow!("The region iterator has stopped"))
+                });
+                if let Err(e) = result {
+                    let _ = sender.send(Err(e));
+                }
+            });
+        }
+
+        let tiles =
+            tiles_for_bbox(bbox, u32::from(zoom), TileScheme::Xyz).map(|(zoom, x, y)| Tile { zoom: zoom as u8, x, y });
+        RegionTiles {
+            tiles: Box::new(tiles),
+            jobs: Some(job_sender),
+            runs: VecDeque::new(),
+            max_runs: threads * REGION_RUNS_PER_THREAD,
+        }
+    }
+
+    fn ancestor_to_rasterize(&self, tile: &Tile) -> Option<Tile> {
+        let max_zoom = self.options.max_zoom.min(MAX_ZOOM);
+        let max_overzoom = if self.options.overzoom {
+            max_zoom + MAX_OVERZOOM_LEVELS
+        } else {
+            max_zoom
+        };
+        if tile.zoom < self.options.min_zoom || tile.zoom > max_overzoom {
+            self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let ancestor = tile_to_ancestor(tile, tile.zoom.min(max_zoom));
+        if ancestor.normalized().is_err() {
+            self.stats.skipped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(ancestor)
+    }
+
+    // Also returns the number of entities the tile was drawn from. The entities of a column past the
+    // antimeridian are the ones of the column it repeats, so they have to be projected onto that one.
+    fn rasterize(&self, tile: &Tile, pixels: &mut TilePixels) -> (TileRenderedPixels, usize) {
+        self.stats.rasterized.fetch_add(1, Ordering::Relaxed);
+        let tile = &tile
+            .normalized()
+            .expect("ancestor_to_rasterize only lets through tiles on the map");
+        if cfg!(feature = "perf-stats") {
+            crate::perf_stats::start_tile(tile.zoom);
+        }
+
+        let entities = {
+            let _m = crate::perf_stats::measure("Get tile entities");
+            self.reader.get_entities_in_tile_with_neighbors(tile, &None)
+        };
+        let feature_count =
+            entities.nodes.len() + entities.ways.len() + entities.multipolygons.len() + entities.routes.len();
+        let rendered = self
+            .drawer
+            .draw_to_pixels(&entities, tile, pixels, self.options.scale, self.styler);
+
+        if cfg!(feature = "perf-stats") {
+            crate::perf_stats::finish_tile(&mut self.perf_stats.lock().unwrap());
+        }
+        (rendered, feature_count)
+    }
+
+    fn resample(&self, ancestor_pixels: &TileRenderedPixels, ancestor: &Tile, tile: &Tile) -> TileRenderedPixels {
+        self.stats.resampled.fetch_add(1, Ordering::Relaxed);
+        upscale_from_ancestor(ancestor_pixels, ancestor, tile)
+    }
+}
+
+// `RegionTiles` hands out the tiles to the workers in runs of this many, so that overzoomed neighbours
+// still share their ancestor, and has at most `REGION_RUNS_PER_THREAD` runs per worker in flight.
+const REGION_TILES_PER_RUN: usize = 8;
+const REGION_RUNS_PER_THREAD: usize = 2;
+
+type RegionTile = Result<(Tile, Vec<u8>)>;
+type RegionJob = (Vec<Tile>, SyncSender<RegionTile>);
+
+/// Returned by `BatchRenderer::render_region_iter`. Stops at the first tile that fails to render.
+pub struct RegionTiles<'b> {
+    tiles: Box<dyn Iterator<Item = Tile> + 'b>,
+    jobs: Option<Sender<RegionJob>>,
+    // The results of the runs in flight, in the order of the tiles. Each channel has room for the whole
+    // run, so that the workers never wait for the caller.
+    runs: VecDeque<Receiver<RegionTile>>,
+    max_runs: usize,
+}
+
+impl RegionTiles<'_> {
+    // Once all tiles are handed out, the job channel is closed so that the workers can finish.
+    fn start_runs(&mut self) {
+        while self.runs.len() < self.max_runs {
+            let Some(jobs) = &self.jobs else {
+                return;
+            };
+            let run = self.tiles.by_ref().take(REGION_TILES_PER_RUN).collect::<Vec<_>>();
+            if run.is_empty() {
+                self.jobs = None;
+                return;
+            }
+            let (sender, receiver) = sync_channel(run.len() + 1);
+            if jobs.send((run, sender)).is_err() {
+                return;
+            }
+            self.runs.push_back(receiver);
+        }
+    }
+
+    fn stop(&mut self) {
+        self.tiles = Box::new(std::iter::empty());
+        self.jobs = None;
+        self.runs.clear();
+    }
+}
+
+impl Iterator for RegionTiles<'_> {
+    type Item = RegionTile;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.start_runs();
+            match self.runs.front()?.recv() {
+                Ok(result) => {
+                    if result.is_err() {
+                        self.stop();
+                    }
+                    return Some(result);
+                }
+                // The worker is done with the run.
+                Err(_) => {
+                    self.runs.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Nearest-neighbour upscaling of the part of `ancestor_pixels` that `tile` covers.
+fn upscale_from_ancestor(ancestor_pixels: &TileRenderedPixels, ancestor: &Tile, tile: &Tile) -> TileRenderedPixels {
+    let dimension = ancestor_pixels.dimension;
+    let factor = 1usize << (tile.zoom - ancestor.zoom);
+    let rel_x = (tile.x as usize) - (ancestor.x as usize) * factor;
+    let rel_y = (tile.y as usize) - (ancestor.y as usize) * factor;
+
+    let to_source = |x: usize, y: usize| ((rel_x * dimension + x) / factor, (rel_y * dimension + y) / factor);
+
+    let mut triples = Vec::with_capacity(dimension * dimension);
+    for y in 0..dimension {
+        for x in 0..dimension {
+            let (src_x, src_y) = to_source(x, y);
+            triples.push(ancestor_pixels.triples[src_y * dimension + src_x]);
+        }
+    }
+
+    TileRenderedPixels {
+        triples,
+        dimension,
+        is_blank: ancestor_pixels.is_blank,
+        feature_index: ancestor_pixels
+            .feature_index
+            .as_ref()
+            .map(|index| index.resample(dimension, to_source)),
+        timed_out: ancestor_pixels.timed_out,
+    }
+}
@@ -1,32 +1,146 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use renderer::geodata::clip::ClipPolygon;
+use renderer::geodata::importer::ImportOptions;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-fn import(input: &Path, tmp_output: &Path, output: &Path) -> Result<()> {
+fn import(input: &Path, tmp_output: &Path, output: &Path, options: &ImportOptions) -> Result<()> {
     println!("Importing OSM data from {}", input.to_string_lossy());
-    renderer::geodata::importer::import(input, tmp_output)?;
+    renderer::geodata::importer::import_with_options(input, tmp_output, options)?;
     fs::rename(tmp_output, output)?;
 
     Ok(())
 }
 
+// Prints at most this many problems, the rest are only counted.
+const MAX_PRINTED_PROBLEMS: usize = 100;
+
+fn validate(input: &Path, options: &ImportOptions, max_problems: usize) -> Result<()> {
+    println!("Validating OSM data from {}", input.to_string_lossy());
+    let problems = renderer::geodata::importer::validate(input, options)?;
+    for problem in problems.iter().take(MAX_PRINTED_PROBLEMS) {
+        eprintln!("{}", problem);
+    }
+    if problems.len() > MAX_PRINTED_PROBLEMS {
+        eprintln!("... and {} more", problems.len() - MAX_PRINTED_PROBLEMS);
+    }
+    if problems.len() > max_problems {
+        bail!(
+            "Found {} problems, at most {} are allowed",
+            problems.len(),
+            max_problems
+        );
+    }
+    println!("Found {} problems", problems.len());
+
+    Ok(())
+}
+
 fn main() {
-    let args: Vec<_> = env::args().collect();
+    let mut args: Vec<_> = env::args().collect();
 
-    if args.len() != 3 {
+    let mut options = ImportOptions::default();
+    if let Some(flag_idx) = args.iter().position(|x| x == "--ignore-roles") {
+        options.ignore_multipolygon_roles = true;
+        args.remove(flag_idx);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--gzip") {
+        options.gzip_output = true;
+        args.remove(flag_idx);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--import-routes") {
+        options.import_routes = true;
+        args.remove(flag_idx);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--resolve-forward-refs") {
+        options.resolve_forward_refs = true;
+        args.remove(flag_idx);
+    }
+    let validate_only = match args.iter().position(|x| x == "--validate") {
+        Some(flag_idx) => {
+            args.remove(flag_idx);
+            true
+        }
+        None => false,
+    };
+    let mut max_problems = 0;
+    if let Some(flag_idx) = args.iter().position(|x| x == "--max-problems") {
+        match args.get(flag_idx + 1).map(|x| x.parse()) {
+            Some(Ok(value)) => max_problems = value,
+            _ => {
+                eprintln!("--max-problems expects a number");
+                std::process::exit(1);
+            }
+        }
+        args.drain(flag_idx..flag_idx + 2);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--max-multipolygon-segments") {
+        match args.get(flag_idx + 1).map(|x| x.parse()) {
+            Some(Ok(value)) => options.max_multipolygon_segments = value,
+            _ => {
+                eprintln!("--max-multipolygon-segments expects a number");
+                std::process::exit(1);
+            }
+        }
+        args.drain(flag_idx..flag_idx + 2);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--inherit-relation-tags") {
+        let Some(keys) = args.get(flag_idx + 1) else {
+            eprintln!("--inherit-relation-tags expects a comma-separated list of keys");
+            std::process::exit(1);
+        };
+        options.inherited_relation_tags = keys.split(',').filter(|k| !k.is_empty()).map(String::from).collect();
+        args.drain(flag_idx..flag_idx + 2);
+    }
+    if let Some(flag_idx) = args.iter().position(|x| x == "--clip-polygon") {
+        let Some(geojson_path) = args.get(flag_idx + 1) else {
+            eprintln!("--clip-polygon expects a GeoJSON file");
+            std::process::exit(1);
+        };
+        match ClipPolygon::load_geojson(Path::new(geojson_path)) {
+            Ok(clip_polygon) => options.clip_polygon = Some(clip_polygon),
+            Err(err) => {
+                for cause in err.chain() {
+                    eprintln!("{}", cause);
+                }
+                std::process::exit(1);
+            }
+        }
+        args.drain(flag_idx..flag_idx + 2);
+    }
+
+    let expected_arg_count = if validate_only { 2 } else { 3 };
+    if args.len() != expected_arg_count {
         let bin_name = args.first().map(String::as_str).unwrap_or("importer");
-        eprintln!("Usage: {} INPUT OUTPUT", bin_name);
+        eprintln!(
+            "Usage: {} [--ignore-roles] [--gzip] [--import-routes] [--resolve-forward-refs] [--clip-polygon GEOJSON] [--max-multipolygon-segments N] [--inherit-relation-tags KEYS] INPUT OUTPUT",
+            bin_name
+        );
+        eprintln!(
+            "       {} --validate [--max-problems N] [--ignore-roles] [--resolve-forward-refs] INPUT",
+            bin_name
+        );
         std::process::exit(1);
     }
 
+    if validate_only {
+        if let Err(err) = validate(Path::new(&args[1]), &options, max_problems) {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let input = PathBuf::from(&args[1]);
     let output = PathBuf::from(&args[2]);
 
     let mut tmp_output = output.clone();
     tmp_output.set_extension("tmp");
 
-    match import(&input, &tmp_output, &output) {
+    match import(&input, &tmp_output, &output, &options) {
         Ok(_) => println!("Successfully imported OSM data to {}", output.to_string_lossy()),
         Err(err) => {
             // Make a best-effort attempt to remove the unfinished mess
@@ -1,6 +1,9 @@
-use renderer::http_server::run_server;
+use renderer::coords::BoundingBox;
+use renderer::draw::drawer::RenderOptions;
+use renderer::http_server::{run_server, CacheConfig, CorsConfig, WarmRegion};
 use renderer::mapcss::styler::StyleType;
 use std::env;
+use std::time::Duration;
 use tini::Ini;
 
 fn fail() -> ! {
@@ -17,6 +20,14 @@ fn get_value_from_config(config: &Ini, section: &str, name: &str) -> String {
     }
 }
 
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
 
@@ -59,6 +70,74 @@ fn main() {
                 }
             });
 
+    let cors_section = "cors";
+    let mut cors = CorsConfig::default();
+    if let Some(origins) = config.get::<String>(cors_section, "origins") {
+        cors.allowed_origins = split_list(&origins);
+    }
+    if let Some(methods) = config.get::<String>(cors_section, "methods") {
+        cors.allowed_methods = split_list(&methods);
+    }
+    if let Some(max_age_str) = config.get::<String>(cors_section, "max-age") {
+        cors.max_age = match max_age_str.parse() {
+            Ok(max_age) => Some(max_age),
+            Err(_) => {
+                eprintln!("Invalid CORS max age: {}", max_age_str);
+                fail();
+            }
+        };
+    }
+
+    let mut render_options = RenderOptions::default();
+    if let Some(max_time_str) = config.get::<String>("render", "max-time-ms") {
+        render_options.max_render_time = match max_time_str.parse() {
+            Ok(max_time) => Some(Duration::from_millis(max_time)),
+            Err(_) => {
+                eprintln!("Invalid maximum render time: {}", max_time_str);
+                fail();
+            }
+        };
+    }
+
+    let cache_section = "cache";
+    let mut cache = CacheConfig::default();
+    if let Some(size_str) = config.get::<String>(cache_section, "size") {
+        cache.capacity = match size_str.parse() {
+            Ok(size) => size,
+            Err(_) => {
+                eprintln!("Invalid cache size: {}", size_str);
+                fail();
+            }
+        };
+    }
+    if let Some(bbox_str) = config.get::<String>(cache_section, "warm-bbox") {
+        let bbox = match split_list(&bbox_str)
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<f64>, _>>()
+        {
+            Ok(coords) if coords.len() == 4 => BoundingBox {
+                min_lat: coords[0],
+                min_lon: coords[1],
+                max_lat: coords[2],
+                max_lon: coords[3],
+            },
+            _ => {
+                eprintln!("Invalid bounding box to warm up the cache for: {}", bbox_str);
+                fail();
+            }
+        };
+        let zooms_str = get_value_from_config(&config, cache_section, "warm-zooms");
+        let zooms = match split_list(&zooms_str).iter().map(|x| x.parse()).collect() {
+            Ok(zooms) => zooms,
+            Err(_) => {
+                eprintln!("Invalid zoom levels to warm up the cache for: {}", zooms_str);
+                fail();
+            }
+        };
+        cache.warm_region = Some(WarmRegion { bbox, zooms });
+    }
+
     let osm_ids = if args.len() >= 3 {
         Some(
             args[2..]
@@ -77,6 +156,9 @@ fn main() {
         &stylesheet_type,
         font_size_multiplier,
         osm_ids,
+        cors,
+        render_options,
+        cache,
     );
 
     if let Err(e) = res {
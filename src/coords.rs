@@ -1,3 +1,5 @@
+use crate::tile::TileScheme;
+
 pub trait Coords {
     fn lat(&self) -> f64;
     fn lon(&self) -> f64;
@@ -12,3 +14,74 @@ impl Coords for (f64, f64) {
         self.1
     }
 }
+
+/// A geographic bounding box. A box with `min_lon > max_lon` crosses the antimeridian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub max_lat: f64,
+    pub min_lon: f64,
+    pub max_lon: f64,
+}
+
+/// The latitude where Web Mercator tiles end, so that the whole map is a square.
+pub(crate) const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_6;
+
+/// Enumerates all `(zoom, x, y)` tiles that intersect a given bounding box, column by column, with `y`
+/// in the given scheme. Coordinates outside of the Web Mercator range are clamped to it.
+/// # Examples
+/// ```
+/// use renderer::coords::{tiles_for_bbox, BoundingBox};
+/// use renderer::tile::TileScheme;
+/// let bbox = BoundingBox { min_lat: 55.7496, max_lat: 55.7510, min_lon: 37.6091, max_lon: 37.6120 };
+/// assert_eq!(tiles_for_bbox(&bbox, 18, TileScheme::Xyz).collect::<Vec<_>>(), vec![
+///     (18, 158458, 81953), (18, 158458, 81954),
+///     (18, 158459, 81953), (18, 158459, 81954),
+///     (18, 158460, 81953), (18, 158460, 81954),
+/// ]);
+/// assert_eq!(tiles_for_bbox(&bbox, 18, TileScheme::Tms).take(2).collect::<Vec<_>>(), vec![
+///     (18, 158458, 180189), (18, 158458, 180190),
+/// ]);
+///
+/// let across_antimeridian = BoundingBox { min_lat: -10.0, max_lat: 10.0, min_lon: 179.5, max_lon: -179.5 };
+/// assert_eq!(tiles_for_bbox(&across_antimeridian, 3, TileScheme::Xyz).collect::<Vec<_>>(), vec![
+///     (3, 7, 3), (3, 7, 4),
+///     (3, 0, 3), (3, 0, 4),
+/// ]);
+///
+/// let world = BoundingBox { min_lat: -90.0, max_lat: 90.0, min_lon: -180.0, max_lon: 180.0 };
+/// assert_eq!(tiles_for_bbox(&world, 0, TileScheme::Xyz).collect::<Vec<_>>(), vec![(0, 0, 0)]);
+/// assert_eq!(tiles_for_bbox(&world, 2, TileScheme::Tms).count(), 16);
+/// ```
+pub fn tiles_for_bbox(bbox: &BoundingBox, zoom: u32, scheme: TileScheme) -> impl Iterator<Item = (u32, u32, u32)> {
+    assert!(zoom < 32);
+    let tile_count = 1u64 << zoom;
+    let max_index = (tile_count - 1) as u32;
+
+    let to_index = |coord: f64| (coord * tile_count as f64).floor().clamp(0.0, f64::from(max_index)) as u32;
+    let lon_to_x = |lon: f64| to_index((lon.clamp(-180.0, 180.0) + 180.0) / 360.0);
+    let lat_to_y = |lat: f64| {
+        let lat_rad = lat.clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT).to_radians();
+        to_index((1.0 - lat_rad.tan().asinh() / std::f64::consts::PI) / 2.0)
+    };
+
+    let (min_x, max_x) = (lon_to_x(bbox.min_lon), lon_to_x(bbox.max_lon));
+    let (min_y, max_y) = {
+        let (north_y, south_y) = (lat_to_y(bbox.max_lat), lat_to_y(bbox.min_lat));
+        match scheme {
+            TileScheme::Xyz => (north_y, south_y),
+            TileScheme::Tms => (max_index - south_y, max_index - north_y),
+        }
+    };
+
+    let x_ranges = if bbox.min_lon > bbox.max_lon {
+        vec![min_x..=max_index, 0..=max_x]
+    } else {
+        vec![min_x..=max_x]
+    };
+
+    x_ranges
+        .into_iter()
+        .flatten()
+        .flat_map(move |x| (min_y..=max_y).map(move |y| (zoom, x, y)))
+}
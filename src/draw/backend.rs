@@ -0,0 +1,55 @@
+use crate::draw::graticule::GraticuleOptions;
+use crate::draw::hillshade::DemSource;
+use crate::geodata::reader::Node;
+use crate::mapcss::styler::{Scrim, Style, StyledArea, Styler};
+use crate::tile::Tile;
+use anyhow::Result;
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum DrawType {
+    Fill,
+    Stroke,
+    Casing,
+}
+
+/// One step of rendering a tile. `Drawer::render` emits the hillshade first, then all fills, then all casings and strokes,
+/// then the node symbols, then the labels and finally the overlays, each group in the order decided by the styler. Styles
+/// with render layers repeat the scrim, fills, casings and strokes sequence for every layer.
+pub enum DrawCommand<'c, 'a: 'c> {
+    /// Terrain shading under all features.
+    Hillshade(&'c DemSource),
+    /// A flat color over the whole tile between two render layers.
+    Scrim(&'c Scrim),
+    /// Multipolygons are only filled and routes are only stroked, ways get all three draw types.
+    Area {
+        area: &'c StyledArea<'a, 'c>,
+        style: &'c Style,
+        draw_type: DrawType,
+    },
+    AreaLabel {
+        area: &'c StyledArea<'a, 'c>,
+        style: &'c Style,
+    },
+    /// Only emitted for nodes with a `Style::symbol`.
+    NodeSymbol {
+        node: &'c Node<'a>,
+        style: &'c Style,
+    },
+    NodeLabel {
+        node: &'c Node<'a>,
+        style: &'c Style,
+    },
+    Graticule(&'c GraticuleOptions),
+}
+
+/// Turns the draw commands for a single tile into an encoded output file. Implement this to add a new
+/// output format without touching the render loop.
+pub trait OutputBackend {
+    /// Called once before any commands for the tile. `scale` is the requested tile scale. An error means the
+    /// backend can't draw the tile at all, and is returned without sending any commands.
+    fn begin_tile(&mut self, tile: &Tile, scale: usize, styler: &Styler) -> Result<()>;
+
+    fn draw_command(&mut self, command: &DrawCommand<'_, '_>);
+
+    fn finish(&mut self) -> Result<Vec<u8>>;
+}
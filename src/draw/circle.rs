@@ -0,0 +1,61 @@
+use crate::draw::tile_pixels::{RgbaColor, TilePixels};
+use crate::mapcss::color::Color;
+
+/// Fills and/or strokes a circle around `center`, e.g. the dot of a town or a station. `center` is in
+/// scaled tile pixels, where the pixel `(x, y)` covers `x..x + 1` and `y..y + 1`. `radius` and
+/// `stroke_width` are in unscaled tile pixels, and the stroke is centered on the edge of the circle.
+/// Both edges are antialiased by the distance of every pixel from them.
+pub fn draw_circle(
+    (center_x, center_y): (f64, f64),
+    radius: f64,
+    fill: Option<&Color>,
+    stroke: Option<&Color>,
+    stroke_width: f64,
+    opacity: f64,
+    scale: f64,
+    pixels: &mut TilePixels,
+) {
+    let radius = radius * scale;
+    let half_stroke_width = match stroke {
+        Some(_) => stroke_width * scale / 2.0,
+        None => 0.0,
+    };
+    let (fill_edge, outer_edge) = (radius - half_stroke_width, radius + half_stroke_width);
+    if outer_edge <= 0.0 {
+        return;
+    }
+
+    let fill = fill.map(|color| RgbaColor::from_color(color, opacity));
+    let stroke = stroke.map(|color| RgbaColor::from_color(color, opacity));
+    // How much of a pixel at `distance` from the center is inside of the circle with the `edge` radius.
+    let coverage = |distance: f64, edge: f64| (edge + 0.5 - distance).clamp(0.0, 1.0);
+
+    let bb = pixels.bb().clone();
+    let extent = outer_edge.ceil() + 1.0;
+    let to_pixel_range = |center: f64, min: i32, max: i32| {
+        ((center - extent).floor() as i32).max(min)..=((center + extent).ceil() as i32).min(max)
+    };
+    for y in to_pixel_range(center_y, bb.min_y, bb.max_y) {
+        for x in to_pixel_range(center_x, bb.min_x, bb.max_x) {
+            let distance = (f64::from(x) + 0.5 - center_x).hypot(f64::from(y) + 0.5 - center_y);
+            let fill_coverage = coverage(distance, fill_edge);
+            let stroke_coverage = coverage(distance, outer_edge) - fill_coverage;
+
+            // The fill and the stroke don't overlap, so their premultiplied colors just add up.
+            let mut color = RgbaColor::from_components(0, 0, 0, 0);
+            for (part, part_coverage) in [(&fill, fill_coverage), (&stroke, stroke_coverage)] {
+                if let Some(part) = part {
+                    let part = part.scaled(part_coverage);
+                    color.r += part.r;
+                    color.g += part.g;
+                    color.b += part.b;
+                    color.a += part.a;
+                }
+            }
+            if color.a > 0.0 {
+                pixels.set_pixel(x, y, &color);
+            }
+        }
+    }
+    pixels.bump_generation();
+}
@@ -0,0 +1,103 @@
+use crate::draw::point::Point;
+use crate::draw::point_pairs::PointPairIter;
+use crate::draw::tile_pixels::BoundingBox;
+
+/// Liang–Barsky: returns the part of the segment `p1`-`p2` that lies within `bb` expanded by `margin`
+/// as a `(t_from, t_to)` range, where 0 corresponds to `p1` and 1 to `p2`.
+pub fn clip_segment(p1: &Point, p2: &Point, bb: &BoundingBox, margin: f64) -> Option<(f64, f64)> {
+    let (x1, y1) = (f64::from(p1.x), f64::from(p1.y));
+    let (dx, dy) = (f64::from(p2.x) - x1, f64::from(p2.y) - y1);
+
+    let mut t_from = 0.0f64;
+    let mut t_to = 1.0f64;
+
+    let checks = [
+        (-dx, x1 - (f64::from(bb.min_x) - margin)),
+        (dx, f64::from(bb.max_x) + margin - x1),
+        (-dy, y1 - (f64::from(bb.min_y) - margin)),
+        (dy, f64::from(bb.max_y) + margin - y1),
+    ];
+
+    for (p, q) in checks {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t_from = t_from.max(t);
+            } else {
+                t_to = t_to.min(t);
+            }
+        }
+    }
+
+    if t_from > t_to {
+        None
+    } else {
+        Some((t_from, t_to))
+    }
+}
+
+/// Drops the parts of a contour that lie entirely above or below `bb`. Consecutive vertices on the same
+/// side of the box are collapsed into a single edge that stays on that side, so rows inside the box
+/// see exactly the same edges as before and the fill doesn't change.
+///
+/// Unlike Sutherland–Hodgman, this never moves vertices that are kept. Edges to the left or right of
+/// the box are left alone too, since the scanline fill pairs edges by their extent along each row and
+/// replacing them could change which spans get filled.
+pub fn clip_contour<'a>(points: PointPairIter<'a>, bb: &BoundingBox) -> PointPairIter<'a> {
+    let mut result = Vec::new();
+    let mut chain: Vec<Point> = Vec::new();
+
+    for (p1, p2) in points {
+        if chain.last() != Some(&p1) {
+            push_clipped_chain(&chain, bb, &mut result);
+            chain.clear();
+            chain.push(p1);
+        }
+        chain.push(p2);
+    }
+    push_clipped_chain(&chain, bb, &mut result);
+
+    Box::new(result.into_iter())
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum VerticalSide {
+    Above,
+    Inside,
+    Below,
+}
+
+fn push_clipped_chain(chain: &[Point], bb: &BoundingBox, result: &mut Vec<(Point, Point)>) {
+    let side = |p: &Point| {
+        if p.y < bb.min_y {
+            VerticalSide::Above
+        } else if p.y > bb.max_y {
+            VerticalSide::Below
+        } else {
+            VerticalSide::Inside
+        }
+    };
+
+    let mut prev: Option<&Point> = None;
+    for idx in 0..chain.len() {
+        let current = &chain[idx];
+        let is_inner_vertex = idx > 0 && idx + 1 < chain.len();
+        if is_inner_vertex {
+            let current_side = side(current);
+            if current_side != VerticalSide::Inside
+                && side(&chain[idx - 1]) == current_side
+                && side(&chain[idx + 1]) == current_side
+            {
+                continue;
+            }
+        }
+        if let Some(prev) = prev {
+            result.push((prev.clone(), current.clone()));
+        }
+        prev = Some(current);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::geodata::reader::{Multipolygon, OsmArea, OsmEntities, OsmEntity, Way};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{Style, StyledArea};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Replaces the style with colors derived from the feature IDs, which makes it easy to tell apart
+/// adjacent features when debugging geometry.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum DebugColorMode {
+    #[default]
+    Off,
+    /// Strokes every way and fills every closed way and multipolygon, ignoring the style entirely.
+    ById,
+}
+
+const DEBUG_FILL_OPACITY: f64 = 0.5;
+
+/// The faint gray of the ways and multipolygons that no style rule matches, see `RenderOptions::render_unstyled`.
+pub const UNSTYLED_COLOR: Color = Color {
+    r: 0x99,
+    g: 0x99,
+    b: 0x99,
+};
+const UNSTYLED_OPACITY: f64 = 0.6;
+
+/// A bright color that always stays the same for the same ID.
+pub fn debug_color(global_id: u64) -> Color {
+    // The SplitMix64 finalizer, so that consecutive IDs get unrelated colors.
+    let mut hash = global_id.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    hash ^= hash >> 31;
+
+    let hue = (hash & 0xFFFF) as f64 / 65536.0 * 6.0;
+    let saturation = 0.65 + ((hash >> 16) & 0xFF) as f64 / 255.0 * 0.3;
+    let value = 0.75 + ((hash >> 24) & 0xFF) as f64 / 255.0 * 0.2;
+
+    let chroma = value * saturation;
+    let second = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, second, 0.0),
+        1 => (second, chroma, 0.0),
+        2 => (0.0, chroma, second),
+        3 => (0.0, second, chroma),
+        4 => (second, 0.0, chroma),
+        _ => (chroma, 0.0, second),
+    };
+    let to_component = |c: f64| ((c + value - chroma) * f64::from(u8::MAX)).round() as u8;
+    Color {
+        r: to_component(r),
+        g: to_component(g),
+        b: to_component(b),
+    }
+}
+
+/// All ways and multipolygons of the tile styled with their debug colors, ordered by ID.
+pub fn debug_styled_areas<'a, 'wr>(entities: &'wr OsmEntities<'a>) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+    let mut result = entities
+        .ways
+        .iter()
+        .map(|way: &'wr Way<'a>| (StyledArea::Way(way), way.global_id(), way.is_area()))
+        .chain(
+            entities
+                .multipolygons
+                .iter()
+                .map(|mp: &'wr Multipolygon<'a>| (StyledArea::Multipolygon(mp), mp.global_id(), true)),
+        )
+        .collect::<Vec<_>>();
+    result.sort_by_key(|(_, global_id, _)| *global_id);
+
+    result
+        .into_iter()
+        .map(|(area, global_id, is_closed)| {
+            let color = debug_color(global_id);
+            let fill_color = if is_closed { Some(color.clone()) } else { None };
+            (
+                area,
+                Arc::new(flat_style(color, fill_color, None, Some(DEBUG_FILL_OPACITY))),
+            )
+        })
+        .collect()
+}
+
+/// The ways and multipolygons of the tile that aren't in `styled_areas`, outlined with a thin stroke of
+/// `UNSTYLED_COLOR`. Routes are left out, since they're only imported for the styles that draw them.
+pub fn unstyled_areas<'a, 'wr>(
+    entities: &'wr OsmEntities<'a>,
+    styled_areas: &[(StyledArea<'a, 'wr>, Arc<Style>)],
+) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+    let mut styled_ways = HashSet::new();
+    let mut styled_multipolygons = HashSet::new();
+    for (area, _) in styled_areas {
+        match area {
+            StyledArea::Way(way) => styled_ways.insert(way.global_id()),
+            StyledArea::Multipolygon(mp) => styled_multipolygons.insert(mp.global_id()),
+            StyledArea::Route(_) => false,
+        };
+    }
+
+    let style = Arc::new(flat_style(UNSTYLED_COLOR, None, Some(UNSTYLED_OPACITY), None));
+    entities
+        .ways
+        .iter()
+        .filter(|way| !styled_ways.contains(&way.global_id()))
+        .map(StyledArea::Way)
+        .chain(
+            entities
+                .multipolygons
+                .iter()
+                .filter(|mp| !styled_multipolygons.contains(&mp.global_id()))
+                .map(StyledArea::Multipolygon),
+        )
+        .map(|area| (area, style.clone()))
+        .collect()
+}
+
+fn flat_style(color: Color, fill_color: Option<Color>, opacity: Option<f64>, fill_opacity: Option<f64>) -> Style {
+    Style {
+        layer: None,
+        z_index: 0.0,
+        render_layer: None,
+
+        color: Some(color),
+        fill_color,
+        is_foreground_fill: false,
+        background_color: None,
+        opacity,
+        fill_opacity,
+
+        width: Some(1.0),
+        dashes: None,
+        line_cap: None,
+        line_join: None,
+
+        casing_color: None,
+        casing_width: None,
+        casing_dashes: None,
+        casing_line_cap: None,
+
+        line_decoration: None,
+        flow_arrows: None,
+
+        icon_image: None,
+        icon_allow_overlap: false,
+        fill_image: None,
+        fill_hatch: None,
+        symbol: None,
+        text_style: None,
+    }
+}
@@ -0,0 +1,139 @@
+use crate::draw::line::draw_lines;
+use crate::draw::point::Point;
+use crate::draw::point_pairs::PointPairIter;
+use crate::draw::tile_pixels::TilePixels;
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{FlowArrows, LineDecoration, TickSide};
+
+/// Places a tick every `spacing` pixels along the line, starting half a spacing away from its
+/// beginning. Each tick starts on the line and points `length` pixels away to the given side
+/// of the line direction.
+pub fn compute_ticks(points: PointPairIter<'_>, decoration: &LineDecoration, scale: f64) -> Vec<(Point, Point)> {
+    let length = decoration.length * scale;
+    let side_mul = match decoration.side {
+        TickSide::Left => 1.0,
+        TickSide::Right => -1.0,
+    };
+
+    let mut ticks = Vec::new();
+    walk_along(points, decoration.spacing * scale, |x, y, dir_x, dir_y| {
+        // The Y axis points down, so (dir_y, -dir_x) is to the left of the direction of travel.
+        let (normal_x, normal_y) = (side_mul * dir_y, -side_mul * dir_x);
+        ticks.push((to_point(x, y), to_point(x + normal_x * length, y + normal_y * length)));
+    });
+    ticks
+}
+
+/// A chevron pointing from its wings towards `tip`.
+#[derive(Debug, PartialEq)]
+pub struct FlowArrow {
+    pub tip: Point,
+    pub left_wing: Point,
+    pub right_wing: Point,
+}
+
+/// Places arrows along the line the same way `compute_ticks` places ticks. Every arrow points in the
+/// direction of the line, i.e. from its first node to its last one.
+pub fn compute_flow_arrows(points: PointPairIter<'_>, arrows: &FlowArrows, scale: f64) -> Vec<FlowArrow> {
+    let half_size = arrows.size * scale / 2.0;
+
+    let mut result = Vec::new();
+    walk_along(points, arrows.spacing * scale, |x, y, dir_x, dir_y| {
+        let (back_x, back_y) = (x - dir_x * half_size, y - dir_y * half_size);
+        result.push(FlowArrow {
+            tip: to_point(x + dir_x * half_size, y + dir_y * half_size),
+            left_wing: to_point(back_x + dir_y * half_size, back_y - dir_x * half_size),
+            right_wing: to_point(back_x - dir_y * half_size, back_y + dir_x * half_size),
+        });
+    });
+    result
+}
+
+// Calls `f` with the position and the unit direction of the line every `spacing` pixels, starting
+// half a spacing away from the beginning of the line.
+fn walk_along(points: PointPairIter<'_>, spacing: f64, mut f: impl FnMut(f64, f64, f64, f64)) {
+    let mut next_mark_at = spacing / 2.0;
+    let mut traveled = 0.0;
+
+    for (p1, p2) in points {
+        let segment_len = p1.dist(&p2);
+        if segment_len == 0.0 {
+            continue;
+        }
+
+        let (dir_x, dir_y) = (
+            f64::from(p2.x - p1.x) / segment_len,
+            f64::from(p2.y - p1.y) / segment_len,
+        );
+
+        while next_mark_at <= traveled + segment_len {
+            let offset = next_mark_at - traveled;
+            f(
+                f64::from(p1.x) + dir_x * offset,
+                f64::from(p1.y) + dir_y * offset,
+                dir_x,
+                dir_y,
+            );
+            next_mark_at += spacing;
+        }
+
+        traveled += segment_len;
+    }
+}
+
+fn to_point(x: f64, y: f64) -> Point {
+    Point {
+        x: x.round() as i32,
+        y: y.round() as i32,
+    }
+}
+
+pub fn draw_ticks(
+    points: PointPairIter<'_>,
+    decoration: &LineDecoration,
+    scale: f64,
+    width: f64,
+    color: &Color,
+    opacity: f64,
+    pixels: &mut TilePixels,
+) {
+    for tick in compute_ticks(points, decoration, scale) {
+        draw_lines(
+            Box::new(std::iter::once(tick)),
+            width,
+            color,
+            opacity,
+            &None,
+            &None,
+            &None,
+            false,
+            true,
+            pixels,
+        );
+    }
+}
+
+pub fn draw_flow_arrows(
+    points: PointPairIter<'_>,
+    arrows: &FlowArrows,
+    scale: f64,
+    color: &Color,
+    opacity: f64,
+    pixels: &mut TilePixels,
+) {
+    for arrow in compute_flow_arrows(points, arrows, scale) {
+        let chevron = vec![(arrow.left_wing, arrow.tip.clone()), (arrow.tip, arrow.right_wing)];
+        draw_lines(
+            Box::new(chevron.into_iter()),
+            scale,
+            color,
+            opacity,
+            &None,
+            &None,
+            &None,
+            false,
+            true,
+            pixels,
+        );
+    }
+}
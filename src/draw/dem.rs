@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::path::Path;
+
+/// A regular grid of elevations in meters in geographic coordinates. Rows go from north to south,
+/// and `west_lon`/`north_lat` are the outer edges of the top left cell.
+pub struct Dem {
+    width: usize,
+    height: usize,
+    west_lon: f64,
+    north_lat: f64,
+    cell_lon: f64,
+    cell_lat: f64,
+    /// NaN marks cells without data.
+    elevations: Vec<f32>,
+}
+
+impl fmt::Debug for Dem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Dem({}x{} cells of {}x{} degrees from ({}, {}))",
+            self.width, self.height, self.cell_lon, self.cell_lat, self.north_lat, self.west_lon
+        )
+    }
+}
+
+impl Dem {
+    pub fn new(
+        width: usize,
+        height: usize,
+        (north_lat, west_lon): (f64, f64),
+        (cell_lat, cell_lon): (f64, f64),
+        elevations: Vec<f32>,
+    ) -> Result<Dem> {
+        if width == 0 || height == 0 || elevations.len() != width * height {
+            bail!("Expected {}x{} elevations, got {}", width, height, elevations.len());
+        }
+        if cell_lat <= 0.0 || cell_lon <= 0.0 {
+            bail!("Invalid DEM cell size: {}x{} degrees", cell_lon, cell_lat);
+        }
+        Ok(Dem {
+            width,
+            height,
+            west_lon,
+            north_lat,
+            cell_lon,
+            cell_lat,
+            elevations,
+        })
+    }
+
+    /// Loads a single-band GeoTIFF in geographic coordinates. Only uncompressed, striped files are
+    /// supported, i.e. the ones produced by `gdal_translate -co COMPRESS=NONE -co TILED=NO`.
+    pub fn load_geotiff(path: &Path) -> Result<Dem> {
+        let bytes = std::fs::read(path).context(format!("Failed to read {}", path.to_string_lossy()))?;
+        parse_geotiff(&bytes).context(format!("Failed to parse {}", path.to_string_lossy()))
+    }
+
+    /// Bilinearly interpolated elevation, or `None` outside of the grid and next to
+    /// cells without data.
+    pub fn elevation_at(&self, lat: f64, lon: f64) -> Option<f64> {
+        let col = (lon - self.west_lon) / self.cell_lon;
+        let row = (self.north_lat - lat) / self.cell_lat;
+        if !(0.0..=self.width as f64).contains(&col) || !(0.0..=self.height as f64).contains(&row) {
+            return None;
+        }
+
+        // Interpolate between cell centers, clamping to the outermost ones at the edges.
+        let x = (col - 0.5).clamp(0.0, (self.width - 1) as f64);
+        let y = (row - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+        // Corners with zero weight are skipped, so that a cell center next to a hole still has data.
+        let lerp = |from: f64, to: f64, t: f64| if t == 0.0 { from } else { from + (to - from) * t };
+        let get = |x: usize, y: usize| f64::from(self.elevations[y * self.width + x]);
+        let top = lerp(get(x0, y0), get(x1, y0), fx);
+        let elevation = lerp(top, lerp(get(x0, y1), get(x1, y1), fx), fy);
+
+        if elevation.is_nan() {
+            None
+        } else {
+            Some(elevation)
+        }
+    }
+}
+
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_HEIGHT: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_STRIP_OFFSETS: u16 = 273;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_STRIP_BYTE_COUNTS: u16 = 279;
+const TAG_TILE_WIDTH: u16 = 322;
+const TAG_SAMPLE_FORMAT: u16 = 339;
+const TAG_MODEL_PIXEL_SCALE: u16 = 33550;
+const TAG_MODEL_TIEPOINT: u16 = 33922;
+const TAG_GDAL_NODATA: u16 = 42113;
+
+const SAMPLE_FORMAT_UINT: u32 = 1;
+const SAMPLE_FORMAT_INT: u32 = 2;
+const SAMPLE_FORMAT_FLOAT: u32 = 3;
+
+struct TiffBytes<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl TiffBytes<'_> {
+    fn get<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+        match self.bytes.get(offset..offset + N) {
+            Some(slice) => {
+                let mut result = [0; N];
+                result.copy_from_slice(slice);
+                if self.big_endian {
+                    result.reverse();
+                }
+                Ok(result)
+            }
+            None => bail!("Unexpected end of file at offset {}", offset),
+        }
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        self.get(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        self.get(offset).map(u32::from_le_bytes)
+    }
+
+    // Reads the values of an IFD entry as numbers, wherever they are stored.
+    fn entry_values(&self, entry_offset: usize) -> Result<Vec<f64>> {
+        let field_type = self.u16(entry_offset + 2)?;
+        let count = self.u32(entry_offset + 4)? as usize;
+        let value_size = match field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => bail!("Unsupported TIFF field type {}", field_type),
+        };
+        let values_offset = if value_size * count <= 4 {
+            entry_offset + 8
+        } else {
+            self.u32(entry_offset + 8)? as usize
+        };
+
+        (0..count)
+            .map(|idx| {
+                let offset = values_offset + idx * value_size;
+                Ok(match field_type {
+                    1 | 2 | 7 => f64::from(self.get::<1>(offset)?[0]),
+                    6 => f64::from(self.get::<1>(offset)?[0] as i8),
+                    3 => f64::from(self.u16(offset)?),
+                    8 => f64::from(self.u16(offset)? as i16),
+                    4 => f64::from(self.u32(offset)?),
+                    9 => f64::from(self.u32(offset)? as i32),
+                    11 => f64::from(self.get(offset).map(f32::from_le_bytes)?),
+                    12 => self.get(offset).map(f64::from_le_bytes)?,
+                    _ => {
+                        let (num, denom) = (self.u32(offset)?, self.u32(offset + 4)?);
+                        if field_type == 5 {
+                            f64::from(num) / f64::from(denom)
+                        } else {
+                            f64::from(num as i32) / f64::from(denom as i32)
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+fn parse_geotiff(bytes: &[u8]) -> Result<Dem> {
+    let big_endian = match bytes.get(..2) {
+        Some(b"II") => false,
+        Some(b"MM") => true,
+        _ => bail!("Not a TIFF file"),
+    };
+    let tiff = TiffBytes { bytes, big_endian };
+    if tiff.u16(2)? != 42 {
+        bail!("Not a classic TIFF file");
+    }
+
+    let ifd_offset = tiff.u32(4)? as usize;
+    let entry_count = tiff.u16(ifd_offset)? as usize;
+    let mut tags = std::collections::HashMap::new();
+    for idx in 0..entry_count {
+        let entry_offset = ifd_offset + 2 + idx * 12;
+        let tag = tiff.u16(entry_offset)?;
+        let values = tiff.entry_values(entry_offset)?;
+        tags.insert(tag, values);
+    }
+
+    let single = |tag, default: Option<u32>| match (tags.get(&tag).and_then(|v| v.first()), default) {
+        (Some(value), _) => Ok(*value as u32),
+        (None, Some(default)) => Ok(default),
+        (None, None) => bail!("Missing TIFF tag {}", tag),
+    };
+    let all = |tag| tags.get(&tag).context(format!("Missing TIFF tag {}", tag));
+
+    if tags.contains_key(&TAG_TILE_WIDTH) {
+        bail!("Tiled TIFF files are not supported");
+    }
+    if single(TAG_COMPRESSION, Some(1))? != 1 {
+        bail!("Compressed TIFF files are not supported");
+    }
+    if single(TAG_SAMPLES_PER_PIXEL, Some(1))? != 1 {
+        bail!("Only single-band TIFF files are supported");
+    }
+
+    let width = single(TAG_IMAGE_WIDTH, None)? as usize;
+    let height = single(TAG_IMAGE_HEIGHT, None)? as usize;
+    let bits_per_sample = single(TAG_BITS_PER_SAMPLE, Some(1))?;
+    let sample_format = single(TAG_SAMPLE_FORMAT, Some(SAMPLE_FORMAT_UINT))?;
+    // Checked up front, as a sample size of zero would never advance through the strips.
+    let sample_size = match (sample_format, bits_per_sample) {
+        (SAMPLE_FORMAT_UINT | SAMPLE_FORMAT_INT, 8 | 16 | 32) | (SAMPLE_FORMAT_FLOAT, 32 | 64) => {
+            (bits_per_sample / 8) as usize
+        }
+        _ => bail!(
+            "Unsupported sample format {} with {} bits per sample",
+            sample_format,
+            bits_per_sample
+        ),
+    };
+
+    let read_sample = |offset: usize| -> Result<f32> {
+        Ok(match (sample_format, bits_per_sample) {
+            (SAMPLE_FORMAT_UINT, 8) => f32::from(tiff.get::<1>(offset)?[0]),
+            (SAMPLE_FORMAT_INT, 8) => f32::from(tiff.get::<1>(offset)?[0] as i8),
+            (SAMPLE_FORMAT_UINT, 16) => f32::from(tiff.u16(offset)?),
+            (SAMPLE_FORMAT_INT, 16) => f32::from(tiff.u16(offset)? as i16),
+            (SAMPLE_FORMAT_UINT, 32) => tiff.u32(offset)? as f32,
+            (SAMPLE_FORMAT_INT, 32) => tiff.u32(offset)? as i32 as f32,
+            (SAMPLE_FORMAT_FLOAT, 32) => tiff.get(offset).map(f32::from_le_bytes)?,
+            (SAMPLE_FORMAT_FLOAT, 64) => tiff.get(offset).map(f64::from_le_bytes)? as f32,
+            _ => unreachable!(),
+        })
+    };
+
+    let nodata = tags
+        .get(&TAG_GDAL_NODATA)
+        .map(|chars| chars.iter().map(|&c| c as u8 as char).collect::<String>())
+        .and_then(|text| text.trim_end_matches('\0').trim().parse::<f32>().ok());
+
+    let mut elevations = Vec::with_capacity(width * height);
+    for (offset, byte_count) in all(TAG_STRIP_OFFSETS)?.iter().zip(all(TAG_STRIP_BYTE_COUNTS)?) {
+        let (offset, byte_count) = (*offset as usize, *byte_count as usize);
+        for sample_offset in (offset..offset + byte_count).step_by(sample_size) {
+            if elevations.len() == width * height {
+                break;
+            }
+            let elevation = read_sample(sample_offset)?;
+            elevations.push(if Some(elevation) == nodata { f32::NAN } else { elevation });
+        }
+    }
+
+    let scale = all(TAG_MODEL_PIXEL_SCALE)?;
+    let tiepoint = all(TAG_MODEL_TIEPOINT)?;
+    if scale.len() < 2 || tiepoint.len() < 6 {
+        bail!("Invalid georeferencing tags");
+    }
+    let (cell_lon, cell_lat) = (scale[0], scale[1]);
+    let west_lon = tiepoint[3] - tiepoint[0] * cell_lon;
+    let north_lat = tiepoint[4] + tiepoint[1] * cell_lat;
+
+    Dem::new(width, height, (north_lat, west_lon), (cell_lat, cell_lon), elevations)
+}
@@ -1,39 +1,175 @@
-use crate::draw::fill::{fill_contour, Filler};
+use crate::draw::backend::{DrawCommand, DrawType, OutputBackend};
+use crate::draw::circle::draw_circle;
+use crate::draw::clip::clip_contour;
+use crate::draw::debug_colors::{debug_styled_areas, unstyled_areas, DebugColorMode};
+use crate::draw::decoration::{draw_flow_arrows, draw_ticks};
+use crate::draw::feature_index::{FeatureId, FeatureIndex};
+use crate::draw::fill::{fill_contour, fill_contour_antialiased, to_closed_contour, Filler};
+use crate::draw::geojson_writer::{GeoJsonBackend, GeoJsonOptions};
+use crate::draw::graticule::{draw_graticule, GraticuleOptions};
+use crate::draw::hillshade::{draw_hillshade, DemSource};
+use crate::draw::icon::{EdgeWrap, Icon, ImageSampling, SamplingFilter};
 use crate::draw::icon_cache::IconCache;
-use crate::draw::labeler::Labeler;
+use crate::draw::jpeg_writer::{rgb_triples_to_jpeg, JpegOptions};
+use crate::draw::labelable::Labelable;
+use crate::draw::labeler::{thin_out_node_labels, LabelPlacement, LabelThinning, Labeler};
 use crate::draw::line::draw_lines;
-use crate::draw::png_writer::rgb_triples_to_png;
+use crate::draw::png_writer::{rgb16_triples_to_png, rgb_triples_to_png_with_profile, PngBitDepth, PngOptions};
 use crate::draw::point_pairs::PointPairCollection;
-use crate::draw::tile_pixels::{RgbTriples, TilePixels};
+use crate::draw::tile_pixels::{downscale_rgb_triples, Rgb16Triples, RgbTriples, RgbaColor, TilePixels};
+use crate::draw::TILE_SIZE;
+use crate::geodata::importer::EntityKind;
 use crate::geodata::reader::{Node, OsmEntities, OsmEntity};
-use crate::mapcss::styler::{Style, StyledArea, Styler, TextPosition};
-use crate::tile::Tile;
-use anyhow::Result;
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{
+    FillHatch, NodeSymbol, OrderKeyFn, RenderLayer, Scrim, Style, StyledArea, Styler, TextPosition,
+};
+use crate::tile::{coords_to_xy_tile_relative, Tile};
+use anyhow::{bail, Result};
+use std::error::Error;
+use std::fmt;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct Drawer {
     icon_cache: IconCache,
     labeler: Labeler,
+    options: RenderOptions,
 }
 
-#[derive(Clone, Eq, PartialEq, Hash)]
-enum DrawType {
-    Fill,
-    Stroke,
-    Casing,
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    /// When greater than 1, the tile is rendered at `supersample` times the requested resolution
+    /// and then box-filtered down, which smooths all edges uniformly at the cost of memory and time.
+    pub supersample: usize,
+    /// Clip geometry to the tile (plus a buffer) before rasterizing it, so that huge off-screen spans
+    /// aren't walked pixel by pixel.
+    pub clip_geometry: bool,
+    /// Blend the pixels on the outline of filled areas by how much of them is inside. Much cheaper than
+    /// supersampling, since interior pixels are filled just like without anti-aliasing.
+    pub antialias_fill_edges: bool,
+    pub png: PngOptions,
+    pub jpeg: JpegOptions,
+    pub geojson: GeoJsonOptions,
+    /// An optional coordinate grid drawn on top of all features and labels.
+    pub graticule: Option<GraticuleOptions>,
+    /// Terrain shading from an external elevation model, drawn under all features.
+    pub hillshade: Option<DemSource>,
+    pub debug_colors: DebugColorMode,
+    /// Outline the ways and multipolygons that no style rule matches in a faint gray, under everything the
+    /// style draws. Useful for finding the features a style is still missing. A style with a `*` rule
+    /// matches everything, so there's nothing left to outline.
+    pub render_unstyled: bool,
+    /// Record which way or multipolygon covers every pixel, see `TileRenderedPixels::feature_index`.
+    pub feature_index: bool,
+    /// Projected coordinates closer than this (in tile pixels, before scaling) to a tile edge are snapped
+    /// onto it, so that shapes ending at the seam between two tiles line up without hairline gaps.
+    ///
+    /// This is opt-in: the default of `0.0` snaps nothing and keeps the rendering of existing styles exactly
+    /// as it was, and something like `1.0` closes the gaps. Only vertices are snapped. An edge that crosses
+    /// the seam between two vertices is projected from the same vertices on both tiles, so it lines up
+    /// anyway.
+    pub edge_snap_epsilon: f64,
+    /// Draw fill patterns and icons `scale` times larger on scaled tiles, instead of keeping one image pixel
+    /// per tile pixel.
+    pub scale_images: bool,
+    /// How fill patterns are sampled. Only makes a difference when they're scaled.
+    pub pattern_sampling: ImageSampling,
+    /// How icons are sampled. Only makes a difference when they're scaled.
+    pub icon_sampling: ImageSampling,
+    /// Consecutive fills with the same style are composited as a single shape, so that translucent areas
+    /// of the same class (e.g. two overlapping parks) don't get darker where they overlap.
+    pub knockout_fills: bool,
+    /// Stop drawing a tile once it has taken this long, see `RenderTimedOut`. The deadline is checked between
+    /// draw commands, so a single huge feature can still overshoot it.
+    pub max_render_time: Option<Duration>,
+    /// Drops the less important node labels that are too close to each other at low zooms, before they get a
+    /// chance to collide.
+    pub label_thinning: Option<LabelThinning>,
+    /// Don't rasterize the text of labels, but return it with its position in `RenderOutput::labels`, for
+    /// front-ends that draw the labels themselves. Icons are still drawn. Labels still collide with each
+    /// other and with icons as if they were drawn, and the ones that lose aren't returned, so the front-end
+    /// doesn't need to check collisions itself.
+    pub label_anchors: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            supersample: 1,
+            clip_geometry: true,
+            antialias_fill_edges: false,
+            png: PngOptions::default(),
+            jpeg: JpegOptions::default(),
+            geojson: GeoJsonOptions::default(),
+            graticule: None,
+            hillshade: None,
+            debug_colors: DebugColorMode::Off,
+            render_unstyled: false,
+            feature_index: false,
+            edge_snap_epsilon: 0.0,
+            scale_images: false,
+            pattern_sampling: ImageSampling {
+                filter: SamplingFilter::Nearest,
+                wrap: EdgeWrap::Repeat,
+            },
+            icon_sampling: ImageSampling {
+                filter: SamplingFilter::Nearest,
+                wrap: EdgeWrap::Clamp,
+            },
+            knockout_fills: false,
+            max_render_time: None,
+            label_thinning: None,
+            label_anchors: false,
+        }
+    }
 }
 
 pub struct TileRenderedPixels {
     pub triples: RgbTriples,
     pub dimension: usize,
+    pub is_blank: bool,
+    /// Only present if `RenderOptions::feature_index` is set.
+    pub feature_index: Option<FeatureIndex>,
+    /// The tile hit `RenderOptions::max_render_time`, so it only has what was drawn before that.
+    pub timed_out: bool,
+}
+
+/// A tile from `Drawer::draw_tile_with_labels`.
+pub struct RenderOutput {
+    /// The encoded PNG.
+    pub image: Vec<u8>,
+    /// The labels that weren't rasterized and didn't collide with an earlier one, in the order they were
+    /// placed in. Only filled with `RenderOptions::label_anchors`.
+    pub labels: Vec<LabelPlacement>,
+}
+
+/// The error `Drawer::render` (and `draw_tile`/`draw_tile_jpeg`) returns when a tile hits
+/// `RenderOptions::max_render_time`. It still carries the encoded tile with whatever was drawn in time.
+#[derive(Debug)]
+pub struct RenderTimedOut {
+    pub partial_tile: Vec<u8>,
+}
+
+impl Error for RenderTimedOut {}
+
+impl fmt::Display for RenderTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the tile took too long to render")
+    }
 }
 
 impl Drawer {
     pub fn new(base_path: &Path) -> Drawer {
+        Drawer::with_options(base_path, RenderOptions::default())
+    }
+
+    pub fn with_options(base_path: &Path, options: RenderOptions) -> Drawer {
         Drawer {
             icon_cache: IconCache::new(base_path),
-            labeler: Labeler::default(),
+            labeler: Labeler::new(options.scale_images.then(|| options.icon_sampling.clone())),
+            options,
         }
     }
 
@@ -45,16 +181,58 @@ impl Drawer {
         scale: usize,
         styler: &Styler,
     ) -> Result<Vec<u8>> {
-        let rendered_pixels = self.draw_to_pixels(entities, tile, pixels, scale, styler);
+        let mut backend = RasterBackend::new(self, pixels, RasterFormat::Png(self.options.png.clone()));
+        self.render(entities, tile, scale, styler, &mut backend)
+    }
 
-        {
-            let _m = crate::perf_stats::measure("RGB triples to PNG");
-            rgb_triples_to_png(
-                &rendered_pixels.triples,
-                rendered_pixels.dimension,
-                rendered_pixels.dimension,
-            )
-        }
+    pub fn draw_tile_jpeg(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+    ) -> Result<Vec<u8>> {
+        let mut backend = RasterBackend::new(self, pixels, RasterFormat::Jpeg(self.options.jpeg.clone()));
+        self.render(entities, tile, scale, styler, &mut backend)
+    }
+
+    /// Same as `draw_tile`, but over a basemap image instead of a flat canvas, see `RasterBackend::with_underlay`.
+    pub fn draw_tile_with_underlay(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+        underlay: &Icon,
+    ) -> Result<Vec<u8>> {
+        let mut backend =
+            RasterBackend::new(self, pixels, RasterFormat::Png(self.options.png.clone())).with_underlay(underlay);
+        self.render(entities, tile, scale, styler, &mut backend)
+    }
+
+    /// Same as `draw_tile`, but also returns the labels, see `RenderOptions::label_anchors`.
+    pub fn draw_tile_with_labels(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+    ) -> Result<RenderOutput> {
+        let mut backend = RasterBackend::new(self, pixels, RasterFormat::Png(self.options.png.clone()));
+        let image = self.render(entities, tile, scale, styler, &mut backend)?;
+        Ok(RenderOutput {
+            image,
+            labels: backend.take_labels(),
+        })
+    }
+
+    /// The features of the tile as GeoJSON, see `GeoJsonBackend`.
+    pub fn draw_tile_geojson(&self, entities: &OsmEntities<'_>, tile: &Tile, styler: &Styler) -> Result<Vec<u8>> {
+        let mut backend = GeoJsonBackend::new(self.options.geojson.clone());
+        self.render(entities, tile, 1, styler, &mut backend)
     }
 
     pub fn draw_to_pixels(
@@ -65,91 +243,148 @@ impl Drawer {
         scale: usize,
         styler: &Styler,
     ) -> TileRenderedPixels {
-        {
-            let _m = crate::perf_stats::measure("Resetting TilePixels");
-            pixels.reset(&styler.canvas_fill_color);
+        let mut backend = RasterBackend::new(self, pixels, RasterFormat::Png(PngOptions::default()));
+        let timed_out = self
+            .emit_draw_commands(entities, tile, scale, styler, &mut backend)
+            .expect("A backend without an underlay never fails to begin a tile");
+        TileRenderedPixels {
+            timed_out,
+            ..backend.finish_pixels()
         }
+    }
 
-        let styled_areas = {
-            let _m = crate::perf_stats::measure("Style areas");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, false)
-        };
-
-        let float_scale = scale as f64;
+    /// Feeds all draw commands for the tile to `backend` and returns whatever it produces. The tile is
+    /// normalized first, so an `x` past the antimeridian wraps around and a row off the map is an error,
+    /// see `Tile::normalized`.
+    pub fn render(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        scale: usize,
+        styler: &Styler,
+        backend: &mut dyn OutputBackend,
+    ) -> Result<Vec<u8>> {
+        let tile = &tile.normalized()?;
+        let timed_out = self.emit_draw_commands(entities, tile, scale, styler, backend)?;
+        let output = backend.finish()?;
+        if timed_out {
+            return Err(RenderTimedOut { partial_tile: output }.into());
+        }
+        Ok(output)
+    }
 
-        let draw_areas_with_type = |pixels: &mut TilePixels, draw_type, use_multipolygons| {
-            self.draw_areas(
-                pixels,
-                &styled_areas,
-                tile,
-                float_scale,
-                draw_type,
-                use_multipolygons,
-                styler.use_caps_for_dashes,
-            );
+    // Returns whether the commands were cut short by `RenderOptions::max_render_time`, or the error of a
+    // backend that can't draw the tile at all.
+    fn emit_draw_commands(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        scale: usize,
+        styler: &Styler,
+        backend: &mut dyn OutputBackend,
+    ) -> Result<bool> {
+        let backend = &mut DeadlineBackend {
+            backend,
+            deadline: self.options.max_render_time.map(|max_time| Instant::now() + max_time),
+            timed_out: false,
         };
+        backend.begin_tile(tile, scale, styler)?;
 
-        {
-            let _m = crate::perf_stats::measure("Fill areas");
-            draw_areas_with_type(pixels, &DrawType::Fill, true);
-        }
-        {
-            let _m = crate::perf_stats::measure("Draw areas");
-            draw_areas_with_type(pixels, &DrawType::Casing, false);
-            draw_areas_with_type(pixels, &DrawType::Stroke, false);
-        }
-
-        {
-            let _m = crate::perf_stats::measure("Blend after areas");
-            pixels.blend_unfinished_pixels(false);
+        if let Some(hillshade) = self.options.hillshade.as_ref() {
+            let _m = crate::perf_stats::measure("Draw hillshade");
+            backend.draw_command(&DrawCommand::Hillshade(hillshade));
         }
 
-        let styled_areas_for_labels = {
-            let _m = crate::perf_stats::measure("Style area for labels");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, true)
+        let styled_areas = {
+            let _m = crate::perf_stats::measure("Style areas");
+            match self.options.debug_colors {
+                DebugColorMode::Off => styler.style_areas(
+                    entities.ways.iter(),
+                    entities.multipolygons.iter(),
+                    entities.routes.iter(),
+                    tile.zoom,
+                    false,
+                ),
+                DebugColorMode::ById => debug_styled_areas(entities),
+            }
         };
 
-        let styled_nodes = {
-            let _m = crate::perf_stats::measure("Style nodes");
-            styler.style_entities(entities.nodes.iter(), tile.zoom, true)
-        };
+        if self.options.render_unstyled && self.options.debug_colors == DebugColorMode::Off {
+            let _m = crate::perf_stats::measure("Draw unstyled areas");
+            for (area, style) in &unstyled_areas(entities, &styled_areas) {
+                // Multipolygons are only filled when styled, but here their rings are outlined too.
+                backend.draw_command(&DrawCommand::Area {
+                    area,
+                    style,
+                    draw_type: DrawType::Stroke,
+                });
+            }
+        }
 
-        {
-            let _m = crate::perf_stats::measure("Draw labels");
-            self.draw_labels(pixels, tile, float_scale, &styled_areas_for_labels, &styled_nodes);
+        let render_layers = match self.options.debug_colors {
+            DebugColorMode::Off => styler.render_layers.as_slice(),
+            DebugColorMode::ById => &[],
+        };
+        for (scrim, areas) in split_by_render_layer(&styled_areas, render_layers) {
+            if let Some(scrim) = scrim {
+                let _m = crate::perf_stats::measure("Draw scrims");
+                backend.draw_command(&DrawCommand::Scrim(scrim));
+            }
+            {
+                let _m = crate::perf_stats::measure("Fill areas");
+                emit_area_commands(backend, &areas, DrawType::Fill);
+            }
+            {
+                let _m = crate::perf_stats::measure("Draw areas");
+                emit_area_commands(backend, &areas, DrawType::Casing);
+                emit_area_commands(backend, &areas, DrawType::Stroke);
+            }
         }
 
-        {
-            let _m = crate::perf_stats::measure("Blend after labels");
-            pixels.blend_unfinished_pixels(true);
+        if self.options.debug_colors == DebugColorMode::Off && !backend.is_past_deadline() {
+            emit_label_commands(entities, tile, styler, self.options.label_thinning.as_ref(), backend);
         }
 
-        TileRenderedPixels {
-            triples: pixels.to_rgb_triples(),
-            dimension: pixels.dimension(),
+        if let Some(graticule) = self.options.graticule.as_ref() {
+            let _m = crate::perf_stats::measure("Draw graticule");
+            backend.draw_command(&DrawCommand::Graticule(graticule));
         }
+
+        Ok(backend.timed_out)
     }
 
-    fn draw_areas(
+    // Labels go to `labels` instead of the pixels with `RenderOptions::label_anchors`.
+    #[expect(clippy::too_many_arguments)]
+    fn label_one_entity<'e, E>(
         &self,
-        pixels: &mut TilePixels,
-        areas: &[(StyledArea<'_, '_>, Arc<Style>)],
+        entity: &E,
+        style: &Style,
         tile: &Tile,
         scale: f64,
-        draw_type: &DrawType,
-        use_multipolygons: bool,
-        use_caps_for_dashes: bool,
-    ) {
-        for (area, style) in areas {
-            match area {
-                StyledArea::Way(way) => {
-                    self.draw_one_area(pixels, tile, scale, *way, style, draw_type, use_caps_for_dashes);
-                }
-                StyledArea::Multipolygon(rel) if use_multipolygons => {
-                    self.draw_one_area(pixels, tile, scale, *rel, style, draw_type, use_caps_for_dashes);
-                }
-                _ => {}
-            }
+        default_text_position: TextPosition,
+        pixels: &mut TilePixels,
+        labels: &mut Vec<LabelPlacement>,
+        order_key: &OrderKeyFn,
+    ) where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let (labeler, icon_cache) = (&self.labeler, &self.icon_cache);
+        if !self.options.label_anchors {
+            labeler.label_entity(entity, style, tile, scale, icon_cache, default_text_position, pixels);
+            return;
+        }
+
+        let anchor = labeler.anchor_entity(entity, style, tile, scale, icon_cache, default_text_position, pixels);
+        if let Some((text, (x, y), font_size)) = anchor {
+            let supersample = self.options.supersample.max(1) as f64;
+            labels.push(LabelPlacement {
+                text: text.to_string(),
+                position: (x / supersample, y / supersample),
+                font_size: font_size / supersample,
+                z_index: style.z_index,
+                order_key: order_key(&entity.tags(), style, true),
+                priority: labels.len(),
+            });
         }
     }
 
@@ -165,7 +400,9 @@ impl Drawer {
     ) where
         A: OsmEntity<'e> + PointPairCollection<'e>,
     {
-        let points = area.to_point_pairs(tile, scale);
+        let edge_snap_epsilon = self.options.edge_snap_epsilon;
+        let points = area.to_snapped_point_pairs(tile, scale, edge_snap_epsilon);
+        let clip = self.options.clip_geometry;
         let float_or_one = |num: &Option<f64>| num.unwrap_or(1.0);
 
         let scale_dashes =
@@ -173,13 +410,38 @@ impl Drawer {
 
         match *draw_type {
             DrawType::Fill => {
+                let points = to_closed_contour(points, area.contour_kind());
+                let points = if clip {
+                    clip_contour(points, pixels.bb())
+                } else {
+                    points
+                };
                 let opacity = float_or_one(&style.fill_opacity);
-                if let Some(ref color) = style.fill_color {
+                let fill_contour = if self.options.antialias_fill_edges {
+                    fill_contour_antialiased
+                } else {
+                    fill_contour
+                };
+                if let Some(ref hatch) = style.fill_hatch {
+                    let tile_size = TILE_SIZE as f64 * scale;
+                    let filler = Filler::Hatch {
+                        hatch,
+                        background: style.fill_color.as_ref(),
+                        scale,
+                        origin: (f64::from(tile.x) * tile_size, f64::from(tile.y) * tile_size),
+                    };
+                    fill_contour(points, &filler, opacity, pixels);
+                } else if let Some(ref color) = style.fill_color {
                     fill_contour(points, &Filler::Color(color), opacity, pixels);
                 } else if let Some(ref icon_name) = style.fill_image {
                     let read_icon_cache = self.icon_cache.open_read_session(icon_name);
                     if let Some(Some(icon)) = read_icon_cache.get(icon_name) {
-                        fill_contour(points, &Filler::Image(icon), opacity, pixels);
+                        let filler = Filler::Image {
+                            icon,
+                            scale: if self.options.scale_images { scale } else { 1.0 },
+                            sampling: &self.options.pattern_sampling,
+                        };
+                        fill_contour(points, &filler, opacity, pixels);
                     }
                 }
             }
@@ -193,7 +455,9 @@ impl Drawer {
                             1.0,
                             &scale_dashes(&style.casing_dashes),
                             &style.casing_line_cap,
+                            &style.line_join,
                             use_caps_for_dashes,
+                            clip,
                             pixels,
                         );
                     }
@@ -201,62 +465,502 @@ impl Drawer {
             }
             DrawType::Stroke => {
                 if let Some(color) = style.color.as_ref() {
+                    let width = scale * float_or_one(&style.width);
+                    let opacity = float_or_one(&style.opacity);
                     draw_lines(
                         points,
-                        scale * float_or_one(&style.width),
+                        width,
                         color,
-                        float_or_one(&style.opacity),
+                        opacity,
                         &scale_dashes(&style.dashes),
                         &style.line_cap,
+                        &style.line_join,
                         use_caps_for_dashes,
+                        clip,
                         pixels,
                     );
+                    if let Some(decoration) = style.line_decoration.as_ref() {
+                        let points = area.to_snapped_point_pairs(tile, scale, edge_snap_epsilon);
+                        draw_ticks(points, decoration, scale, width, color, opacity, pixels);
+                    }
+                    if let Some(arrows) = style.flow_arrows.as_ref() {
+                        let points = area.to_snapped_point_pairs(tile, scale, edge_snap_epsilon);
+                        let arrow_color = arrows.color.as_ref().unwrap_or(color);
+                        draw_flow_arrows(points, arrows, scale, arrow_color, opacity, pixels);
+                    }
                 }
             }
         }
 
         pixels.bump_generation();
     }
+}
+
+// Drops all commands after the deadline, so that a slow tile stops as soon as the command that is being
+// drawn is done.
+struct DeadlineBackend<'b> {
+    backend: &'b mut dyn OutputBackend,
+    deadline: Option<Instant>,
+    timed_out: bool,
+}
+
+impl DeadlineBackend<'_> {
+    fn is_past_deadline(&mut self) -> bool {
+        if !self.timed_out {
+            self.timed_out = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        }
+        self.timed_out
+    }
+}
+
+impl OutputBackend for DeadlineBackend<'_> {
+    fn begin_tile(&mut self, tile: &Tile, scale: usize, styler: &Styler) -> Result<()> {
+        self.backend.begin_tile(tile, scale, styler)
+    }
+
+    fn draw_command(&mut self, command: &DrawCommand<'_, '_>) {
+        if !self.is_past_deadline() {
+            self.backend.draw_command(command);
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        self.backend.finish()
+    }
+}
+
+fn emit_label_commands(
+    entities: &OsmEntities<'_>,
+    tile: &Tile,
+    styler: &Styler,
+    label_thinning: Option<&LabelThinning>,
+    backend: &mut dyn OutputBackend,
+) {
+    let styled_areas_for_labels = {
+        let _m = crate::perf_stats::measure("Style area for labels");
+        styler.style_areas(
+            entities.ways.iter(),
+            entities.multipolygons.iter(),
+            entities.routes.iter(),
+            tile.zoom,
+            true,
+        )
+    };
+
+    let styled_nodes = {
+        let _m = crate::perf_stats::measure("Style nodes");
+        styler.style_entities(entities.nodes.iter(), tile.zoom, true)
+    };
+    let styled_nodes = match label_thinning {
+        Some(thinning) => thin_out_node_labels(styled_nodes, tile, thinning),
+        None => styled_nodes,
+    };
+
+    {
+        let _m = crate::perf_stats::measure("Draw node symbols");
+        for (node, style) in &styled_nodes {
+            if style.symbol.is_some() {
+                backend.draw_command(&DrawCommand::NodeSymbol { node, style });
+            }
+        }
+    }
+
+    {
+        let _m = crate::perf_stats::measure("Draw labels");
+        for (area, style) in &styled_areas_for_labels {
+            backend.draw_command(&DrawCommand::AreaLabel { area, style });
+        }
+        for (node, style) in &styled_nodes {
+            backend.draw_command(&DrawCommand::NodeLabel { node, style });
+        }
+    }
+}
+
+type StyledAreaRef<'s, 'a, 'wr> = &'s (StyledArea<'a, 'wr>, Arc<Style>);
+
+// The areas of every render layer from the bottom one to the top one, together with the scrim that goes
+// under them. The order of the areas within a layer is kept.
+fn split_by_render_layer<'s, 'a, 'wr>(
+    areas: &'s [(StyledArea<'a, 'wr>, Arc<Style>)],
+    render_layers: &'s [RenderLayer],
+) -> Vec<(Option<&'s Scrim>, Vec<StyledAreaRef<'s, 'a, 'wr>>)> {
+    let layer_name = |style: &'s Style| {
+        style
+            .render_layer
+            .as_deref()
+            .filter(|name| render_layers.iter().any(|layer| layer.name == *name))
+    };
+
+    // The default layer has a z-index of 0 and goes under the named layers with the same z-index.
+    let mut layers = vec![(0.0, None, None)];
+    layers.extend(
+        render_layers
+            .iter()
+            .map(|layer| (layer.z_index, Some(layer.name.as_str()), layer.scrim.as_ref())),
+    );
+    layers.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    layers
+        .into_iter()
+        .map(|(_, name, scrim)| {
+            let layer_areas = areas.iter().filter(|(_, style)| layer_name(style) == name).collect();
+            (scrim, layer_areas)
+        })
+        .collect()
+}
+
+fn emit_area_commands(backend: &mut dyn OutputBackend, areas: &[StyledAreaRef<'_, '_, '_>], draw_type: DrawType) {
+    for (area, style) in areas.iter().copied() {
+        let skip = match area {
+            // An `area=no` way is a line even if the style gives it a fill.
+            StyledArea::Way(way) => draw_type == DrawType::Fill && way.area_override() == Some(false),
+            StyledArea::Multipolygon(_) => draw_type != DrawType::Fill,
+            StyledArea::Route(_) => draw_type == DrawType::Fill,
+        };
+        if skip {
+            continue;
+        }
+        backend.draw_command(&DrawCommand::Area {
+            area,
+            style,
+            draw_type: draw_type.clone(),
+        });
+    }
+}
+
+fn draw_node_symbol(node: &Node<'_>, symbol: &NodeSymbol, tile: &Tile, scale: f64, pixels: &mut TilePixels) {
+    let (x, y) = coords_to_xy_tile_relative(node, tile);
+    draw_circle(
+        (x * scale, y * scale),
+        symbol.size / 2.0,
+        symbol.fill_color.as_ref(),
+        symbol.stroke_color.as_ref(),
+        symbol.stroke_width,
+        1.0,
+        scale,
+        pixels,
+    );
+}
+
+fn draw_scrim(scrim: &Scrim, pixels: &mut TilePixels) {
+    let color = RgbaColor::from_color(&scrim.color, scrim.opacity);
+    let bb = pixels.bb().clone();
+    for y in bb.min_y..=bb.max_y {
+        for x in bb.min_x..=bb.max_x {
+            pixels.set_pixel(x, y, &color);
+        }
+    }
+    pixels.bump_generation();
+}
+
+pub enum RasterFormat {
+    Png(PngOptions),
+    Jpeg(JpegOptions),
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+enum RasterPhase {
+    Areas,
+    Labels,
+    Overlays,
+}
+
+/// Rasterizes the draw commands into `TilePixels`, which are then encoded into `format`.
+pub struct RasterBackend<'d> {
+    drawer: &'d Drawer,
+    pixels: &'d mut TilePixels,
+    format: RasterFormat,
+    tile: Option<Tile>,
+    scale: f64,
+    supersample: usize,
+    use_caps_for_dashes: bool,
+    phase: RasterPhase,
+    underlay: Option<&'d Icon>,
+    labels: Vec<LabelPlacement>,
+    // The styler's, only used with `RenderOptions::label_anchors`.
+    label_order_key: Option<Arc<OrderKeyFn>>,
+    // Only tracked with `RenderOptions::knockout_fills`, and only if the previous command was a fill.
+    previous_fill_class: Option<FillClass>,
+}
+
+// What a fill looks like, regardless of its shape.
+#[derive(PartialEq)]
+struct FillClass {
+    layer: Option<i64>,
+    color: Option<Color>,
+    opacity: Option<f64>,
+    image: Option<String>,
+    hatch: Option<FillHatch>,
+}
+
+impl FillClass {
+    fn new(style: &Style) -> FillClass {
+        FillClass {
+            layer: style.layer,
+            color: style.fill_color.clone(),
+            opacity: style.fill_opacity,
+            image: style.fill_image.clone(),
+            hatch: style.fill_hatch.clone(),
+        }
+    }
+}
+
+impl<'d> RasterBackend<'d> {
+    pub fn new(drawer: &'d Drawer, pixels: &'d mut TilePixels, format: RasterFormat) -> RasterBackend<'d> {
+        RasterBackend {
+            drawer,
+            pixels,
+            format,
+            tile: None,
+            scale: 1.0,
+            supersample: 1,
+            use_caps_for_dashes: false,
+            phase: RasterPhase::Areas,
+            underlay: None,
+            labels: Vec::new(),
+            label_order_key: None,
+            previous_fill_class: None,
+        }
+    }
+
+    /// Features are drawn over `underlay` instead of just the canvas, see `TilePixels::draw_underlay`. It has
+    /// to be as large as the requested tile (before supersampling), otherwise `begin_tile` fails.
+    pub fn with_underlay(self, underlay: &'d Icon) -> RasterBackend<'d> {
+        RasterBackend {
+            underlay: Some(underlay),
+            ..self
+        }
+    }
+
+    fn check_underlay(&self) -> Result<()> {
+        if let Some(underlay) = self.underlay {
+            let tile_size = self.pixels.dimension() / self.supersample;
+            if (underlay.width, underlay.height) != (tile_size, tile_size) {
+                bail!(
+                    "The underlay is {}x{} pixels, but the tile is {}x{}",
+                    underlay.width,
+                    underlay.height,
+                    tile_size,
+                    tile_size
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The labels of the last tile, see `RenderOptions::label_anchors`.
+    pub fn take_labels(&mut self) -> Vec<LabelPlacement> {
+        std::mem::take(&mut self.labels)
+    }
+
+    /// Same as `finish`, but returns the pixels without encoding them.
+    pub fn finish_pixels(&mut self) -> TileRenderedPixels {
+        let (triples, dimension) = self.finish_triples(TilePixels::to_rgb_triples);
+        let feature_index = self.pixels.to_feature_index().map(|index| match self.supersample {
+            1 => index,
+            factor => index.downscale(factor),
+        });
+        TileRenderedPixels {
+            triples,
+            dimension,
+            is_blank: self.pixels.is_blank(),
+            feature_index,
+            timed_out: false,
+        }
+    }
+
+    fn finish_rgb16_pixels(&mut self) -> (Rgb16Triples, usize) {
+        self.finish_triples(TilePixels::to_rgb16_triples)
+    }
+
+    fn finish_triples<T>(&mut self, to_triples: impl Fn(&TilePixels) -> Vec<(T, T, T)>) -> (Vec<(T, T, T)>, usize)
+    where
+        T: Copy + Into<u32> + TryFrom<u32>,
+    {
+        self.advance_to(RasterPhase::Overlays);
+
+        let mut triples = to_triples(self.pixels);
+        let mut dimension = self.pixels.dimension();
+
+        if self.supersample > 1 {
+            let _m = crate::perf_stats::measure("Downscaling supersampled pixels");
+            triples = downscale_rgb_triples(&triples, dimension, self.supersample);
+            dimension /= self.supersample;
+        }
+
+        (triples, dimension)
+    }
+
+    // Semi-transparent pixels are blended once all areas and then once all labels are drawn.
+    fn advance_to(&mut self, phase: RasterPhase) {
+        if self.phase < RasterPhase::Labels && phase >= RasterPhase::Labels {
+            let _m = crate::perf_stats::measure("Blend after areas");
+            self.pixels.blend_unfinished_pixels(false);
+        }
+        if self.phase < RasterPhase::Overlays && phase >= RasterPhase::Overlays {
+            let _m = crate::perf_stats::measure("Blend after labels");
+            self.pixels.blend_unfinished_pixels(true);
+        }
+        self.phase = self.phase.max(phase);
+    }
+}
+
+impl OutputBackend for RasterBackend<'_> {
+    fn begin_tile(&mut self, tile: &Tile, scale: usize, styler: &Styler) -> Result<()> {
+        self.supersample = self.drawer.options.supersample.max(1);
+        let scale = scale * self.supersample;
+
+        if self.pixels.dimension() != TILE_SIZE * scale {
+            let _m = crate::perf_stats::measure("Re-scaling TilePixels");
+            *self.pixels = TilePixels::new(scale);
+        }
 
-    fn draw_labels(
-        &self,
-        pixels: &mut TilePixels,
-        tile: &Tile,
-        scale: f64,
-        areas: &[(StyledArea<'_, '_>, Arc<Style>)],
-        nodes: &[(&Node<'_>, Arc<Style>)],
-    ) {
         {
-            let _m = crate::perf_stats::measure("Label areas");
-            for (area, style) in areas {
+            let _m = crate::perf_stats::measure("Resetting TilePixels");
+            self.pixels.reset(&styler.canvas_fill_color);
+            self.pixels.set_feature_index_enabled(self.drawer.options.feature_index);
+        }
+        self.check_underlay()?;
+        if let Some(underlay) = self.underlay {
+            let _m = crate::perf_stats::measure("Draw underlay");
+            self.pixels.draw_underlay(underlay);
+        }
+
+        self.tile = Some(tile.clone());
+        self.scale = scale as f64;
+        self.use_caps_for_dashes = styler.use_caps_for_dashes;
+        self.phase = RasterPhase::Areas;
+        self.labels.clear();
+        self.label_order_key = Some(styler.order_key_fn());
+        self.previous_fill_class = None;
+        Ok(())
+    }
+
+    fn draw_command(&mut self, command: &DrawCommand<'_, '_>) {
+        let phase = match command {
+            DrawCommand::Hillshade(_) | DrawCommand::Scrim(_) | DrawCommand::Area { .. } => RasterPhase::Areas,
+            DrawCommand::AreaLabel { .. } | DrawCommand::NodeSymbol { .. } | DrawCommand::NodeLabel { .. } => {
+                RasterPhase::Labels
+            }
+            DrawCommand::Graticule(_) => RasterPhase::Overlays,
+        };
+        self.advance_to(phase);
+
+        let drawer = self.drawer;
+        let fill_class = match command {
+            DrawCommand::Area {
+                style,
+                draw_type: DrawType::Fill,
+                ..
+            } if drawer.options.knockout_fills => Some(FillClass::new(style)),
+            _ => None,
+        };
+        let continues_fill_class = fill_class.is_some() && fill_class == self.previous_fill_class;
+        self.previous_fill_class = fill_class;
+
+        let tile = self.tile.as_ref().expect("begin_tile() must be called before drawing");
+        let (scale, pixels) = (self.scale, &mut *self.pixels);
+
+        match command {
+            DrawCommand::Hillshade(source) => draw_hillshade(source, tile, scale as usize, pixels),
+            DrawCommand::Scrim(scrim) => draw_scrim(scrim, pixels),
+            DrawCommand::Area { area, style, draw_type } => {
+                let caps = self.use_caps_for_dashes;
+                let (kind, global_id) = match area {
+                    StyledArea::Way(way) => (EntityKind::Way, way.global_id()),
+                    StyledArea::Multipolygon(rel) => (EntityKind::Relation, rel.global_id()),
+                    StyledArea::Route(route) => (EntityKind::Relation, route.global_id()),
+                };
+                pixels.set_current_feature(Some(FeatureId { kind, global_id }));
+                if continues_fill_class {
+                    pixels.merge_with_previous_generation();
+                }
                 match area {
-                    StyledArea::Way(way) => self.labeler.label_entity(
-                        *way,
+                    StyledArea::Way(way) => drawer.draw_one_area(pixels, tile, scale, *way, style, draw_type, caps),
+                    StyledArea::Multipolygon(rel) => {
+                        drawer.draw_one_area(pixels, tile, scale, *rel, style, draw_type, caps)
+                    }
+                    StyledArea::Route(route) => {
+                        drawer.draw_one_area(pixels, tile, scale, *route, style, draw_type, caps)
+                    }
+                }
+                pixels.set_current_feature(None);
+            }
+            DrawCommand::AreaLabel { area, style } => {
+                let labels = &mut self.labels;
+                let order_key = self
+                    .label_order_key
+                    .as_deref()
+                    .expect("begin_tile() sets the order key");
+                match area {
+                    StyledArea::Way(way) => {
+                        drawer.label_one_entity(*way, style, tile, scale, TextPosition::Line, pixels, labels, order_key)
+                    }
+                    StyledArea::Multipolygon(rel) => drawer.label_one_entity(
+                        *rel,
                         style,
                         tile,
                         scale,
-                        &self.icon_cache,
-                        TextPosition::Line,
+                        TextPosition::Center,
                         pixels,
+                        labels,
+                        order_key,
                     ),
-                    StyledArea::Multipolygon(rel) => self.labeler.label_entity(
-                        *rel,
+                    StyledArea::Route(route) => drawer.label_one_entity(
+                        *route,
                         style,
                         tile,
                         scale,
-                        &self.icon_cache,
-                        TextPosition::Center,
+                        TextPosition::Line,
                         pixels,
+                        labels,
+                        order_key,
                     ),
                 }
             }
+            DrawCommand::NodeSymbol { node, style } => {
+                if let Some(symbol) = style.symbol.as_ref() {
+                    draw_node_symbol(node, symbol, tile, scale, pixels);
+                }
+            }
+            DrawCommand::NodeLabel { node, style } => drawer.label_one_entity(
+                *node,
+                style,
+                tile,
+                scale,
+                TextPosition::Center,
+                pixels,
+                &mut self.labels,
+                self.label_order_key
+                    .as_deref()
+                    .expect("begin_tile() sets the order key"),
+            ),
+            DrawCommand::Graticule(graticule) => draw_graticule(graticule, tile, scale, &drawer.labeler, pixels),
         }
+    }
 
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        if let RasterFormat::Png(PngOptions {
+            bit_depth: PngBitDepth::Sixteen,
+            color_profile,
+        }) = &self.format
         {
-            let _m = crate::perf_stats::measure("Label nodes");
-            for &(node, ref style) in nodes {
-                self.labeler
-                    .label_entity(node, style, tile, scale, &self.icon_cache, TextPosition::Center, pixels);
+            let color_profile = color_profile.clone();
+            let (triples, dimension) = self.finish_rgb16_pixels();
+            let _m = crate::perf_stats::measure("RGB triples to PNG");
+            return rgb16_triples_to_png(&triples, dimension, dimension, &color_profile);
+        }
+
+        let rendered_pixels = self.finish_pixels();
+        let (triples, dimension) = (&rendered_pixels.triples, rendered_pixels.dimension);
+        match &self.format {
+            RasterFormat::Png(options) => {
+                let _m = crate::perf_stats::measure("RGB triples to PNG");
+                rgb_triples_to_png_with_profile(triples, dimension, dimension, &options.color_profile)
+            }
+            RasterFormat::Jpeg(options) => {
+                let _m = crate::perf_stats::measure("RGB triples to JPEG");
+                rgb_triples_to_jpeg(triples, dimension, dimension, options)
             }
         }
     }
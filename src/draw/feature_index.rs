@@ -0,0 +1,55 @@
+use crate::geodata::importer::EntityKind;
+
+/// Identifies a drawn way or multipolygon. Ways and relations have separate ID spaces in OSM, so the
+/// ID alone isn't enough.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FeatureId {
+    pub kind: EntityKind,
+    pub global_id: u64,
+}
+
+/// The feature drawn last (so the topmost one) on every pixel of a tile, including the full width of
+/// strokes and casings. Labels aren't recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureIndex {
+    dimension: usize,
+    features: Vec<Option<FeatureId>>,
+}
+
+impl FeatureIndex {
+    /// Takes the features of a square tile row by row, the same order as the RGB triples.
+    pub fn new(dimension: usize, features: Vec<Option<FeatureId>>) -> FeatureIndex {
+        assert_eq!(features.len(), dimension * dimension);
+        FeatureIndex { dimension, features }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<FeatureId> {
+        self.features[y * self.dimension + x]
+    }
+
+    pub fn features(&self) -> &[Option<FeatureId>] {
+        &self.features
+    }
+
+    /// Picks the feature of the central pixel of every `factor`x`factor` block, as IDs can't be averaged.
+    pub fn downscale(&self, factor: usize) -> FeatureIndex {
+        let dimension = self.dimension / factor;
+        self.resample(dimension, |x, y| (x * factor + factor / 2, y * factor + factor / 2))
+    }
+
+    /// Builds a `dimension`x`dimension` index where every pixel gets the feature at `to_source(x, y)`.
+    pub fn resample(&self, dimension: usize, to_source: impl Fn(usize, usize) -> (usize, usize)) -> FeatureIndex {
+        let mut features = Vec::with_capacity(dimension * dimension);
+        for y in 0..dimension {
+            for x in 0..dimension {
+                let (source_x, source_y) = to_source(x, y);
+                features.push(self.get(source_x, source_y));
+            }
+        }
+        FeatureIndex { dimension, features }
+    }
+}
@@ -106,7 +106,35 @@ impl Rasterizer {
         self.draw_quad(m012_x, m012_y, m12_x, m12_y, x2, y2);
     }
 
-    pub fn save_to_figure(&self, pixels: &mut TilePixels) -> bool {
+    /// With `smooth`, the edges of the glyphs are softened a bit before they are drawn.
+    pub fn save_to_figure(&self, pixels: &mut TilePixels, allow_overlap: bool, smooth: bool) -> bool {
+        self.for_each_figure_pixel(smooth, |x, y, total| {
+            pixels.set_label_pixel(x, y, &RgbaColor::from_color(&self.color, total), allow_overlap)
+        })
+    }
+
+    /// Same as `save_to_figure`, but the pixels only keep other labels away, see `TilePixels::reserve_label_pixel`.
+    pub fn reserve_in_figure(&self, pixels: &mut TilePixels, allow_overlap: bool, smooth: bool) -> bool {
+        self.for_each_figure_pixel(smooth, |x, y, _| pixels.reserve_label_pixel(x, y, allow_overlap))
+    }
+
+    fn for_each_figure_pixel(&self, smooth: bool, mut set_pixel: impl FnMut(i32, i32, f64) -> bool) -> bool {
+        if !smooth {
+            return self.for_each_covered_pixel(set_pixel);
+        }
+
+        let mut coverage = BTreeMap::new();
+        self.for_each_covered_pixel(|x, y, total| {
+            coverage.insert((y, x), total);
+            true
+        });
+        smooth_coverage(&coverage)
+            .into_iter()
+            .all(|((y, x), total)| set_pixel(x, y, total))
+    }
+
+    // Row by row, stops as soon as `f` returns false.
+    fn for_each_covered_pixel(&self, mut f: impl FnMut(i32, i32, f64) -> bool) -> bool {
         for (y, stripe) in &self.stripes {
             let cur_a = stripe.a.iter().collect();
             let cur_s = stripe.s.iter().collect();
@@ -138,7 +166,7 @@ impl Rasterizer {
             for x in x_min..=x_max {
                 s_acc += extract_val(&cur_s, &mut s_idx, x);
                 let total = (extract_val(&cur_a, &mut a_idx, x) + s_acc).min(1.0);
-                if total > 0.0 && !pixels.set_label_pixel(x, *y, &RgbaColor::from_color(&self.color, total)) {
+                if total > 0.0 && !f(x, *y, total) {
                     return false;
                 }
             }
@@ -147,3 +175,22 @@ impl Rasterizer {
         true
     }
 }
+
+// Mostly keeps the pixel itself, so that thin strokes don't fade.
+const SMOOTHING_KERNEL: [[f64; 3]; 3] = [[1.0, 2.0, 1.0], [2.0, 12.0, 2.0], [1.0, 2.0, 1.0]];
+
+// Lightly blurs the coverage, which softens the edges of small text. Nothing spreads farther than one pixel
+// from the glyphs.
+fn smooth_coverage(coverage: &BTreeMap<(i32, i32), f64>) -> BTreeMap<(i32, i32), f64> {
+    let total_weight = SMOOTHING_KERNEL.iter().flatten().sum::<f64>();
+    let mut smoothed = BTreeMap::new();
+    // The kernel is symmetric, so spreading every pixel over its neighbours is the same as convolving.
+    for (&(y, x), &total) in coverage {
+        for (dy, row) in (-1..=1).zip(SMOOTHING_KERNEL.iter()) {
+            for (dx, weight) in (-1..=1).zip(row.iter()) {
+                *smoothed.entry((y + dy, x + dx)).or_insert(0.0) += total * weight / total_weight;
+            }
+        }
+    }
+    smoothed
+}
@@ -34,15 +34,51 @@ impl TextPlacer {
     where
         E: Labelable + OsmEntity<'e>,
     {
-        let font_size = match text_style.font_size {
-            Some(font_size) => font_size * global_scale,
-            _ => return true,
-        };
+        match self.rasterize(on, text_style, tile, global_scale, y_offset, default_text_position) {
+            Some(rasterizer) => {
+                let _m = crate::perf_stats::measure("Save glyphs to figure");
+                rasterizer.save_to_figure(pixels, text_style.allow_overlap, text_style.smoothing)
+            }
+            None => true,
+        }
+    }
 
-        let text_to_draw = match on.tags().get_by_key(&text_style.text) {
-            Some(text_to_draw) => text_to_draw,
-            _ => return true,
-        };
+    /// Same as `place`, but the text only takes up room for the labels after it and isn't drawn, for labels
+    /// that are returned by `anchor` instead.
+    pub fn reserve<'e, E>(
+        &self,
+        on: &E,
+        text_style: &TextStyle,
+        tile: &Tile,
+        global_scale: f64,
+        y_offset: usize,
+        default_text_position: TextPosition,
+        pixels: &mut TilePixels,
+    ) -> bool
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        match self.rasterize(on, text_style, tile, global_scale, y_offset, default_text_position) {
+            Some(rasterizer) => rasterizer.reserve_in_figure(pixels, text_style.allow_overlap, text_style.smoothing),
+            None => true,
+        }
+    }
+
+    // Returns `None` if there's nothing to draw.
+    fn rasterize<'e, E>(
+        &self,
+        on: &E,
+        text_style: &TextStyle,
+        tile: &Tile,
+        global_scale: f64,
+        y_offset: usize,
+        default_text_position: TextPosition,
+    ) -> Option<Rasterizer>
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let font_size = text_style.font_size? * global_scale;
+        let text_to_draw = on.tags().get_by_key(&text_style.text)?;
 
         let text_pos = text_style.text_position.as_ref().unwrap_or(&default_text_position);
 
@@ -60,7 +96,7 @@ impl TextPlacer {
             TextPosition::Line => {
                 if let Some(mut points) = on.get_waypoints(tile, global_scale) {
                     if points.len() < 2 {
-                        return true;
+                        return None;
                     }
                     if points[0].x > points.iter().last().unwrap().x {
                         points.reverse();
@@ -74,7 +110,7 @@ impl TextPlacer {
                         .sum();
 
                     if glyphs.total_width > total_way_length {
-                        return true;
+                        return None;
                     }
 
                     let mut cur_dist = (total_way_length - glyphs.total_width) / 2.0;
@@ -110,61 +146,118 @@ impl TextPlacer {
                 }
             }
             TextPosition::Center => {
-                if let Some((center_x, center_y)) = on.get_label_position(tile, global_scale) {
-                    let mut glyph_rows = Vec::new();
-                    let mut current_row = Vec::new();
-                    let mut current_row_width = 0.0;
-                    let mut max_row_width = 0.0;
-
-                    for (idx, glyph) in glyphs.glyphs.iter().enumerate() {
-                        current_row.push(glyph);
-                        current_row_width += glyph.width;
-                        let is_last_glyph = idx + 1 == glyphs.glyphs.len();
-                        let should_break =
-                            glyph.ch.is_whitespace() && (current_row_width + glyph.width > MAX_TEXT_WIDTH);
-                        if !current_row.is_empty() && (should_break || is_last_glyph) {
-                            glyph_rows.push((current_row.clone(), current_row_width));
-                            if current_row_width > max_row_width {
-                                max_row_width = current_row_width;
-                            }
-                            current_row.clear();
-                            current_row_width = 0.0;
-                        }
-                    }
+                if let Some(center) = on.get_label_position(tile, global_scale) {
+                    self.rasterize_centered(&glyphs, center, y_offset, scale, &mut rasterizer);
+                }
+            }
+        }
 
-                    let row_height = vm.ascent - vm.descent + vm.line_gap;
-                    let total_height = row_height * glyph_rows.len() as f64;
+        Some(rasterizer)
+    }
 
-                    let mut cur_y = center_y;
-                    if y_offset > 0 {
-                        cur_y += y_offset as f64;
-                    } else {
-                        cur_y -= total_height / 2.0;
-                    }
+    /// Places `text` centered at a given point of the tile, for labels that don't belong to any OSM entity.
+    pub fn place_at(
+        &self,
+        text: &str,
+        center: (f64, f64),
+        font_size: f64,
+        color: &Color,
+        allow_overlap: bool,
+        pixels: &mut TilePixels,
+    ) -> bool {
+        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
+        let glyphs = self.text_to_glyphs(text, scale);
+        let mut rasterizer = Rasterizer::new(color);
+        self.rasterize_centered(&glyphs, center, 0, scale, &mut rasterizer);
+        rasterizer.save_to_figure(pixels, allow_overlap, false)
+    }
 
-                    for (row, row_width) in &glyph_rows {
-                        let mut cur_x = center_x - row_width / 2.0;
-                        for glyph in row.iter() {
-                            let baseline = cur_y + vm.ascent;
-                            let x_offset = cur_x;
-                            let tr = |point: &(f64, f64)| {
-                                let (x, y) = point;
-                                (x_offset + x, baseline - y)
-                            };
-                            {
-                                let _m = crate::perf_stats::measure("Rasterize glyph (center)");
-                                glyph.rasterize(&mut rasterizer, scale, tr);
-                            }
-                            cur_x += glyph.width;
-                        }
-                        cur_y += row_height;
-                    }
+    /// Where `place` would put the text and what it would say, without rasterizing anything: the text, the
+    /// center of the text in tile pixels and the font size. Labels along a line are centered on the middle of
+    /// the way. Collisions with other labels aren't checked here, see `reserve`.
+    pub fn anchor<'e, E>(
+        &self,
+        on: &E,
+        text_style: &TextStyle,
+        tile: &Tile,
+        global_scale: f64,
+        y_offset: usize,
+        default_text_position: TextPosition,
+    ) -> Option<(&'e str, (f64, f64), f64)>
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let font_size = text_style.font_size? * global_scale;
+        let text = on.tags().get_by_key(&text_style.text)?;
+        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
+        let glyphs = self.text_to_glyphs(text, scale);
+
+        let position = match text_style.text_position.as_ref().unwrap_or(&default_text_position) {
+            TextPosition::Line => {
+                let points = on.get_waypoints(tile, global_scale)?;
+                if points.len() < 2 {
+                    return None;
+                }
+                let total_way_length = points.windows(2).map(|w| w[0].dist(&w[1])).sum::<f64>();
+                if glyphs.total_width > total_way_length {
+                    return None;
                 }
+                let way_pos = compute_way_position(&points, total_way_length / 2.0);
+                (way_pos.x, way_pos.y)
             }
+            TextPosition::Center => {
+                let (center_x, center_y) = on.get_label_position(tile, global_scale)?;
+                let (top_y, total_height) = self.centered_text_top(&glyphs, center_y, y_offset, scale);
+                (center_x, top_y + total_height / 2.0)
+            }
+        };
+        Some((text, position, font_size))
+    }
+
+    fn rasterize_centered(
+        &self,
+        glyphs: &Glyphs,
+        (center_x, center_y): (f64, f64),
+        y_offset: usize,
+        scale: f64,
+        rasterizer: &mut Rasterizer,
+    ) {
+        let vm = self.get_v_metrics(scale);
+        let row_height = vm.ascent - vm.descent + vm.line_gap;
+        let (mut cur_y, _) = self.centered_text_top(glyphs, center_y, y_offset, scale);
+
+        for (row, row_width) in &split_into_rows(glyphs) {
+            let mut cur_x = center_x - row_width / 2.0;
+            for glyph in row.iter() {
+                let baseline = cur_y + vm.ascent;
+                let x_offset = cur_x;
+                let tr = |point: &(f64, f64)| {
+                    let (x, y) = point;
+                    (x_offset + x, baseline - y)
+                };
+                {
+                    let _m = crate::perf_stats::measure("Rasterize glyph (center)");
+                    glyph.rasterize(rasterizer, scale, tr);
+                }
+                cur_x += glyph.width;
+            }
+            cur_y += row_height;
         }
+    }
+
+    // The top of the first row of centered text and the height of all rows. Text under an icon starts
+    // `y_offset` below the center, and is centered vertically otherwise.
+    fn centered_text_top(&self, glyphs: &Glyphs, center_y: f64, y_offset: usize, scale: f64) -> (f64, f64) {
+        let vm = self.get_v_metrics(scale);
+        let row_height = vm.ascent - vm.descent + vm.line_gap;
+        let total_height = row_height * split_into_rows(glyphs).len() as f64;
 
-        let _m = crate::perf_stats::measure("Save glyphs to figure");
-        rasterizer.save_to_figure(pixels)
+        let top_y = if y_offset > 0 {
+            center_y + y_offset as f64
+        } else {
+            center_y - total_height / 2.0
+        };
+        (top_y, total_height)
     }
 
     fn text_to_glyphs(&self, text: &str, scale: f64) -> Glyphs {
@@ -253,6 +346,26 @@ struct Glyphs {
     total_width: f64,
 }
 
+// Long texts are broken into rows at whitespace, every row with its width.
+fn split_into_rows(glyphs: &Glyphs) -> Vec<(Vec<&Glyph>, f64)> {
+    let mut glyph_rows = Vec::new();
+    let mut current_row = Vec::new();
+    let mut current_row_width = 0.0;
+
+    for (idx, glyph) in glyphs.glyphs.iter().enumerate() {
+        current_row.push(glyph);
+        current_row_width += glyph.width;
+        let is_last_glyph = idx + 1 == glyphs.glyphs.len();
+        let should_break = glyph.ch.is_whitespace() && (current_row_width + glyph.width > MAX_TEXT_WIDTH);
+        if !current_row.is_empty() && (should_break || is_last_glyph) {
+            glyph_rows.push((current_row.clone(), current_row_width));
+            current_row.clear();
+            current_row_width = 0.0;
+        }
+    }
+    glyph_rows
+}
+
 fn get_angle(points: &[Point], start_idx: usize) -> f64 {
     let from = &points[start_idx];
     let to = &points[start_idx + 1];
@@ -276,7 +389,7 @@ fn compute_way_position(points: &[Point], advance_by: f64) -> WayPosition {
             let from = &points[point_idx];
             let to = &points[point_idx + 1];
             let ratio = to_travel / from.dist(to);
-            let coord_dist = |from_c, to_c| (f64::from(from_c) + (f64::from(to_c - from_c) * ratio));
+            let coord_dist = |from_c, to_c| f64::from(from_c) + (f64::from(to_c - from_c) * ratio);
             return WayPosition {
                 x: coord_dist(from.x, to.x),
                 y: coord_dist(from.y, to.y),
@@ -0,0 +1,238 @@
+use crate::coords::Coords;
+use crate::draw::backend::{DrawCommand, OutputBackend};
+use crate::geodata::find_polygons::{point_in_ring, signed_area};
+use crate::geodata::importer::EntityKind;
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Polygon, Route, Tags, Way};
+use crate::mapcss::styler::{StyledArea, Styler};
+use crate::tile::Tile;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Default)]
+pub struct GeoJsonOptions {
+    /// Decimal places the coordinates are rounded to, trailing zeros dropped. 6 places are about 10 cm,
+    /// which is finer than a pixel at `MAX_ZOOM`. `None` writes coordinates with their full precision.
+    pub precision: Option<u8>,
+}
+
+/// Writes the entities that get drawn on a tile as a GeoJSON `FeatureCollection` with their tags as
+/// properties: every way, multipolygon and route with a style, and every node with a label. Styles
+/// themselves aren't exported.
+pub struct GeoJsonBackend {
+    options: GeoJsonOptions,
+    features: Vec<String>,
+    exported: HashSet<(EntityKind, u64)>,
+}
+
+impl GeoJsonBackend {
+    pub fn new(options: GeoJsonOptions) -> GeoJsonBackend {
+        GeoJsonBackend {
+            options,
+            features: Vec::new(),
+            exported: HashSet::new(),
+        }
+    }
+
+    fn add_feature<'a>(&mut self, kind: EntityKind, entity: &impl OsmEntity<'a>, geometry: impl FnOnce(&mut Geometry)) {
+        if !self.exported.insert((kind, entity.global_id())) {
+            return;
+        }
+        let mut geometry_json = Geometry {
+            out: String::new(),
+            precision: self.options.precision,
+        };
+        geometry(&mut geometry_json);
+
+        let mut feature = format!(
+            "{{\"type\":\"Feature\",\"id\":\"{}/{}\",\"geometry\":{},\"properties\":",
+            kind,
+            entity.global_id(),
+            geometry_json.out
+        );
+        write_properties(&mut feature, &entity.tags());
+        feature.push('}');
+        self.features.push(feature);
+    }
+}
+
+impl OutputBackend for GeoJsonBackend {
+    fn begin_tile(&mut self, _: &Tile, _: usize, _: &Styler) -> Result<()> {
+        self.features.clear();
+        self.exported.clear();
+        Ok(())
+    }
+
+    fn draw_command(&mut self, command: &DrawCommand<'_, '_>) {
+        match command {
+            DrawCommand::Area { area, .. } | DrawCommand::AreaLabel { area, .. } => match area {
+                StyledArea::Way(way) => self.add_feature(EntityKind::Way, *way, |geometry| geometry.way(way)),
+                StyledArea::Multipolygon(mp) => {
+                    self.add_feature(EntityKind::Relation, *mp, |geometry| geometry.multipolygon(mp))
+                }
+                StyledArea::Route(route) => {
+                    self.add_feature(EntityKind::Relation, *route, |geometry| geometry.route(route))
+                }
+            },
+            DrawCommand::NodeSymbol { node, .. } | DrawCommand::NodeLabel { node, .. } => {
+                self.add_feature(EntityKind::Node, *node, |geometry| geometry.point(node))
+            }
+            DrawCommand::Hillshade(_) | DrawCommand::Scrim(_) | DrawCommand::Graticule(_) => {}
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let features = self.features.join(",");
+        Ok(format!("{{\"type\":\"FeatureCollection\",\"features\":[{}]}}", features).into_bytes())
+    }
+}
+
+struct Geometry {
+    out: String,
+    precision: Option<u8>,
+}
+
+impl Geometry {
+    fn point(&mut self, node: &Node<'_>) {
+        self.out.push_str("{\"type\":\"Point\",\"coordinates\":");
+        self.position(node);
+        self.out.push('}');
+    }
+
+    // Only closed ways are areas, so their nodes already make a ring.
+    fn way(&mut self, way: &Way<'_>) {
+        let nodes = (0..way.node_count()).map(|idx| way.get_node(idx)).collect::<Vec<_>>();
+        if way.is_area() {
+            self.out.push_str("{\"type\":\"Polygon\",\"coordinates\":[");
+            self.line(nodes);
+            self.out.push_str("]}");
+        } else {
+            self.out.push_str("{\"type\":\"LineString\",\"coordinates\":");
+            self.line(nodes);
+            self.out.push('}');
+        }
+    }
+
+    // Rings of multipolygons are oriented when they're imported, so counter-clockwise rings are the outer
+    // ones, and every inner ring goes to the polygon of the outer ring that contains it.
+    fn multipolygon(&mut self, mp: &Multipolygon<'_>) {
+        let rings = (0..mp.polygon_count())
+            .map(|idx| polygon_nodes(&mp.get_polygon(idx)))
+            .collect::<Vec<_>>();
+        let (outers, inners): (Vec<_>, Vec<_>) = rings.into_iter().partition(|ring| signed_area(lon_lats(ring)) >= 0.0);
+
+        let mut polygons = outers.into_iter().map(|outer| vec![outer]).collect::<Vec<_>>();
+        for inner in inners {
+            let Some(first_node) = inner.first() else {
+                continue;
+            };
+            let point = (first_node.lon(), first_node.lat());
+            let containing_polygon = polygons
+                .iter_mut()
+                .find(|polygon| point_in_ring(lon_lats(&polygon[0]), point));
+            if let Some(polygon) = containing_polygon {
+                polygon.push(inner);
+            }
+        }
+
+        self.out.push_str("{\"type\":\"MultiPolygon\",\"coordinates\":[");
+        for (idx, polygon) in polygons.into_iter().enumerate() {
+            if idx > 0 {
+                self.out.push(',');
+            }
+            self.out.push('[');
+            for (ring_idx, ring) in polygon.into_iter().enumerate() {
+                if ring_idx > 0 {
+                    self.out.push(',');
+                }
+                self.line(ring);
+            }
+            self.out.push(']');
+        }
+        self.out.push_str("]}");
+    }
+
+    fn route(&mut self, route: &Route<'_>) {
+        self.out.push_str("{\"type\":\"MultiLineString\",\"coordinates\":[");
+        for idx in 0..route.polyline_count() {
+            if idx > 0 {
+                self.out.push(',');
+            }
+            self.line(polygon_nodes(&route.get_polyline(idx)));
+        }
+        self.out.push_str("]}");
+    }
+
+    fn line(&mut self, nodes: Vec<Node<'_>>) {
+        self.out.push('[');
+        for (idx, node) in nodes.iter().enumerate() {
+            if idx > 0 {
+                self.out.push(',');
+            }
+            self.position(node);
+        }
+        self.out.push(']');
+    }
+
+    fn position(&mut self, node: &Node<'_>) {
+        self.out.push('[');
+        self.number(node.lon());
+        self.out.push(',');
+        self.number(node.lat());
+        self.out.push(']');
+    }
+
+    fn number(&mut self, value: f64) {
+        let Some(precision) = self.precision else {
+            let _ = write!(self.out, "{}", value);
+            return;
+        };
+        let rounded = format!("{:.*}", usize::from(precision), value);
+        let rounded = if rounded.contains('.') {
+            rounded.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            &rounded
+        };
+        // Small negative numbers round to "-0", which is a valid but odd-looking JSON number.
+        self.out.push_str(if rounded == "-0" { "0" } else { rounded });
+    }
+}
+
+fn lon_lats<'a>(ring: &'a [Node<'_>]) -> impl Iterator<Item = (f64, f64)> + 'a {
+    ring.iter().map(|node| (node.lon(), node.lat()))
+}
+
+fn polygon_nodes<'a>(polygon: &Polygon<'a>) -> Vec<Node<'a>> {
+    (0..polygon.node_count()).map(|idx| polygon.get_node(idx)).collect()
+}
+
+fn write_properties(out: &mut String, tags: &Tags<'_>) {
+    out.push('{');
+    for (idx, (key, value)) in tags.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        write_string(out, key.str);
+        out.push(':');
+        write_string(out, value.str);
+    }
+    out.push('}');
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
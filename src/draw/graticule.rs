@@ -0,0 +1,158 @@
+use crate::coords::MAX_MERCATOR_LAT;
+use crate::draw::labeler::Labeler;
+use crate::draw::line::draw_lines;
+use crate::draw::point::Point;
+use crate::draw::tile_pixels::TilePixels;
+use crate::draw::TILE_SIZE;
+use crate::mapcss::color::Color;
+use crate::tile::{coords_to_xy_tile_relative, Tile};
+use std::f64::consts::PI;
+
+#[derive(Clone, Debug)]
+pub enum GraticuleSpacing {
+    /// Meridians and parallels every given number of degrees. A step that isn't positive, or that would put
+    /// more lines on a tile than it has pixels across, draws nothing.
+    Degrees(f64),
+    /// The borders of the tile itself.
+    TileBorder,
+}
+
+#[derive(Clone, Debug)]
+pub struct GraticuleOptions {
+    pub spacing: GraticuleSpacing,
+    pub color: Color,
+    pub width: f64,
+    /// Label every intersection with its coordinates (or the tile with its `z/x/y` name for
+    /// `GraticuleSpacing::TileBorder`).
+    pub labels: bool,
+    pub font_size: f64,
+}
+
+impl Default for GraticuleOptions {
+    fn default() -> GraticuleOptions {
+        GraticuleOptions {
+            spacing: GraticuleSpacing::TileBorder,
+            color: Color { r: 255, g: 0, b: 255 },
+            width: 1.0,
+            labels: true,
+            font_size: 10.0,
+        }
+    }
+}
+
+pub struct Graticule {
+    pub lines: Vec<(Point, Point)>,
+    pub labels: Vec<(String, (f64, f64))>,
+}
+
+/// Returns the graticule lines crossing the tile in tile-relative pixel coordinates, together with
+/// the labels and their centers.
+pub fn compute_graticule(spacing: &GraticuleSpacing, tile: &Tile, scale: f64) -> Graticule {
+    let dimension = (TILE_SIZE as f64 * scale).round() as i32;
+    let to_point = |x: f64, y: f64| Point {
+        x: (x * scale).round() as i32,
+        y: (y * scale).round() as i32,
+    };
+
+    let mut lines = Vec::new();
+    let mut labels = Vec::new();
+
+    match *spacing {
+        GraticuleSpacing::Degrees(step) if step.is_finite() && step > 0.0 => {
+            let (min_lon, max_lat) = tile_corner_coords(tile, tile.x, tile.y);
+            let (max_lon, min_lat) = tile_corner_coords(tile, tile.x + 1, tile.y + 1);
+            let (min_lat, max_lat) = (min_lat.max(-MAX_MERCATOR_LAT), max_lat.min(MAX_MERCATOR_LAT));
+            if (max_lon - min_lon).max(max_lat - min_lat) / step > f64::from(dimension) {
+                return Graticule { lines, labels };
+            }
+
+            let multiples = |from: f64, to: f64| {
+                ((from / step).ceil() as i64..=(to / step).floor() as i64).map(move |k| k as f64 * step)
+            };
+            let meridians = multiples(min_lon, max_lon).collect::<Vec<_>>();
+            let parallels = multiples(min_lat, max_lat).collect::<Vec<_>>();
+
+            for &lon in &meridians {
+                let (x, _) = coords_to_xy_tile_relative(&(0.0, lon), tile);
+                let top = to_point(x, 0.0);
+                lines.push((top.clone(), Point { x: top.x, y: dimension }));
+            }
+            for &lat in &parallels {
+                let (_, y) = coords_to_xy_tile_relative(&(lat, 0.0), tile);
+                let left = to_point(0.0, y);
+                lines.push((
+                    left.clone(),
+                    Point {
+                        x: dimension,
+                        y: left.y,
+                    },
+                ));
+            }
+            for &lat in &parallels {
+                for &lon in &meridians {
+                    let (x, y) = coords_to_xy_tile_relative(&(lat, lon), tile);
+                    let text = format!("{} {}", format_degrees(lat, 'N', 'S'), format_degrees(lon, 'E', 'W'));
+                    labels.push((text, (x * scale, y * scale)));
+                }
+            }
+        }
+        GraticuleSpacing::Degrees(_) => {}
+        GraticuleSpacing::TileBorder => {
+            let corners = [(0, 0), (dimension, 0), (dimension, dimension), (0, dimension)];
+            for idx in 0..corners.len() {
+                let (x1, y1) = corners[idx];
+                let (x2, y2) = corners[(idx + 1) % corners.len()];
+                lines.push((Point { x: x1, y: y1 }, Point { x: x2, y: y2 }));
+            }
+            // Only a quarter of a label at the corner would be visible, so the tile name goes to the center.
+            let center = f64::from(dimension) / 2.0;
+            labels.push((format!("{}/{}/{}", tile.zoom, tile.x, tile.y), (center, center)));
+        }
+    }
+
+    Graticule { lines, labels }
+}
+
+/// Draws the graticule on top of everything that has already been drawn to `pixels`.
+pub fn draw_graticule(options: &GraticuleOptions, tile: &Tile, scale: f64, labeler: &Labeler, pixels: &mut TilePixels) {
+    let graticule = compute_graticule(&options.spacing, tile, scale);
+
+    draw_lines(
+        Box::new(graticule.lines.into_iter()),
+        options.width * scale,
+        &options.color,
+        1.0,
+        &None,
+        &None,
+        &None,
+        false,
+        true,
+        pixels,
+    );
+    pixels.bump_generation();
+    pixels.blend_unfinished_pixels(false);
+
+    if options.labels {
+        for (text, center) in &graticule.labels {
+            labeler.label_point(text, *center, options.font_size * scale, &options.color, pixels);
+        }
+        pixels.blend_unfinished_pixels(true);
+    }
+}
+
+fn tile_corner_coords(tile: &Tile, x: u32, y: u32) -> (f64, f64) {
+    let tile_count = f64::from(1u32 << tile.zoom);
+    let lon = f64::from(x) / tile_count * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * f64::from(y) / tile_count))
+        .sinh()
+        .atan()
+        .to_degrees();
+    (lon, lat)
+}
+
+fn format_degrees(value: f64, positive_suffix: char, negative_suffix: char) -> String {
+    let formatted = format!("{:.6}", value.abs());
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    let suffix = if value < 0.0 { negative_suffix } else { positive_suffix };
+    format!("{}°{}", trimmed, suffix)
+}
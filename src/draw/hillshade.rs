@@ -0,0 +1,122 @@
+use crate::draw::dem::Dem;
+use crate::draw::tile_pixels::{RgbaColor, TilePixels};
+use crate::draw::TILE_SIZE;
+use crate::mapcss::color::Color;
+use crate::tile::Tile;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+
+/// Shades the terrain under the vector features. The sun position is given in degrees: the azimuth
+/// is measured clockwise from north and the altitude up from the horizon.
+#[derive(Clone, Debug)]
+pub struct DemSource {
+    pub dem: Arc<Dem>,
+    pub sun_azimuth: f64,
+    pub sun_altitude: f64,
+    /// The opacity of the darkest shadows and the brightest highlights.
+    pub opacity: f64,
+}
+
+impl DemSource {
+    pub fn new(dem: Dem) -> DemSource {
+        DemSource {
+            dem: Arc::new(dem),
+            sun_azimuth: 315.0,
+            sun_altitude: 45.0,
+            opacity: 0.5,
+        }
+    }
+}
+
+/// Returns the shade of every pixel of the tile, row by row, relative to flat terrain: negative values
+/// are shadows and positive ones are highlights, both in [-1, 1]. Pixels that the DEM doesn't cover
+/// are `None`.
+pub fn compute_hillshade(source: &DemSource, tile: &Tile, scale: usize) -> Vec<Option<f64>> {
+    let dimension = TILE_SIZE * scale;
+    let pixels_per_world = (TILE_SIZE * scale) as f64 * f64::from(1u32 << tile.zoom);
+    let origin_x = f64::from(tile.x) * (TILE_SIZE * scale) as f64;
+    let origin_y = f64::from(tile.y) * (TILE_SIZE * scale) as f64;
+
+    let to_coords = |x: f64, y: f64| {
+        let lon = (origin_x + x) / pixels_per_world * 360.0 - 180.0;
+        let lat = (PI * (1.0 - 2.0 * (origin_y + y) / pixels_per_world))
+            .sinh()
+            .atan()
+            .to_degrees();
+        (lat, lon)
+    };
+    let elevation = |x: f64, y: f64| {
+        let (lat, lon) = to_coords(x, y);
+        source.dem.elevation_at(lat, lon)
+    };
+
+    let zenith = (90.0 - source.sun_altitude).to_radians();
+    let azimuth = (450.0 - source.sun_azimuth).to_radians();
+    let flat = zenith.cos();
+
+    let mut result = Vec::with_capacity(dimension * dimension);
+    for y in 0..dimension {
+        let center_y = y as f64 + 0.5;
+        let (lat, _) = to_coords(0.0, center_y);
+        let pixel_meters = EARTH_CIRCUMFERENCE * lat.to_radians().cos() / pixels_per_world;
+
+        for x in 0..dimension {
+            let center_x = x as f64 + 0.5;
+            let center = match elevation(center_x, center_y) {
+                Some(center) => center,
+                None => {
+                    result.push(None);
+                    continue;
+                }
+            };
+
+            // Central differences, falling back to the center when a neighbor lacks data.
+            let sample = |dx: f64, dy: f64| elevation(center_x + dx, center_y + dy);
+            let derivative = |before: Option<f64>, after: Option<f64>| match (before, after) {
+                (Some(b), Some(a)) => (a - b) / (2.0 * pixel_meters),
+                (Some(b), None) => (center - b) / pixel_meters,
+                (None, Some(a)) => (a - center) / pixel_meters,
+                (None, None) => 0.0,
+            };
+            let dz_dx = derivative(sample(-1.0, 0.0), sample(1.0, 0.0));
+            // Positive when the terrain rises to the south, as in the usual raster formulation.
+            let dz_dy = derivative(sample(0.0, -1.0), sample(0.0, 1.0));
+
+            let slope = dz_dx.hypot(dz_dy).atan();
+            let aspect = dz_dy.atan2(-dz_dx);
+            let shade = zenith.cos() * slope.cos() + zenith.sin() * slope.sin() * (azimuth - aspect).cos();
+
+            let relative = if shade < flat {
+                (shade - flat) / flat
+            } else if flat < 1.0 {
+                (shade - flat) / (1.0 - flat)
+            } else {
+                0.0
+            };
+            result.push(Some(relative.clamp(-1.0, 1.0)));
+        }
+    }
+    result
+}
+
+/// Darkens the shadows and lightens the highlights, leaving flat terrain and uncovered pixels alone.
+pub fn draw_hillshade(source: &DemSource, tile: &Tile, scale: usize, pixels: &mut TilePixels) {
+    let dimension = TILE_SIZE * scale;
+    for (idx, shade) in compute_hillshade(source, tile, scale).into_iter().enumerate() {
+        let shade = match shade {
+            Some(shade) if shade != 0.0 => shade,
+            _ => continue,
+        };
+        let (x, y) = ((idx % dimension) as i32, (idx / dimension) as i32);
+        let component = if shade < 0.0 { 0 } else { u8::MAX };
+        let color = Color {
+            r: component,
+            g: component,
+            b: component,
+        };
+        pixels.set_pixel(x, y, &RgbaColor::from_color(&color, source.opacity * shade.abs()));
+    }
+    pixels.bump_generation();
+}
@@ -4,6 +4,26 @@ use png::{ColorType, Decoder, Transformations};
 use std::fs::File;
 use std::path::Path;
 
+/// How an image is sampled between its pixels when it's drawn at a different size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SamplingFilter {
+    Nearest,
+    Bilinear,
+}
+
+/// What the image looks like beyond its edges: another copy of itself or its outermost pixels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EdgeWrap {
+    Repeat,
+    Clamp,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageSampling {
+    pub filter: SamplingFilter,
+    pub wrap: EdgeWrap,
+}
+
 pub struct Icon {
     pixels: Vec<RgbaColor>,
     pub width: usize,
@@ -57,7 +77,47 @@ impl Icon {
         })
     }
 
+    /// Takes the (premultiplied) pixels row by row.
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<RgbaColor>) -> Icon {
+        assert_eq!(pixels.len(), width * height);
+        Icon { pixels, width, height }
+    }
+
     pub fn get(&self, x: usize, y: usize) -> RgbaColor {
         self.pixels[y * self.width + x].clone()
     }
+
+    /// The color at `(x, y)` in image pixels, the center of pixel `(i, j)` being at `(i + 0.5, j + 0.5)`.
+    pub fn sample(&self, x: f64, y: f64, sampling: &ImageSampling) -> RgbaColor {
+        let get = |x: f64, y: f64| {
+            let wrap = |coord: f64, size: usize| match sampling.wrap {
+                EdgeWrap::Repeat => (coord as i64).rem_euclid(size as i64) as usize,
+                EdgeWrap::Clamp => (coord as i64).clamp(0, size as i64 - 1) as usize,
+            };
+            self.get(wrap(x, self.width), wrap(y, self.height))
+        };
+
+        match sampling.filter {
+            SamplingFilter::Nearest => get(x.floor(), y.floor()),
+            SamplingFilter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let top = lerp(&get(x0, y0), &get(x0 + 1.0, y0), tx);
+                let bottom = lerp(&get(x0, y0 + 1.0), &get(x0 + 1.0, y0 + 1.0), tx);
+                lerp(&top, &bottom, ty)
+            }
+        }
+    }
+}
+
+// Interpolating premultiplied colors keeps transparent pixels from darkening their neighbours.
+fn lerp(a: &RgbaColor, b: &RgbaColor, t: f64) -> RgbaColor {
+    let mix = |a: f64, b: f64| a + (b - a) * t;
+    RgbaColor {
+        r: mix(a.r, b.r),
+        g: mix(a.g, b.g),
+        b: mix(a.b, b.b),
+        a: mix(a.a, b.a),
+    }
 }
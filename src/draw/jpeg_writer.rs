@@ -0,0 +1,419 @@
+use anyhow::{bail, Result};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Chroma is stored at half the resolution in both directions, which is smaller but blurs
+    /// the colors of thin lines and text.
+    Yuv420,
+    /// Chroma is stored at full resolution.
+    Yuv444,
+}
+
+#[derive(Clone, Debug)]
+pub struct JpegOptions {
+    /// From 1 (smallest) to 100 (sharpest), with the same meaning as in libjpeg.
+    pub quality: u8,
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+impl Default for JpegOptions {
+    fn default() -> JpegOptions {
+        JpegOptions {
+            quality: 85,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+        }
+    }
+}
+
+// Tiles are always composited over an opaque canvas (black if the style doesn't define one),
+// so the triples have no alpha left to flatten and can be encoded as is.
+pub fn rgb_triples_to_jpeg(
+    triples: &[(u8, u8, u8)],
+    width: usize,
+    height: usize,
+    options: &JpegOptions,
+) -> Result<Vec<u8>> {
+    if !(1..=100).contains(&options.quality) {
+        bail!("JPEG quality must be between 1 and 100, got {}", options.quality);
+    }
+    if width == 0 || height == 0 || width > usize::from(u16::MAX) || height > usize::from(u16::MAX) {
+        bail!("Can't encode a {}x{} image as JPEG", width, height);
+    }
+
+    let luma_table = scale_quantization_table(&LUMA_QUANTIZATION_TABLE, options.quality);
+    let chroma_table = scale_quantization_table(&CHROMA_QUANTIZATION_TABLE, options.quality);
+
+    let mut buf = Vec::new();
+    buf.extend([0xFF, 0xD8]);
+    write_quantization_tables(&mut buf, &luma_table, &chroma_table);
+    write_frame_header(&mut buf, width, height, options.chroma_subsampling);
+    write_huffman_tables(&mut buf);
+    write_scan_header(&mut buf);
+
+    let planes = to_ycbcr_planes(triples, width, height);
+    let mut encoder = ScanEncoder::new();
+    let luma_codes = (HuffmanCodes::new(&LUMA_DC), HuffmanCodes::new(&LUMA_AC));
+    let chroma_codes = (HuffmanCodes::new(&CHROMA_DC), HuffmanCodes::new(&CHROMA_AC));
+
+    let mcu_size = match options.chroma_subsampling {
+        ChromaSubsampling::Yuv420 => 16,
+        ChromaSubsampling::Yuv444 => 8,
+    };
+    for mcu_y in (0..height).step_by(mcu_size) {
+        for mcu_x in (0..width).step_by(mcu_size) {
+            for block_y in (0..mcu_size).step_by(8) {
+                for block_x in (0..mcu_size).step_by(8) {
+                    let block = planes.block(0, mcu_x + block_x, mcu_y + block_y, 1);
+                    encoder.encode_block(0, &block, &luma_table, &luma_codes);
+                }
+            }
+            let chroma_step = mcu_size / 8;
+            for component in 1..=2 {
+                let block = planes.block(component, mcu_x, mcu_y, chroma_step);
+                encoder.encode_block(component, &block, &chroma_table, &chroma_codes);
+            }
+        }
+    }
+
+    buf.extend(encoder.finish());
+    buf.extend([0xFF, 0xD9]);
+    Ok(buf)
+}
+
+struct YCbCrPlanes {
+    planes: [Vec<f64>; 3],
+    width: usize,
+    height: usize,
+}
+
+fn to_ycbcr_planes(triples: &[(u8, u8, u8)], width: usize, height: usize) -> YCbCrPlanes {
+    let mut planes = [
+        Vec::with_capacity(triples.len()),
+        Vec::with_capacity(triples.len()),
+        Vec::with_capacity(triples.len()),
+    ];
+    for &(r, g, b) in triples {
+        let (r, g, b) = (f64::from(r), f64::from(g), f64::from(b));
+        planes[0].push(0.299 * r + 0.587 * g + 0.114 * b);
+        planes[1].push(-0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0);
+        planes[2].push(0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0);
+    }
+    YCbCrPlanes { planes, width, height }
+}
+
+impl YCbCrPlanes {
+    // Returns an 8x8 block starting at (`x`, `y`) where every sample averages a `step`x`step` square
+    // of pixels. Pixels past the right and bottom edges repeat the last column and row.
+    fn block(&self, component: usize, x: usize, y: usize, step: usize) -> [f64; 64] {
+        let plane = &self.planes[component];
+        let mut block = [0.0; 64];
+        for block_y in 0..8 {
+            for block_x in 0..8 {
+                let mut sum = 0.0;
+                for dy in 0..step {
+                    for dx in 0..step {
+                        let px = (x + block_x * step + dx).min(self.width - 1);
+                        let py = (y + block_y * step + dy).min(self.height - 1);
+                        sum += plane[py * self.width + px];
+                    }
+                }
+                block[block_y * 8 + block_x] = sum / (step * step) as f64 - 128.0;
+            }
+        }
+        block
+    }
+}
+
+struct ScanEncoder {
+    output: Vec<u8>,
+    bit_buffer: u32,
+    bit_count: u32,
+    prev_dc: [i32; 3],
+    dct_basis: [[f64; 8]; 8],
+}
+
+impl ScanEncoder {
+    fn new() -> ScanEncoder {
+        ScanEncoder {
+            output: Vec::new(),
+            bit_buffer: 0,
+            bit_count: 0,
+            prev_dc: [0; 3],
+            dct_basis: dct_basis(),
+        }
+    }
+
+    fn encode_block(
+        &mut self,
+        component: usize,
+        block: &[f64; 64],
+        quantization_table: &[u8; 64],
+        (dc_codes, ac_codes): &(HuffmanCodes, HuffmanCodes),
+    ) {
+        let coeffs = forward_dct(block, &self.dct_basis);
+        let mut quantized = [0i32; 64];
+        for (zigzag_idx, &natural_idx) in ZIGZAG.iter().enumerate() {
+            let value = (coeffs[natural_idx] / f64::from(quantization_table[zigzag_idx])).round() as i32;
+            // Baseline JPEG can't represent AC coefficients above 10 bits.
+            quantized[zigzag_idx] = if zigzag_idx == 0 {
+                value
+            } else {
+                value.clamp(-1023, 1023)
+            };
+        }
+
+        let dc_diff = quantized[0] - self.prev_dc[component];
+        self.prev_dc[component] = quantized[0];
+        let dc_size = magnitude_size(dc_diff);
+        self.write_code(dc_codes.codes[dc_size as usize]);
+        self.write_magnitude(dc_diff, dc_size);
+
+        let mut zero_run = 0;
+        for &coeff in &quantized[1..] {
+            if coeff == 0 {
+                zero_run += 1;
+                continue;
+            }
+            while zero_run >= 16 {
+                self.write_code(ac_codes.codes[0xF0]);
+                zero_run -= 16;
+            }
+            let size = magnitude_size(coeff);
+            self.write_code(ac_codes.codes[(zero_run << 4 | size) as usize]);
+            self.write_magnitude(coeff, size);
+            zero_run = 0;
+        }
+        if zero_run > 0 {
+            self.write_code(ac_codes.codes[0x00]);
+        }
+    }
+
+    fn write_code(&mut self, (code, length): (u16, u8)) {
+        self.write_bits(u32::from(code), u32::from(length));
+    }
+
+    fn write_magnitude(&mut self, value: i32, size: u32) {
+        let bits = if value < 0 { value - 1 } else { value };
+        self.write_bits((bits as u32) & ((1 << size) - 1), size);
+    }
+
+    fn write_bits(&mut self, bits: u32, count: u32) {
+        self.bit_buffer = (self.bit_buffer << count) | bits;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            let byte = (self.bit_buffer >> (self.bit_count - 8)) as u8;
+            self.output.push(byte);
+            if byte == 0xFF {
+                self.output.push(0x00);
+            }
+            self.bit_count -= 8;
+            self.bit_buffer &= (1 << self.bit_count) - 1;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            let padding = 8 - self.bit_count;
+            self.write_bits((1 << padding) - 1, padding);
+        }
+        self.output
+    }
+}
+
+fn magnitude_size(value: i32) -> u32 {
+    32 - value.unsigned_abs().leading_zeros()
+}
+
+fn dct_basis() -> [[f64; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let normalize = if u == 0 { std::f64::consts::FRAC_1_SQRT_2 } else { 1.0 };
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = 0.5 * normalize * ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / 16.0).cos();
+        }
+    }
+    basis
+}
+
+fn forward_dct(block: &[f64; 64], basis: &[[f64; 8]; 8]) -> [f64; 64] {
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| block[y * 8 + x] * basis[u][x]).sum();
+        }
+    }
+
+    let mut result = [0.0; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            result[v * 8 + u] = (0..8).map(|y| rows[y * 8 + u] * basis[v][y]).sum();
+        }
+    }
+    result
+}
+
+// The tables are stored in zigzag order, which is also how they're written to the file.
+fn scale_quantization_table(base_table: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = u32::from(quality);
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    let mut table = [0; 64];
+    for (zigzag_idx, &natural_idx) in ZIGZAG.iter().enumerate() {
+        let value = (u32::from(base_table[natural_idx]) * scale + 50) / 100;
+        table[zigzag_idx] = value.clamp(1, 255) as u8;
+    }
+    table
+}
+
+fn write_segment(buf: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    buf.extend([0xFF, marker]);
+    buf.extend(((payload.len() + 2) as u16).to_be_bytes());
+    buf.extend(payload);
+}
+
+fn write_quantization_tables(buf: &mut Vec<u8>, luma_table: &[u8; 64], chroma_table: &[u8; 64]) {
+    let mut payload = Vec::new();
+    for (table_id, table) in [luma_table, chroma_table].iter().enumerate() {
+        payload.push(table_id as u8);
+        payload.extend(table.iter());
+    }
+    write_segment(buf, 0xDB, &payload);
+}
+
+fn write_frame_header(buf: &mut Vec<u8>, width: usize, height: usize, chroma_subsampling: ChromaSubsampling) {
+    let luma_sampling = match chroma_subsampling {
+        ChromaSubsampling::Yuv420 => 0x22,
+        ChromaSubsampling::Yuv444 => 0x11,
+    };
+    let mut payload = vec![8];
+    payload.extend((height as u16).to_be_bytes());
+    payload.extend((width as u16).to_be_bytes());
+    payload.extend([3, 1, luma_sampling, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    write_segment(buf, 0xC0, &payload);
+}
+
+fn write_huffman_tables(buf: &mut Vec<u8>) {
+    let mut payload = Vec::new();
+    for (class_and_id, spec) in [
+        (0x00, &LUMA_DC),
+        (0x10, &LUMA_AC),
+        (0x01, &CHROMA_DC),
+        (0x11, &CHROMA_AC),
+    ] {
+        payload.push(class_and_id);
+        payload.extend(spec.code_counts.iter());
+        payload.extend(spec.symbols.iter());
+    }
+    write_segment(buf, 0xC4, &payload);
+}
+
+fn write_scan_header(buf: &mut Vec<u8>) {
+    write_segment(buf, 0xDA, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+}
+
+struct HuffmanSpec {
+    code_counts: [u8; 16],
+    symbols: &'static [u8],
+}
+
+struct HuffmanCodes {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanCodes {
+    fn new(spec: &HuffmanSpec) -> HuffmanCodes {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut symbols = spec.symbols.iter();
+        for (length_idx, &count) in spec.code_counts.iter().enumerate() {
+            for _ in 0..count {
+                codes[*symbols.next().unwrap() as usize] = (code, length_idx as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanCodes { codes }
+    }
+}
+
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// The example tables from Annex K of the JPEG standard, in natural order.
+#[rustfmt::skip]
+const LUMA_QUANTIZATION_TABLE: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+#[rustfmt::skip]
+const CHROMA_QUANTIZATION_TABLE: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+const LUMA_DC: HuffmanSpec = HuffmanSpec {
+    code_counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+const CHROMA_DC: HuffmanSpec = HuffmanSpec {
+    code_counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+#[rustfmt::skip]
+const LUMA_AC: HuffmanSpec = HuffmanSpec {
+    code_counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D],
+    symbols: &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+        0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+        0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+        0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+        0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+        0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+        0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+        0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+        0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+        0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+        0xF9, 0xFA,
+    ],
+};
+
+#[rustfmt::skip]
+const CHROMA_AC: HuffmanSpec = HuffmanSpec {
+    code_counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    symbols: &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+        0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+        0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+        0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+        0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+        0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+        0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+        0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+        0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+        0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+        0xF9, 0xFA,
+    ],
+};
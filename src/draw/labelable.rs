@@ -1,5 +1,5 @@
 use crate::draw::point::Point;
-use crate::geodata::reader::{Multipolygon, Node, Way};
+use crate::geodata::reader::{Multipolygon, Node, Polygon, Route, Way};
 use crate::tile::{coords_to_xy_tile_relative, Tile};
 use std::cmp::Ordering;
 use std::collections::binary_heap::BinaryHeap;
@@ -40,6 +40,9 @@ impl Labelable for Way<'_> {
 
 impl Labelable for Multipolygon<'_> {
     fn get_label_position(&self, tile: &Tile, scale: f64) -> LabelPosition {
+        if let Some(label_node) = self.label_node() {
+            return label_node.get_label_position(tile, scale);
+        }
         let polygons = (0..self.polygon_count())
             .map(|poly_idx| {
                 let poly = self.get_polygon(poly_idx);
@@ -58,6 +61,34 @@ impl Labelable for Multipolygon<'_> {
     }
 }
 
+// Labels follow the longest part of the route, as text can't jump over the gaps between the parts.
+impl Labelable for Route<'_> {
+    fn get_label_position(&self, tile: &Tile, scale: f64) -> LabelPosition {
+        let polyline = self.longest_polyline()?;
+        polyline
+            .get_node(polyline.node_count() / 2)
+            .get_label_position(tile, scale)
+    }
+
+    fn get_waypoints(&self, tile: &Tile, scale: f64) -> Option<Vec<Point>> {
+        let polyline = self.longest_polyline()?;
+        Some(
+            (0..polyline.node_count())
+                .map(|idx| Point::from_node(&polyline.get_node(idx), tile, scale))
+                .collect(),
+        )
+    }
+}
+
+impl Route<'_> {
+    fn longest_polyline(&self) -> Option<Polygon<'_>> {
+        (0..self.polyline_count())
+            .map(|idx| self.get_polyline(idx))
+            .filter(|polyline| polyline.node_count() > 0)
+            .max_by_key(|polyline| polyline.node_count())
+    }
+}
+
 fn nodes_to_points<'n>(nodes: impl Iterator<Item = Node<'n>>, tile: &Tile, scale: f64) -> Vec<PointF> {
     nodes
         .map(|n| {
@@ -203,6 +234,8 @@ fn get_label_position(mut polygons: Polygons, scale: f64) -> Option<PointF> {
     Some(polylabel(&polygons, &bb, precision))
 }
 
+// Leaves the largest ring first, followed only by the rings inside it (i.e. its holes), so that the label
+// is placed at the pole of inaccessibility of the largest part of the area.
 fn filter_polygons(polygons: &mut Polygons) {
     let mut largest_poly_idx = 0;
     let mut largest_poly_area = get_polygon_area(&polygons[0]);
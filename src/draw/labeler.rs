@@ -1,18 +1,68 @@
 use crate::draw::font::text_placer::TextPlacer;
-use crate::draw::icon::Icon;
+use crate::draw::icon::{Icon, ImageSampling};
 use crate::draw::icon_cache::IconCache;
 use crate::draw::labelable::Labelable;
 use crate::draw::tile_pixels::TilePixels;
-use crate::geodata::reader::OsmEntity;
-use crate::mapcss::styler::{Style, TextPosition};
+use crate::draw::TILE_SIZE;
+use crate::geodata::reader::{Node, OsmEntity};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{OrderKey, Style, TextPosition};
 use crate::tile::Tile;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Caps the density of node labels at low zooms: the map is split into a grid of `cell_size`x`cell_size`
+/// pixel cells, and only the most important node label of every cell is placed.
+#[derive(Clone, Debug)]
+pub struct LabelThinning {
+    cell_size: f64,
+    max_zoom: u8,
+}
+
+impl LabelThinning {
+    /// Tiles above `max_zoom` are labeled as usual. `cell_size` has to be positive.
+    pub fn new(cell_size: f64, max_zoom: u8) -> Result<LabelThinning> {
+        if !cell_size.is_finite() || cell_size <= 0.0 {
+            bail!("Invalid label thinning cell size: {}", cell_size);
+        }
+        Ok(LabelThinning { cell_size, max_zoom })
+    }
+}
+
+/// A label that isn't rasterized, for front-ends that draw the text themselves, see
+/// `RenderOptions::label_anchors`. Coordinates and sizes are in pixels of the output tile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelPlacement {
+    pub text: String,
+    /// The center of the text. Labels along a line are centered on the middle of the way.
+    pub position: (f64, f64),
+    pub font_size: f64,
+    /// The `z-index` of the label's style.
+    pub z_index: f64,
+    /// The key the styler ordered the label by, see `Styler::set_order_key`.
+    pub order_key: OrderKey,
+    /// When the label was placed: area labels go first and node labels after them, each in the order of their
+    /// `order_key`. A label only makes it if it doesn't collide with any label placed before it, so 0 is the
+    /// label that won every collision it was in.
+    pub priority: usize,
+}
 
 #[derive(Default)]
 pub struct Labeler {
     text_placer: TextPlacer,
+    /// Icons are drawn at the tile scale with this sampling if present, and pixel for pixel otherwise.
+    icon_scaling: Option<ImageSampling>,
 }
 
 impl Labeler {
+    pub fn new(icon_scaling: Option<ImageSampling>) -> Labeler {
+        Labeler {
+            text_placer: TextPlacer::default(),
+            icon_scaling,
+        }
+    }
+
     pub fn label_entity<'e, E>(
         &self,
         entity: &E,
@@ -36,6 +86,51 @@ impl Labeler {
         pixels.bump_label_generation(succeeded);
     }
 
+    /// Same as `label_entity`, but returns the text with its position instead of rasterizing it. Icons are
+    /// still drawn. The text isn't, but it still takes up its room, so a label that collides with an earlier
+    /// one, or whose icon doesn't fit, isn't returned.
+    pub fn anchor_entity<'e, E>(
+        &self,
+        entity: &E,
+        style: &Style,
+        tile: &Tile,
+        scale: f64,
+        icon_cache: &IconCache,
+        default_text_position: TextPosition,
+        pixels: &mut TilePixels,
+    ) -> Option<(&'e str, (f64, f64), f64)>
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let y_offset = self.label_with_icon(entity, style, tile, scale, icon_cache, pixels);
+        let text_style = style.text_style.as_ref();
+        let succeeded = y_offset.is_some_and(|y_offset| {
+            text_style.is_none_or(|text_style| {
+                self.text_placer.reserve(
+                    entity,
+                    text_style,
+                    tile,
+                    scale,
+                    y_offset,
+                    default_text_position.clone(),
+                    pixels,
+                )
+            })
+        });
+        pixels.bump_label_generation(succeeded);
+
+        if !succeeded {
+            return None;
+        }
+        self.text_placer
+            .anchor(entity, text_style?, tile, scale, y_offset?, default_text_position)
+    }
+
+    pub fn label_point(&self, text: &str, center: (f64, f64), font_size: f64, color: &Color, pixels: &mut TilePixels) {
+        let succeeded = self.text_placer.place_at(text, center, font_size, color, true, pixels);
+        pixels.bump_label_generation(succeeded);
+    }
+
     fn label_with_icon(
         &self,
         entity: &impl Labelable,
@@ -57,8 +152,9 @@ impl Labeler {
                 Some(center) => center,
                 _ => return Some(0),
             };
-            if self.draw_icon(icon, center_x, center_y, pixels) {
-                Some(icon.height / 2)
+            let icon_scale = if self.icon_scaling.is_some() { scale } else { 1.0 };
+            if self.draw_icon(icon, icon_scale, center_x, center_y, style.icon_allow_overlap, pixels) {
+                Some(scaled_icon_size(icon.height, icon_scale) / 2)
             } else {
                 None
             }
@@ -88,15 +184,34 @@ impl Labeler {
         }
     }
 
-    fn draw_icon(&self, icon: &Icon, center_x: f64, center_y: f64, pixels: &mut TilePixels) -> bool {
+    fn draw_icon(
+        &self,
+        icon: &Icon,
+        icon_scale: f64,
+        center_x: f64,
+        center_y: f64,
+        allow_overlap: bool,
+        pixels: &mut TilePixels,
+    ) -> bool {
         let get_start_coord = |coord, dimension| (coord - (dimension as f64 / 2.0)) as i32;
 
-        let start_x = get_start_coord(center_x, icon.width);
-        let start_y = get_start_coord(center_y, icon.height);
+        let (width, height) = (
+            scaled_icon_size(icon.width, icon_scale),
+            scaled_icon_size(icon.height, icon_scale),
+        );
+        let start_x = get_start_coord(center_x, width);
+        let start_y = get_start_coord(center_y, height);
 
-        for x in 0..icon.width {
-            for y in 0..icon.height {
-                if !pixels.set_label_pixel(start_x + x as i32, start_y + y as i32, &icon.get(x, y)) {
+        for x in 0..width {
+            for y in 0..height {
+                let color = match self.icon_scaling {
+                    Some(ref sampling) => {
+                        let to_icon_coord = |coord: usize| (coord as f64 + 0.5) / icon_scale;
+                        icon.sample(to_icon_coord(x), to_icon_coord(y), sampling)
+                    }
+                    None => icon.get(x, y),
+                };
+                if !pixels.set_label_pixel(start_x + x as i32, start_y + y as i32, &color, allow_overlap) {
                     return false;
                 }
             }
@@ -105,3 +220,37 @@ impl Labeler {
         true
     }
 }
+
+/// Keeps the first node in every cell of `thinning` that has a label, since the labels are already sorted from
+/// the most to the least important one. The grid is aligned to the whole map rather than to the tile, so
+/// that the neighboring tiles agree on which labels are kept.
+pub fn thin_out_node_labels<'n, 'a>(
+    styled_nodes: Vec<(&'n Node<'a>, Arc<Style>)>,
+    tile: &Tile,
+    thinning: &LabelThinning,
+) -> Vec<(&'n Node<'a>, Arc<Style>)> {
+    if tile.zoom > thinning.max_zoom {
+        return styled_nodes;
+    }
+
+    let to_cell = |tile_coord: u32, coord: f64| {
+        ((f64::from(tile_coord) * TILE_SIZE as f64 + coord) / thinning.cell_size).floor() as i64
+    };
+    let mut taken_cells = HashSet::new();
+    styled_nodes
+        .into_iter()
+        .filter(|(node, style)| {
+            if style.text_style.is_none() && style.icon_image.is_none() {
+                return true;
+            }
+            match node.get_label_position(tile, 1.0) {
+                Some((x, y)) => taken_cells.insert((to_cell(tile.x, x), to_cell(tile.y, y))),
+                None => true,
+            }
+        })
+        .collect()
+}
+
+fn scaled_icon_size(size: usize, icon_scale: f64) -> usize {
+    (size as f64 * icon_scale).round() as usize
+}
@@ -1,11 +1,14 @@
+use crate::draw::clip::clip_segment;
+use crate::draw::fill::{fill_contour, Filler};
+use crate::draw::mask::Canvas;
 use crate::draw::opacity_calculator::OpacityCalculator;
 use crate::draw::point::Point;
 use crate::draw::point_pairs::PointPairIter;
 use crate::draw::tile_pixels::RgbaColor;
-use crate::draw::tile_pixels::TilePixels;
 use crate::mapcss::color::Color;
-use crate::mapcss::styler::{is_non_trivial_cap, LineCap};
+use crate::mapcss::styler::{is_non_trivial_cap, LineCap, LineJoin};
 
+#[expect(clippy::too_many_arguments)]
 pub fn draw_lines(
     points: PointPairIter<'_>,
     width: f64,
@@ -13,8 +16,10 @@ pub fn draw_lines(
     opacity: f64,
     dashes: &Option<Vec<f64>>,
     line_cap: &Option<LineCap>,
+    line_join: &Option<LineJoin>,
     use_caps_for_dashes: bool,
-    pixels: &mut TilePixels,
+    clip: bool,
+    pixels: &mut impl Canvas,
 ) {
     let half_width = width / 2.0;
     let line_cap_for_dashes = if use_caps_for_dashes { line_cap } else { &None };
@@ -23,13 +28,40 @@ pub fn draw_lines(
 
     let has_caps = is_non_trivial_cap(line_cap);
 
+    // Joins are only drawn for solid lines, as a vertex might fall into a gap between dashes.
+    let line_join = if dashes.is_none() { line_join.as_ref() } else { None };
+    let join_drawer = line_join.map(|line_join| JoinDrawer {
+        line_join,
+        half_width,
+        color,
+        opacity,
+        opacity_calculator_for_round_joins: OpacityCalculator::new(half_width, &Some(vec![0.0]), &Some(LineCap::Round)),
+        clip,
+    });
+    let mut first_segment: Option<(Point, Point)> = None;
+    let mut prev_segment: Option<(Point, Point)> = None;
+
     let mut peekable_points = points.peekable();
     let mut first = true;
 
     while let Some((p1, p2)) = peekable_points.next() {
-        draw_line(&p1, &p2, color, opacity, &opacity_calculator, pixels);
+        draw_line(&p1, &p2, color, opacity, &opacity_calculator, clip, pixels);
         opacity_calculator.add_traveled_distance(p1.dist(&p2));
 
+        if let Some(join_drawer) = join_drawer.as_ref() {
+            if p1 != p2 {
+                if let Some((prev_p1, prev_p2)) = prev_segment.as_ref() {
+                    if *prev_p2 == p1 {
+                        join_drawer.draw(prev_p1, &p1, &p2, pixels);
+                    }
+                }
+                if first_segment.is_none() {
+                    first_segment = Some((p1.clone(), p2.clone()));
+                }
+                prev_segment = Some((p1.clone(), p2.clone()));
+            }
+        }
+
         if p1 != p2 && has_caps {
             if first {
                 let cap_end = p1.push_away_from(&p2, half_width);
@@ -39,6 +71,7 @@ pub fn draw_lines(
                     color,
                     opacity,
                     &opacity_calculator_for_outer_caps,
+                    clip,
                     pixels,
                 );
             }
@@ -51,6 +84,7 @@ pub fn draw_lines(
                     color,
                     opacity,
                     &opacity_calculator_for_outer_caps,
+                    clip,
                     pixels,
                 );
             }
@@ -58,17 +92,110 @@ pub fn draw_lines(
 
         first = false;
     }
+
+    // Closed lines also need a join where they start and end.
+    if let (Some(join_drawer), Some((last_p1, last_p2)), Some((first_p1, first_p2))) =
+        (join_drawer.as_ref(), prev_segment.as_ref(), first_segment.as_ref())
+    {
+        if last_p2 == first_p1 && (last_p1, last_p2) != (first_p1, first_p2) {
+            join_drawer.draw(last_p1, first_p1, first_p2, pixels);
+        }
+    }
+}
+
+struct JoinDrawer<'a> {
+    line_join: &'a LineJoin,
+    half_width: f64,
+    color: &'a Color,
+    opacity: f64,
+    opacity_calculator_for_round_joins: OpacityCalculator,
+    clip: bool,
+}
+
+impl JoinDrawer<'_> {
+    // Fills the wedge between the ends of the `p0`-`p1` and `p1`-`p2` segments on the outer side of the turn.
+    fn draw(&self, p0: &Point, p1: &Point, p2: &Point, pixels: &mut impl Canvas) {
+        let unit_normal = |from: &Point, to: &Point| {
+            let (dx, dy) = (f64::from(to.x - from.x), f64::from(to.y - from.y));
+            let len = (dx * dx + dy * dy).sqrt();
+            (-dy / len, dx / len)
+        };
+        let (n1, n2) = (unit_normal(p0, p1), unit_normal(p1, p2));
+
+        let cross = f64::from(p1.x - p0.x) * f64::from(p2.y - p1.y) - f64::from(p1.y - p0.y) * f64::from(p2.x - p1.x);
+        if cross == 0.0 && n1 == n2 {
+            return;
+        }
+
+        match *self.line_join {
+            LineJoin::Round => {
+                // The round cap at the end of the first segment covers the wedge and stays
+                // within the circle around the vertex.
+                let cap_end = p1.push_away_from(p0, self.half_width);
+                draw_line(
+                    p1,
+                    &cap_end,
+                    self.color,
+                    self.opacity,
+                    &self.opacity_calculator_for_round_joins,
+                    self.clip,
+                    pixels,
+                );
+            }
+            LineJoin::Bevel | LineJoin::Miter(_) => {
+                let side = if cross > 0.0 { -1.0 } else { 1.0 };
+                let offset = |(nx, ny): (f64, f64), dist: f64| Point {
+                    x: p1.x + (side * nx * dist).round() as i32,
+                    y: p1.y + (side * ny * dist).round() as i32,
+                };
+                let corner1 = offset(n1, self.half_width);
+                let corner2 = offset(n2, self.half_width);
+
+                let mut contour = vec![p1.clone(), corner1.clone()];
+                if let LineJoin::Miter(limit) = *self.line_join {
+                    // The miter is 1 / cos(theta / 2) times longer than half the width,
+                    // where theta is the angle between the normals.
+                    let bisector = (n1.0 + n2.0, n1.1 + n2.1);
+                    let bisector_len = (bisector.0 * bisector.0 + bisector.1 * bisector.1).sqrt();
+                    if bisector_len > 0.0 && 2.0 / bisector_len <= limit {
+                        let miter_len = 2.0 * self.half_width / bisector_len;
+                        contour.push(offset(
+                            (bisector.0 / bisector_len, bisector.1 / bisector_len),
+                            miter_len,
+                        ));
+                    }
+                }
+                contour.push(corner2);
+                contour.push(p1.clone());
+
+                let edges = contour
+                    .windows(2)
+                    .map(|w| (w[0].clone(), w[1].clone()))
+                    .collect::<Vec<_>>();
+                fill_contour(
+                    Box::new(edges.into_iter()),
+                    &Filler::Color(self.color),
+                    self.opacity,
+                    pixels,
+                );
+            }
+        }
+    }
 }
 
 // Full-blown Bresenham with anti-aliasing and thick line support.
 // Mostly inspired by http://kt8216.unixcab.org/murphy/index.html
+//
+// When clipping, only the steps that can touch the tile are walked. The Bresenham state at any step
+// can be computed directly, so the clipped line is pixel-for-pixel the same as the unclipped one.
 fn draw_line(
     p1: &Point,
     p2: &Point,
     color: &Color,
     initial_opacity: f64,
     opacity_calculator: &OpacityCalculator,
-    pixels: &mut TilePixels,
+    clip: bool,
+    pixels: &mut impl Canvas,
 ) {
     if p1 == p2 {
         return;
@@ -85,8 +212,36 @@ fn draw_line(
     let (mn_delta, mx_delta) = swap_x_y_if_needed(dx, dy, should_swap_x_y);
     let (mn_inc, mx_inc) = swap_x_y_if_needed(get_inc(p1.x, p2.x), get_inc(p1.y, p2.y), should_swap_x_y);
 
-    let mut error = 0;
-    let mut p_error = 0;
+    let total_steps = mx_delta;
+    let (first_step, last_step) = if clip {
+        // Perpendiculars stay within half the line width from the center, which itself deviates
+        // from the ideal line by less than a pixel.
+        let margin = opacity_calculator.half_line_width().ceil() + 2.0;
+        match clip_segment(p1, p2, pixels.bb(), margin) {
+            Some((t_from, t_to)) => {
+                let to_step = |t: f64| (t * f64::from(total_steps)) as i32;
+                ((to_step(t_from) - 1).max(0), (to_step(t_to) + 2).min(total_steps))
+            }
+            None => return,
+        }
+    } else {
+        (0, total_steps)
+    };
+
+    // The error is corrected `(2 * mn_delta * k + mx_delta - 1) / (2 * mx_delta)` times after k updates.
+    let corrections_after = |updates: i32| {
+        ((2 * i64::from(mn_delta) * i64::from(updates) + i64::from(mx_delta) - 1) / (2 * i64::from(mx_delta))) as i32
+    };
+    let error_after = |updates: i32, corrections: i32| {
+        (2 * i64::from(mn_delta) * i64::from(updates) - 2 * i64::from(mx_delta) * i64::from(corrections)) as i32
+    };
+
+    let skipped_corrections = corrections_after(first_step);
+    let mut error = error_after(first_step, skipped_corrections);
+    let mut p_error = error_after(skipped_corrections, corrections_after(skipped_corrections));
+    *mx += first_step * mx_inc;
+    *mn += skipped_corrections * mn_inc;
+    let mut step = first_step;
 
     let update_error = |error: &mut i32| {
         let was_corrected = if *error + 2 * mn_delta > mx_delta {
@@ -153,7 +308,12 @@ fn draw_line(
                 draw_perpendiculars(*mn, *mx, p_error);
             }
         }
+
+        if step == last_step {
+            break;
+        }
         *mx += mx_inc;
+        step += 1;
     }
 }
 
@@ -0,0 +1,125 @@
+use crate::draw::fill::{fill_contour, fill_contour_antialiased, Filler};
+use crate::draw::line::draw_lines;
+use crate::draw::point_pairs::PointPairIter;
+use crate::draw::tile_pixels::{BoundingBox, RgbaColor};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{LineCap, LineJoin};
+
+/// Something that fills and strokes can be rasterized into.
+pub trait Canvas {
+    fn bb(&self) -> &BoundingBox;
+
+    /// `color` is premultiplied by its alpha.
+    fn set_pixel(&mut self, x: i32, y: i32, color: &RgbaColor);
+}
+
+/// How much of every pixel of a tile is covered, with 8 bits of precision. Shapes are drawn into it with the
+/// same rasterization as the colored ones (so antialiased edges get partial coverage), and
+/// `TilePixels::apply_mask` then restricts drawing to the covered pixels.
+#[derive(Clone)]
+pub struct Mask {
+    bb: BoundingBox,
+    dimension: usize,
+    coverage: Vec<u8>,
+}
+
+impl Mask {
+    /// Nothing is covered.
+    pub fn new(dimension: usize) -> Mask {
+        Mask::with_coverage(dimension, 0)
+    }
+
+    /// Everything is covered.
+    pub fn full(dimension: usize) -> Mask {
+        Mask::with_coverage(dimension, u8::MAX)
+    }
+
+    fn with_coverage(dimension: usize, coverage: u8) -> Mask {
+        let max_coord = dimension as i32 - 1;
+        Mask {
+            bb: BoundingBox {
+                min_x: 0,
+                max_x: max_coord,
+                min_y: 0,
+                max_y: max_coord,
+            },
+            dimension,
+            coverage: vec![coverage; dimension * dimension],
+        }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// From 0.0 (not covered, which is also the case outside of the tile) to 1.0 (fully covered).
+    pub fn coverage(&self, x: i32, y: i32) -> f64 {
+        match self.idx(x, y) {
+            Some(idx) => f64::from(self.coverage[idx]) / f64::from(u8::MAX),
+            None => 0.0,
+        }
+    }
+
+    pub fn fill_contour(&mut self, points: PointPairIter<'_>, antialias: bool) {
+        if antialias {
+            fill_contour_antialiased(points, &Filler::Color(&COVERED), 1.0, self);
+        } else {
+            fill_contour(points, &Filler::Color(&COVERED), 1.0, self);
+        }
+    }
+
+    pub fn stroke(
+        &mut self,
+        points: PointPairIter<'_>,
+        width: f64,
+        line_cap: &Option<LineCap>,
+        line_join: &Option<LineJoin>,
+    ) {
+        draw_lines(
+            points, width, &COVERED, 1.0, &None, line_cap, line_join, false, true, self,
+        );
+    }
+
+    /// Only keeps the coverage that is shared with `other`.
+    pub fn intersect(&mut self, other: &Mask) {
+        assert_eq!(self.dimension, other.dimension);
+        for (coverage, other_coverage) in self.coverage.iter_mut().zip(other.coverage.iter()) {
+            *coverage = ((u32::from(*coverage) * u32::from(*other_coverage) + 127) / 255) as u8;
+        }
+    }
+
+    /// Covered pixels become uncovered and vice versa, e.g. to turn a water mask into a land one.
+    pub fn invert(&mut self) {
+        for coverage in self.coverage.iter_mut() {
+            *coverage = u8::MAX - *coverage;
+        }
+    }
+
+    fn idx(&self, x: i32, y: i32) -> Option<usize> {
+        let bb = &self.bb;
+        if x < bb.min_x || x > bb.max_x || y < bb.min_y || y > bb.max_y {
+            return None;
+        }
+        Some(y as usize * self.dimension + x as usize)
+    }
+}
+
+impl Canvas for Mask {
+    fn bb(&self) -> &BoundingBox {
+        &self.bb
+    }
+
+    // Overlapping shapes (e.g. the segments of a stroke) don't add up, the pixel keeps the largest coverage.
+    fn set_pixel(&mut self, x: i32, y: i32, color: &RgbaColor) {
+        if let Some(idx) = self.idx(x, y) {
+            let coverage = (color.a.clamp(0.0, 1.0) * f64::from(u8::MAX)).round() as u8;
+            self.coverage[idx] = self.coverage[idx].max(coverage);
+        }
+    }
+}
+
+const COVERED: Color = Color {
+    r: u8::MAX,
+    g: u8::MAX,
+    b: u8::MAX,
+};
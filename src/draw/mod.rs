@@ -1,13 +1,25 @@
 const TILE_SIZE: usize = crate::tile::TILE_SIZE as usize;
 
+pub mod backend;
+pub mod circle;
+pub mod clip;
+pub mod debug_colors;
+pub mod decoration;
+pub mod dem;
 pub mod drawer;
+pub mod feature_index;
 pub mod fill;
 pub mod font;
+pub mod geojson_writer;
+pub mod graticule;
+pub mod hillshade;
 pub mod icon;
 pub mod icon_cache;
+pub mod jpeg_writer;
 pub mod labelable;
 pub mod labeler;
 pub mod line;
+pub mod mask;
 pub mod opacity_calculator;
 pub mod png_writer;
 pub mod point;
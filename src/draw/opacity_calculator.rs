@@ -42,6 +42,10 @@ impl OpacityCalculator {
         }
     }
 
+    pub fn half_line_width(&self) -> f64 {
+        self.half_line_width
+    }
+
     pub fn add_traveled_distance(&mut self, distance: f64) {
         self.traveled_distance += distance;
     }
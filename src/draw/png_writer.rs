@@ -1,20 +1,90 @@
 use anyhow::{Context, Result};
-use png::{ColorType, Encoder};
+use png::{BitDepth, ColorType, Encoder, Info, SrgbRenderingIntent};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PngBitDepth {
+    #[default]
+    Eight,
+    /// Channels are computed from the same blended colors with 16 bits of precision, which is useful
+    /// for further editing, e.g. in print workflows.
+    Sixteen,
+}
+
+/// How the colors of the image should be interpreted by the viewer.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum ColorProfile {
+    /// Nothing is embedded, most viewers assume sRGB anyway.
+    #[default]
+    None,
+    /// An `sRGB` chunk (with the perceptual rendering intent).
+    Srgb,
+    /// The contents of an `.icc` file, embedded as an `iCCP` chunk.
+    Icc(Arc<[u8]>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PngOptions {
+    pub bit_depth: PngBitDepth,
+    pub color_profile: ColorProfile,
+}
 
 pub fn rgb_triples_to_png(triples: &[(u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
-    let mut buf = Vec::new();
-    {
-        let mut png_encoder = Encoder::new(&mut buf, width as u32, height as u32);
-        png_encoder.set_color(ColorType::Rgb);
-        let mut png_writer = png_encoder.write_header().context("Failed to write PNG header")?;
+    rgb_triples_to_png_with_profile(triples, width, height, &ColorProfile::None)
+}
 
-        let mut image_bytes = Vec::new();
-        for &(r, g, b) in triples {
-            image_bytes.extend([r, g, b].iter());
+pub fn rgb_triples_to_png_with_profile(
+    triples: &[(u8, u8, u8)],
+    width: usize,
+    height: usize,
+    color_profile: &ColorProfile,
+) -> Result<Vec<u8>> {
+    let mut image_bytes = Vec::new();
+    for &(r, g, b) in triples {
+        image_bytes.extend([r, g, b].iter());
+    }
+    encode_png(&image_bytes, width, height, BitDepth::Eight, color_profile)
+}
+
+pub fn rgb16_triples_to_png(
+    triples: &[(u16, u16, u16)],
+    width: usize,
+    height: usize,
+    color_profile: &ColorProfile,
+) -> Result<Vec<u8>> {
+    // PNG stores 16-bit samples in network byte order.
+    let mut image_bytes = Vec::new();
+    for &(r, g, b) in triples {
+        for c in [r, g, b] {
+            image_bytes.extend(c.to_be_bytes().iter());
         }
+    }
+    encode_png(&image_bytes, width, height, BitDepth::Sixteen, color_profile)
+}
 
+fn encode_png(
+    image_bytes: &[u8],
+    width: usize,
+    height: usize,
+    bit_depth: BitDepth,
+    color_profile: &ColorProfile,
+) -> Result<Vec<u8>> {
+    let mut info = Info::with_size(width as u32, height as u32);
+    info.color_type = ColorType::Rgb;
+    info.bit_depth = bit_depth;
+    match color_profile {
+        ColorProfile::None => {}
+        ColorProfile::Srgb => info.srgb = Some(SrgbRenderingIntent::Perceptual),
+        ColorProfile::Icc(profile) => info.icc_profile = Some(Cow::Borrowed(profile)),
+    }
+
+    let mut buf = Vec::new();
+    {
+        let png_encoder = Encoder::with_info(&mut buf, info).context("Failed to set up the PNG encoder")?;
+        let mut png_writer = png_encoder.write_header().context("Failed to write PNG header")?;
         png_writer
-            .write_image_data(image_bytes.as_slice())
+            .write_image_data(image_bytes)
             .context("Failed to write PNG data")?;
     }
     Ok(buf)
@@ -9,9 +9,25 @@ pub struct Point {
 
 impl Point {
     pub fn from_node(node: &Node<'_>, tile: &t::Tile, scale: f64) -> Point {
+        Point::from_node_snapped(node, tile, scale, 0.0)
+    }
+
+    /// Same as `from_node`, but a coordinate closer than `edge_snap_epsilon` (in unscaled tile pixels) to a
+    /// tile edge is moved onto it before rounding. Both tiles sharing the edge then rasterize it at the
+    /// same place, even if it lands just off the pixel boundary.
+    pub fn from_node_snapped(node: &Node<'_>, tile: &t::Tile, scale: f64, edge_snap_epsilon: f64) -> Point {
         let (x, y) = t::coords_to_xy_tile_relative(node, tile);
 
-        let to_coord = |c: f64| (c * scale).round() as i32;
+        let snap = |c: f64| {
+            let tile_size = f64::from(t::TILE_SIZE);
+            let edge = (c / tile_size).round() * tile_size;
+            if (c - edge).abs() < edge_snap_epsilon {
+                edge
+            } else {
+                c
+            }
+        };
+        let to_coord = |c: f64| (snap(c) * scale).round() as i32;
         Point {
             x: to_coord(x),
             y: to_coord(y),
@@ -1,41 +1,76 @@
+use crate::draw::fill::ContourKind;
 use crate::draw::point::Point;
-use crate::geodata::reader::{Multipolygon, Polygon, Way};
+use crate::geodata::reader::{Multipolygon, OsmArea, Polygon, Route, Way};
 use crate::tile::Tile;
 
 pub type PointPairIter<'a> = Box<dyn Iterator<Item = (Point, Point)> + 'a>;
 
 pub trait PointPairCollection<'a> {
-    fn to_point_pairs(&'a self, tile: &'a Tile, scale: f64) -> PointPairIter<'a>;
+    fn to_point_pairs(&'a self, tile: &'a Tile, scale: f64) -> PointPairIter<'a> {
+        self.to_snapped_point_pairs(tile, scale, 0.0)
+    }
+
+    /// See `Point::from_node_snapped`.
+    fn to_snapped_point_pairs(&'a self, tile: &'a Tile, scale: f64, edge_snap_epsilon: f64) -> PointPairIter<'a>;
+
+    fn contour_kind(&self) -> ContourKind {
+        ContourKind::Closed
+    }
 }
 
 macro_rules! implement_to_point_pairs {
-    ($s:expr, $tile:expr, $scale:expr) => {
+    ($s:expr, $tile:expr, $scale:expr, $epsilon:expr) => {
         Box::new((1..$s.node_count()).map(move |idx| {
             let n1 = $s.get_node(idx - 1);
             let n2 = $s.get_node(idx);
             (
-                Point::from_node(&n1, $tile, $scale),
-                Point::from_node(&n2, $tile, $scale),
+                Point::from_node_snapped(&n1, $tile, $scale, $epsilon),
+                Point::from_node_snapped(&n2, $tile, $scale, $epsilon),
             )
         }))
     };
 }
 
 impl<'w> PointPairCollection<'w> for Way<'w> {
-    fn to_point_pairs(&'w self, tile: &'w Tile, scale: f64) -> PointPairIter<'w> {
-        implement_to_point_pairs!(self, tile, scale)
+    fn to_snapped_point_pairs(&'w self, tile: &'w Tile, scale: f64, edge_snap_epsilon: f64) -> PointPairIter<'w> {
+        implement_to_point_pairs!(self, tile, scale, edge_snap_epsilon)
+    }
+
+    fn contour_kind(&self) -> ContourKind {
+        if self.is_closed() {
+            ContourKind::Closed
+        } else {
+            ContourKind::Open
+        }
     }
 }
 
 impl<'p> Polygon<'p> {
-    fn into_point_pairs(self, tile: &'p Tile, scale: f64) -> PointPairIter<'p> {
-        implement_to_point_pairs!(self, tile, scale)
+    fn into_point_pairs(self, tile: &'p Tile, scale: f64, edge_snap_epsilon: f64) -> PointPairIter<'p> {
+        implement_to_point_pairs!(self, tile, scale, edge_snap_epsilon)
     }
 }
 
 impl<'r> PointPairCollection<'r> for Multipolygon<'r> {
-    fn to_point_pairs(&'r self, tile: &'r Tile, scale: f64) -> PointPairIter<'r> {
+    fn to_snapped_point_pairs(&'r self, tile: &'r Tile, scale: f64, edge_snap_epsilon: f64) -> PointPairIter<'r> {
         let polygon_count = self.polygon_count();
-        Box::new((0..polygon_count).flat_map(move |idx| self.get_polygon(idx).into_point_pairs(tile, scale)))
+        Box::new(
+            (0..polygon_count)
+                .flat_map(move |idx| self.get_polygon(idx).into_point_pairs(tile, scale, edge_snap_epsilon)),
+        )
+    }
+}
+
+impl<'r> PointPairCollection<'r> for Route<'r> {
+    fn to_snapped_point_pairs(&'r self, tile: &'r Tile, scale: f64, edge_snap_epsilon: f64) -> PointPairIter<'r> {
+        let polyline_count = self.polyline_count();
+        Box::new(
+            (0..polyline_count)
+                .flat_map(move |idx| self.get_polyline(idx).into_point_pairs(tile, scale, edge_snap_epsilon)),
+        )
+    }
+
+    fn contour_kind(&self) -> ContourKind {
+        ContourKind::Open
     }
 }
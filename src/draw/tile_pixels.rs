@@ -1,3 +1,6 @@
+use crate::draw::feature_index::{FeatureId, FeatureIndex};
+use crate::draw::icon::Icon;
+use crate::draw::mask::{Canvas, Mask};
 use crate::draw::TILE_SIZE;
 use crate::mapcss::color::Color;
 
@@ -24,6 +27,16 @@ impl RgbaColor {
     pub fn from_components(r: u8, g: u8, b: u8, a: u8) -> RgbaColor {
         RgbaColor::from_color(&Color { r, g, b }, component_to_opacity(a))
     }
+
+    /// Multiplies all components, which for a premultiplied color is the same as reducing its opacity.
+    pub fn scaled(&self, factor: f64) -> RgbaColor {
+        RgbaColor {
+            r: self.r * factor,
+            g: self.g * factor,
+            b: self.b * factor,
+            a: self.a * factor,
+        }
+    }
 }
 
 pub struct TilePixels {
@@ -35,6 +48,11 @@ pub struct TilePixels {
     next_pixels: Vec<Option<NextPixel>>,
     generation: usize,
     label_generation_statuses: Vec<bool>,
+    is_blank: bool,
+    // Only allocated while the feature index is enabled.
+    feature_ids: Option<Vec<Option<FeatureId>>>,
+    current_feature: Option<FeatureId>,
+    mask: Option<Mask>,
 }
 
 #[derive(Clone)]
@@ -44,6 +62,7 @@ struct NextPixel {
 }
 
 pub type RgbTriples = Vec<(u8, u8, u8)>;
+pub type Rgb16Triples = Vec<(u16, u16, u16)>;
 
 #[derive(Clone)]
 pub struct BoundingBox {
@@ -83,6 +102,10 @@ impl TilePixels {
             next_pixels: vec![None; pixel_count],
             generation: 0,
             label_generation_statuses: Vec::new(),
+            is_blank: true,
+            feature_ids: None,
+            current_feature: None,
+            mask: None,
         }
     }
 
@@ -102,6 +125,59 @@ impl TilePixels {
 
         self.generation = 0;
         self.label_generation_statuses.clear();
+        self.is_blank = true;
+
+        if let Some(feature_ids) = self.feature_ids.as_mut() {
+            feature_ids.fill(None);
+        }
+        self.current_feature = None;
+        self.mask = None;
+    }
+
+    /// Composites a (premultiplied) image over the canvas, e.g. a basemap tile for an overlay style. The image
+    /// covers the whole tile: a tile `n` times larger than the image repeats every image pixel `n`x`n` times.
+    pub fn draw_underlay(&mut self, image: &Icon) {
+        assert_eq!(image.width, image.height);
+        let factor = (self.scaled_tile_size / image.width.max(1)).max(1);
+        for y in 0..self.scaled_tile_size {
+            for x in 0..self.scaled_tile_size {
+                let color = image.get((x / factor).min(image.width - 1), (y / factor).min(image.height - 1));
+                if color.a <= 0.0 {
+                    continue;
+                }
+                let Some(idx) = self.global_coords_to_idx(x as i32, y as i32, false) else {
+                    continue;
+                };
+                self.next_pixels[idx] = Some(NextPixel {
+                    color,
+                    generation: self.generation,
+                });
+                self.blend_pixel(idx, false);
+            }
+        }
+    }
+
+    /// Start or stop recording which feature covers every pixel.
+    pub fn set_feature_index_enabled(&mut self, enabled: bool) {
+        if enabled != self.feature_ids.is_some() {
+            self.feature_ids = enabled.then(|| vec![None; self.pixels.len()]);
+        }
+    }
+
+    /// Pixels that are set from now on are attributed to `feature` in the feature index.
+    pub fn set_current_feature(&mut self, feature: Option<FeatureId>) {
+        self.current_feature = feature;
+    }
+
+    /// Everything drawn from now on (except for labels) is restricted to the pixels covered by `mask`,
+    /// until the mask is cleared or the pixels are reset.
+    pub fn apply_mask(&mut self, mask: &Mask) {
+        assert_eq!(mask.dimension(), self.scaled_tile_size);
+        self.mask = Some(mask.clone());
+    }
+
+    pub fn clear_mask(&mut self) {
+        self.mask = None;
     }
 
     pub fn set_pixel(&mut self, x: i32, y: i32, color: &RgbaColor) {
@@ -110,6 +186,25 @@ impl TilePixels {
             _ => return,
         };
 
+        let masked_color;
+        let color = match &self.mask {
+            Some(mask) => {
+                let coverage = mask.coverage(x, y);
+                if coverage == 0.0 {
+                    return;
+                }
+                masked_color = color.scaled(coverage);
+                &masked_color
+            }
+            None => color,
+        };
+
+        if let (Some(feature_ids), Some(feature)) = (self.feature_ids.as_mut(), self.current_feature) {
+            if color.a > 0.0 {
+                feature_ids[idx] = Some(feature);
+            }
+        }
+
         let mut from_same_generation = false;
         if let Some(next_pixel) = &mut self.next_pixels[idx] {
             if next_pixel.generation == self.generation {
@@ -128,7 +223,9 @@ impl TilePixels {
         }
     }
 
-    pub fn set_label_pixel(&mut self, x: i32, y: i32, color: &RgbaColor) -> bool {
+    /// Returns false if the pixel is already taken by a previously placed label, unless `allow_overlap`
+    /// is set.
+    pub fn set_label_pixel(&mut self, x: i32, y: i32, color: &RgbaColor, allow_overlap: bool) -> bool {
         let idx = match self.global_coords_to_idx(x, y, true) {
             Some(idx) => idx,
             _ => return true,
@@ -136,7 +233,10 @@ impl TilePixels {
 
         let label_generation = self.label_generation_statuses.len();
         if let Some(next_pixel) = &mut self.next_pixels[idx] {
-            if next_pixel.generation < label_generation && self.label_generation_statuses[next_pixel.generation] {
+            if !allow_overlap
+                && next_pixel.generation < label_generation
+                && self.label_generation_statuses[next_pixel.generation]
+            {
                 return false;
             }
         }
@@ -147,10 +247,29 @@ impl TilePixels {
         true
     }
 
+    /// Same as `set_label_pixel`, but the pixel keeps its color, and an empty one stays transparent. Later
+    /// labels collide with it all the same.
+    pub fn reserve_label_pixel(&mut self, x: i32, y: i32, allow_overlap: bool) -> bool {
+        let color = match self.global_coords_to_idx(x, y, true) {
+            Some(idx) => match &self.next_pixels[idx] {
+                Some(next_pixel) => next_pixel.color.clone(),
+                None => RgbaColor::from_components(0, 0, 0, 0),
+            },
+            None => return true,
+        };
+        self.set_label_pixel(x, y, &color, allow_overlap)
+    }
+
     pub fn bump_generation(&mut self) {
         self.generation += 1;
     }
 
+    /// Undoes the last `bump_generation`, so that the pixels set from now on are merged with the ones of the
+    /// previous shape (keeping the most opaque color) instead of being blended over them.
+    pub fn merge_with_previous_generation(&mut self) {
+        self.generation = self.generation.saturating_sub(1);
+    }
+
     pub fn blend_unfinished_pixels(&mut self, for_labels: bool) {
         for idx in 0..self.next_pixels.len() {
             self.blend_pixel(idx, for_labels);
@@ -162,6 +281,29 @@ impl TilePixels {
     }
 
     pub fn to_rgb_triples(&self) -> RgbTriples {
+        self.to_triples(|c| (f64::from(u8::MAX) * c) as u8)
+    }
+
+    /// Same as `to_rgb_triples`, but with 16 bits per channel. Components are rounded, so a color that
+    /// is exactly representable with 8 bits gets each of its channels multiplied by 257.
+    pub fn to_rgb16_triples(&self) -> Rgb16Triples {
+        self.to_triples(|c| (f64::from(u16::MAX) * c).round() as u16)
+    }
+
+    /// `None` unless the feature index is enabled.
+    pub fn to_feature_index(&self) -> Option<FeatureIndex> {
+        let feature_ids = self.feature_ids.as_ref()?;
+        let non_label_pixel_range = || self.scaled_tile_size..2 * self.scaled_tile_size;
+        let mut features = Vec::with_capacity(self.scaled_tile_size * self.scaled_tile_size);
+        for y in non_label_pixel_range() {
+            for x in non_label_pixel_range() {
+                features.push(feature_ids[self.local_coords_to_idx(x, y)]);
+            }
+        }
+        Some(FeatureIndex::new(self.scaled_tile_size, features))
+    }
+
+    fn to_triples<T>(&self, to_component: impl Fn(f64) -> T) -> Vec<(T, T, T)> {
         let mut triples = Vec::new();
 
         let non_label_pixel_range = || self.scaled_tile_size..2 * self.scaled_tile_size;
@@ -169,10 +311,7 @@ impl TilePixels {
         for y in non_label_pixel_range() {
             for x in non_label_pixel_range() {
                 let p = &self.pixels[self.local_coords_to_idx(x, y)];
-                let postdivide = |val| {
-                    let mul = if p.a == 0.0 { 0.0 } else { val / p.a };
-                    (f64::from(u8::MAX) * mul) as u8
-                };
+                let postdivide = |val| to_component(if p.a == 0.0 { 0.0 } else { val / p.a });
                 triples.push((postdivide(p.r), postdivide(p.g), postdivide(p.b)));
             }
         }
@@ -180,6 +319,12 @@ impl TilePixels {
         triples
     }
 
+    // A tile stays blank until blending actually changes at least one pixel, so drawing
+    // something fully transparent on top of the canvas doesn't count.
+    pub fn is_blank(&self) -> bool {
+        self.is_blank
+    }
+
     pub fn dimension(&self) -> usize {
         self.scaled_tile_size
     }
@@ -206,6 +351,9 @@ impl TilePixels {
         let next_pixel_ref = &mut self.next_pixels[idx];
         if let Some(next_pixel) = next_pixel_ref {
             if !for_labels || self.label_generation_statuses[next_pixel.generation] {
+                if next_pixel.color.a > 0.0 {
+                    self.is_blank = false;
+                }
                 let old_pixel = &mut self.pixels[idx];
                 let new_pixel = {
                     let blend = |new_value, old_value| new_value + (1.0 - next_pixel.color.a) * old_value;
@@ -223,6 +371,45 @@ impl TilePixels {
     }
 }
 
+impl Canvas for TilePixels {
+    fn bb(&self) -> &BoundingBox {
+        TilePixels::bb(self)
+    }
+
+    fn set_pixel(&mut self, x: i32, y: i32, color: &RgbaColor) {
+        TilePixels::set_pixel(self, x, y, color)
+    }
+}
+
+/// Box-filters a square image of `dimension`x`dimension` pixels by averaging every `factor`x`factor` block.
+pub fn downscale_rgb_triples<T>(triples: &[(T, T, T)], dimension: usize, factor: usize) -> Vec<(T, T, T)>
+where
+    T: Copy + Into<u32> + TryFrom<u32>,
+{
+    let target_dimension = dimension / factor;
+    let block_size = (factor * factor) as u32;
+    let mut result = Vec::with_capacity(target_dimension * target_dimension);
+
+    for y in 0..target_dimension {
+        for x in 0..target_dimension {
+            let (mut r, mut g, mut b) = (0, 0, 0);
+            for dy in 0..factor {
+                for dx in 0..factor {
+                    let p = triples[(y * factor + dy) * dimension + x * factor + dx];
+                    r += p.0.into();
+                    g += p.1.into();
+                    b += p.2.into();
+                }
+            }
+            // The average of components never exceeds their maximum, so the conversion can't fail.
+            let average = |sum| T::try_from((sum + block_size / 2) / block_size).unwrap_or_else(|_| unreachable!());
+            result.push((average(r), average(g), average(b)));
+        }
+    }
+
+    result
+}
+
 fn component_to_opacity(comp: u8) -> f64 {
     f64::from(comp) / f64::from(u8::MAX)
 }
@@ -0,0 +1,41 @@
+//! Entry points for the benchmarks in `benches/`, which can only reach the public API. Only built with
+//! the `bench-internals` feature, so that they don't become part of it.
+
+use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
+
+/// The segments of a multipolygon relation, as they come out of the importer before assembly.
+pub struct SyntheticRelation {
+    segments: Vec<NodeDescPair>,
+}
+
+/// Builds `ring_count` concentric rings of `nodes_per_ring` nodes each, alternating between outer
+/// rings and holes. The segments are shuffled deterministically, so assembly can't just follow them
+/// in order.
+pub fn synthetic_relation(ring_count: usize, nodes_per_ring: usize) -> SyntheticRelation {
+    let mut segments = Vec::new();
+    for ring in 0..ring_count {
+        let radius = 0.01 * (ring_count - ring) as f64;
+        let node = |idx: usize| {
+            let idx = idx % nodes_per_ring;
+            let angle = 2.0 * std::f64::consts::PI * idx as f64 / nodes_per_ring as f64;
+            NodeDesc::new(ring * nodes_per_ring + idx, radius * angle.sin(), radius * angle.cos())
+        };
+        for idx in 0..nodes_per_ring {
+            segments.push(NodeDescPair::new(node(idx), node(idx + 1), ring % 2 == 1));
+        }
+    }
+
+    // A fixed LCG is enough to scatter the segments.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    for idx in (1..segments.len()).rev() {
+        state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        segments.swap(idx, (state >> 33) as usize % (idx + 1));
+    }
+
+    SyntheticRelation { segments }
+}
+
+/// Returns the number of assembled rings.
+pub fn assemble_relation(relation: &SyntheticRelation, ignore_roles: bool) -> usize {
+    find_polygons_in_multipolygon(0, &relation.segments, ignore_roles).map_or(0, |polygons| polygons.len())
+}
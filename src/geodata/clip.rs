@@ -0,0 +1,319 @@
+use crate::geodata::find_polygons::point_in_ring;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// An area to clip the imported data to. A point is inside when it's inside an odd number of rings,
+/// so holes and several separate parts are supported.
+#[derive(Clone, Debug)]
+pub struct ClipPolygon {
+    /// Closed rings of `(lon, lat)` pairs.
+    rings: Vec<Vec<(f64, f64)>>,
+}
+
+impl ClipPolygon {
+    /// Takes rings of `(lon, lat)` pairs, the same order as in GeoJSON. Rings are closed if needed.
+    pub fn new(rings: Vec<Vec<(f64, f64)>>) -> Result<ClipPolygon> {
+        let mut closed_rings = Vec::new();
+        for mut ring in rings {
+            if ring.is_empty() {
+                bail!("A clip polygon ring can't be empty");
+            }
+            if ring.first() != ring.last() {
+                ring.push(ring[0]);
+            }
+            if ring.len() < 4 {
+                bail!("A clip polygon ring needs at least 3 distinct points");
+            }
+            closed_rings.push(ring);
+        }
+        if closed_rings.is_empty() {
+            bail!("A clip polygon needs at least one ring");
+        }
+        Ok(ClipPolygon { rings: closed_rings })
+    }
+
+    /// Accepts a `Polygon` or a `MultiPolygon` geometry, or a `Feature`/`FeatureCollection` made of
+    /// them, in which case all the polygons are combined.
+    pub fn from_geojson(geojson: &str) -> Result<ClipPolygon> {
+        let mut parser = JsonParser {
+            text: geojson.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.text.len() {
+            bail!("Unexpected trailing characters at offset {}", parser.pos);
+        }
+
+        let mut rings = Vec::new();
+        collect_rings(&value, &mut rings)?;
+        ClipPolygon::new(rings)
+    }
+
+    pub fn load_geojson(path: &Path) -> Result<ClipPolygon> {
+        let text = std::fs::read_to_string(path).context(format!("Failed to read {}", path.to_string_lossy()))?;
+        ClipPolygon::from_geojson(&text).context(format!("Failed to parse {}", path.to_string_lossy()))
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        let inside_count = self
+            .rings
+            .iter()
+            .filter(|ring| point_in_ring(ring.iter().cloned(), (lon, lat)))
+            .count();
+        inside_count % 2 == 1
+    }
+}
+
+fn collect_rings(value: &JsonValue, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<()> {
+    let object = value.as_object().context("Expected a GeoJSON object")?;
+    let get = |key: &str| {
+        object
+            .get(key)
+            .context(format!("Missing \"{}\" in a GeoJSON object", key))
+    };
+    let object_type = match get("type")? {
+        JsonValue::String(object_type) => object_type.as_str(),
+        _ => bail!("GeoJSON \"type\" should be a string"),
+    };
+
+    match object_type {
+        "FeatureCollection" => {
+            for feature in get("features")?.as_array().context("Expected an array of features")? {
+                collect_rings(feature, rings)?;
+            }
+        }
+        "Feature" => collect_rings(get("geometry")?, rings)?,
+        "Polygon" => rings.extend(to_rings(get("coordinates")?)?),
+        "MultiPolygon" => {
+            let polygons = get("coordinates")?
+                .as_array()
+                .context("Expected an array of polygons")?;
+            for polygon in polygons {
+                rings.extend(to_rings(polygon)?);
+            }
+        }
+        _ => bail!("Unsupported GeoJSON type {}, expected a polygon", object_type),
+    }
+    Ok(())
+}
+
+fn to_rings(coordinates: &JsonValue) -> Result<Vec<Vec<(f64, f64)>>> {
+    let as_number = |value: &JsonValue| match value {
+        JsonValue::Number(num) => Ok(*num),
+        _ => bail!("Expected a number in GeoJSON coordinates"),
+    };
+    let to_position = |position: &JsonValue| -> Result<(f64, f64)> {
+        match position.as_array() {
+            Some(lon_lat) if lon_lat.len() >= 2 => Ok((as_number(&lon_lat[0])?, as_number(&lon_lat[1])?)),
+            _ => bail!("Expected a [lon, lat] position"),
+        }
+    };
+
+    coordinates
+        .as_array()
+        .context("Expected an array of rings")?
+        .iter()
+        .map(|ring| {
+            ring.as_array()
+                .context("Expected an array of positions")?
+                .iter()
+                .map(to_position)
+                .collect()
+        })
+        .collect()
+}
+
+enum JsonValue {
+    /// `true`, `false` or `null`.
+    Literal,
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(BTreeMap<String, JsonValue>),
+}
+
+impl JsonValue {
+    fn as_array(&self) -> Option<&Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    fn as_object(&self) -> Option<&BTreeMap<String, JsonValue>> {
+        match self {
+            JsonValue::Object(object) => Some(object),
+            _ => None,
+        }
+    }
+}
+
+// Arrays and objects nested deeper than this are rejected instead of overflowing the stack. The deepest
+// GeoJSON is a `MultiPolygon` in a `FeatureCollection`, at 7 levels; serde_json has the same limit.
+const MAX_JSON_DEPTH: usize = 128;
+
+// Just enough JSON to read GeoJSON files: the values that aren't needed are parsed and thrown away.
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<u8> {
+        self.skip_whitespace();
+        match self.text.get(self.pos) {
+            Some(c) => Ok(*c),
+            None => bail!("Unexpected end of JSON"),
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        let c = self.peek()?;
+        if c != expected {
+            bail!(
+                "Expected '{}' at offset {}, got '{}'",
+                expected as char,
+                self.pos,
+                c as char
+            );
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue> {
+        match self.peek()? {
+            b'{' => self.parse_nested(Self::parse_object),
+            b'[' => self.parse_nested(Self::parse_array),
+            b'"' => self.parse_string().map(JsonValue::String),
+            b't' => self.parse_literal("true"),
+            b'f' => self.parse_literal("false"),
+            b'n' => self.parse_literal("null"),
+            _ => self.parse_number(),
+        }
+    }
+
+    fn parse_nested(&mut self, parse: fn(&mut Self) -> Result<JsonValue>) -> Result<JsonValue> {
+        if self.depth == MAX_JSON_DEPTH {
+            bail!(
+                "JSON nested deeper than {} levels at offset {}",
+                MAX_JSON_DEPTH,
+                self.pos
+            );
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_literal(&mut self, literal: &str) -> Result<JsonValue> {
+        if !self.text[self.pos..].starts_with(literal.as_bytes()) {
+            bail!("Unexpected token at offset {}", self.pos);
+        }
+        self.pos += literal.len();
+        Ok(JsonValue::Literal)
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue> {
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+        {
+            self.pos += 1;
+        }
+        let number = std::str::from_utf8(&self.text[start..self.pos])?;
+        match number.parse() {
+            Ok(number) => Ok(JsonValue::Number(number)),
+            Err(_) => bail!("Invalid JSON value at offset {}", start),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut result = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(c) => *c,
+                None => bail!("Unterminated JSON string"),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.text.get(self.pos).cloned();
+                    self.pos += 1;
+                    match escaped {
+                        Some(b'n') => result.push(b'\n'),
+                        Some(b't') => result.push(b'\t'),
+                        Some(b'r') => result.push(b'\r'),
+                        Some(b'b') => result.push(8),
+                        Some(b'f') => result.push(12),
+                        Some(b'u') => {
+                            let hex = self.text.get(self.pos..self.pos + 4).context("Invalid \\u escape")?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex)?, 16)?;
+                            self.pos += 4;
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            result.extend(c.to_string().as_bytes());
+                        }
+                        Some(other) => result.push(other),
+                        None => bail!("Unterminated JSON string"),
+                    }
+                }
+                _ => result.push(c),
+            }
+        }
+        Ok(String::from_utf8(result)?)
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue> {
+        self.expect(b'[')?;
+        let mut array = Vec::new();
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Ok(JsonValue::Array(array));
+        }
+        loop {
+            array.push(self.parse_value()?);
+            if self.peek()? == b',' {
+                self.pos += 1;
+            } else {
+                self.expect(b']')?;
+                return Ok(JsonValue::Array(array));
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue> {
+        self.expect(b'{')?;
+        let mut object = BTreeMap::new();
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Ok(JsonValue::Object(object));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            object.insert(key, self.parse_value()?);
+            if self.peek()? == b',' {
+                self.pos += 1;
+            } else {
+                self.expect(b'}')?;
+                return Ok(JsonValue::Object(object));
+            }
+        }
+    }
+}
@@ -29,34 +29,134 @@ impl NodeDescPair {
     }
 }
 
+// With `ignore_roles`, segments are joined into rings regardless of their member roles, and the rings
+// are then classified by containment: a ring inside an odd number of other rings is a hole. Outer
+// rings are oriented counter-clockwise and holes clockwise.
 pub(super) fn find_polygons_in_multipolygon(
     relation_id: u64,
     relation_segments: &[NodeDescPair],
+    ignore_roles: bool,
 ) -> Option<Vec<Polygon>> {
-    let connections = get_connections(relation_segments);
+    let connections = get_connections(relation_segments, ignore_roles);
     let mut available_segments = vec![true; relation_segments.len()];
-    find_rings(relation_id, relation_segments, &connections, &mut available_segments).map(|all_rings| {
+    find_rings(
+        relation_id,
+        relation_segments,
+        &connections,
+        &mut available_segments,
+        ignore_roles,
+    )
+    .map(|all_rings| {
         let mut polygons = Vec::new();
+        let mut ring_positions = Vec::new();
         for ring in all_rings {
             let mut polygon = Polygon::default();
+            let mut positions = Vec::new();
             for idx in 0..ring.len() {
                 let seg = &relation_segments[ring[idx]];
                 if idx == 0 {
                     polygon.push(seg.node1.id);
+                    positions.push(seg.node1.pos);
                 }
                 let last_node = polygon[polygon.len() - 1];
-                polygon.push(if last_node == seg.node1.id {
-                    seg.node2.id
+                let next_node = if last_node == seg.node1.id {
+                    &seg.node2
                 } else {
-                    seg.node1.id
-                });
+                    &seg.node1
+                };
+                polygon.push(next_node.id);
+                positions.push(next_node.pos);
+            }
+            if is_degenerate(&positions) {
+                eprintln!(
+                    "Relation #{} has a ring that collapses to a line or a point, skipping it",
+                    relation_id
+                );
+                continue;
             }
             polygons.push(polygon);
+            ring_positions.push(positions);
+        }
+        if ignore_roles {
+            orient_by_containment(&mut polygons, &ring_positions);
         }
         polygons
     })
 }
 
+fn orient_by_containment(polygons: &mut [Polygon], ring_positions: &[Vec<NodePos>]) {
+    for (idx, polygon) in polygons.iter_mut().enumerate() {
+        let containing_ring_count = ring_positions
+            .iter()
+            .enumerate()
+            .filter(|&(other_idx, other)| other_idx != idx && ring_contains_ring(other, &ring_positions[idx]))
+            .count();
+        let is_inner = containing_ring_count % 2 == 1;
+        let is_counter_clockwise = signed_area(ring_positions[idx].iter().map(to_lon_lat)) > 0.0;
+        if is_inner == is_counter_clockwise {
+            polygon.reverse();
+        }
+    }
+}
+
+// In squared degrees, which is about a square centimeter near the equator.
+const MIN_RING_AREA: f64 = 1e-14;
+
+fn is_degenerate(ring: &[NodePos]) -> bool {
+    let distinct_vertices = ring.iter().collect::<HashSet<_>>().len();
+    distinct_vertices < 3 || signed_area(ring.iter().map(to_lon_lat)).abs() < MIN_RING_AREA
+}
+
+fn to_lon_lat(pos: &NodePos) -> (f64, f64) {
+    (f64::from_bits(pos.1), f64::from_bits(pos.0))
+}
+
+/// Shoelace area of a closed ring of `(x, y)` vertices, positive for counter-clockwise rings.
+///
+/// Coordinates are taken relative to the first vertex, otherwise the products of large coordinates
+/// would cancel out with a rounding error much bigger than the area of a small polygon.
+pub(crate) fn signed_area(mut ring: impl Iterator<Item = (f64, f64)>) -> f64 {
+    let Some((origin_x, origin_y)) = ring.next() else {
+        return 0.0;
+    };
+    let mut prev = (0.0, 0.0);
+    let mut doubled_area = 0.0;
+    for (x, y) in ring {
+        let (x2, y2) = (x - origin_x, y - origin_y);
+        doubled_area += prev.0 * y2 - x2 * prev.1;
+        prev = (x2, y2);
+    }
+    doubled_area / 2.0
+}
+
+// Rings of a valid multipolygon may touch but don't cross, so testing any vertex of `inner` that
+// isn't shared with `outer` is enough.
+fn ring_contains_ring(outer: &[NodePos], inner: &[NodePos]) -> bool {
+    inner
+        .iter()
+        .find(|pos| !outer.contains(pos))
+        .is_some_and(|pos| ring_contains_point(outer, to_lon_lat(pos)))
+}
+
+fn ring_contains_point(ring: &[NodePos], point: (f64, f64)) -> bool {
+    point_in_ring(ring.iter().map(to_lon_lat), point)
+}
+
+/// Even-odd test for a closed ring of `(x, y)` vertices, i.e. one where the last vertex repeats the first.
+pub(crate) fn point_in_ring(ring: impl Iterator<Item = (f64, f64)>, (x, y): (f64, f64)) -> bool {
+    let mut is_inside = false;
+    let mut prev = None;
+    for (x2, y2) in ring {
+        if let Some((x1, y1)) = prev {
+            if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+                is_inside = !is_inside;
+            }
+        }
+        prev = Some((x2, y2));
+    }
+    is_inside
+}
+
 struct SearchParams {
     first_pos: NodePos,
     is_inner: bool,
@@ -70,12 +170,13 @@ struct ConnectedSegment {
 
 type SegmentConnections = HashMap<NodePos, Vec<ConnectedSegment>>;
 
-fn get_connections(relation_segments: &[NodeDescPair]) -> SegmentConnections {
+fn get_connections(relation_segments: &[NodeDescPair], ignore_roles: bool) -> SegmentConnections {
     let mut connections = SegmentConnections::new();
 
     for (idx, seg) in relation_segments.iter().enumerate() {
-        add_to_connections(&mut connections, seg.node1.pos, seg.node2.pos, idx, seg.is_inner);
-        add_to_connections(&mut connections, seg.node2.pos, seg.node1.pos, idx, seg.is_inner);
+        let is_inner = seg.is_inner && !ignore_roles;
+        add_to_connections(&mut connections, seg.node1.pos, seg.node2.pos, idx, is_inner);
+        add_to_connections(&mut connections, seg.node2.pos, seg.node1.pos, idx, is_inner);
     }
 
     connections
@@ -114,6 +215,7 @@ fn find_rings(
     relation_segments: &[NodeDescPair],
     connections: &SegmentConnections,
     available_segments: &mut Vec<bool>,
+    ignore_roles: bool,
 ) -> Option<Vec<Vec<usize>>> {
     let mut res = Vec::new();
     let mut unmatched_count = relation_segments.len();
@@ -135,7 +237,7 @@ fn find_rings(
         };
         let search_params = SearchParams {
             first_pos: start_segment.node1.pos,
-            is_inner: start_segment.is_inner,
+            is_inner: start_segment.is_inner && !ignore_roles,
         };
 
         if !find_ring_from(start_segment.node2.pos, &search_params, connections, &mut ring) {
@@ -1,64 +1,254 @@
 use crate::coords;
+use crate::geodata::clip::ClipPolygon;
 use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
 use crate::geodata::saver::save_to_internal_format;
 use anyhow::{anyhow, bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 #[cfg(feature = "pbf")]
-use osmpbf::{Element, ElementReader, RelMemberType};
+use osmpbf::{Blob, BlobDecode, BlobReader, Element, ElementReader, RelMemberType};
 use quick_xml::events::attributes::Attributes;
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::reader::Reader;
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
+use std::mem;
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    /// Assemble multipolygons without looking at the `inner`/`outer` member roles, which are often
+    /// missing or wrong. Holes are then told apart from outer rings by containment.
+    pub ignore_multipolygon_roles: bool,
+    /// Gzip the output file. `GeodataReader` detects compressed files on its own.
+    pub gzip_output: bool,
+    /// Drop everything outside of the polygon. Ways and multipolygons with at least one node inside
+    /// are kept whole, along with all their nodes.
+    pub clip_polygon: Option<ClipPolygon>,
+    /// Extra copies of the way and multipolygon geometry, simplified for lower zooms.
+    pub simplification_levels: Vec<SimplificationLevel>,
+    /// Keep `type=route` relations, with their member ways joined into lines, so that a style can
+    /// draw the whole route (e.g. a bus line in its `colour`) with `relation` selectors.
+    pub import_routes: bool,
+    /// Read the input twice, the first time only to number all nodes and ways, so that references to
+    /// entities further down the file (e.g. ways listed before their nodes) are resolved too. Only needed
+    /// for files that aren't ordered nodes, then ways, then relations.
+    pub resolve_forward_refs: bool,
+    /// Multipolygons with more segments (pairs of consecutive nodes in the member ways) than this are
+    /// reported as `TooManySegments` instead of being assembled, which could take forever for a corrupt
+    /// relation. The default is several times the size of the largest country boundaries.
+    pub max_multipolygon_segments: usize,
+    /// Threads for decoding PBF blocks and assembling multipolygons, all available cores if not set. The
+    /// output is the same for any number of threads.
+    pub thread_count: Option<usize>,
+    /// Tags copied from route and boundary relations onto their member ways, e.g. `ref` and `network`, so
+    /// that the ways can be styled by the relations they belong to. Tags a way already has aren't
+    /// overwritten, and a way in several relations gets the tags of the first one in the file.
+    pub inherited_relation_tags: Vec<String>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            ignore_multipolygon_roles: false,
+            gzip_output: false,
+            clip_polygon: None,
+            simplification_levels: Vec::new(),
+            import_routes: false,
+            resolve_forward_refs: false,
+            max_multipolygon_segments: 10_000_000,
+            thread_count: None,
+            inherited_relation_tags: Vec::new(),
+        }
+    }
+}
+
+/// Geometry simplified once at import time for tiles up to `max_zoom` (and down to the `max_zoom`
+/// of the next coarser level), so that low zoom tiles don't have to walk every node of detailed ways.
+#[derive(Clone, Debug)]
+pub struct SimplificationLevel {
+    pub max_zoom: u8,
+    /// How far the simplified geometry can stray from the original one, in pixels at `max_zoom`.
+    pub tolerance: f64,
+}
 
 pub fn import<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
+    import_with_options(input, output, &ImportOptions::default())
+}
+
+pub fn import_with_options<P: AsRef<Path>>(input: P, output: P, options: &ImportOptions) -> Result<()> {
     let output_file = File::create(output.as_ref()).context(format!(
         "Failed to open {} for writing",
         output.as_ref().to_string_lossy()
     ))?;
-    let mut writer = BufWriter::new(output_file);
+    let entity_storages = parse_input_with_options(input, options, &mut PrintProgress)?;
 
-    let parsed = match input.as_ref().extension().and_then(OsStr::to_str) {
+    if options.gzip_output {
+        // The saver issues lots of tiny writes, so they are buffered before reaching the encoder.
+        let mut writer = BufWriter::new(GzEncoder::new(output_file, Compression::default()));
+        save_with_options(&entity_storages, options, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|encoder| encoder.finish())
+            .context("Failed to finish writing the compressed output file")?;
+        Ok(())
+    } else {
+        save_with_options(&entity_storages, options, &mut BufWriter::new(output_file))
+    }
+}
+
+/// Parses an `.osm`/`.xml` (or `.pbf`, with the `pbf` feature) file without writing anything, so the
+/// result can be inspected or modified before it's passed to `save`.
+pub fn parse_input<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
+    parse_input_with_progress(input, &mut PrintProgress)
+}
+
+pub fn parse_input_with_progress<P: AsRef<Path>>(
+    input: P,
+    progress_sink: &mut dyn ProgressSink,
+) -> Result<EntityStorages> {
+    parse_input_with_options(input, &ImportOptions::default(), progress_sink)
+}
+
+pub fn parse_input_with_options<P: AsRef<Path>>(
+    input: P,
+    options: &ImportOptions,
+    progress_sink: &mut dyn ProgressSink,
+) -> Result<EntityStorages> {
+    let mut progress = ProgressReporter {
+        sink: progress_sink,
+        peak_estimated_memory: 0,
+    };
+    let mut entity_storages = match input.as_ref().extension().and_then(OsStr::to_str) {
         Some("osm") | Some("xml") => {
             let input_file = File::open(input.as_ref()).context(format!(
                 "Failed to open {} for reading",
                 input.as_ref().to_string_lossy()
             ))?;
+            let id_index = if options.resolve_forward_refs {
+                let input_file = File::open(input.as_ref()).context(format!(
+                    "Failed to open {} for reading",
+                    input.as_ref().to_string_lossy()
+                ))?;
+                Some(index_osm_xml_ids(Reader::from_reader(BufReader::new(input_file)))?)
+            } else {
+                None
+            };
             let parser = Reader::from_reader(BufReader::new(input_file));
-            parse_osm_xml(parser)?
+            parse_osm_xml(parser, options, id_index, thread_count(options), &mut progress)?
         }
         #[cfg(feature = "pbf")]
-        Some("pbf") => parse_pbf(input)?,
+        Some("pbf") => {
+            let id_index = if options.resolve_forward_refs {
+                Some(index_pbf_ids(&input)?)
+            } else {
+                None
+            };
+            parse_pbf(input, options, id_index, thread_count(options), &mut progress)?
+        }
         _ => bail!("Extension not supported"),
     };
 
+    if let Some(clip_polygon) = options.clip_polygon.as_ref() {
+        println!("Clipping to the polygon");
+        clip_to_polygon(&mut entity_storages, clip_polygon);
+        progress.report(&entity_storages);
+    }
+
+    Ok(entity_storages)
+}
+
+/// Parses the input and assembles the multipolygons just like `import` does, but returns the problems
+/// found along the way instead of writing anything.
+pub fn validate<P: AsRef<Path>>(input: P, options: &ImportOptions) -> Result<Vec<ImportProblem>> {
+    Ok(parse_input_with_options(input, options, &mut PrintProgress)?.problems)
+}
+
+pub fn save(entity_storages: &EntityStorages, writer: &mut dyn Write) -> Result<()> {
+    save_with_options(entity_storages, &ImportOptions::default(), writer)
+}
+
+/// Same as `save`, but also writes the `simplification_levels` from `options`.
+pub fn save_with_options(
+    entity_storages: &EntityStorages,
+    options: &ImportOptions,
+    writer: &mut dyn Write,
+) -> Result<()> {
     println!("Converting geodata to internal format");
-    save_to_internal_format(&mut writer, &parsed).context("Failed to write the imported data to the output file")?;
-    Ok(())
+    save_to_internal_format(writer, entity_storages, &options.simplification_levels)
+        .context("Failed to write the imported data to the output file")
 }
 
 pub(super) struct OsmEntityStorage<E: Default> {
     global_id_to_local_id: HashMap<u64, usize>,
     entities: Vec<E>,
+    has_reserved_ids: bool,
 }
 
 impl<E: Default> OsmEntityStorage<E> {
+    fn estimated_memory_usage(&self) -> usize {
+        let map_entry_size = mem::size_of::<u64>() + mem::size_of::<usize>();
+        self.entities.capacity() * mem::size_of::<E>() + self.global_id_to_local_id.capacity() * map_entry_size
+    }
+
     fn new() -> OsmEntityStorage<E> {
         OsmEntityStorage {
             global_id_to_local_id: HashMap::new(),
             entities: Vec::new(),
+            has_reserved_ids: false,
+        }
+    }
+
+    // The IDs must have been assigned in the order the entities are going to be added.
+    fn with_reserved_ids(global_id_to_local_id: HashMap<u64, usize>) -> OsmEntityStorage<E> {
+        OsmEntityStorage {
+            global_id_to_local_id,
+            entities: Vec::new(),
+            has_reserved_ids: true,
         }
     }
 
-    fn add(&mut self, global_id: u64, entity: E) {
-        let old_size = self.entities.len();
-        self.global_id_to_local_id.insert(global_id, old_size);
-        self.entities.push(entity);
+    // Returns false and drops the entity if one with the same ID has already been added. Reserved IDs
+    // are taken in the order the entities are added, so a duplicate is one whose slot is already filled.
+    // An entity that comes before its slot or has none at all means that the IDs were assigned to
+    // different entities than the ones that are added, and the refs to them would be wrong.
+    fn add(&mut self, global_id: u64, entity: E) -> Result<bool> {
+        let local_id = self.entities.len();
+        let is_new = if self.has_reserved_ids {
+            match self.translate_id(global_id) {
+                Some(reserved_id) if reserved_id == local_id => true,
+                Some(reserved_id) if reserved_id < local_id => false,
+                Some(reserved_id) => bail!(
+                    "Entity #{} was added at {} instead of its reserved ID {}",
+                    global_id as i64,
+                    local_id,
+                    reserved_id
+                ),
+                None => bail!("Entity #{} has no reserved ID", global_id as i64),
+            }
+        } else {
+            match self.global_id_to_local_id.entry(global_id) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(local_id);
+                    true
+                }
+            }
+        };
+        if is_new {
+            self.entities.push(entity);
+        }
+        Ok(is_new)
     }
 
     fn translate_id(&self, global_id: u64) -> Option<usize> {
@@ -68,38 +258,396 @@ impl<E: Default> OsmEntityStorage<E> {
     pub(super) fn get_entities(&self) -> &Vec<E> {
         &self.entities
     }
+
+    // Removes the entities that aren't marked in `keep` and returns the new positions of the old ones.
+    fn retain(&mut self, keep: &[bool]) -> Vec<Option<usize>> {
+        let mut kept_count = 0;
+        let new_ids = keep
+            .iter()
+            .map(|&is_kept| {
+                kept_count += usize::from(is_kept);
+                if is_kept {
+                    Some(kept_count - 1)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut idx = 0;
+        self.entities.retain(|_| {
+            idx += 1;
+            keep[idx - 1]
+        });
+        self.global_id_to_local_id
+            .retain(|_, local_id| match new_ids[*local_id] {
+                Some(new_id) => {
+                    *local_id = new_id;
+                    true
+                }
+                None => false,
+            });
+        new_ids
+    }
+}
+
+// The local IDs of all nodes and ways of the input, numbered in the order they appear in it, just like
+// `OsmEntityStorage::add` would do.
+#[derive(Default)]
+struct IdIndex {
+    node_ids: HashMap<u64, usize>,
+    node_count: usize,
+    way_ids: HashMap<u64, usize>,
+    way_count: usize,
+}
+
+impl IdIndex {
+    // Duplicates are dropped by `OsmEntityStorage::add`, which also reports them, so they don't get a
+    // local ID.
+    fn add(&mut self, kind: EntityKind, id: u64) {
+        let (ids, count) = match kind {
+            EntityKind::Node => (&mut self.node_ids, &mut self.node_count),
+            EntityKind::Way => (&mut self.way_ids, &mut self.way_count),
+            EntityKind::Relation => return,
+        };
+        if let Entry::Vacant(entry) = ids.entry(id) {
+            entry.insert(*count);
+            *count += 1;
+        }
+    }
+
+    // Nodes that `check_node_coords` is going to skip don't get a local ID, or the ones of all
+    // the nodes after them would be off by one.
+    fn add_node(&mut self, id: u64, lat: f64, lon: f64) {
+        if lat.is_finite() && lon.is_finite() {
+            self.add(EntityKind::Node, id);
+        }
+    }
+}
+
+fn index_osm_xml_ids<R: BufRead>(mut parser: Reader<R>) -> Result<IdIndex> {
+    let mut id_index = IdIndex::default();
+    parser.config_mut().check_end_names = false;
+    parser.config_mut().allow_unmatched_ends = true;
+
+    println!("Indexing XML IDs");
+    let mut buf = Vec::new();
+    loop {
+        let e = parser
+            .read_event_into(&mut buf)
+            .context("Failed to parse the input file")?;
+        match e {
+            Event::Eof => break,
+            Event::Start(start) | Event::Empty(start) => {
+                let name = start.local_name();
+                let attrs = &mut start.attributes();
+                match name.as_ref() {
+                    b"node" => {
+                        let id = get_id(&mut parser, name.as_ref(), attrs)?;
+                        let lat = parse_required_attr(&mut parser, name.as_ref(), attrs, b"lat")?;
+                        let lon = parse_required_attr(&mut parser, name.as_ref(), attrs, b"lon")?;
+                        id_index.add_node(id, lat, lon);
+                    }
+                    b"way" => id_index.add(EntityKind::Way, get_id(&mut parser, name.as_ref(), attrs)?),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(id_index)
 }
 
-pub(super) struct EntityStorages {
+#[cfg(feature = "pbf")]
+fn index_pbf_ids<P: AsRef<Path>>(input: P) -> Result<IdIndex> {
+    let mut id_index = IdIndex::default();
+    println!("Indexing PBF IDs");
+    ElementReader::from_path(input)?.for_each(|element| match element {
+        Element::DenseNode(el_node) => id_index.add_node(el_node.id() as u64, el_node.lat(), el_node.lon()),
+        Element::Way(el_way) => id_index.add(EntityKind::Way, el_way.id() as u64),
+        _ => {}
+    })?;
+    Ok(id_index)
+}
+
+pub struct EntityStorages {
     pub(super) node_storage: OsmEntityStorage<RawNode>,
     pub(super) way_storage: OsmEntityStorage<RawWay>,
     pub(super) polygon_storage: Vec<Polygon>,
+    pub(super) polyline_storage: Vec<Polyline>,
     pub(super) multipolygon_storage: OsmEntityStorage<Multipolygon>,
+    pub(super) route_storage: OsmEntityStorage<Route>,
+    problems: Vec<ImportProblem>,
+    extent: Option<coords::BoundingBox>,
+}
+
+// Entities refer to each other by their position in these slices, so they can be modified in place
+// but not removed or reordered.
+impl EntityStorages {
+    fn new(id_index: Option<IdIndex>) -> EntityStorages {
+        let (node_storage, way_storage) = match id_index {
+            Some(id_index) => (
+                OsmEntityStorage::with_reserved_ids(id_index.node_ids),
+                OsmEntityStorage::with_reserved_ids(id_index.way_ids),
+            ),
+            None => (OsmEntityStorage::new(), OsmEntityStorage::new()),
+        };
+        EntityStorages {
+            node_storage,
+            way_storage,
+            polygon_storage: Vec::new(),
+            polyline_storage: Vec::new(),
+            multipolygon_storage: OsmEntityStorage::new(),
+            route_storage: OsmEntityStorage::new(),
+            problems: Vec::new(),
+            extent: None,
+        }
+    }
+
+    pub fn nodes(&self) -> &[RawNode] {
+        &self.node_storage.entities
+    }
+
+    pub fn nodes_mut(&mut self) -> &mut [RawNode] {
+        &mut self.node_storage.entities
+    }
+
+    pub fn ways(&self) -> &[RawWay] {
+        &self.way_storage.entities
+    }
+
+    pub fn ways_mut(&mut self) -> &mut [RawWay] {
+        &mut self.way_storage.entities
+    }
+
+    /// The node refs of the rings that `Multipolygon::polygon_ids` point to.
+    pub fn polygons(&self) -> &[RawRefs] {
+        &self.polygon_storage
+    }
+
+    /// The node refs of the lines that `Route::polyline_ids` point to.
+    pub fn polylines(&self) -> &[RawRefs] {
+        &self.polyline_storage
+    }
+
+    pub fn multipolygons(&self) -> &[Multipolygon] {
+        &self.multipolygon_storage.entities
+    }
+
+    pub fn multipolygons_mut(&mut self) -> &mut [Multipolygon] {
+        &mut self.multipolygon_storage.entities
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.route_storage.entities
+    }
+
+    pub fn routes_mut(&mut self) -> &mut [Route] {
+        &mut self.route_storage.entities
+    }
+
+    pub fn problems(&self) -> &[ImportProblem] {
+        &self.problems
+    }
+
+    /// The bounding box of the dataset as declared by the input file (the `bbox` of a PBF header),
+    /// which may differ from the extent of the entities themselves.
+    pub fn extent(&self) -> Option<&coords::BoundingBox> {
+        self.extent.as_ref()
+    }
+
+    pub fn estimated_memory_usage(&self) -> usize {
+        self.node_storage.estimated_memory_usage()
+            + self.way_storage.estimated_memory_usage()
+            + self.polygon_storage.capacity() * mem::size_of::<Polygon>()
+            + self.polyline_storage.capacity() * mem::size_of::<Polyline>()
+            + self.multipolygon_storage.estimated_memory_usage()
+            + self.route_storage.estimated_memory_usage()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EntityKind {
+    Node,
+    Way,
+    Relation,
+}
+
+impl fmt::Display for EntityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EntityKind::Node => "node",
+            EntityKind::Way => "way",
+            EntityKind::Relation => "relation",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something wrong with the input data that the importer worked around, usually by dropping a reference
+/// or a whole entity.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportProblem {
+    DuplicateId {
+        kind: EntityKind,
+        id: u64,
+    },
+    CoordsOutOfRange {
+        node_id: u64,
+        lat: f64,
+        lon: f64,
+    },
+    /// The node is skipped, so the ways and relations referring to it get an `UnresolvedRef` too.
+    NonFiniteCoords {
+        node_id: u64,
+    },
+    UnresolvedRef {
+        kind: EntityKind,
+        id: u64,
+        ref_kind: EntityKind,
+        ref_id: u64,
+    },
+    BrokenMultipolygon {
+        relation_id: u64,
+    },
+    /// Every ring of the multipolygon collapses to a line or a point, so it's skipped.
+    DegenerateMultipolygon {
+        relation_id: u64,
+    },
+    /// See `ImportOptions::max_multipolygon_segments`.
+    TooManySegments {
+        relation_id: u64,
+        segment_count: usize,
+    },
+}
+
+impl fmt::Display for ImportProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportProblem::DuplicateId { kind, id } => write!(f, "Duplicate {} #{}", kind, *id as i64),
+            ImportProblem::CoordsOutOfRange { node_id, lat, lon } => write!(
+                f,
+                "Node #{} has out of range coordinates ({}, {})",
+                *node_id as i64, lat, lon
+            ),
+            ImportProblem::NonFiniteCoords { node_id } => {
+                write!(f, "Node #{} has non-finite coordinates", *node_id as i64)
+            }
+            ImportProblem::UnresolvedRef {
+                kind,
+                id,
+                ref_kind,
+                ref_id,
+            } => write!(
+                f,
+                "{} #{} refers to a missing {} #{}",
+                kind, *id as i64, ref_kind, *ref_id as i64
+            ),
+            ImportProblem::BrokenMultipolygon { relation_id } => {
+                write!(f, "Relation #{} is not a valid multipolygon", *relation_id as i64)
+            }
+            ImportProblem::DegenerateMultipolygon { relation_id } => {
+                write!(f, "Relation #{} has no rings that enclose an area", *relation_id as i64)
+            }
+            ImportProblem::TooManySegments {
+                relation_id,
+                segment_count,
+            } => write!(
+                f,
+                "Relation #{} has too many segments ({}) to be assembled",
+                *relation_id as i64, segment_count
+            ),
+        }
+    }
+}
+
+fn check_duplicate(is_new: bool, kind: EntityKind, id: u64, problems: &mut Vec<ImportProblem>) {
+    if !is_new {
+        problems.push(ImportProblem::DuplicateId { kind, id });
+    }
+}
+
+// Returns false if the node has to be skipped.
+fn check_node_coords(node: &RawNode, problems: &mut Vec<ImportProblem>) -> bool {
+    if !node.lat.is_finite() || !node.lon.is_finite() {
+        problems.push(ImportProblem::NonFiniteCoords {
+            node_id: node.global_id,
+        });
+        return false;
+    }
+    if !(-90.0..=90.0).contains(&node.lat) || !(-180.0..=180.0).contains(&node.lon) {
+        problems.push(ImportProblem::CoordsOutOfRange {
+            node_id: node.global_id,
+            lat: node.lat,
+            lon: node.lon,
+        });
+    }
+    true
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportStats {
+    pub node_count: usize,
+    pub way_count: usize,
+    pub multipolygon_count: usize,
+    /// Estimated size (in bytes) of the entity vectors and ID maps, based on their capacities.
+    /// Heap memory owned by individual entities (tags, node references) isn't included.
+    pub estimated_memory: usize,
+    pub peak_estimated_memory: usize,
 }
 
-fn print_storage_stats(entity_storages: &EntityStorages) {
-    println!(
-        "Got {} nodes, {} ways and {} multipolygon relations so far",
-        entity_storages.node_storage.entities.len(),
-        entity_storages.way_storage.entities.len(),
-        entity_storages.multipolygon_storage.entities.len()
-    );
+/// Receives import statistics every 100,000 parsed entities and at the end of parsing.
+pub trait ProgressSink {
+    fn report(&mut self, stats: &ImportStats);
+}
+
+struct PrintProgress;
+
+impl ProgressSink for PrintProgress {
+    fn report(&mut self, stats: &ImportStats) {
+        println!(
+            "Got {} nodes, {} ways and {} multipolygon relations so far",
+            stats.node_count, stats.way_count, stats.multipolygon_count
+        );
+    }
+}
+
+struct ProgressReporter<'a> {
+    sink: &'a mut dyn ProgressSink,
+    peak_estimated_memory: usize,
+}
+
+impl ProgressReporter<'_> {
+    fn report(&mut self, entity_storages: &EntityStorages) {
+        let estimated_memory = entity_storages.estimated_memory_usage();
+        self.peak_estimated_memory = self.peak_estimated_memory.max(estimated_memory);
+        self.sink.report(&ImportStats {
+            node_count: entity_storages.node_storage.entities.len(),
+            way_count: entity_storages.way_storage.entities.len(),
+            multipolygon_count: entity_storages.multipolygon_storage.entities.len(),
+            estimated_memory,
+            peak_estimated_memory: self.peak_estimated_memory,
+        });
+    }
 }
 
 #[cfg(feature = "pbf")]
-fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+fn parse_pbf<P: AsRef<Path>>(
+    input: P,
+    options: &ImportOptions,
+    id_index: Option<IdIndex>,
+    thread_count: usize,
+    progress: &mut ProgressReporter<'_>,
+) -> Result<EntityStorages> {
+    let mut entity_storages = EntityStorages::new(id_index);
+    let mut relations = Vec::new();
 
     let mut elem_count = 0;
+    let mut extent = None;
     println!("Parsing PBF");
 
-    let reader = ElementReader::from_path(input)?;
-    reader.for_each(|element| {
+    let mut on_element = |element: Element<'_>| -> Result<()> {
         match element {
             Element::DenseNode(el_node) => {
                 let mut node = RawNode {
@@ -112,7 +660,11 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                     node.tags.insert(key.to_string(), value.to_string());
                 }
                 elem_count += 1;
-                entity_storages.node_storage.add(node.global_id, node);
+                if check_node_coords(&node, &mut entity_storages.problems) {
+                    let id = node.global_id;
+                    let is_new = entity_storages.node_storage.add(id, node)?;
+                    check_duplicate(is_new, EntityKind::Node, id, &mut entity_storages.problems);
+                }
             }
             Element::Way(el_way) => {
                 let mut way = RawWay {
@@ -124,74 +676,155 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                     way.tags.insert(key.to_string(), value.to_string());
                 }
                 for r in el_way.refs() {
-                    if let Some(local_id) = entity_storages.node_storage.translate_id(r as u64) {
-                        way.node_ids.push(local_id);
+                    match entity_storages.node_storage.translate_id(r as u64) {
+                        Some(local_id) => way.node_ids.push(local_id),
+                        None => entity_storages.problems.push(ImportProblem::UnresolvedRef {
+                            kind: EntityKind::Way,
+                            id: way.global_id,
+                            ref_kind: EntityKind::Node,
+                            ref_id: r as u64,
+                        }),
                     }
                 }
                 postprocess_node_refs(&mut way.node_ids);
                 elem_count += 1;
-                entity_storages.way_storage.add(way.global_id, way);
+                let id = way.global_id;
+                let is_new = entity_storages.way_storage.add(id, way)?;
+                check_duplicate(is_new, EntityKind::Way, id, &mut entity_storages.problems);
             }
             Element::Relation(el_rel) => {
                 let mut relation = RawRelation {
                     global_id: el_rel.id() as u64,
-                    way_refs: Vec::<RelationWayRef>::default(),
-                    tags: RawTags::default(),
+                    ..Default::default()
                 };
                 for (key, value) in el_rel.tags() {
                     relation.tags.insert(key.to_string(), value.to_string());
                 }
-                for way in el_rel.members() {
-                    if way.member_type == RelMemberType::Way {
-                        if let Some(local_id) = entity_storages.way_storage.translate_id(way.member_id as u64) {
-                            let is_inner = way.role().unwrap() == "inner";
+                for member in el_rel.members() {
+                    if member.member_type == RelMemberType::Node {
+                        let role = member.role()?;
+                        if !is_label_role(role) {
+                            continue;
+                        }
+                        match entity_storages.node_storage.translate_id(member.member_id as u64) {
+                            Some(local_id) => relation.add_label_node(role, local_id),
+                            None => entity_storages.problems.push(ImportProblem::UnresolvedRef {
+                                kind: EntityKind::Relation,
+                                id: relation.global_id,
+                                ref_kind: EntityKind::Node,
+                                ref_id: member.member_id as u64,
+                            }),
+                        }
+                    } else if member.member_type == RelMemberType::Way {
+                        if let Some(local_id) = entity_storages.way_storage.translate_id(member.member_id as u64) {
+                            let is_inner = member.role()? == "inner";
                             relation.way_refs.push(RelationWayRef {
                                 way_id: local_id,
                                 is_inner,
                             });
+                        } else {
+                            entity_storages.problems.push(ImportProblem::UnresolvedRef {
+                                kind: EntityKind::Relation,
+                                id: relation.global_id,
+                                ref_kind: EntityKind::Way,
+                                ref_id: member.member_id as u64,
+                            });
                         }
                     }
                 }
-                if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                    let segments = relation.to_segments(&entity_storages);
-                    if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                        let mut multipolygon = Multipolygon {
-                            global_id: relation.global_id,
-                            polygon_ids: Vec::new(),
-                            tags: relation.tags,
-                        };
-                        for poly in polygons {
-                            multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                            entity_storages.polygon_storage.push(poly);
-                        }
-                        elem_count += 1;
-                        entity_storages
-                            .multipolygon_storage
-                            .add(relation.global_id, multipolygon);
-                    }
+                if is_relation_needed(&relation, options) {
+                    elem_count += 1;
+                    relations.push(relation);
                 }
             }
             Element::Node(_) => panic!(),
         }
         if elem_count % 100_000 == 0 {
-            print_storage_stats(&entity_storages);
+            progress.report(&entity_storages);
         }
-    })?;
+        Ok(())
+    };
 
-    print_storage_stats(&entity_storages);
+    // Same as `ElementReader::for_each`, which skips the header block: a valid file might have nothing but
+    // the header, and it's the only place that says what area the file covers.
+    let mut blobs = BlobReader::from_path(input)?;
+    loop {
+        let batch = blobs
+            .by_ref()
+            .take(thread_count * PBF_BLOBS_PER_THREAD)
+            .collect::<Result<Vec<_>, _>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        for decoded in decode_blobs(&batch, thread_count) {
+            match decoded? {
+                BlobDecode::OsmHeader(header) => {
+                    extent = header.bbox().map(|bbox| coords::BoundingBox {
+                        min_lat: bbox.bottom,
+                        max_lat: bbox.top,
+                        min_lon: bbox.left,
+                        max_lon: bbox.right,
+                    });
+                }
+                BlobDecode::OsmData(block) => {
+                    let mut result = Ok(());
+                    block.for_each_element(|element| {
+                        if result.is_ok() {
+                            result = on_element(element);
+                        }
+                    });
+                    result?;
+                }
+                BlobDecode::Unknown(_) => {}
+            }
+        }
+    }
+    entity_storages.extent = extent;
+
+    assemble_relations(&mut entity_storages, relations, options, thread_count)?;
+    progress.report(&entity_storages);
 
     Ok(entity_storages)
 }
 
-fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+#[cfg(feature = "pbf")]
+const PBF_BLOBS_PER_THREAD: usize = 8;
+
+// Decompressing and decoding the blocks takes most of the time, so it's done in parallel. The elements of
+// the decoded blocks are still handled one by one in the file order, which numbers the entities (and
+// resolves the references between them) exactly like a single thread would.
+#[cfg(feature = "pbf")]
+fn decode_blobs(blobs: &[Blob], thread_count: usize) -> Vec<osmpbf::Result<BlobDecode<'_>>> {
+    if thread_count <= 1 {
+        return blobs.iter().map(Blob::decode).collect();
+    }
+    let chunk_size = blobs.len().div_ceil(thread_count);
+    thread::scope(|scope| {
+        let handles = blobs
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(Blob::decode).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+fn parse_osm_xml<R: BufRead>(
+    mut parser: Reader<R>,
+    options: &ImportOptions,
+    id_index: Option<IdIndex>,
+    assembly_thread_count: usize,
+    progress: &mut ProgressReporter<'_>,
+) -> Result<EntityStorages> {
+    let mut entity_storages = EntityStorages::new(id_index);
+    let mut relations = Vec::new();
+    let mut problems = mem::take(&mut entity_storages.problems);
 
     let mut elem_count = 0;
+    let mut unexpected_elem_count = 0;
+
+    // Closing tags without a matching opening one are skipped just like stray elements.
+    parser.config_mut().check_end_names = false;
+    parser.config_mut().allow_unmatched_ends = true;
 
     println!("Parsing XML");
     let mut buf = Vec::new();
@@ -200,16 +833,29 @@ fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
             .read_event_into(&mut buf)
             .context("Failed to parse the input file")?;
         let mut on_elem = |start: BytesStart, have_subelements: bool| -> Result<()> {
-            process_element(
+            let is_expected = process_element(
                 &mut parser,
                 start.local_name().as_ref(),
                 &mut start.attributes(),
                 &mut entity_storages,
+                &mut relations,
+                &mut problems,
                 have_subelements,
+                options,
             )?;
+            if !is_expected {
+                if unexpected_elem_count == 0 {
+                    eprintln!(
+                        "Skipping unexpected top-level element {}",
+                        ascii_name_as_str(start.local_name().as_ref())
+                    );
+                }
+                unexpected_elem_count += 1;
+                return Ok(());
+            }
             elem_count += 1;
             if elem_count % 100_000 == 0 {
-                print_storage_stats(&entity_storages);
+                progress.report(&entity_storages);
             }
             Ok(())
         };
@@ -223,18 +869,29 @@ fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
         buf.clear();
     }
 
-    print_storage_stats(&entity_storages);
+    if unexpected_elem_count > 0 {
+        eprintln!("Skipped {} unexpected top-level elements", unexpected_elem_count);
+    }
+    entity_storages.problems = problems;
+
+    assemble_relations(&mut entity_storages, relations, options, assembly_thread_count)?;
+    progress.report(&entity_storages);
 
     Ok(entity_storages)
 }
 
+// Returns false for elements that aren't expected at the top level, e.g. a `<tag>` outside of
+// any node, way or relation.
 fn process_element<R: BufRead>(
     parser: &mut Reader<R>,
     name: &[u8],
     attrs: &mut Attributes,
     entity_storages: &mut EntityStorages,
+    relations: &mut Vec<RawRelation>,
+    problems: &mut Vec<ImportProblem>,
     have_subelements: bool,
-) -> Result<()> {
+    options: &ImportOptions,
+) -> Result<bool> {
     match name {
         b"node" => {
             let mut node = RawNode {
@@ -244,9 +901,21 @@ fn process_element<R: BufRead>(
                 tags: RawTags::default(),
             };
             if have_subelements {
-                process_subelements(name, &mut node, entity_storages, process_node_subelement, parser)?;
+                process_subelements(
+                    name,
+                    &mut node,
+                    entity_storages,
+                    problems,
+                    process_node_subelement,
+                    parser,
+                )?;
+            }
+            if !check_node_coords(&node, problems) {
+                return Ok(true);
             }
-            entity_storages.node_storage.add(node.global_id, node);
+            let id = node.global_id;
+            let is_new = entity_storages.node_storage.add(id, node)?;
+            check_duplicate(is_new, EntityKind::Node, id, problems);
         }
         b"way" => {
             let mut way = RawWay {
@@ -255,58 +924,376 @@ fn process_element<R: BufRead>(
                 tags: RawTags::default(),
             };
             if have_subelements {
-                process_subelements(name, &mut way, entity_storages, process_way_subelement, parser)?;
+                process_subelements(
+                    name,
+                    &mut way,
+                    entity_storages,
+                    problems,
+                    process_way_subelement,
+                    parser,
+                )?;
             }
             postprocess_node_refs(&mut way.node_ids);
-            entity_storages.way_storage.add(way.global_id, way);
+            let id = way.global_id;
+            let is_new = entity_storages.way_storage.add(id, way)?;
+            check_duplicate(is_new, EntityKind::Way, id, problems);
         }
         b"relation" => {
             let mut relation = RawRelation {
                 global_id: get_id(parser, name, attrs)?,
-                way_refs: Vec::<RelationWayRef>::default(),
-                tags: RawTags::default(),
+                ..Default::default()
             };
             if have_subelements {
                 process_subelements(
                     name,
                     &mut relation,
                     entity_storages,
+                    problems,
                     process_relation_subelement,
                     parser,
                 )?;
             }
-            if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                let segments = relation.to_segments(entity_storages);
-                if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                    let mut multipolygon = Multipolygon {
-                        global_id: relation.global_id,
-                        polygon_ids: Vec::new(),
-                        tags: relation.tags,
-                    };
-                    for poly in polygons {
-                        multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                        entity_storages.polygon_storage.push(poly);
-                    }
-                    entity_storages
-                        .multipolygon_storage
-                        .add(relation.global_id, multipolygon);
-                }
+            if is_relation_needed(&relation, options) {
+                relations.push(relation);
             }
         }
-        _ => {}
+        b"osm" | b"bounds" | b"bound" | b"note" | b"meta" | b"remark" => {}
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn clip_to_polygon(entity_storages: &mut EntityStorages, clip_polygon: &ClipPolygon) {
+    let is_inside = entity_storages
+        .nodes()
+        .iter()
+        .map(|node| clip_polygon.contains(node.lat, node.lon))
+        .collect::<Vec<_>>();
+    let has_node_inside = |node_ids: &RawRefs| node_ids.iter().any(|&id| is_inside[id]);
+
+    let mut keep_node = is_inside.clone();
+    let mut keep_nodes_of = |node_ids: &RawRefs| {
+        for &id in node_ids {
+            keep_node[id] = true;
+        }
+    };
+
+    let keep_way = entity_storages
+        .ways()
+        .iter()
+        .map(|way| has_node_inside(&way.node_ids))
+        .collect::<Vec<_>>();
+    for (way, _) in entity_storages.ways().iter().zip(&keep_way).filter(|(_, keep)| **keep) {
+        keep_nodes_of(&way.node_ids);
+    }
+
+    let polygons = &entity_storages.polygon_storage;
+    let polylines = &entity_storages.polyline_storage;
+    let keep_multipolygon = entity_storages
+        .multipolygons()
+        .iter()
+        .map(|mp| mp.polygon_ids.iter().any(|&id| has_node_inside(&polygons[id])))
+        .collect::<Vec<_>>();
+    for (mp, _) in entity_storages
+        .multipolygons()
+        .iter()
+        .zip(&keep_multipolygon)
+        .filter(|(_, keep)| **keep)
+    {
+        for &polygon_id in &mp.polygon_ids {
+            keep_nodes_of(&polygons[polygon_id]);
+        }
+        keep_nodes_of(&mp.label_node_id.into_iter().collect());
+    }
+
+    let keep_route = entity_storages
+        .routes()
+        .iter()
+        .map(|route| route.polyline_ids.iter().any(|&id| has_node_inside(&polylines[id])))
+        .collect::<Vec<_>>();
+    for (route, _) in entity_storages
+        .routes()
+        .iter()
+        .zip(&keep_route)
+        .filter(|(_, keep)| **keep)
+    {
+        for &polyline_id in &route.polyline_ids {
+            keep_nodes_of(&polylines[polyline_id]);
+        }
+    }
+
+    let new_node_ids = entity_storages.node_storage.retain(&keep_node);
+    let remap_nodes = |node_ids: &mut RawRefs| {
+        for id in node_ids.iter_mut() {
+            *id = new_node_ids[*id].expect("nodes of kept entities are kept too");
+        }
+    };
+
+    entity_storages.way_storage.retain(&keep_way);
+    for way in entity_storages.ways_mut() {
+        remap_nodes(&mut way.node_ids);
+    }
+
+    entity_storages.multipolygon_storage.retain(&keep_multipolygon);
+    entity_storages.route_storage.retain(&keep_route);
+    // Rings and lines are moved to the end of a fresh storage in the order their owners refer to them.
+    let move_refs = |old_storage: &mut Vec<RawRefs>, new_storage: &mut Vec<RawRefs>, ids: &mut RawRefs| {
+        for id in ids.iter_mut() {
+            let mut node_ids = mem::take(&mut old_storage[*id]);
+            remap_nodes(&mut node_ids);
+            *id = new_storage.len();
+            new_storage.push(node_ids);
+        }
+    };
+    let mut old_polygons = mem::take(&mut entity_storages.polygon_storage);
+    for mp in &mut entity_storages.multipolygon_storage.entities {
+        mp.label_node_id = mp.label_node_id.and_then(|id| new_node_ids[id]);
+        move_refs(
+            &mut old_polygons,
+            &mut entity_storages.polygon_storage,
+            &mut mp.polygon_ids,
+        );
+    }
+    let mut old_polylines = mem::take(&mut entity_storages.polyline_storage);
+    for route in &mut entity_storages.route_storage.entities {
+        move_refs(
+            &mut old_polylines,
+            &mut entity_storages.polyline_storage,
+            &mut route.polyline_ids,
+        );
+    }
+}
+
+fn thread_count(options: &ImportOptions) -> usize {
+    let thread_count = options
+        .thread_count
+        .unwrap_or_else(|| thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1));
+    thread_count.max(1)
+}
+
+fn assemble_relations(
+    entity_storages: &mut EntityStorages,
+    relations: Vec<RawRelation>,
+    options: &ImportOptions,
+    thread_count: usize,
+) -> Result<()> {
+    if !options.inherited_relation_tags.is_empty() {
+        inherit_relation_tags(
+            &mut entity_storages.way_storage.entities,
+            &relations,
+            &options.inherited_relation_tags,
+        );
+    }
+    // Boundaries are only kept for their tags.
+    let (routes, multipolygons) = relations
+        .into_iter()
+        .filter(|relation| !relation.is_boundary())
+        .partition(RawRelation::is_route);
+    assemble_multipolygons(entity_storages, multipolygons, options, thread_count)?;
+    if options.import_routes {
+        assemble_routes(entity_storages, routes)?;
     }
     Ok(())
 }
 
+// Boundary relations aren't assembled into areas, but their member ways can inherit their tags.
+fn is_relation_needed(relation: &RawRelation, options: &ImportOptions) -> bool {
+    relation.is_multipolygon()
+        || relation.is_route()
+        || (relation.is_boundary() && !options.inherited_relation_tags.is_empty())
+}
+
+fn inherit_relation_tags(ways: &mut [RawWay], relations: &[RawRelation], keys: &[String]) {
+    for relation in relations.iter().filter(|r| r.is_route() || r.is_boundary()) {
+        let inherited_tags = keys
+            .iter()
+            .filter_map(|key| relation.tags.get_key_value(key))
+            .collect::<Vec<_>>();
+        for way_ref in &relation.way_refs {
+            let way_tags = &mut ways[way_ref.way_id].tags;
+            for &(key, value) in &inherited_tags {
+                way_tags.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+// Assembling polygons only needs read access to nodes and ways, so the relations are split
+// into chunks that are processed in parallel. The results are then merged in the original
+// relation order, which makes the output independent of the thread count.
+fn assemble_multipolygons(
+    entity_storages: &mut EntityStorages,
+    relations: Vec<RawRelation>,
+    options: &ImportOptions,
+    thread_count: usize,
+) -> Result<()> {
+    if relations.is_empty() {
+        return Ok(());
+    }
+
+    let (relations, oversized): (Vec<_>, Vec<_>) = relations
+        .into_iter()
+        .partition(|relation| relation.segment_count(entity_storages) <= options.max_multipolygon_segments);
+    for relation in oversized {
+        let segment_count = relation.segment_count(entity_storages);
+        entity_storages.problems.push(ImportProblem::TooManySegments {
+            relation_id: relation.global_id,
+            segment_count,
+        });
+    }
+    if relations.is_empty() {
+        return Ok(());
+    }
+
+    println!("Assembling {} multipolygon relations", relations.len());
+
+    let assembled = {
+        let storages = &*entity_storages;
+        let chunk_size = relations.len().div_ceil(thread_count.max(1));
+        thread::scope(|scope| {
+            let handles = relations
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|relation| {
+                                let segments = relation.to_segments(storages);
+                                find_polygons_in_multipolygon(
+                                    relation.global_id,
+                                    &segments,
+                                    options.ignore_multipolygon_roles,
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        })
+    };
+
+    for (relation, polygons) in relations.into_iter().zip(assembled) {
+        let Some(polygons) = polygons else {
+            entity_storages.problems.push(ImportProblem::BrokenMultipolygon {
+                relation_id: relation.global_id,
+            });
+            continue;
+        };
+        if polygons.is_empty() {
+            entity_storages.problems.push(ImportProblem::DegenerateMultipolygon {
+                relation_id: relation.global_id,
+            });
+            continue;
+        }
+        let mut multipolygon = Multipolygon {
+            global_id: relation.global_id,
+            polygon_ids: Vec::new(),
+            label_node_id: relation.label_node_id.or(relation.admin_centre_node_id),
+            tags: relation.tags,
+        };
+        for poly in polygons {
+            multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
+            entity_storages.polygon_storage.push(poly);
+        }
+        let is_new = entity_storages
+            .multipolygon_storage
+            .add(relation.global_id, multipolygon)?;
+        check_duplicate(
+            is_new,
+            EntityKind::Relation,
+            relation.global_id,
+            &mut entity_storages.problems,
+        );
+    }
+    Ok(())
+}
+
+fn assemble_routes(entity_storages: &mut EntityStorages, relations: Vec<RawRelation>) -> Result<()> {
+    if relations.is_empty() {
+        return Ok(());
+    }
+
+    println!("Assembling {} route relations", relations.len());
+
+    for relation in relations {
+        let polylines = join_route_members(&relation, entity_storages.ways());
+        let mut route = Route {
+            global_id: relation.global_id,
+            polyline_ids: Vec::new(),
+            tags: relation.tags,
+        };
+        for polyline in polylines {
+            route.polyline_ids.push(entity_storages.polyline_storage.len());
+            entity_storages.polyline_storage.push(polyline);
+        }
+        let is_new = entity_storages.route_storage.add(relation.global_id, route)?;
+        check_duplicate(
+            is_new,
+            EntityKind::Relation,
+            relation.global_id,
+            &mut entity_storages.problems,
+        );
+    }
+    Ok(())
+}
+
+// Appends every member way to the line built so far, reversing it if it's mapped against the direction of
+// travel, and starts a new line when a member doesn't connect to the previous one. Roles like `forward` are
+// ignored: the direction is decided by how the members connect.
+fn join_route_members(relation: &RawRelation, ways: &[RawWay]) -> Vec<RawRefs> {
+    let mut polylines: Vec<RawRefs> = Vec::new();
+    // The direction of the first way of a line is only known once the next one connects to it.
+    let mut last_has_single_way = false;
+
+    for way_ref in &relation.way_refs {
+        let node_ids = &ways[way_ref.way_id].node_ids;
+        if node_ids.len() < 2 {
+            continue;
+        }
+        let (first, last) = (&node_ids[0], &node_ids[node_ids.len() - 1]);
+
+        let joined = match polylines.last_mut() {
+            Some(polyline) => {
+                let connects = |node_id: Option<&usize>| node_id == Some(first) || node_id == Some(last);
+                if last_has_single_way && !connects(polyline.last()) && connects(polyline.first()) {
+                    polyline.reverse();
+                }
+                if polyline.last() == Some(first) {
+                    polyline.extend(node_ids.iter().skip(1));
+                    true
+                } else if polyline.last() == Some(last) {
+                    polyline.extend(node_ids.iter().rev().skip(1));
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        };
+
+        last_has_single_way = !joined;
+        if !joined {
+            polylines.push(node_ids.clone());
+        }
+    }
+
+    polylines
+}
+
 fn process_subelements<E: Default, R: BufRead, F>(
     entity_name: &[u8],
     entity: &mut E,
     entity_storages: &EntityStorages,
+    problems: &mut Vec<ImportProblem>,
     subelement_processor: F,
     parser: &mut Reader<R>,
 ) -> Result<()>
 where
-    F: Fn(&mut Reader<R>, &mut E, &EntityStorages, &[u8], &mut Attributes) -> Result<()>,
+    F: Fn(&mut Reader<R>, &mut E, &EntityStorages, &mut Vec<ImportProblem>, &[u8], &mut Attributes) -> Result<()>,
 {
     let mut buf = Vec::new();
     loop {
@@ -321,6 +1308,7 @@ where
                 parser,
                 entity,
                 entity_storages,
+                problems,
                 start.local_name().as_ref(),
                 &mut start.attributes(),
             )?,
@@ -331,6 +1319,10 @@ where
     Ok(())
 }
 
+// Drops segments that a way traverses more than once. When the way immediately goes back the way it
+// came (a spike, which encloses no area), the node at the tip of the spike is dropped as well, so that
+// the way stays connected instead of jumping from the tip to whatever follows. This runs once per way,
+// so all relations that share a way see the same nodes.
 fn postprocess_node_refs(refs: &mut RawRefs) {
     if refs.is_empty() {
         return;
@@ -346,6 +1338,11 @@ fn postprocess_node_refs(refs: &mut RawRefs) {
         if !seen_node_pairs.contains(&node_pair) && !seen_node_pairs.contains(&(prev, cur)) {
             seen_node_pairs.insert(node_pair);
             refs_without_duplicates.push(cur);
+        } else {
+            let len = refs_without_duplicates.len();
+            if len >= 2 && refs_without_duplicates[len - 1] == prev && refs_without_duplicates[len - 2] == cur {
+                refs_without_duplicates.pop();
+            }
         }
     }
 
@@ -356,6 +1353,7 @@ fn process_node_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     node: &mut RawNode,
     _: &EntityStorages,
+    _: &mut Vec<ImportProblem>,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
@@ -366,6 +1364,7 @@ fn process_way_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     way: &mut RawWay,
     entity_storages: &EntityStorages,
+    problems: &mut Vec<ImportProblem>,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
@@ -373,8 +1372,14 @@ fn process_way_subelement<R: BufRead>(
         return Ok(());
     }
     if sub_name == b"nd" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)? {
-            way.node_ids.push(r);
+        match get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)? {
+            (_, Some(r)) => way.node_ids.push(r),
+            (ref_id, None) => problems.push(ImportProblem::UnresolvedRef {
+                kind: EntityKind::Way,
+                id: way.global_id,
+                ref_kind: EntityKind::Node,
+                ref_id,
+            }),
         }
     }
     Ok(())
@@ -384,16 +1389,44 @@ fn process_relation_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     relation: &mut RawRelation,
     entity_storages: &EntityStorages,
+    problems: &mut Vec<ImportProblem>,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
     if try_add_tag(parser, sub_name, sub_attrs, &mut relation.tags)? {
         return Ok(());
     }
-    if sub_name == b"member" && get_required_attr(parser, sub_name, sub_attrs, b"type")? == "way" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.way_storage)? {
-            let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
-            relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
+    if sub_name != b"member" {
+        return Ok(());
+    }
+    let member_type = get_required_attr(parser, sub_name, sub_attrs, b"type")?;
+    if member_type == "node" {
+        // Attributes are looked up in order, so `ref` has to come before `role`.
+        let node_ref = get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)?;
+        let role = get_required_attr(parser, sub_name, sub_attrs, b"role")?;
+        if is_label_role(&role) {
+            match node_ref {
+                (_, Some(r)) => relation.add_label_node(&role, r),
+                (ref_id, None) => problems.push(ImportProblem::UnresolvedRef {
+                    kind: EntityKind::Relation,
+                    id: relation.global_id,
+                    ref_kind: EntityKind::Node,
+                    ref_id,
+                }),
+            }
+        }
+    } else if member_type == "way" {
+        match get_ref(parser, sub_name, sub_attrs, &entity_storages.way_storage)? {
+            (_, Some(r)) => {
+                let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
+                relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
+            }
+            (ref_id, None) => problems.push(ImportProblem::UnresolvedRef {
+                kind: EntityKind::Relation,
+                id: relation.global_id,
+                ref_kind: EntityKind::Way,
+                ref_id,
+            }),
         }
     }
     Ok(())
@@ -449,11 +1482,13 @@ fn get_ref<E: Default, R: BufRead>(
     elem_name: &[u8],
     attrs: &mut Attributes,
     storage: &OsmEntityStorage<E>,
-) -> Result<Option<usize>> {
-    let reference = parse_required_attr(parser, elem_name, attrs, b"ref")?;
-    Ok(storage.translate_id(reference))
+) -> Result<(u64, Option<usize>)> {
+    let reference = parse_required_attr::<i64, R>(parser, elem_name, attrs, b"ref")? as u64;
+    Ok((reference, storage.translate_id(reference)))
 }
 
+// Attribute values are unescaped by quick-xml, so `&amp;` and numeric character references are stored
+// decoded. OSM only puts tag values into attributes, so there is no CDATA to handle here.
 fn try_add_tag<R: BufRead>(
     parser: &mut Reader<R>,
     elem_name: &[u8],
@@ -469,19 +1504,21 @@ fn try_add_tag<R: BufRead>(
     Ok(true)
 }
 
+// Editors like JOSM use negative IDs for objects that haven't been uploaded yet. They are stored
+// as their two's complement, just like the IDs from PBF files, and `global_id as i64` restores them.
 fn get_id<R: BufRead>(parser: &mut Reader<R>, elem_name: &[u8], attrs: &mut Attributes) -> Result<u64> {
-    parse_required_attr(parser, elem_name, attrs, b"id")
+    parse_required_attr::<i64, R>(parser, elem_name, attrs, b"id").map(|id| id as u64)
 }
 
-pub(super) type RawRefs = Vec<usize>;
-pub(super) type RawTags = BTreeMap<String, String>;
+pub type RawRefs = Vec<usize>;
+pub type RawTags = BTreeMap<String, String>;
 
 #[derive(Default)]
-pub(super) struct RawNode {
-    pub(super) global_id: u64,
-    pub(super) lat: f64,
-    pub(super) lon: f64,
-    pub(super) tags: RawTags,
+pub struct RawNode {
+    pub global_id: u64,
+    pub lat: f64,
+    pub lon: f64,
+    pub tags: RawTags,
 }
 
 impl coords::Coords for RawNode {
@@ -495,10 +1532,10 @@ impl coords::Coords for RawNode {
 }
 
 #[derive(Default)]
-pub(super) struct RawWay {
-    pub(super) global_id: u64,
-    pub(super) node_ids: RawRefs,
-    pub(super) tags: RawTags,
+pub struct RawWay {
+    pub global_id: u64,
+    pub node_ids: RawRefs,
+    pub tags: RawTags,
 }
 
 pub struct RelationWayRef {
@@ -510,10 +1547,45 @@ pub struct RelationWayRef {
 struct RawRelation {
     global_id: u64,
     way_refs: Vec<RelationWayRef>,
+    label_node_id: Option<usize>,
+    admin_centre_node_id: Option<usize>,
     tags: RawTags,
 }
 
 impl RawRelation {
+    fn is_multipolygon(&self) -> bool {
+        self.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon")
+    }
+
+    fn is_route(&self) -> bool {
+        self.tags.get("type").is_some_and(|v| v == "route")
+    }
+
+    fn is_boundary(&self) -> bool {
+        self.tags.get("type").is_some_and(|v| v == "boundary")
+    }
+
+    fn add_label_node(&mut self, role: &str, node_id: usize) {
+        if role == "label" {
+            self.label_node_id = Some(node_id);
+        } else {
+            self.admin_centre_node_id = Some(node_id);
+        }
+    }
+
+    // The length of `to_segments`, without building them.
+    fn segment_count(&self, entity_storages: &EntityStorages) -> usize {
+        self.way_refs
+            .iter()
+            .map(|way_ref| {
+                entity_storages.way_storage.entities[way_ref.way_id]
+                    .node_ids
+                    .len()
+                    .saturating_sub(1)
+            })
+            .sum()
+    }
+
     fn to_segments(&self, entity_storages: &EntityStorages) -> Vec<NodeDescPair> {
         let create_node_desc = |way: &RawWay, node_idx_in_way| {
             let node_id = way.node_ids[node_idx_in_way];
@@ -536,11 +1608,85 @@ impl RawRelation {
     }
 }
 
+// Node members with other roles aren't needed for rendering.
+fn is_label_role(role: &str) -> bool {
+    role == "label" || role == "admin_centre"
+}
+
 pub(super) type Polygon = RawRefs;
+pub(super) type Polyline = RawRefs;
 
-#[derive(Default)]
-pub(super) struct Multipolygon {
-    pub(super) global_id: u64,
-    pub(super) polygon_ids: RawRefs,
-    pub(super) tags: RawTags,
+#[derive(Debug, Default, PartialEq)]
+pub struct Multipolygon {
+    pub global_id: u64,
+    pub polygon_ids: RawRefs,
+    /// Where to put the name of the area, taken from the `label` or else the `admin_centre` member.
+    pub label_node_id: Option<usize>,
+    pub tags: RawTags,
+}
+
+/// A `type=route` relation.
+#[derive(Debug, Default, PartialEq)]
+pub struct Route {
+    pub global_id: u64,
+    /// The member ways joined into as few lines as possible. Lines follow the member order, and every
+    /// way is oriented so that it continues the previous one.
+    pub polyline_ids: RawRefs,
+    pub tags: RawTags,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_nano_moscow(assembly_thread_count: usize) -> EntityStorages {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/osm/nano_moscow.osm");
+        let mut progress = ProgressReporter {
+            sink: &mut PrintProgress,
+            peak_estimated_memory: 0,
+        };
+        parse_osm_xml(
+            Reader::from_file(input).unwrap(),
+            &ImportOptions::default(),
+            None,
+            assembly_thread_count,
+            &mut progress,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reserved_ids_have_to_be_taken_in_order() {
+        let reserved = |ids: &[u64]| {
+            let ids = ids.iter().enumerate().map(|(local_id, &id)| (id, local_id)).collect();
+            OsmEntityStorage::<u64>::with_reserved_ids(ids)
+        };
+
+        let mut storage = reserved(&[10, 11]);
+        assert!(storage.add(10, 10).unwrap());
+        assert!(!storage.add(10, 10).unwrap());
+        assert!(storage.add(11, 11).unwrap());
+        assert_eq!(storage.get_entities(), &[10, 11]);
+
+        // Entity 10 was indexed, but never added, so 11 would end up in its slot.
+        assert!(reserved(&[10, 11]).add(11, 11).is_err());
+        assert!(reserved(&[10]).add(12, 12).is_err());
+    }
+
+    #[test]
+    fn test_parallel_assembly_matches_serial() {
+        let serial = parse_nano_moscow(1);
+        let parallel = parse_nano_moscow(4);
+
+        assert!(!serial.multipolygon_storage.entities.is_empty());
+        assert_eq!(serial.polygon_storage, parallel.polygon_storage);
+        assert_eq!(
+            serial.multipolygon_storage.entities,
+            parallel.multipolygon_storage.entities
+        );
+        assert_eq!(
+            serial.multipolygon_storage.global_id_to_local_id,
+            parallel.multipolygon_storage.global_id_to_local_id
+        );
+    }
 }
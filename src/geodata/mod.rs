@@ -1,4 +1,10 @@
-mod find_polygons;
+#[cfg(feature = "bench-internals")]
+#[doc(hidden)]
+pub mod bench_support;
+pub mod clip;
+pub(crate) mod find_polygons;
 pub mod importer;
+pub mod query;
 pub mod reader;
 mod saver;
+mod simplify;
@@ -0,0 +1,105 @@
+use crate::coords::Coords;
+use crate::geodata::find_polygons::point_in_ring;
+use crate::geodata::importer::EntityKind;
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Route, Tags, Way};
+use crate::geodata::simplify::distance_to_segment;
+
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// An entity found by `GeodataReader::features_at`.
+pub enum FeatureRef<'a> {
+    Node(Node<'a>),
+    Way(Way<'a>),
+    Multipolygon(Multipolygon<'a>),
+    Route(Route<'a>),
+}
+
+impl FeatureRef<'_> {
+    pub fn kind(&self) -> EntityKind {
+        match self {
+            FeatureRef::Node(_) => EntityKind::Node,
+            FeatureRef::Way(_) => EntityKind::Way,
+            FeatureRef::Multipolygon(_) | FeatureRef::Route(_) => EntityKind::Relation,
+        }
+    }
+}
+
+impl<'a> OsmEntity<'a> for FeatureRef<'a> {
+    fn global_id(&self) -> u64 {
+        match self {
+            FeatureRef::Node(node) => node.global_id(),
+            FeatureRef::Way(way) => way.global_id(),
+            FeatureRef::Multipolygon(multipolygon) => multipolygon.global_id(),
+            FeatureRef::Route(route) => route.global_id(),
+        }
+    }
+
+    fn tags(&self) -> Tags<'a> {
+        match self {
+            FeatureRef::Node(node) => node.tags(),
+            FeatureRef::Way(way) => way.tags(),
+            FeatureRef::Multipolygon(multipolygon) => multipolygon.tags(),
+            FeatureRef::Route(route) => route.tags(),
+        }
+    }
+}
+
+// The north-west and the south-east corners of a box that has every point within `tolerance` meters
+// of `(lat, lon)`.
+pub(super) fn search_box((lat, lon): (f64, f64), tolerance: f64) -> ((f64, f64), (f64, f64)) {
+    let lat_delta = (tolerance / EARTH_RADIUS).to_degrees();
+    let lon_delta = lat_delta / lon_scale(lat);
+    ((lat + lat_delta, lon - lon_delta), (lat - lat_delta, lon + lon_delta))
+}
+
+pub(super) fn is_near(feature: &FeatureRef<'_>, point: (f64, f64), tolerance: f64) -> bool {
+    // Distances are small enough for an equirectangular projection centered on the point.
+    let to_local = |node: Node<'_>| {
+        (
+            (node.lon() - point.1).to_radians() * EARTH_RADIUS * lon_scale(point.0),
+            (node.lat() - point.0).to_radians() * EARTH_RADIUS,
+        )
+    };
+    let is_near_line = |points: &[(f64, f64)]| match points {
+        [] => false,
+        [(x, y)] => x.hypot(*y) <= tolerance,
+        _ => points
+            .windows(2)
+            .any(|w| distance_to_segment((0.0, 0.0), w[0], w[1]) <= tolerance),
+    };
+    let is_inside = |ring: &[(f64, f64)]| point_in_ring(ring.iter().cloned(), (0.0, 0.0));
+
+    match feature {
+        FeatureRef::Node(node) => is_near_line(&[to_local(node.clone())]),
+        FeatureRef::Way(way) => {
+            let points = (0..way.node_count())
+                .map(|idx| to_local(way.get_node(idx)))
+                .collect::<Vec<_>>();
+            (way.is_area() && is_inside(&points)) || is_near_line(&points)
+        }
+        FeatureRef::Multipolygon(multipolygon) => {
+            let rings = (0..multipolygon.polygon_count())
+                .map(|idx| {
+                    let polygon = multipolygon.get_polygon(idx);
+                    (0..polygon.node_count())
+                        .map(|node_idx| to_local(polygon.get_node(node_idx)))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            // Inner rings are holes, so being inside of an odd number of rings means being inside of the area.
+            rings.iter().filter(|ring| is_inside(ring)).count() % 2 == 1 || rings.iter().any(|ring| is_near_line(ring))
+        }
+        FeatureRef::Route(route) => (0..route.polyline_count()).any(|idx| {
+            let polyline = route.get_polyline(idx);
+            let points = (0..polyline.node_count())
+                .map(|node_idx| to_local(polyline.get_node(node_idx)))
+                .collect::<Vec<_>>();
+            is_near_line(&points)
+        }),
+    }
+}
+
+// Meridians converge towards the poles, so a degree of longitude gets shorter than a degree of latitude.
+fn lon_scale(lat: f64) -> f64 {
+    lat.to_radians().cos().max(f64::EPSILON)
+}
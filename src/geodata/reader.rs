@@ -1,17 +1,20 @@
 use crate::coords::Coords;
+use crate::geodata::query::{self, FeatureRef};
 use crate::tile;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use flate2::read::GzDecoder;
 use memmap2::{Mmap, MmapOptions};
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::Cursor;
+use std::io::{self, Cursor, Read};
 use std::mem;
 use std::ops::Deref;
 use std::slice;
 use std::str;
+use std::sync::OnceLock;
 
 pub trait OsmEntity<'a> {
     fn global_id(&self) -> u64;
@@ -22,6 +25,7 @@ pub struct OsmEntities<'a> {
     pub nodes: Vec<Node<'a>>,
     pub ways: Vec<Way<'a>>,
     pub multipolygons: Vec<Multipolygon<'a>>,
+    pub routes: Vec<Route<'a>>,
 }
 
 #[derive(Default)]
@@ -29,18 +33,84 @@ pub(super) struct OsmEntityIds {
     pub(super) nodes: Vec<u32>,
     pub(super) ways: Vec<u32>,
     pub(super) multipolygons: Vec<u32>,
+    pub(super) routes: Vec<u32>,
 }
 
 pub trait OsmArea {
     fn is_closed(&self) -> bool;
+
+    /// Whether the entity is a polygon (that matches `area` selectors) rather than a line.
+    fn is_area(&self) -> bool {
+        self.is_closed()
+    }
 }
 
 pub struct GeodataReader<'a> {
     storages: ObjectStorages<'a>,
-    _mmap: Mmap,
+    tag_counts: OnceLock<TagCounts>,
+    _backing: Backing,
+}
+
+// For every key, the number of entities that have each of its values.
+type TagCounts = BTreeMap<String, BTreeMap<String, usize>>;
+
+enum Backing {
+    Mapped(Mmap),
+    // Stored as u32 so that the decompressed bytes have the same alignment guarantees as the mapped ones.
+    Decompressed(Vec<u32>, usize),
+}
+
+impl Backing {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Backing::Mapped(mmap) => mmap.deref(),
+            Backing::Decompressed(ints, len) => unsafe { slice::from_raw_parts(ints.as_ptr() as *const u8, *len) },
+        }
+    }
 }
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Decompresses straight into the u32s of `Backing::Decompressed`, so that the data isn't held in memory twice.
+// Also returns the number of decompressed bytes.
+fn decompress_to_ints(gzipped: &[u8]) -> io::Result<(Vec<u32>, usize)> {
+    const INT_SIZE: usize = mem::size_of::<u32>();
+    // The gzip trailer ends with the decompressed size (mod 2^32), which is only a hint for files with
+    // several members or over 4 GiB. With one more u32 than that, the end of the data is reached before the
+    // buffer has to grow.
+    let size_hint = gzipped
+        .len()
+        .checked_sub(INT_SIZE)
+        .map_or(0, |at| LittleEndian::read_u32(&gzipped[at..]));
+    let mut ints = vec![0u32; (size_hint as usize).div_ceil(INT_SIZE) + 1];
+    let mut decoder = GzDecoder::new(gzipped);
+    let mut len = 0;
+    loop {
+        if len == ints.len() * INT_SIZE {
+            ints.resize(2 * ints.len(), 0);
+        }
+        let bytes = unsafe { slice::from_raw_parts_mut(ints.as_mut_ptr() as *mut u8, ints.len() * INT_SIZE) };
+        match decoder.read(&mut bytes[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    ints.truncate(len.div_ceil(INT_SIZE));
+    Ok((ints, len))
+}
+
+/// Every file produced by the importer starts with these bytes, followed by `FORMAT_VERSION` as a u32.
+pub(super) const FORMAT_MAGIC: [u8; 4] = *b"OSMR";
+/// Bumped on every change to the layout of the file, so that files of other versions are rejected
+/// instead of being misread.
+pub(super) const FORMAT_VERSION: u32 = 2;
+const HEADER_SIZE: usize = FORMAT_MAGIC.len() + mem::size_of::<u32>();
+
 impl<'a> GeodataReader<'a> {
+    /// Loads a file produced by the importer. Gzipped files are detected by their magic number and
+    /// decompressed into memory instead of being memory-mapped.
     pub fn load(file_name: &str) -> Result<GeodataReader<'a>> {
         let input_file = File::open(file_name).context(format!("Failed to open {} for memory mapping", file_name))?;
         let mmap = unsafe {
@@ -49,26 +119,54 @@ impl<'a> GeodataReader<'a> {
                 .context(format!("Failed to map {} to memory", file_name))?
         };
 
-        let raw_mmap_bytes = mmap.deref() as *const [u8];
-        // `raw_mmap_bytes` points to bytes that are destroyed when `mmap` is dropped.
-        // The bytes are only ever accessed from `storages`, which is bundled together with `mmap`
-        // in `GeodataReader`. Therefore, `mmap` is still not dropped whenever we access the bytes.
-        let storages = ObjectStorages::from_bytes(unsafe { &*raw_mmap_bytes });
-        Ok(GeodataReader { storages, _mmap: mmap })
-    }
+        let backing = if mmap.starts_with(&GZIP_MAGIC) {
+            let (ints, len) = decompress_to_ints(&mmap).context(format!("Failed to decompress {}", file_name))?;
+            Backing::Decompressed(ints, len)
+        } else {
+            Backing::Mapped(mmap)
+        };
 
-    pub fn get_entities_in_tile_with_neighbors(&self, t: &tile::Tile, osm_ids: &Option<HashSet<u64>>) -> OsmEntities {
+        let raw_bytes =
+            check_header(backing.bytes()).context(format!("Failed to load {}", file_name))? as *const [u8];
+        // `raw_bytes` points to bytes that are destroyed when `backing` is dropped.
+        // The bytes are only ever accessed from `storages`, which is bundled together with `backing`
+        // in `GeodataReader`. Therefore, `backing` is still not dropped whenever we access the bytes.
+        let storages = ObjectStorages::from_bytes(unsafe { &*raw_bytes });
+        Ok(GeodataReader {
+            storages,
+            tag_counts: OnceLock::new(),
+            _backing: backing,
+        })
+    }
+
+    pub fn get_entities_in_tile_with_neighbors(
+        &self,
+        t: &tile::Tile,
+        osm_ids: &Option<HashSet<u64>>,
+    ) -> OsmEntities<'_> {
         let mut entity_ids = OsmEntityIds::default();
 
-        let deltas = [-1, 0, 1];
-        for dx in &deltas {
-            for dy in &deltas {
-                let adjacent_tile = tile::Tile {
-                    x: (t.x as i32 + dx) as u32,
-                    y: (t.y as i32 + dy) as u32,
-                    zoom: t.zoom,
-                };
-                self.get_entities_in_tile(&adjacent_tile, &mut entity_ids);
+        // Columns wrap around the antimeridian, just like in `Tile::normalized`, so a tile past it gets the
+        // entities of the one it repeats. Neighbors across the antimeridian would be projected a whole world
+        // away from the tile, and the ones off the northern and the southern edge of the map have nothing in
+        // them, so both are left out.
+        if t.zoom <= tile::MAX_ZOOM {
+            let tiles_per_side = 1i64 << t.zoom;
+            let deltas = [-1, 0, 1];
+            for dx in &deltas {
+                for dy in &deltas {
+                    let x = i64::from(t.x) % tiles_per_side + dx;
+                    let y = i64::from(t.y) + dy;
+                    if !(0..tiles_per_side).contains(&x) || !(0..tiles_per_side).contains(&y) {
+                        continue;
+                    }
+                    let adjacent_tile = tile::Tile {
+                        x: x as u32,
+                        y: y as u32,
+                        zoom: t.zoom,
+                    };
+                    self.get_entities_in_tile(&adjacent_tile, &mut entity_ids);
+                }
             }
         }
 
@@ -80,27 +178,141 @@ impl<'a> GeodataReader<'a> {
         uniq(&mut entity_ids.nodes);
         uniq(&mut entity_ids.ways);
         uniq(&mut entity_ids.multipolygons);
+        uniq(&mut entity_ids.routes);
 
+        let level = self.simplification_level_for(t.zoom);
         let nodes = entity_ids.nodes.iter().map(|id| self.get_node(*id as usize));
-        let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize));
+        let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize, level));
         let multipolygons = entity_ids.multipolygons.iter().filter_map(|id| {
-            let mp = self.get_multipolygon(*id as usize);
+            let mp = self.get_multipolygon(*id as usize, level);
             if mp.polygon_count() > 0 {
                 Some(mp)
             } else {
                 None
             }
         });
+        let routes = entity_ids.routes.iter().map(|id| self.get_route(*id as usize, level));
 
         OsmEntities {
             nodes: filter_entities_by_ids(nodes, osm_ids),
             ways: filter_entities_by_ids(ways, osm_ids),
             multipolygons: filter_entities_by_ids(multipolygons, osm_ids),
+            routes: filter_entities_by_ids(routes, osm_ids),
         }
     }
 
+    /// All tag keys present on nodes, ways, multipolygons and routes, sorted, with the number of entities that
+    /// have each of them. Computed by scanning the whole file on the first call to this method or to
+    /// `distinct_values`.
+    pub fn distinct_keys(&self) -> Vec<(String, usize)> {
+        self.tag_counts()
+            .iter()
+            .map(|(key, values)| (key.clone(), values.values().sum()))
+            .collect()
+    }
+
+    /// All values of `key`, sorted, with the number of entities that have each of them.
+    pub fn distinct_values(&self, key: &str) -> Vec<(String, usize)> {
+        self.tag_counts()
+            .get(key)
+            .map(|values| values.iter().map(|(value, count)| (value.clone(), *count)).collect())
+            .unwrap_or_default()
+    }
+
+    fn tag_counts(&self) -> &TagCounts {
+        self.tag_counts.get_or_init(|| {
+            let mut tag_counts = TagCounts::new();
+            let mut add_tags = |tags: Tags<'_>| {
+                for (key, value) in tags.iter() {
+                    let values = match tag_counts.get_mut(key.str) {
+                        Some(values) => values,
+                        None => tag_counts.entry(key.str.to_string()).or_default(),
+                    };
+                    match values.get_mut(value.str) {
+                        Some(count) => *count += 1,
+                        None => {
+                            values.insert(value.str.to_string(), 1);
+                        }
+                    }
+                }
+            };
+
+            let storages = self.storages();
+            for idx in 0..storages.node_storage.object_count {
+                add_tags(self.get_node(idx).tags());
+            }
+            for idx in 0..storages.way_storage.object_count {
+                add_tags(self.get_way(idx, None).tags());
+            }
+            for idx in 0..storages.multipolygon_storage.object_count {
+                add_tags(self.get_multipolygon(idx, None).tags());
+            }
+            for idx in 0..storages.route_storage.object_count {
+                add_tags(self.get_route(idx, None).tags());
+            }
+            tag_counts
+        })
+    }
+
+    /// Entities that contain the point at `(lat, lon)` (for areas) or pass within `tolerance` meters of it
+    /// (for nodes and lines), using their full geometry. Nodes come first, then ways, multipolygons and routes.
+    /// Untagged entities, like the nodes of a way or the member ways of a multipolygon, are skipped.
+    pub fn features_at(&self, lat: f64, lon: f64, tolerance: f64) -> Vec<FeatureRef<'_>> {
+        let (north_west, south_east) = query::search_box((lat, lon), tolerance);
+        let (min_tile, max_tile) = (
+            tile::coords_to_max_zoom_tile(&north_west),
+            tile::coords_to_max_zoom_tile(&south_east),
+        );
+        let mut entity_ids = OsmEntityIds::default();
+        self.get_entities_in_tile_range(
+            tile::TileRange {
+                min_x: min_tile.x,
+                max_x: max_tile.x,
+                min_y: min_tile.y,
+                max_y: max_tile.y,
+            },
+            &mut entity_ids,
+        );
+
+        let uniq = |ids: &mut Vec<u32>| {
+            ids.sort_unstable();
+            ids.dedup();
+        };
+        uniq(&mut entity_ids.nodes);
+        uniq(&mut entity_ids.ways);
+        uniq(&mut entity_ids.multipolygons);
+        uniq(&mut entity_ids.routes);
+
+        let nodes = entity_ids
+            .nodes
+            .iter()
+            .map(|id| FeatureRef::Node(self.get_node(*id as usize)));
+        let ways = entity_ids
+            .ways
+            .iter()
+            .map(|id| FeatureRef::Way(self.get_way(*id as usize, None)));
+        let multipolygons = entity_ids
+            .multipolygons
+            .iter()
+            .map(|id| FeatureRef::Multipolygon(self.get_multipolygon(*id as usize, None)));
+        let routes = entity_ids
+            .routes
+            .iter()
+            .map(|id| FeatureRef::Route(self.get_route(*id as usize, None)));
+
+        nodes
+            .chain(ways)
+            .chain(multipolygons)
+            .chain(routes)
+            .filter(|feature| feature.tags().iter().next().is_some() && query::is_near(feature, (lat, lon), tolerance))
+            .collect()
+    }
+
     pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
-        let mut bounds = tile::tile_to_max_zoom_tile_range(t);
+        self.get_entities_in_tile_range(tile::tile_to_max_zoom_tile_range(t), entity_ids);
+    }
+
+    fn get_entities_in_tile_range(&'a self, mut bounds: tile::TileRange, entity_ids: &mut OsmEntityIds) {
         let mut start_from_index = 0;
 
         let tile_count = self.tile_count();
@@ -115,6 +327,7 @@ impl<'a> GeodataReader<'a> {
                         entity_ids.nodes.extend(self.tile_local_ids(current_index, 0));
                         entity_ids.ways.extend(self.tile_local_ids(current_index, 1));
                         entity_ids.multipolygons.extend(self.tile_local_ids(current_index, 2));
+                        entity_ids.routes.extend(self.tile_local_ids(current_index, 3));
 
                         current_index += 1;
                         if current_index >= tile_count {
@@ -188,29 +401,73 @@ impl<'a> GeodataReader<'a> {
         }
     }
 
-    fn get_way(&'a self, idx: usize) -> Way<'a> {
+    // The coarsest level that is still meant for `zoom`, or `None` for the original geometry.
+    fn simplification_level_for(&self, zoom: u8) -> Option<usize> {
+        self.storages()
+            .simplification_levels
+            .iter()
+            .position(|level| zoom <= level.max_zoom)
+    }
+
+    fn get_way(&'a self, idx: usize, level: Option<usize>) -> Way<'a> {
         let bytes = self.storages().way_storage.get_object(idx);
-        let node_ids_start_pos = mem::size_of::<u64>();
-        let node_ids = self.get_ints_by_ref(&bytes[node_ids_start_pos..]);
+        let node_ids = match level {
+            Some(level) => {
+                let level = &self.storages().simplification_levels[level];
+                self.get_ints_by_ref(level.way_storage.get_object(idx))
+            }
+            None => {
+                let node_ids_start_pos = mem::size_of::<u64>();
+                self.get_ints_by_ref(&bytes[node_ids_start_pos..])
+            }
+        };
         Way {
             entity: BaseOsmEntity { bytes, reader: self },
             node_ids,
         }
     }
 
-    fn get_polygon(&'a self, idx: usize) -> Polygon<'a> {
-        let bytes = self.storages().polygon_storage.get_object(idx);
+    fn get_polygon(&'a self, idx: usize, level: Option<usize>) -> Polygon<'a> {
+        let bytes = match level {
+            Some(level) => self.storages().simplification_levels[level]
+                .polygon_storage
+                .get_object(idx),
+            None => self.storages().polygon_storage.get_object(idx),
+        };
+        let node_ids = self.get_ints_by_ref(bytes);
+        Polygon { reader: self, node_ids }
+    }
+
+    fn get_polyline(&'a self, idx: usize, level: Option<usize>) -> Polygon<'a> {
+        let bytes = match level {
+            Some(level) => self.storages().simplification_levels[level]
+                .polyline_storage
+                .get_object(idx),
+            None => self.storages().polyline_storage.get_object(idx),
+        };
         let node_ids = self.get_ints_by_ref(bytes);
         Polygon { reader: self, node_ids }
     }
 
-    fn get_multipolygon(&'a self, idx: usize) -> Multipolygon<'a> {
+    fn get_multipolygon(&'a self, idx: usize, level: Option<usize>) -> Multipolygon<'a> {
         let bytes = self.storages().multipolygon_storage.get_object(idx);
         let way_ids_start_pos = mem::size_of::<u64>();
         let way_ids = self.get_ints_by_ref(&bytes[way_ids_start_pos..]);
         Multipolygon {
             entity: BaseOsmEntity { bytes, reader: self },
             polygon_ids: way_ids,
+            level,
+        }
+    }
+
+    fn get_route(&'a self, idx: usize, level: Option<usize>) -> Route<'a> {
+        let bytes = self.storages().route_storage.get_object(idx);
+        let polyline_ids_start_pos = mem::size_of::<u64>();
+        let polyline_ids = self.get_ints_by_ref(&bytes[polyline_ids_start_pos..]);
+        Route {
+            entity: BaseOsmEntity { bytes, reader: self },
+            polyline_ids,
+            level,
         }
     }
 
@@ -261,6 +518,22 @@ where
     }
 }
 
+// Returns the bytes after the header. The header keeps the rest aligned to 4 bytes.
+fn check_header(bytes: &[u8]) -> Result<&[u8]> {
+    if bytes.len() < HEADER_SIZE || bytes[..FORMAT_MAGIC.len()] != FORMAT_MAGIC {
+        bail!("Not a file produced by the importer, or one produced by an older version; import the data again");
+    }
+    let version = LittleEndian::read_u32(&bytes[FORMAT_MAGIC.len()..HEADER_SIZE]);
+    if version != FORMAT_VERSION {
+        bail!(
+            "The file has format version {}, but this version of the renderer reads version {}; import the data again",
+            version,
+            FORMAT_VERSION
+        );
+    }
+    Ok(&bytes[HEADER_SIZE..])
+}
+
 struct ObjectStorage<'a> {
     object_count: usize,
     object_size: usize,
@@ -292,17 +565,33 @@ struct ObjectStorages<'a> {
     node_storage: ObjectStorage<'a>,
     way_storage: ObjectStorage<'a>,
     polygon_storage: ObjectStorage<'a>,
+    polyline_storage: ObjectStorage<'a>,
     multipolygon_storage: ObjectStorage<'a>,
+    route_storage: ObjectStorage<'a>,
     tile_storage: ObjectStorage<'a>,
+    simplification_levels: Vec<SimplificationLevel<'a>>,
     ints: &'a [u32],
     strings: &'a [u8],
 }
 
+// Geometry for tiles up to `max_zoom`, with node refs for every way, polygon and polyline.
+struct SimplificationLevel<'a> {
+    max_zoom: u8,
+    way_storage: ObjectStorage<'a>,
+    polygon_storage: ObjectStorage<'a>,
+    polyline_storage: ObjectStorage<'a>,
+}
+
 const INT_REF_SIZE: usize = 2 * mem::size_of::<u32>();
 const NODE_SIZE: usize = mem::size_of::<u64>() + 2 * mem::size_of::<f64>() + INT_REF_SIZE;
 const POLYGON_SIZE: usize = INT_REF_SIZE;
-const WAY_OR_MULTIPOLYGON_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
-const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 3 * INT_REF_SIZE;
+const WAY_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
+const MULTIPOLYGON_SIZE: usize = WAY_SIZE + mem::size_of::<u32>();
+const ROUTE_SIZE: usize = WAY_SIZE;
+
+/// Stored instead of the label node index for multipolygons that don't have one.
+pub(super) const NO_LABEL_NODE: u32 = u32::MAX;
+const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 4 * INT_REF_SIZE;
 
 impl ObjectStorages<'_> {
     // All geodata members have sizes divisible by 4, so the u8* -> u32* cast should be safe,
@@ -310,10 +599,29 @@ impl ObjectStorages<'_> {
     #[expect(clippy::cast_ptr_alignment)]
     fn from_bytes(bytes: &[u8]) -> ObjectStorages<'_> {
         let (node_storage, rest) = ObjectStorage::from_bytes(bytes, NODE_SIZE);
-        let (way_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
+        let (way_storage, rest) = ObjectStorage::from_bytes(rest, WAY_SIZE);
         let (polygon_storage, rest) = ObjectStorage::from_bytes(rest, POLYGON_SIZE);
-        let (multipolygon_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
-        let (tile_storage, rest) = ObjectStorage::from_bytes(rest, TILE_SIZE);
+        let (polyline_storage, rest) = ObjectStorage::from_bytes(rest, POLYGON_SIZE);
+        let (multipolygon_storage, rest) = ObjectStorage::from_bytes(rest, MULTIPOLYGON_SIZE);
+        let (route_storage, rest) = ObjectStorage::from_bytes(rest, ROUTE_SIZE);
+        let (tile_storage, mut rest) = ObjectStorage::from_bytes(rest, TILE_SIZE);
+
+        let level_count = LittleEndian::read_u32(rest) as usize;
+        rest = &rest[mem::size_of::<u32>()..];
+        let mut simplification_levels = Vec::with_capacity(level_count);
+        for _ in 0..level_count {
+            let max_zoom = LittleEndian::read_u32(rest) as u8;
+            let (way_storage, level_rest) = ObjectStorage::from_bytes(&rest[mem::size_of::<u32>()..], INT_REF_SIZE);
+            let (polygon_storage, level_rest) = ObjectStorage::from_bytes(level_rest, INT_REF_SIZE);
+            let (polyline_storage, level_rest) = ObjectStorage::from_bytes(level_rest, INT_REF_SIZE);
+            simplification_levels.push(SimplificationLevel {
+                max_zoom,
+                way_storage,
+                polygon_storage,
+                polyline_storage,
+            });
+            rest = level_rest;
+        }
 
         let int_count = LittleEndian::read_u32(rest) as usize;
         let start_pos = mem::size_of::<u32>();
@@ -327,8 +635,11 @@ impl ObjectStorages<'_> {
             node_storage,
             way_storage,
             polygon_storage,
+            polyline_storage,
             multipolygon_storage,
+            route_storage,
             tile_storage,
+            simplification_levels,
             ints,
             strings,
         }
@@ -372,6 +683,29 @@ impl<'a> Tags<'a> {
         }
     }
 
+    /// `yes`, `true` and `1` are true, `no`, `false` and `0` are false, anything else is `None`.
+    pub fn tag_bool(&self, key: &str) -> Option<bool> {
+        match self.get_by_key(key)?.trim() {
+            "yes" | "true" | "1" => Some(true),
+            "no" | "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn tag_num(&self, key: &str) -> Option<f64> {
+        self.get_by_key(key)?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite())
+    }
+
+    /// Parses integer values like `layer=-1` or `layer=+2`. Fractional values are rejected.
+    pub fn tag_i32(&self, key: &str) -> Option<i32> {
+        let value = self.get_by_key(key)?.trim();
+        value.strip_prefix('+').unwrap_or(value).parse::<i32>().ok()
+    }
+
     pub fn iter(&'a self) -> impl Iterator<Item = (StringWithOffset<'a>, StringWithOffset<'a>)> {
         (0..self.get_kv_count()).map(move |idx| self.get_kv(idx))
     }
@@ -469,6 +803,16 @@ impl<'a> Way<'a> {
         let node_id = self.node_ids[idx];
         self.entity.reader.get_node(node_id as usize)
     }
+
+    /// `area=no` makes a closed way a line instead of a polygon. Open ways are always lines, so `area=yes`
+    /// doesn't change anything for them.
+    pub fn area_override(&self) -> Option<bool> {
+        match self.tags().get_by_key("area") {
+            Some("yes") => Some(true),
+            Some("no") => Some(false),
+            _ => None,
+        }
+    }
 }
 
 impl OsmArea for Way<'_> {
@@ -480,8 +824,13 @@ impl OsmArea for Way<'_> {
         let last_node = self.get_node(self.node_count() - 1);
         (first_node.lat(), first_node.lon()) == (last_node.lat(), last_node.lon())
     }
+
+    fn is_area(&self) -> bool {
+        self.is_closed() && self.area_override() != Some(false)
+    }
 }
 
+/// A ring of a multipolygon or a line of a route.
 pub struct Polygon<'a> {
     reader: &'a GeodataReader<'a>,
     node_ids: &'a [u32],
@@ -501,6 +850,7 @@ impl<'a> Polygon<'a> {
 pub struct Multipolygon<'a> {
     entity: BaseOsmEntity<'a>,
     polygon_ids: &'a [u32],
+    level: Option<usize>,
 }
 
 implement_osm_entity!(Multipolygon<'a>);
@@ -512,7 +862,16 @@ impl<'a> Multipolygon<'a> {
 
     pub fn get_polygon(&self, idx: usize) -> Polygon<'a> {
         let polygon_id = self.polygon_ids[idx];
-        self.entity.reader.get_polygon(polygon_id as usize)
+        self.entity.reader.get_polygon(polygon_id as usize, self.level)
+    }
+
+    /// The `label` (or `admin_centre`) member of the relation, which says where the name should go.
+    pub fn label_node(&self) -> Option<Node<'a>> {
+        let label_node_pos = mem::size_of::<u64>() + INT_REF_SIZE;
+        match LittleEndian::read_u32(&self.entity.bytes[label_node_pos..]) {
+            NO_LABEL_NODE => None,
+            node_id => Some(self.entity.reader.get_node(node_id as usize)),
+        }
     }
 }
 
@@ -521,3 +880,23 @@ impl OsmArea for Multipolygon<'_> {
         true
     }
 }
+
+/// The member ways of a `type=route` relation, joined into lines that follow the direction of the route.
+pub struct Route<'a> {
+    entity: BaseOsmEntity<'a>,
+    polyline_ids: &'a [u32],
+    level: Option<usize>,
+}
+
+implement_osm_entity!(Route<'a>);
+
+impl<'a> Route<'a> {
+    pub fn polyline_count(&self) -> usize {
+        self.polyline_ids.len()
+    }
+
+    pub fn get_polyline(&self, idx: usize) -> Polygon<'a> {
+        let polyline_id = self.polyline_ids[idx];
+        self.entity.reader.get_polyline(polyline_id as usize, self.level)
+    }
+}
@@ -1,4 +1,8 @@
-use crate::geodata::importer::{EntityStorages, Multipolygon, Polygon, RawNode, RawRefs, RawWay};
+use crate::geodata::importer::{
+    EntityStorages, Multipolygon, Polygon, Polyline, RawNode, RawRefs, RawWay, Route, SimplificationLevel,
+};
+use crate::geodata::reader::{FORMAT_MAGIC, FORMAT_VERSION, NO_LABEL_NODE};
+use crate::geodata::simplify::simplify;
 use crate::tile;
 use anyhow::{bail, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
@@ -11,6 +15,7 @@ struct TileReferences {
     local_node_ids: BTreeSet<usize>,
     local_way_ids: BTreeSet<usize>,
     local_multipolygon_ids: BTreeSet<usize>,
+    local_route_ids: BTreeSet<usize>,
 }
 
 #[derive(Default)]
@@ -18,28 +23,57 @@ struct TileIdToReferences {
     refs: BTreeMap<(u32, u32), TileReferences>,
 }
 
-pub(super) fn save_to_internal_format(writer: &mut dyn Write, entity_storages: &EntityStorages) -> Result<()> {
+// Offset and length of a sequence in `BufferedData::all_ints`.
+type IntRef = (u32, u32);
+
+pub(super) fn save_to_internal_format(
+    writer: &mut dyn Write,
+    entity_storages: &EntityStorages,
+    simplification_levels: &[SimplificationLevel],
+) -> Result<()> {
+    save_header(writer)?;
+
     let mut buffered_data = BufferedData::default();
     let nodes = &entity_storages.node_storage.get_entities();
     save_nodes(writer, nodes, &mut buffered_data)?;
 
     let ways = &entity_storages.way_storage.get_entities();
-    save_ways(writer, ways, &mut buffered_data)?;
+    let way_node_refs = save_ways(writer, ways, &mut buffered_data)?;
 
     let polygons = &entity_storages.polygon_storage;
-    save_polygons(writer, polygons, &mut buffered_data)?;
+    let polygon_node_refs = save_polygons(writer, polygons, &mut buffered_data)?;
+
+    let polylines = &entity_storages.polyline_storage;
+    let polyline_node_refs = save_polygons(writer, polylines, &mut buffered_data)?;
 
     let multipolygons = &entity_storages.multipolygon_storage.get_entities();
     save_multipolygons(writer, multipolygons, &mut buffered_data)?;
 
+    let routes = &entity_storages.route_storage.get_entities();
+    save_routes(writer, routes, &mut buffered_data)?;
+
     let tile_references = get_tile_references(entity_storages);
     save_tile_references(writer, &tile_references, &mut buffered_data)?;
 
+    let geometries = Geometries {
+        nodes,
+        ways: (ways, &way_node_refs),
+        polygons: (polygons, &polygon_node_refs),
+        polylines: (polylines, &polyline_node_refs),
+    };
+    save_simplification_levels(writer, &geometries, simplification_levels, &mut buffered_data)?;
+
     buffered_data.save(writer)?;
 
     Ok(())
 }
 
+fn save_header(writer: &mut dyn Write) -> Result<()> {
+    writer.write_all(&FORMAT_MAGIC)?;
+    writer.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+    Ok(())
+}
+
 impl TileIdToReferences {
     fn tile_ref_by_node(&mut self, node: &RawNode) -> &mut TileReferences {
         let node_tile = tile::coords_to_max_zoom_tile(node);
@@ -62,34 +96,120 @@ fn save_nodes(writer: &mut dyn Write, nodes: &[RawNode], data: &mut BufferedData
     Ok(())
 }
 
-fn save_ways(writer: &mut dyn Write, ways: &[RawWay], data: &mut BufferedData) -> Result<()> {
+// Returns where the node refs of every way were saved.
+fn save_ways(writer: &mut dyn Write, ways: &[RawWay], data: &mut BufferedData) -> Result<Vec<IntRef>> {
     writer.write_u32::<LittleEndian>(to_u32_safe(ways.len())?)?;
+    let mut node_refs = Vec::with_capacity(ways.len());
     for way in ways {
         writer.write_u64::<LittleEndian>(way.global_id)?;
-        save_refs(writer, way.node_ids.iter(), data)?;
+        node_refs.push(save_refs(writer, way.node_ids.iter(), data)?);
         save_tags(writer, &way.tags, data)?;
     }
-    Ok(())
+    Ok(node_refs)
 }
 
-fn save_polygons(writer: &mut dyn Write, polygons: &[Polygon], data: &mut BufferedData) -> Result<()> {
+fn save_polygons(writer: &mut dyn Write, polygons: &[Polygon], data: &mut BufferedData) -> Result<Vec<IntRef>> {
     writer.write_u32::<LittleEndian>(to_u32_safe(polygons.len())?)?;
-    for polygon in polygons {
-        save_refs(writer, polygon.iter(), data)?;
+    polygons
+        .iter()
+        .map(|polygon| save_refs(writer, polygon.iter(), data))
+        .collect()
+}
+
+struct Geometries<'a> {
+    nodes: &'a [RawNode],
+    ways: (&'a [RawWay], &'a [IntRef]),
+    polygons: (&'a [Polygon], &'a [IntRef]),
+    polylines: (&'a [Polyline], &'a [IntRef]),
+}
+
+// Every level is stored as its `max_zoom` followed by the node refs of all ways, of all polygons and then of
+// all polylines, in the same order as the original ones. Geometry that can't be simplified any further points to the
+// original node refs instead of repeating them.
+fn save_simplification_levels(
+    writer: &mut dyn Write,
+    geometries: &Geometries<'_>,
+    levels: &[SimplificationLevel],
+    data: &mut BufferedData,
+) -> Result<()> {
+    let mut levels = levels.to_vec();
+    levels.sort_by_key(|level| level.max_zoom);
+    writer.write_u32::<LittleEndian>(to_u32_safe(levels.len())?)?;
+
+    for level in &levels {
+        println!(
+            "Simplifying geometry for zoom {} and below with a tolerance of {} px",
+            level.max_zoom, level.tolerance
+        );
+        writer.write_u32::<LittleEndian>(u32::from(level.max_zoom))?;
+
+        let (ways, way_node_refs) = geometries.ways;
+        writer.write_u32::<LittleEndian>(to_u32_safe(ways.len())?)?;
+        for (way, original_ref) in ways.iter().zip(way_node_refs) {
+            save_simplified_refs(writer, &way.node_ids, *original_ref, geometries.nodes, level, data)?;
+        }
+
+        for (polygons, polygon_node_refs) in [geometries.polygons, geometries.polylines] {
+            writer.write_u32::<LittleEndian>(to_u32_safe(polygons.len())?)?;
+            for (polygon, original_ref) in polygons.iter().zip(polygon_node_refs) {
+                save_simplified_refs(writer, polygon, *original_ref, geometries.nodes, level, data)?;
+            }
+        }
     }
+
     Ok(())
 }
 
+fn save_simplified_refs(
+    writer: &mut dyn Write,
+    node_ids: &RawRefs,
+    original_ref: IntRef,
+    nodes: &[RawNode],
+    level: &SimplificationLevel,
+    data: &mut BufferedData,
+) -> Result<()> {
+    let points = node_ids
+        .iter()
+        .map(|&id| tile::coords_to_xy(&nodes[id], level.max_zoom))
+        .collect::<Vec<_>>();
+    let kept = simplify(&points, level.tolerance);
+
+    // Rings that collapse are kept as they are, so that they don't turn into lines.
+    let is_ring = node_ids.len() >= 4 && node_ids.first() == node_ids.last();
+    if kept.len() == node_ids.len() || (is_ring && kept.len() < 4) {
+        return save_int_ref(writer, original_ref);
+    }
+    save_refs(writer, kept.iter().map(|&idx| &node_ids[idx]), data).map(|_| ())
+}
+
 fn save_multipolygons(writer: &mut dyn Write, multipolygons: &[Multipolygon], data: &mut BufferedData) -> Result<()> {
     writer.write_u32::<LittleEndian>(to_u32_safe(multipolygons.len())?)?;
     for multipolygon in multipolygons {
         writer.write_u64::<LittleEndian>(multipolygon.global_id)?;
         save_refs(writer, multipolygon.polygon_ids.iter(), data)?;
+        let label_node_id = match multipolygon.label_node_id {
+            Some(id) => to_u32_safe(id).and_then(|id| match id {
+                NO_LABEL_NODE => bail!("{} is reserved for multipolygons without a label node", id),
+                id => Ok(id),
+            })?,
+            None => NO_LABEL_NODE,
+        };
+        writer.write_u32::<LittleEndian>(label_node_id)?;
         save_tags(writer, &multipolygon.tags, data)?;
     }
     Ok(())
 }
 
+fn save_routes(writer: &mut dyn Write, routes: &[Route], data: &mut BufferedData) -> Result<()> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(routes.len())?)?;
+    for route in routes {
+        writer.write_u64::<LittleEndian>(route.global_id)?;
+        save_refs(writer, route.polyline_ids.iter(), data)?;
+        save_tags(writer, &route.tags, data)?;
+    }
+    Ok(())
+}
+
 fn save_tile_references(
     writer: &mut dyn Write,
     tile_references: &TileIdToReferences,
@@ -103,12 +223,13 @@ fn save_tile_references(
         save_refs(writer, v.local_node_ids.iter(), data)?;
         save_refs(writer, v.local_way_ids.iter(), data)?;
         save_refs(writer, v.local_multipolygon_ids.iter(), data)?;
+        save_refs(writer, v.local_route_ids.iter(), data)?;
     }
 
     Ok(())
 }
 
-fn save_refs<'a, I>(writer: &mut dyn Write, refs: I, data: &mut BufferedData) -> Result<()>
+fn save_refs<'a, I>(writer: &mut dyn Write, refs: I, data: &mut BufferedData) -> Result<IntRef>
 where
     I: Iterator<Item = &'a usize>,
 {
@@ -116,8 +237,14 @@ where
     for r in refs {
         data.all_ints.push(to_u32_safe(*r)?);
     }
-    writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
-    writer.write_u32::<LittleEndian>(to_u32_safe(data.all_ints.len() - offset)?)?;
+    let int_ref = (to_u32_safe(offset)?, to_u32_safe(data.all_ints.len() - offset)?);
+    save_int_ref(writer, int_ref)?;
+    Ok(int_ref)
+}
+
+fn save_int_ref(writer: &mut dyn Write, (offset, length): IntRef) -> Result<()> {
+    writer.write_u32::<LittleEndian>(offset)?;
+    writer.write_u32::<LittleEndian>(length)?;
     Ok(())
 }
 
@@ -188,6 +315,16 @@ fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
         insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_multipolygon_ids, i);
     }
 
+    let polylines = &entity_storages.polyline_storage;
+    for (i, route) in entity_storages.route_storage.get_entities().iter().enumerate() {
+        let node_ids = route
+            .polyline_ids
+            .iter()
+            .flat_map(move |polyline_id| polylines[*polyline_id].iter())
+            .map(|idx| &nodes[*idx]);
+        insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_route_ids, i);
+    }
+
     result
 }
 
@@ -296,6 +433,7 @@ mod tests {
                 local_node_ids: [idx].iter().cloned().collect(),
                 local_way_ids: BTreeSet::default(),
                 local_multipolygon_ids: BTreeSet::default(),
+                local_route_ids: BTreeSet::default(),
             });
         }
 
@@ -307,11 +445,21 @@ mod tests {
             let mut writer = BufWriter::new(tmp_file);
 
             let mut data = BufferedData::default();
+            save_header(&mut writer).unwrap();
             save_nodes(&mut writer, &nodes, &mut data).unwrap();
             save_ways(&mut writer, &[], &mut data).unwrap();
             save_polygons(&mut writer, &[], &mut data).unwrap();
+            save_polygons(&mut writer, &[], &mut data).unwrap();
             save_multipolygons(&mut writer, &[], &mut data).unwrap();
+            save_routes(&mut writer, &[], &mut data).unwrap();
             save_tile_references(&mut writer, &tile_refs, &mut data).unwrap();
+            let geometries = Geometries {
+                nodes: &nodes,
+                ways: (&[], &[]),
+                polygons: (&[], &[]),
+                polylines: (&[], &[]),
+            };
+            save_simplification_levels(&mut writer, &geometries, &[], &mut data).unwrap();
             data.save(&mut writer).unwrap();
         }
 
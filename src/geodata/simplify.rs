@@ -0,0 +1,40 @@
+// Douglas-Peucker: returns the indices of the points to keep, always including the first and the last
+// one, so that no dropped point is farther than `tolerance` from the simplified line.
+pub(super) fn simplify(points: &[(f64, f64)], tolerance: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((from, to)) = ranges.pop() {
+        let farthest = (from + 1..to)
+            .map(|idx| (idx, distance_to_segment(points[idx], points[from], points[to])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((idx, distance)) = farthest {
+            if distance > tolerance {
+                keep[idx] = true;
+                ranges.push((from, idx));
+                ranges.push((idx, to));
+            }
+        }
+    }
+
+    (0..points.len()).filter(|&idx| keep[idx]).collect()
+}
+
+pub(super) fn distance_to_segment(p: (f64, f64), from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length_squared = dx * dx + dy * dy;
+    // Closed rings start and end at the same point.
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        (((p.0 - from.0) * dx + (p.1 - from.1) * dy) / length_squared).clamp(0.0, 1.0)
+    };
+    let (closest_x, closest_y) = (from.0 + t * dx, from.1 + t * dy);
+    ((p.0 - closest_x).powi(2) + (p.1 - closest_y).powi(2)).sqrt()
+}
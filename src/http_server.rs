@@ -1,16 +1,22 @@
-use crate::draw::drawer::Drawer;
+use crate::batch::{BatchOptions, BatchRenderer};
+use crate::coords::{tiles_for_bbox, BoundingBox};
+use crate::draw::drawer::{Drawer, RenderOptions, RenderTimedOut};
+use crate::draw::png_writer::rgb_triples_to_png;
 use crate::draw::tile_pixels::TilePixels;
 use crate::geodata::reader::GeodataReader;
 use crate::mapcss::parser::parse_file;
 use crate::mapcss::styler::{StyleType, Styler};
 use crate::perf_stats::PerfStats;
-use crate::tile::{Tile, MAX_ZOOM};
+use crate::tile::{Tile, TileScheme, MAX_ZOOM};
 use anyhow::{anyhow, bail, Context, Result};
+use lru::LruCache;
 use std::collections::HashSet;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
@@ -19,7 +25,97 @@ use std::thread;
 
 enum HandlerMessage {
     Terminate,
-    ServeTile { path: String, stream: TcpStream },
+    ServeTile { request: Request, stream: TcpStream },
+}
+
+/// Controls which `Access-Control-Allow-*` headers are attached to the responses.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Origins that are allowed to fetch the tiles. `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// How long (in seconds) browsers may cache the preflight response.
+    pub max_age: Option<u32>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "OPTIONS".to_string()],
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allow_origin_headers(&self, origin: Option<&str>) -> Vec<String> {
+        if self.allowed_origins.iter().any(|x| x == "*") {
+            return vec!["Access-Control-Allow-Origin: *".to_string()];
+        }
+        match origin {
+            Some(origin) if self.allowed_origins.iter().any(|x| x == origin) => vec![
+                format!("Access-Control-Allow-Origin: {}", origin),
+                "Vary: Origin".to_string(),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    fn preflight_headers(&self, origin: Option<&str>) -> Vec<String> {
+        let mut headers = self.allow_origin_headers(origin);
+        if !headers.is_empty() {
+            headers.push(format!(
+                "Access-Control-Allow-Methods: {}",
+                self.allowed_methods.join(", ")
+            ));
+            if let Some(max_age) = self.max_age {
+                headers.push(format!("Access-Control-Max-Age: {}", max_age));
+            }
+        }
+        headers
+    }
+}
+
+/// Keeps the most recently served tiles in memory.
+#[derive(Clone, Debug, Default)]
+pub struct CacheConfig {
+    /// How many tiles are kept, the least recently used ones are evicted first. 0 disables the cache.
+    pub capacity: usize,
+    /// Rendered into the cache before the server starts accepting connections.
+    pub warm_region: Option<WarmRegion>,
+}
+
+impl CacheConfig {
+    fn validate(&self) -> Result<()> {
+        let Some(warm_region) = &self.warm_region else {
+            return Ok(());
+        };
+        if self.capacity == 0 {
+            bail!("Can't warm up the cache when it's disabled, set its size as well");
+        }
+        if let Some(zoom) = warm_region.zooms.iter().find(|&&zoom| zoom > MAX_ZOOM) {
+            bail!(
+                "Can't warm up the cache for zoom {}, the maximum zoom is {}",
+                zoom,
+                MAX_ZOOM
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WarmRegion {
+    pub bbox: BoundingBox,
+    /// At most `MAX_ZOOM`.
+    pub zooms: Vec<u8>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    origin: Option<String>,
 }
 
 struct HandlerState {
@@ -27,7 +123,10 @@ struct HandlerState {
     current_pixels: Box<TilePixels>,
 }
 
-#[expect(clippy::implicit_hasher)]
+/// Serves `/{z}/{x}/{y}.png` (or `{y}@2x.png` for scaled tiles) with `y` in the XYZ scheme, see `TileScheme`.
+/// Tiles that hit `RenderOptions::max_render_time` are served as they are with a 504 status.
+/// An `x` past the antimeridian wraps around and a `y` off the map is rejected, see `Tile::normalized`.
+#[expect(clippy::implicit_hasher, clippy::too_many_arguments)]
 pub fn run_server(
     address: &str,
     geodata_file: &str,
@@ -35,25 +134,41 @@ pub fn run_server(
     stylesheet_type: &StyleType,
     font_size_multiplier: Option<f64>,
     osm_ids: Option<HashSet<u64>>,
+    cors: CorsConfig,
+    render_options: RenderOptions,
+    cache: CacheConfig,
 ) -> Result<()> {
+    cache.validate()?;
     let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
     let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
 
+    let thread_count =
+        thread::available_parallelism().context("Failed to determine the number of threads to use for rendering")?;
+
     let server = Arc::new(HttpServer {
         styler: Styler::new(rules, stylesheet_type, font_size_multiplier),
         reader: GeodataReader::load(geodata_file).context("Failed to load the geodata file")?,
-        drawer: Drawer::new(&base_path),
+        drawer: Drawer::with_options(&base_path, render_options),
         osm_ids,
+        cors,
         perf_stats: Mutex::new(PerfStats::default()),
+        worker_count: thread_count.into(),
+        tile_cache: Mutex::new(TileCache::new(cache.capacity)),
+        rendered_tiles: AtomicUsize::new(0),
     });
 
-    let thread_count =
-        thread::available_parallelism().context("Failed to determine the number of threads to use for rendering")?;
+    if let Some(warm_region) = &cache.warm_region {
+        server.warm_cache(&warm_region.bbox, &warm_region.zooms, |done, total| {
+            if done.is_multiple_of(WARM_PROGRESS_INTERVAL) || done == total {
+                eprintln!("Warmed up {}/{} tiles", done, total);
+            }
+        })?;
+    }
 
     let mut senders: Vec<Sender<HandlerMessage>> = Vec::new();
     let mut receivers: Vec<Receiver<HandlerMessage>> = Vec::new();
 
-    for _ in 0..thread_count.into() {
+    for _ in 0..server.worker_count {
         let (tx, rx) = mpsc::channel();
         senders.push(tx);
         receivers.push(rx);
@@ -74,8 +189,8 @@ pub fn run_server(
             while let Ok(msg) = receiver.recv() {
                 match msg {
                     HandlerMessage::Terminate => break,
-                    HandlerMessage::ServeTile { path, stream } => {
-                        server_ref.handle_connection(&path, stream, &mut handler_state)
+                    HandlerMessage::ServeTile { request, stream } => {
+                        server_ref.handle_connection(&request, stream, &mut handler_state)
                     }
                 }
             }
@@ -86,15 +201,15 @@ pub fn run_server(
     let mut thread_id = 0;
 
     for mut stream in tcp_listener.incoming().flatten() {
-        let path = match extract_path_from_stream(&mut stream) {
-            Ok(path) => path,
+        let request = match extract_request_from_stream(&mut stream) {
+            Ok(request) => request,
             Err(e) => {
                 eprintln!("{} didn't send a valid HTTP request: {}", peer_addr(&stream), e);
                 continue;
             }
         };
 
-        if path == "/shutdown" {
+        if request.path == "/shutdown" {
             eprintln!("Shutting down due to a shutdown request");
             for sender in senders {
                 sender.send(HandlerMessage::Terminate).unwrap();
@@ -103,7 +218,7 @@ pub fn run_server(
         }
 
         senders[thread_id]
-            .send(HandlerMessage::ServeTile { path, stream })
+            .send(HandlerMessage::ServeTile { request, stream })
             .unwrap();
         thread_id = (thread_id + 1) % senders.len();
     }
@@ -115,33 +230,114 @@ pub fn run_server(
     Ok(())
 }
 
+// How often `run_server` reports how many tiles are already in the cache.
+const WARM_PROGRESS_INTERVAL: usize = 100;
+
 struct HttpServer<'a> {
     styler: Styler,
     reader: GeodataReader<'a>,
     drawer: Drawer,
     osm_ids: Option<HashSet<u64>>,
+    cors: CorsConfig,
     perf_stats: Mutex<PerfStats>,
+    worker_count: usize,
+    tile_cache: Mutex<TileCache>,
+    // Every tile that was drawn instead of being taken from the cache.
+    rendered_tiles: AtomicUsize,
 }
 
 impl HttpServer<'_> {
-    fn handle_connection(&self, path: &str, mut stream: TcpStream, state: &mut HandlerState) {
-        match self.try_handle_connection(path, &mut stream, state) {
+    /// Renders the 1x tiles of `zooms` that intersect `bbox` into the cache, using as many threads as there are
+    /// request handlers. `on_progress` gets the number of the tiles that are done and the total one.
+    fn warm_cache<F>(&self, bbox: &BoundingBox, zooms: &[u8], on_progress: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) + Send,
+    {
+        if self.osm_ids.is_some() {
+            bail!("Can't warm up the cache when only some of the OSM IDs are rendered");
+        }
+
+        let tiles = zooms
+            .iter()
+            .flat_map(|&zoom| tiles_for_bbox(bbox, u32::from(zoom), TileScheme::Xyz))
+            .map(|(zoom, x, y)| Tile { zoom: zoom as u8, x, y })
+            .collect::<Vec<_>>();
+        let batch = BatchRenderer::new(&self.reader, &self.styler, &self.drawer, BatchOptions::default());
+        let done = AtomicUsize::new(0);
+        let on_progress = Mutex::new(on_progress);
+
+        let tiles_per_worker = tiles.len().div_ceil(self.worker_count.max(1)).max(1);
+        let result = thread::scope(|scope| {
+            let workers = tiles
+                .chunks(tiles_per_worker)
+                .map(|part| {
+                    scope.spawn(|| {
+                        batch.render_tiles(part.iter().cloned(), |tile, rendered| {
+                            self.rendered_tiles.fetch_add(1, Ordering::Relaxed);
+                            // A partially drawn tile is better rendered again when it's requested.
+                            if !rendered.timed_out {
+                                let png =
+                                    rgb_triples_to_png(&rendered.triples, rendered.dimension, rendered.dimension)?;
+                                self.tile_cache.lock().unwrap().insert((tile.clone(), 1), png);
+                            }
+                            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                            (on_progress.lock().unwrap())(done, tiles.len());
+                            Ok(())
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+        });
+        self.perf_stats.lock().unwrap().merge(batch.take_perf_stats());
+        result
+    }
+
+    fn handle_connection(&self, request: &Request, mut stream: TcpStream, state: &mut HandlerState) {
+        match self.try_handle_connection(request, &mut stream, state) {
             Ok(_) => {}
             Err(e) => eprintln!("Error processing request from {}: {}", peer_addr(&stream), e),
         }
     }
 
-    fn try_handle_connection(&self, path: &str, stream: &mut TcpStream, state: &mut HandlerState) -> Result<()> {
+    fn try_handle_connection(&self, request: &Request, stream: &mut TcpStream, state: &mut HandlerState) -> Result<()> {
+        let origin = request.origin.as_deref();
+
+        if request.method == "OPTIONS" {
+            serve_preflight(stream, &self.cors, origin);
+            return Ok(());
+        }
+
+        let path = request.path.as_str();
         if cfg!(feature = "perf-stats") && path == "/perf_stats" {
             let perf_stats_html = self.perf_stats.lock().unwrap().to_html();
-            serve_data(stream, perf_stats_html.as_bytes(), "text/html");
+            serve_data(stream, OK, perf_stats_html.as_bytes(), "text/html", &self.cors, origin);
             return Ok(());
         }
 
-        let tile = match extract_tile_from_path(path) {
+        let mut tile = match extract_tile_from_path(path) {
             Some(tile) => tile,
             _ => bail!("<{}> doesn't look like a valid tile ID", path),
         };
+        tile.tile = tile
+            .tile
+            .normalized()
+            .with_context(|| format!("<{}> is not on the map", path))?;
+
+        let (status, tile_png_bytes) = self.get_tile_png(&tile, state)?;
+        if status == GATEWAY_TIMEOUT {
+            eprintln!("Tile {} timed out, serving a partial one", path);
+        }
+        serve_data(stream, status, &tile_png_bytes, "image/png", &self.cors, origin);
+
+        Ok(())
+    }
+
+    fn get_tile_png(&self, tile: &RequestTile, state: &mut HandlerState) -> Result<(&'static str, Vec<u8>)> {
+        let cache_key = (tile.tile.clone(), tile.scale);
+        if let Some(tile_png_bytes) = self.tile_cache.lock().unwrap().get(&cache_key) {
+            return Ok((OK, tile_png_bytes));
+        }
 
         if cfg!(feature = "perf-stats") {
             crate::perf_stats::start_tile(tile.tile.zoom);
@@ -156,41 +352,83 @@ impl HttpServer<'_> {
         if tile.scale != state.current_scale {
             let _m = crate::perf_stats::measure("Re-scaling TilePixels");
             state.current_scale = tile.scale;
-            state.current_pixels = Box::new(TilePixels::new(tile.scale));
+            *state.current_pixels = TilePixels::new(tile.scale);
         }
 
-        let tile_png_bytes = self
-            .drawer
-            .draw_tile(
-                &entities,
-                &tile.tile,
-                &mut state.current_pixels,
-                state.current_scale,
-                &self.styler,
-            )
-            .unwrap();
+        let rendered = self.drawer.draw_tile(
+            &entities,
+            &tile.tile,
+            &mut state.current_pixels,
+            state.current_scale,
+            &self.styler,
+        );
+        self.rendered_tiles.fetch_add(1, Ordering::Relaxed);
 
         if cfg!(feature = "perf-stats") {
             crate::perf_stats::finish_tile(&mut self.perf_stats.lock().unwrap());
         }
 
-        serve_data(stream, &tile_png_bytes, "image/png");
+        match rendered {
+            Ok(tile_png_bytes) => {
+                self.tile_cache
+                    .lock()
+                    .unwrap()
+                    .insert(cache_key, tile_png_bytes.clone());
+                Ok((OK, tile_png_bytes))
+            }
+            Err(e) => match e.downcast::<RenderTimedOut>() {
+                Ok(timed_out) => Ok((GATEWAY_TIMEOUT, timed_out.partial_tile)),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+type CacheKey = (Tile, usize);
 
-        Ok(())
+struct TileCache {
+    // None if the cache is disabled.
+    tiles: Option<LruCache<CacheKey, Vec<u8>>>,
+}
+
+impl TileCache {
+    fn new(capacity: usize) -> TileCache {
+        TileCache {
+            tiles: NonZeroUsize::new(capacity).map(LruCache::new),
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        self.tiles.as_mut()?.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: CacheKey, tile_png_bytes: Vec<u8>) {
+        if let Some(tiles) = self.tiles.as_mut() {
+            tiles.put(key, tile_png_bytes);
+        }
     }
 }
 
-fn serve_data(stream: &mut TcpStream, data: &[u8], content_type: &str) {
-    let header = [
-        "HTTP/1.1 200 OK",
-        &format!("Content-Type: {}", content_type),
-        &format!("Content-Length: {}", data.len()),
-        "Access-Control-Allow-Origin: *",
-        "Connection: close",
-        "",
-        "",
-    ]
-    .join("\r\n");
+const OK: &str = "200 OK";
+// The tile is still there, but only partially drawn.
+const GATEWAY_TIMEOUT: &str = "504 Gateway Timeout";
+
+fn serve_data(
+    stream: &mut impl Write,
+    status: &str,
+    data: &[u8],
+    content_type: &str,
+    cors: &CorsConfig,
+    origin: Option<&str>,
+) {
+    let mut header_lines = vec![
+        format!("HTTP/1.1 {}", status),
+        format!("Content-Type: {}", content_type),
+        format!("Content-Length: {}", data.len()),
+    ];
+    header_lines.extend(cors.allow_origin_headers(origin));
+    header_lines.extend(["Connection: close".to_string(), String::new(), String::new()]);
+    let header = header_lines.join("\r\n");
 
     // Errors at this stage usually happen when the outstanding requests get terminated for some
     // reason (e.g. the user scrolls the map). We're not interested in reporting these errors,
@@ -200,9 +438,17 @@ fn serve_data(stream: &mut TcpStream, data: &[u8], content_type: &str) {
     }
 }
 
-fn extract_path_from_stream(stream: &mut TcpStream) -> Result<String> {
-    let mut rdr = BufReader::new(stream);
-    let first_line = match rdr.by_ref().lines().next() {
+fn serve_preflight(stream: &mut impl Write, cors: &CorsConfig, origin: Option<&str>) {
+    let mut header_lines = vec!["HTTP/1.1 204 No Content".to_string()];
+    header_lines.extend(cors.preflight_headers(origin));
+    header_lines.extend(["Connection: close".to_string(), String::new(), String::new()]);
+    let _ = stream.write_all(header_lines.join("\r\n").as_bytes());
+}
+
+fn extract_request_from_stream(stream: &mut impl Read) -> Result<Request> {
+    let rdr = BufReader::new(stream);
+    let mut lines = rdr.lines();
+    let first_line = match lines.next() {
         Some(Ok(line)) => line,
         _ => bail!("Failed to read the first line from the TCP stream"),
     };
@@ -211,14 +457,32 @@ fn extract_path_from_stream(stream: &mut TcpStream) -> Result<String> {
         bail!("<{}> doesn't look like a valid HTTP request", first_line);
     }
     let method = tokens[0];
-    if method != "GET" {
+    if method != "GET" && method != "OPTIONS" {
         bail!("Invalid HTTP method: {}", method);
     }
     let http_version = tokens[2];
     if http_version != "HTTP/1.1" && http_version != "HTTP/1.0" {
         bail!("Invalid HTTP version: {}", http_version);
     }
-    Ok(tokens[1].to_string())
+
+    let mut origin = None;
+    for line in lines {
+        let line = line.context("Failed to read the request headers")?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("origin") {
+                origin = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        path: tokens[1].to_string(),
+        origin,
+    })
 }
 
 struct RequestTile {
@@ -282,3 +546,168 @@ fn peer_addr(stream: &TcpStream) -> String {
         .map(|x| format!("{}", x))
         .unwrap_or_else(|_| "N/A".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn response_to_string(write: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut response = Vec::new();
+        write(&mut response);
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn test_cross_origin_get_has_allow_origin() {
+        let raw_request = "GET /1/0/0.png HTTP/1.1\r\nHost: localhost\r\nOrigin: https://example.com\r\n\r\n";
+        let request = extract_request_from_stream(&mut raw_request.as_bytes()).unwrap();
+        assert_eq!(request.path, "/1/0/0.png");
+        assert_eq!(request.origin.as_deref(), Some("https://example.com"));
+
+        let cors = CorsConfig {
+            allowed_origins: vec!["https://example.com".to_string()],
+            ..Default::default()
+        };
+        let response =
+            response_to_string(|out| serve_data(out, OK, b"tile", "image/png", &cors, request.origin.as_deref()));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://example.com\r\n"));
+
+        let response =
+            response_to_string(|out| serve_data(out, OK, b"tile", "image/png", &cors, Some("https://evil.com")));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn test_preflight_returns_no_content() {
+        let raw_request = "OPTIONS /1/0/0.png HTTP/1.1\r\nOrigin: https://example.com\r\n\r\n";
+        let request = extract_request_from_stream(&mut raw_request.as_bytes()).unwrap();
+        assert_eq!(request.method, "OPTIONS");
+
+        let cors = CorsConfig {
+            max_age: Some(600),
+            ..Default::default()
+        };
+        let response = response_to_string(|out| serve_preflight(out, &cors, request.origin.as_deref()));
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Max-Age: 600\r\n"));
+    }
+
+    #[test]
+    fn test_warmed_up_tiles_are_served_from_the_cache() {
+        let osm_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/osm/nano_moscow.osm");
+        let bin_file = std::env::temp_dir().join("osm_renderer_warm_cache_nano_moscow.bin");
+        crate::geodata::importer::import(Path::new(osm_file), &bin_file).unwrap();
+        let rules = parse_file(
+            Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/mapcss")),
+            "mapnik.mapcss",
+        )
+        .unwrap();
+
+        let server = HttpServer {
+            styler: Styler::new(rules, &StyleType::Josm, None),
+            reader: GeodataReader::load(bin_file.to_str().unwrap()).unwrap(),
+            drawer: Drawer::new(Path::new(".")),
+            osm_ids: None,
+            cors: CorsConfig::default(),
+            perf_stats: Mutex::new(PerfStats::default()),
+            worker_count: 2,
+            tile_cache: Mutex::new(TileCache::new(100)),
+            rendered_tiles: AtomicUsize::new(0),
+        };
+
+        let bbox = BoundingBox {
+            min_lat: 55.7495,
+            max_lat: 55.7505,
+            min_lon: 37.6085,
+            max_lon: 37.6095,
+        };
+        let zooms = [17, 18_u8];
+        let tiles = zooms
+            .iter()
+            .flat_map(|&zoom| tiles_for_bbox(&bbox, u32::from(zoom), TileScheme::Xyz))
+            .map(|(zoom, x, y)| Tile { zoom: zoom as u8, x, y })
+            .collect::<Vec<_>>();
+        assert!(tiles.len() > 2);
+
+        let mut progress = Vec::new();
+        server
+            .warm_cache(&bbox, &zooms, |done, total| progress.push((done, total)))
+            .unwrap();
+        assert_eq!(progress.len(), tiles.len());
+        assert_eq!(progress.last(), Some(&(tiles.len(), tiles.len())));
+        assert_eq!(server.rendered_tiles.load(Ordering::Relaxed), tiles.len());
+        if cfg!(feature = "perf-stats") {
+            let z18_tiles = tiles.iter().filter(|tile| tile.zoom == 18).count();
+            let perf_stats_html = server.perf_stats.lock().unwrap().to_html();
+            assert!(perf_stats_html.contains(&format!("<h1>Zoom 18 ({} tiles)</h1>", z18_tiles)));
+        }
+
+        let mut state = HandlerState {
+            current_scale: 1,
+            current_pixels: Box::new(TilePixels::new(1)),
+        };
+        let mut get_tile_png = |tile: &Tile| {
+            let request_tile = RequestTile {
+                tile: tile.clone(),
+                scale: 1,
+            };
+            server.get_tile_png(&request_tile, &mut state).unwrap()
+        };
+
+        for tile in &tiles {
+            assert_eq!(get_tile_png(tile).0, OK);
+        }
+        assert_eq!(server.rendered_tiles.load(Ordering::Relaxed), tiles.len());
+
+        let outside_tile = Tile {
+            zoom: 16,
+            x: 39_614,
+            y: 20_488,
+        };
+        let png = get_tile_png(&outside_tile).1;
+        assert_eq!(get_tile_png(&outside_tile).1, png);
+        assert_eq!(server.rendered_tiles.load(Ordering::Relaxed), tiles.len() + 1);
+    }
+
+    #[test]
+    fn test_tile_cache_evicts_least_recently_used() {
+        let key = |x| (Tile { zoom: 1, x, y: 0 }, 1);
+        let mut cache = TileCache::new(2);
+        cache.insert(key(0), vec![0]);
+        cache.insert(key(1), vec![1]);
+        assert_eq!(cache.get(&key(0)), Some(vec![0]));
+        cache.insert(key(2), vec![2]);
+        assert_eq!(cache.get(&key(1)), None);
+        assert_eq!(cache.get(&key(0)), Some(vec![0]));
+        assert_eq!(cache.get(&key(2)), Some(vec![2]));
+
+        let mut disabled_cache = TileCache::new(0);
+        disabled_cache.insert(key(0), vec![0]);
+        assert_eq!(disabled_cache.get(&key(0)), None);
+    }
+
+    #[test]
+    fn test_warm_region_is_validated() {
+        let warm_config = |capacity, zooms: &[u8]| CacheConfig {
+            capacity,
+            warm_region: Some(WarmRegion {
+                bbox: BoundingBox {
+                    min_lat: 55.7,
+                    max_lat: 55.8,
+                    min_lon: 37.5,
+                    max_lon: 37.7,
+                },
+                zooms: zooms.to_vec(),
+            }),
+        };
+        assert!(warm_config(100, &[12, MAX_ZOOM]).validate().is_ok());
+        assert!(warm_config(0, &[12]).validate().is_err());
+        assert!(warm_config(100, &[12, MAX_ZOOM + 1]).validate().is_err());
+        assert!(warm_config(100, &[40]).validate().is_err());
+        assert!(CacheConfig::default().validate().is_ok());
+    }
+}
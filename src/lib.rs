@@ -1,3 +1,4 @@
+pub mod batch;
 pub mod coords;
 pub mod draw;
 pub mod geodata;
@@ -5,3 +6,4 @@ pub mod http_server;
 pub mod mapcss;
 pub mod perf_stats;
 pub mod tile;
+pub mod tile_writer;
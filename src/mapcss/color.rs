@@ -5,6 +5,42 @@ pub struct Color {
     pub b: u8,
 }
 
+/// Parses tag values like `#ff8000`, `#f80`, `rgb(255, 128, 0)` or `orange`.
+pub fn parse_color(value: &str) -> Option<Color> {
+    let value = value.trim();
+    if let Some(components) = value.strip_prefix("rgb(").and_then(|x| x.strip_suffix(')')) {
+        let components = components
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<Result<Vec<u8>, _>>()
+            .ok()?;
+        return match components[..] {
+            [r, g, b] => Some(Color { r, g, b }),
+            _ => None,
+        };
+    }
+    let Some(hex) = value.strip_prefix('#') else {
+        return from_color_name(&value.to_ascii_lowercase());
+    };
+    let digits = hex
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<_>>>()?;
+    match digits.len() {
+        6 => Some(Color {
+            r: digits[0] * 16 + digits[1],
+            g: digits[2] * 16 + digits[3],
+            b: digits[4] * 16 + digits[5],
+        }),
+        3 => Some(Color {
+            r: digits[0] * 17,
+            g: digits[1] * 17,
+            b: digits[2] * 17,
+        }),
+        _ => None,
+    }
+}
+
 pub fn from_color_name(name: &str) -> Option<Color> {
     match name {
         "white" => Some(Color { r: 255, g: 255, b: 255 }),
@@ -12,7 +48,7 @@ pub fn from_color_name(name: &str) -> Option<Color> {
         "blue" => Some(Color { r: 0, g: 0, b: 255 }),
         "brown" => Some(Color { r: 165, g: 42, b: 42 }),
         "green" => Some(Color { r: 0, g: 255, b: 0 }),
-        "grey" => Some(Color { r: 128, g: 128, b: 128 }),
+        "grey" | "gray" => Some(Color { r: 128, g: 128, b: 128 }),
         "pink" => Some(Color { r: 255, g: 192, b: 203 }),
         "purple" => Some(Color { r: 128, g: 0, b: 128 }),
         "red" => Some(Color { r: 255, g: 0, b: 0 }),
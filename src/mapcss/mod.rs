@@ -1,5 +1,6 @@
 pub mod color;
 pub mod parser;
+pub mod quick_style;
 mod style_cache;
 pub mod styler;
 pub mod token;
@@ -17,6 +17,8 @@ pub enum ObjectType {
     Node,
     Way,
     Area,
+    /// Only route relations are styled this way, multipolygons are matched by `area`.
+    Relation,
 }
 
 impl fmt::Display for ObjectType {
@@ -28,6 +30,7 @@ impl fmt::Display for ObjectType {
             ObjectType::Node => "node",
             ObjectType::Way => "way",
             ObjectType::Area => "area",
+            ObjectType::Relation => "relation",
         };
         write!(f, "{}", object_type)
     }
@@ -127,7 +130,20 @@ pub enum PropertyValue {
     String(String),
     Color(Color),
     Numbers(Vec<f64>),
+    Stops(Vec<(f64, f64)>),
     WidthDelta(f64),
+    /// `tag("key")`: the value of the tag of the styled entity.
+    Tag(String),
+    /// `eval(cond(mod(tag("ele"), 100) == 0, 4, 1))`: `if_true` for the entities with a numeric value of the
+    /// tag that leaves `remainder` when divided by `divisor`, `if_false` for all others. Index contours are
+    /// styled this way.
+    ModuloCond {
+        tag_name: String,
+        divisor: f64,
+        remainder: f64,
+        if_true: f64,
+        if_false: f64,
+    },
 }
 
 impl fmt::Display for PropertyValue {
@@ -139,7 +155,28 @@ impl fmt::Display for PropertyValue {
             PropertyValue::Numbers(ref nums) => {
                 write!(f, "{}", nums.iter().map(fmt_item::<f64>).collect::<Vec<_>>().join(","))
             }
+            PropertyValue::Stops(ref stops) => write!(
+                f,
+                "{}",
+                stops
+                    .iter()
+                    .map(|(zoom, value)| format!("{}:{}", zoom, value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
             PropertyValue::WidthDelta(ref delta) => write!(f, "eval(prop(\"width\")) + {}", delta),
+            PropertyValue::Tag(ref key) => write!(f, "tag(\"{}\")", key),
+            PropertyValue::ModuloCond {
+                ref tag_name,
+                divisor,
+                remainder,
+                if_true,
+                if_false,
+            } => write!(
+                f,
+                "eval(cond(mod(tag(\"{}\"), {}) == {}, {}, {}))",
+                tag_name, divisor, remainder, if_true, if_false
+            ),
         }
     }
 }
@@ -418,6 +455,18 @@ impl<'a> Parser<'a> {
 
                 self.expect_simple_token(&Token::RightBracket)?;
 
+                // `[key=*]` is the same as `[key]`, and `[key!=*]` as `[!key]`.
+                if rhs == "*" {
+                    let test_type = match binary_op {
+                        BinaryStringTestType::Equal => UnaryTestType::Exists,
+                        BinaryStringTestType::NotEqual => UnaryTestType::NotExists,
+                    };
+                    return Ok(Test::Unary {
+                        tag_name: lhs,
+                        test_type,
+                    });
+                }
+
                 return Ok(Test::BinaryStringCompare {
                     tag_name: lhs,
                     value: rhs,
@@ -504,6 +553,11 @@ impl<'a> Parser<'a> {
                 expect_semicolon = false;
                 match id {
                     "eval" => self.read_simple_eval(token.position)?,
+                    "tag" => {
+                        let value = self.read_tag_function(token.position)?;
+                        self.expect_simple_token(&Token::SemiColon)?;
+                        value
+                    }
                     _ => {
                         let mut full_id = id.to_string();
                         let token = self.read_mandatory_token()?;
@@ -533,7 +587,7 @@ impl<'a> Parser<'a> {
             },
             Token::Number(num) => {
                 expect_semicolon = false;
-                PropertyValue::Numbers(self.read_number_list(num)?)
+                self.read_numbers(num)?
             }
             _ => return self.unexpected_token(&token)?,
         };
@@ -543,7 +597,21 @@ impl<'a> Parser<'a> {
         Ok(result)
     }
 
+    // Reads the `("key")` part of `tag("key")`.
+    fn read_tag_function(&mut self, position: InputPosition) -> Result<PropertyValue> {
+        self.expect_simple_token(&Token::LeftParen)?;
+        let token = self.read_mandatory_token()?;
+        let key = match token.token {
+            Token::String(key) | Token::Identifier(key) => key.to_string(),
+            _ => return Err(self.parse_error("Expected a tag name in tag(...)", position)),
+        };
+        self.expect_simple_token(&Token::RightParen)?;
+        Ok(PropertyValue::Tag(key))
+    }
+
     // Support the only form of eval() used in Maps.ME: eval(prop("width") + X);
+    // eval(tag("key")), which is just another way to write tag("key");
+    // and eval(cond(mod(tag("key"), X) == Y, A, B)), the way JOSM styles pick index contours.
     fn read_simple_eval(&mut self, position: InputPosition) -> Result<PropertyValue> {
         let mut tokens = Vec::new();
         loop {
@@ -553,6 +621,25 @@ impl<'a> Parser<'a> {
                 token => tokens.push(token),
             }
         }
+        if let [Token::LeftParen, Token::Identifier("tag"), Token::LeftParen, Token::String(key), Token::RightParen, Token::RightParen] =
+            tokens[..]
+        {
+            return Ok(PropertyValue::Tag(key.to_string()));
+        }
+        if let [Token::LeftParen, Token::Identifier("cond"), Token::LeftParen, Token::Identifier("mod"), Token::LeftParen, Token::Identifier("tag"), Token::LeftParen, Token::String(key), Token::RightParen, Token::Comma, Token::Number(divisor), Token::RightParen, Token::Equal, Token::Equal, Token::Number(remainder), Token::Comma, Token::Number(if_true), Token::Comma, Token::Number(if_false), Token::RightParen, Token::RightParen] =
+            tokens[..]
+        {
+            if divisor == 0.0 {
+                return Err(self.parse_error("Division by zero in eval(...)", position));
+            }
+            return Ok(PropertyValue::ModuloCond {
+                tag_name: key.to_string(),
+                divisor,
+                remainder,
+                if_true,
+                if_false,
+            });
+        }
         let expected_prefix = [
             Token::LeftParen,
             Token::Identifier("prop"),
@@ -586,24 +673,44 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn read_number_list(&mut self, first_num: f64) -> Result<Vec<f64>> {
-        let mut numbers = vec![first_num];
+    // Reads either a plain list of numbers (`4, 2`) or a list of zoom stops (`10:0, 12:1`).
+    fn read_numbers(&mut self, first_num: f64) -> Result<PropertyValue> {
+        let mut items = vec![(first_num, None)];
         let mut consumed_number = true;
         loop {
             let next_token = self.read_mandatory_token()?;
             match next_token.token {
+                Token::Colon if consumed_number && items.last().unwrap().1.is_none() => {
+                    let value_token = self.read_mandatory_token()?;
+                    match value_token.token {
+                        Token::Number(value) => items.last_mut().unwrap().1 = Some(value),
+                        _ => return self.unexpected_token(&value_token),
+                    }
+                }
                 Token::Comma if consumed_number => {
                     consumed_number = false;
                 }
                 Token::SemiColon if consumed_number => break,
                 Token::Number(next_num) if !consumed_number => {
                     consumed_number = true;
-                    numbers.push(next_num);
+                    items.push((next_num, None));
                 }
                 _ => return self.unexpected_token(&next_token),
             }
         }
-        Ok(numbers)
+
+        if items.iter().all(|(_, value)| value.is_none()) {
+            return Ok(PropertyValue::Numbers(items.into_iter().map(|(num, _)| num).collect()));
+        }
+
+        match items
+            .into_iter()
+            .map(|(zoom, value)| value.map(|v| (zoom, v)))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(stops) => Ok(PropertyValue::Stops(stops)),
+            None => Err(self.parse_error("Can't mix zoom stops with plain numbers", self.tokenizer.position())),
+        }
     }
 
     fn read_identifier(&mut self) -> Result<String> {
@@ -622,10 +729,9 @@ impl<'a> Parser<'a> {
     }
 
     fn read_optional_token(&mut self) -> Option<Result<TokenWithPosition<'a>>> {
-        self.tokenizer.next().map(|x| {
-            x.context(format!("Failed to tokenize {}", self.file_name))
-                .map_err(Error::from)
-        })
+        self.tokenizer
+            .next()
+            .map(|x| x.context(format!("Failed to tokenize {}", self.file_name)))
     }
 
     fn expect_simple_token(&mut self, expected: &Token<'static>) -> Result<()> {
@@ -660,7 +766,11 @@ fn read_stylesheet(base_path: &Path, file_name: &str) -> Result<String> {
     stylesheet_reader
         .read_to_string(&mut stylesheet)
         .context("Failed to read the stylesheet file")?;
-    Ok(stylesheet)
+    // Some editors start UTF-8 files with a byte order mark, which isn't whitespace for the tokenizer.
+    match stylesheet.strip_prefix('\u{feff}') {
+        Some(without_bom) => Ok(without_bom.to_string()),
+        None => Ok(stylesheet),
+    }
 }
 
 fn id_to_object_type(id: &str) -> Option<ObjectType> {
@@ -671,6 +781,7 @@ fn id_to_object_type(id: &str) -> Option<ObjectType> {
         "node" => Some(ObjectType::Node),
         "way" | "line" => Some(ObjectType::Way),
         "area" => Some(ObjectType::Area),
+        "relation" => Some(ObjectType::Relation),
         _ => None,
     }
 }
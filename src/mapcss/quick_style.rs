@@ -0,0 +1,97 @@
+use crate::mapcss::color::Color;
+use crate::mapcss::parser::*;
+
+/// Conditions that mirror the simplest MapCSS tag tests, e.g. `[highway]` or `[building=yes]`.
+pub enum TagMatch {
+    Any,
+    Exists(String),
+    Equals(String, String),
+    AllOf(Vec<TagMatch>),
+}
+
+/// A programmatic alternative to writing a stylesheet. `fill` is applied to areas, `stroke` and
+/// `width` to ways and area outlines.
+pub struct StyleRule {
+    pub tag_match: TagMatch,
+    pub fill: Option<Color>,
+    pub stroke: Option<Color>,
+    pub width: f64,
+}
+
+/// Converts the rules to their MapCSS equivalents, which can then be passed to `Styler::new`
+/// with `StyleType::Josm`. Canvas color is set with `canvas_fill_color`.
+pub fn to_mapcss_rules(style_rules: &[StyleRule], canvas_fill_color: Option<Color>) -> Vec<Rule> {
+    let mut rules = Vec::new();
+
+    if let Some(color) = canvas_fill_color {
+        rules.push(Rule {
+            selectors: vec![selector(ObjectType::Canvas, &TagMatch::Any)],
+            properties: vec![color_property("fill-color", color)],
+        });
+    }
+
+    for style_rule in style_rules {
+        if let Some(fill) = &style_rule.fill {
+            rules.push(Rule {
+                selectors: vec![selector(ObjectType::Area, &style_rule.tag_match)],
+                properties: vec![color_property("fill-color", fill.clone())],
+            });
+        }
+        if let Some(stroke) = &style_rule.stroke {
+            rules.push(Rule {
+                selectors: vec![
+                    selector(ObjectType::Way, &style_rule.tag_match),
+                    selector(ObjectType::Area, &style_rule.tag_match),
+                ],
+                properties: vec![
+                    color_property("color", stroke.clone()),
+                    Property {
+                        name: "width".to_string(),
+                        value: PropertyValue::Numbers(vec![style_rule.width]),
+                    },
+                ],
+            });
+        }
+    }
+
+    rules
+}
+
+fn selector(object_type: ObjectType, tag_match: &TagMatch) -> Selector {
+    let mut tests = Vec::new();
+    add_tests(tag_match, &mut tests);
+    Selector {
+        object_type,
+        min_zoom: None,
+        max_zoom: None,
+        tests,
+        layer_id: None,
+    }
+}
+
+fn add_tests(tag_match: &TagMatch, tests: &mut Vec<Test>) {
+    match tag_match {
+        TagMatch::Any => {}
+        TagMatch::Exists(tag_name) => tests.push(Test::Unary {
+            tag_name: tag_name.clone(),
+            test_type: UnaryTestType::Exists,
+        }),
+        TagMatch::Equals(tag_name, value) => tests.push(Test::BinaryStringCompare {
+            tag_name: tag_name.clone(),
+            value: value.clone(),
+            test_type: BinaryStringTestType::Equal,
+        }),
+        TagMatch::AllOf(matches) => {
+            for m in matches {
+                add_tests(m, tests);
+            }
+        }
+    }
+}
+
+fn color_property(name: &str, color: Color) -> Property {
+    Property {
+        name: name.to_string(),
+        value: PropertyValue::Color(color),
+    }
+}
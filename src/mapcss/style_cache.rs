@@ -1,4 +1,5 @@
 use crate::geodata::reader::OsmEntity;
+use crate::mapcss::parser::PropertyValue;
 use crate::mapcss::parser::Rule;
 use crate::mapcss::parser::Test;
 use crate::mapcss::parser::UnaryTestType;
@@ -43,6 +44,12 @@ impl StyleCache {
                     *tag_value_matters.entry(tag_name.clone()).or_default() |= value_matters;
                 }
             }
+            // Styles with `tag("key")` differ for every value of the key.
+            for prop in r.properties.iter() {
+                if let PropertyValue::Tag(ref tag_name) | PropertyValue::ModuloCond { ref tag_name, .. } = prop.value {
+                    tag_value_matters.insert(tag_name.clone(), true);
+                }
+            }
         }
 
         StyleCache {
@@ -1,8 +1,8 @@
-use crate::mapcss::color::{from_color_name, Color};
+use crate::mapcss::color::{from_color_name, parse_color, Color};
 use crate::mapcss::parser::*;
 use crate::mapcss::style_cache::StyleCache;
 
-use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Way};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Route, Tags, Way};
 use indexmap::IndexMap;
 use std::cmp::Ordering;
 use std::sync::Arc;
@@ -15,12 +15,77 @@ pub enum LineCap {
     Square,
 }
 
+/// How consecutive segments of a line are connected. `Miter` carries the miter limit: sharper
+/// corners whose miter would be longer than `limit` times the line width fall back to `Bevel`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineJoin {
+    Round,
+    Bevel,
+    Miter(f64),
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum TextPosition {
     Center,
     Line,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TickSide {
+    Left,
+    Right,
+}
+
+/// Short perpendicular ticks repeated along a line, e.g. for cliffs or embankments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineDecoration {
+    pub spacing: f64,
+    pub length: f64,
+    pub side: TickSide,
+}
+
+/// Small arrows repeated along a way that show the direction of flow, i.e. the direction of the way. Which
+/// ways get them is up to the style, e.g. `way[waterway] { flow-arrows: true; }`. They are drawn with the
+/// line color unless `color` is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowArrows {
+    pub spacing: f64,
+    pub size: f64,
+    pub color: Option<Color>,
+}
+
+/// A shape drawn at the position of a node, under the labels, e.g. the dot of a town or a station. Only
+/// `symbol-shape: circle` is supported. Without `symbol-fill-color` and `symbol-stroke-color`, the
+/// symbol is filled with red, as in JOSM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeSymbol {
+    /// The diameter in unscaled pixels (`symbol-size`, 10 by default).
+    pub size: f64,
+    pub fill_color: Option<Color>,
+    pub stroke_color: Option<Color>,
+    /// Centered on the edge of the symbol (`symbol-stroke-width`, 1 by default).
+    pub stroke_width: f64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum HatchPattern {
+    Horizontal,
+    Vertical,
+    Diagonal,
+    BackDiagonal,
+    Cross,
+    Dots,
+}
+
+/// A procedural fill made of thin lines or dots, drawn over `fill-color` if there is one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FillHatch {
+    pub pattern: HatchPattern,
+    pub color: Color,
+    pub spacing: f64,
+    pub width: f64,
+}
+
 pub fn is_non_trivial_cap(line_cap: &Option<LineCap>) -> bool {
     matches!(*line_cap, Some(LineCap::Square) | Some(LineCap::Round))
 }
@@ -44,11 +109,16 @@ pub struct TextStyle {
     pub text_color: Option<Color>,
     pub text_position: Option<TextPosition>,
     pub font_size: Option<f64>,
+    pub allow_overlap: bool,
+    /// Blurs the edges of the glyphs a bit, which helps small text.
+    pub smoothing: bool,
 }
 
 pub struct Style {
     pub layer: Option<i64>,
     pub z_index: f64,
+    /// The name of the `RenderLayer` the feature is drawn into, if it's not the default one.
+    pub render_layer: Option<String>,
 
     pub color: Option<Color>,
     pub fill_color: Option<Color>,
@@ -60,24 +130,83 @@ pub struct Style {
     pub width: Option<f64>,
     pub dashes: Option<Vec<f64>>,
     pub line_cap: Option<LineCap>,
+    pub line_join: Option<LineJoin>,
 
     pub casing_color: Option<Color>,
     pub casing_width: Option<f64>,
     pub casing_dashes: Option<Vec<f64>>,
     pub casing_line_cap: Option<LineCap>,
 
+    pub line_decoration: Option<LineDecoration>,
+    pub flow_arrows: Option<FlowArrows>,
+
     pub icon_image: Option<String>,
+    pub icon_allow_overlap: bool,
     pub fill_image: Option<String>,
+    pub fill_hatch: Option<FillHatch>,
+    pub symbol: Option<NodeSymbol>,
     pub text_style: Option<TextStyle>,
 }
 
+/// Features are drawn in the ascending order of their keys, which are compared component by component.
+/// Features with equal keys are drawn in the order of their IDs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OrderKey(pub [f64; 3]);
+
+/// Maps a styled feature to its `OrderKey`. The last argument tells whether the order is computed
+/// for labels rather than for the features themselves.
+pub type OrderKeyFn = dyn Fn(&Tags<'_>, &Style, bool) -> OrderKey + Send + Sync;
+
+/// Orders features by `layer`, then puts background fills below everything else on the same layer
+/// and finally orders by z-index. That's the order features were always drawn in before order keys
+/// could be customized, and the one JOSM uses: `layer` comes first so that e.g. a bridge is drawn above
+/// everything on the ground, whatever its z-index. See `z_index_first_order_key` for styles that want
+/// z-indices to win over `layer`.
+pub fn default_order_key(_: &Tags<'_>, style: &Style, for_labels: bool) -> OrderKey {
+    let is_foreground = !for_labels && style.is_foreground_fill;
+    OrderKey([
+        style.layer.unwrap_or(0) as f64,
+        f64::from(u8::from(is_foreground)),
+        style.z_index,
+    ])
+}
+
+/// Orders features by z-index, then by `layer` and finally puts background fills below everything else
+/// with the same z-index and `layer`. Unlike with `default_order_key`, a feature class with a low z-index
+/// (e.g. water) stays below the ones with a higher z-index (e.g. roads) even when it's on a higher layer.
+pub fn z_index_first_order_key(tags: &Tags<'_>, style: &Style, for_labels: bool) -> OrderKey {
+    let OrderKey([layer, class, z_index]) = default_order_key(tags, style, for_labels);
+    OrderKey([z_index, layer, class])
+}
+
+/// A named bucket of features, declared with a `canvas::name { z-index: 1; }` rule and filled with
+/// `render-layer: name;`. Buckets are drawn one after another in the order of their z-indices, with the
+/// features that have no (or an unknown) `render-layer` in a default bucket at z-index 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderLayer {
+    pub name: String,
+    pub z_index: f64,
+    /// Drawn over the whole tile right before the features of the layer, e.g. to fade the layers below.
+    pub scrim: Option<Scrim>,
+}
+
+/// A flat color overlay, set with `scrim-color` and `scrim-opacity` on the layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scrim {
+    pub color: Color,
+    pub opacity: f64,
+}
+
 pub struct Styler {
     pub canvas_fill_color: Option<Color>,
     pub use_caps_for_dashes: bool,
+    /// Sorted by z-index.
+    pub render_layers: Vec<RenderLayer>,
 
     casing_width_multiplier: f64,
     font_size_multiplier: Option<f64>,
     rules: Vec<Rule>,
+    order_key: Arc<OrderKeyFn>,
 
     style_cache: RwLock<StyleCache>,
 }
@@ -88,12 +217,32 @@ where
 {
     Way(&'wr Way<'a>),
     Multipolygon(&'wr Multipolygon<'a>),
+    Route(&'wr Route<'a>),
+}
+
+impl<'a> OsmEntity<'a> for StyledArea<'a, '_> {
+    fn global_id(&self) -> u64 {
+        match self {
+            StyledArea::Way(way) => way.global_id(),
+            StyledArea::Multipolygon(mp) => mp.global_id(),
+            StyledArea::Route(route) => route.global_id(),
+        }
+    }
+
+    fn tags(&self) -> Tags<'a> {
+        match self {
+            StyledArea::Way(way) => way.tags(),
+            StyledArea::Multipolygon(mp) => mp.tags(),
+            StyledArea::Route(route) => route.tags(),
+        }
+    }
 }
 
 impl Styler {
     pub fn new(rules: Vec<Rule>, style_type: &StyleType, font_size_multiplier: Option<f64>) -> Styler {
         let use_caps_for_dashes = matches!(*style_type, StyleType::Josm);
         let canvas_fill_color = extract_canvas_fill_color(&rules, style_type);
+        let render_layers = extract_render_layers(&rules);
 
         let casing_width_multiplier = match *style_type {
             StyleType::MapsMe => 1.0,
@@ -105,13 +254,61 @@ impl Styler {
         Styler {
             use_caps_for_dashes,
             canvas_fill_color,
+            render_layers,
             casing_width_multiplier,
             font_size_multiplier,
             rules,
+            order_key: Arc::new(default_order_key),
             style_cache: RwLock::new(style_cache),
         }
     }
 
+    /// The rules the way they are applied, for debugging stylesheets: with the `@name` colors resolved, the
+    /// `@import`ed files inlined and the position of every selector in the cascade (where the later ones
+    /// override the earlier ones).
+    pub fn debug_dump(&self) -> String {
+        let is_universal = |sel: &Selector| matches!(sel.object_type, ObjectType::All);
+        let selectors = self.rules.iter().flat_map(|rule| rule.selectors.iter());
+        let mut next_universal_position = 0;
+        let mut next_position = selectors.filter(|sel| is_universal(sel)).count();
+
+        let mut dumped_rules = Vec::new();
+        for rule in &self.rules {
+            let positions = rule
+                .selectors
+                .iter()
+                .map(|sel| {
+                    let position = if is_universal(sel) {
+                        &mut next_universal_position
+                    } else {
+                        &mut next_position
+                    };
+                    *position += 1;
+                    (*position - 1).to_string()
+                })
+                .collect::<Vec<_>>();
+            let mut header = format!("/* cascade position: {}", positions.join(", "));
+            if let Some(z_index) = rule.properties.iter().rev().find(|prop| prop.name == "z-index") {
+                header.push_str(&format!("; z-index: {}", z_index.value));
+            }
+            dumped_rules.push(format!("{} */\n{}", header, rule));
+        }
+        dumped_rules.join("\n\n")
+    }
+
+    /// Replaces `default_order_key` with a custom draw order policy.
+    pub fn set_order_key<F>(&mut self, order_key: F)
+    where
+        F: Fn(&Tags<'_>, &Style, bool) -> OrderKey + Send + Sync + 'static,
+    {
+        self.order_key = Arc::new(order_key);
+    }
+
+    /// The draw order policy, `default_order_key` unless it was replaced with `set_order_key`.
+    pub fn order_key_fn(&self) -> Arc<OrderKeyFn> {
+        Arc::clone(&self.order_key)
+    }
+
     pub fn style_entities<'e, 'wp, I, A>(&self, areas: I, zoom: u8, for_labels: bool) -> Vec<(&'wp A, Arc<Style>)>
     where
         A: CacheableEntity + StyleableEntity + OsmEntity<'e>,
@@ -148,6 +345,7 @@ impl Styler {
                     styles.push(Arc::new(property_map_to_style(
                         prop_map,
                         base_layer,
+                        zoom,
                         default_z_index,
                         self.casing_width_multiplier,
                         &self.font_size_multiplier,
@@ -160,7 +358,8 @@ impl Styler {
             self.style_cache.write().unwrap().insert(area, zoom, styles)
         }
 
-        styled_areas.sort_by(|a, b| compare_styled_entities(a, b, for_labels));
+        styled_areas
+            .sort_by(|(a, a_style), (b, b_style)| self.compare_styled_entities(*a, a_style, *b, b_style, for_labels));
 
         styled_areas
     }
@@ -169,34 +368,60 @@ impl Styler {
         &self,
         ways: impl Iterator<Item = &'wr Way<'a>>,
         multipolygons: impl Iterator<Item = &'wr Multipolygon<'a>>,
+        routes: impl Iterator<Item = &'wr Route<'a>>,
         zoom: u8,
         for_labels: bool,
     ) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
-        let styled_ways = self.style_entities(ways, zoom, for_labels);
-        let styled_multipolygons = self.style_entities(multipolygons, zoom, for_labels);
+        fn to_styled_areas<'a, 'wr, E>(
+            styled: Vec<(&'wr E, Arc<Style>)>,
+            wrap: impl Fn(&'wr E) -> StyledArea<'a, 'wr>,
+        ) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+            styled
+                .into_iter()
+                .map(|(entity, style)| (wrap(entity), style))
+                .collect()
+        }
 
-        let mut mp_iter = styled_multipolygons.into_iter();
-        let mut way_iter = styled_ways.into_iter();
-        let mut poly = mp_iter.next();
-        let mut way = way_iter.next();
+        let styled_ways = to_styled_areas(self.style_entities(ways, zoom, for_labels), StyledArea::Way);
+        let styled_multipolygons = to_styled_areas(
+            self.style_entities(multipolygons, zoom, for_labels),
+            StyledArea::Multipolygon,
+        );
+        let styled_routes = to_styled_areas(self.style_entities(routes, zoom, for_labels), StyledArea::Route);
+
+        let styled_areas = self.merge_styled_areas(styled_multipolygons, styled_ways, for_labels);
+        self.merge_styled_areas(styled_areas, styled_routes, for_labels)
+    }
+
+    // Both inputs are already sorted. On ties, areas from `first` go first.
+    fn merge_styled_areas<'a, 'wr>(
+        &self,
+        first: Vec<(StyledArea<'a, 'wr>, Arc<Style>)>,
+        second: Vec<(StyledArea<'a, 'wr>, Arc<Style>)>,
+        for_labels: bool,
+    ) -> Vec<(StyledArea<'a, 'wr>, Arc<Style>)> {
+        let mut first_iter = first.into_iter();
+        let mut second_iter = second.into_iter();
+        let mut first_next = first_iter.next();
+        let mut second_next = second_iter.next();
         let mut result = Vec::new();
         loop {
-            let is_rel_better = {
-                match (&poly, &way) {
+            let is_first_better = {
+                match (&first_next, &second_next) {
                     (None, None) => break,
                     (Some(_), None) => true,
                     (None, Some(_)) => false,
-                    (Some(mp), Some(way)) => compare_styled_entities(mp, way, for_labels) != Ordering::Greater,
+                    (Some((a, a_style)), Some((b, b_style))) => {
+                        self.compare_styled_entities(a, a_style, b, b_style, for_labels) != Ordering::Greater
+                    }
                 }
             };
-            if is_rel_better {
-                let (mp, style) = poly.unwrap();
-                result.push((StyledArea::Multipolygon(mp), style));
-                poly = mp_iter.next();
+            if is_first_better {
+                result.push(first_next.unwrap());
+                first_next = first_iter.next();
             } else {
-                let (w, style) = way.unwrap();
-                result.push((StyledArea::Way(w), style));
-                way = way_iter.next();
+                result.push(second_next.unwrap());
+                second_next = second_iter.next();
             }
         }
         result
@@ -208,75 +433,90 @@ impl Styler {
     {
         let mut result: LayerToPropertyMap<'r> = IndexMap::new();
 
-        for rule in &self.rules {
-            for sel in rule.selectors.iter().filter(|x| area_matches(area, x, zoom)) {
-                let layer_id = get_layer_id(sel);
-
-                let update_layer = |layer: &mut PropertyMap<'r>| {
-                    for prop in &rule.properties {
-                        layer.insert(prop.name.clone(), &prop.value);
-                    }
-                };
-
-                {
-                    // Can't use result.entry(...).or_insert_with(...) because we need to immutably
-                    // borrow the result to compute the default value in or_insert_with(), and the
-                    // map is already borrowed as mutable when we call entry().
-                    if !result.contains_key(layer_id) {
-                        let parent_layer = result.get("*").cloned().unwrap_or_default();
-                        result.insert(layer_id, parent_layer);
+        // `*` selectors are the least specific ones, so they go first and anything they set can be overridden
+        // by the other rules, even by the ones that come earlier in the stylesheet.
+        let is_universal = |sel: &Selector| matches!(sel.object_type, ObjectType::All);
+        let matching_selectors = [true, false].into_iter().flat_map(|universal| {
+            self.rules.iter().flat_map(move |rule| {
+                rule.selectors
+                    .iter()
+                    .filter(move |sel| is_universal(sel) == universal && area_matches(area, sel, zoom))
+                    .map(move |sel| (rule, sel))
+            })
+        });
+
+        for (rule, sel) in matching_selectors {
+            let layer_id = get_layer_id(sel);
+
+            let update_layer = |layer: &mut PropertyMap<'r>| {
+                for prop in &rule.properties {
+                    // A color taken from a tag leaves the value it would override to the entities that don't
+                    // have the tag or have a value that isn't a color.
+                    if let PropertyValue::Tag(key) = &prop.value {
+                        let is_color = area.tags().get_by_key(key).and_then(parse_color).is_some();
+                        if !is_color && layer.contains_key(&prop.name) {
+                            continue;
+                        }
                     }
+                    layer.insert(prop.name.clone(), &prop.value);
+                }
+            };
 
-                    update_layer(result.get_mut(layer_id).unwrap());
+            {
+                // Can't use result.entry(...).or_insert_with(...) because we need to immutably
+                // borrow the result to compute the default value in or_insert_with(), and the
+                // map is already borrowed as mutable when we call entry().
+                if !result.contains_key(layer_id) {
+                    let parent_layer = result.get("*").cloned().unwrap_or_default();
+                    result.insert(layer_id, parent_layer);
                 }
 
-                if layer_id == "*" {
-                    for (_, v) in result.iter_mut().filter(|&(k, _)| k != &"*") {
-                        update_layer(v);
-                    }
+                update_layer(result.get_mut(layer_id).unwrap());
+            }
+
+            if layer_id == "*" {
+                for (_, v) in result.iter_mut().filter(|&(k, _)| k != &"*") {
+                    update_layer(v);
                 }
             }
         }
 
         result
     }
-}
-
-#[expect(clippy::float_cmp)]
-fn compare_styled_entities<'a, E1, E2>(
-    (a, a_style): &(&E1, Arc<Style>),
-    (b, b_style): &(&E2, Arc<Style>),
-    for_labels: bool,
-) -> Ordering
-where
-    E1: OsmEntity<'a>,
-    E2: OsmEntity<'a>,
-{
-    let get_layer = |s: &Style| s.layer.unwrap_or(0);
-
-    let (a_layer, b_layer) = (get_layer(a_style), get_layer(b_style));
-
-    if a_layer != b_layer {
-        return a_layer.cmp(&b_layer);
-    }
-
-    if !for_labels && a_style.is_foreground_fill != b_style.is_foreground_fill {
-        return a_style.is_foreground_fill.cmp(&b_style.is_foreground_fill);
-    }
 
-    if a_style.z_index != b_style.z_index {
-        return a_style.z_index.partial_cmp(&b_style.z_index).unwrap();
+    fn compare_styled_entities<'a, E1, E2>(
+        &self,
+        a: &E1,
+        a_style: &Style,
+        b: &E2,
+        b_style: &Style,
+        for_labels: bool,
+    ) -> Ordering
+    where
+        E1: OsmEntity<'a>,
+        E2: OsmEntity<'a>,
+    {
+        let a_key = (self.order_key)(&a.tags(), a_style, for_labels);
+        let b_key = (self.order_key)(&b.tags(), b_style, for_labels);
+
+        a_key
+            .0
+            .iter()
+            .zip(b_key.0.iter())
+            .map(|(x, y)| x.partial_cmp(y).unwrap_or(Ordering::Equal))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or_else(|| a.global_id().cmp(&b.global_id()))
     }
-
-    a.global_id().cmp(&b.global_id())
 }
 
 type LayerToPropertyMap<'r> = IndexMap<&'r str, PropertyMap<'r>>;
+
 type PropertyMap<'r> = IndexMap<String, &'r PropertyValue>;
 
 fn property_map_to_style<'r, 'e, E>(
     current_layer_map: &'r PropertyMap<'r>,
     base_layer_map: Option<&'r PropertyMap<'r>>,
+    zoom: u8,
     default_z_index: f64,
     casing_width_multiplier: f64,
     font_size_multiplier: &Option<f64>,
@@ -297,7 +537,7 @@ where
         }
     };
 
-    let get_color = |prop_name| match current_layer_map.get(prop_name) {
+    let get_color_value = |prop_name: &'static str, value: Option<&&PropertyValue>| match value {
         Some(&PropertyValue::Color(color)) => Some(color.clone()),
         Some(&PropertyValue::Identifier(id)) => {
             let color = from_color_name(id.as_str());
@@ -311,9 +551,35 @@ where
             None
         }
     };
+    // A color taken from a tag that didn't override anything, see `style_area`.
+    let get_color = |prop_name: &'static str| match current_layer_map.get(prop_name) {
+        Some(&PropertyValue::Tag(key)) => {
+            let value = osm_entity.tags().get_by_key(key);
+            let color = value.and_then(parse_color);
+            // Entities without the tag are fine, but a value that isn't a color is likely a tagging mistake.
+            if value.is_some() && color.is_none() {
+                warn(current_layer_map, prop_name, "the tag value is not a color");
+            }
+            color
+        }
+        value => get_color_value(prop_name, value),
+    };
 
     let get_num = |prop_map: &'r PropertyMap<'r>, prop_name| match prop_map.get(prop_name) {
         Some(&PropertyValue::Numbers(nums)) if nums.len() == 1 => Some(nums[0]),
+        Some(&PropertyValue::Stops(stops)) if !stops.is_empty() => Some(interpolate_stops(stops, zoom)),
+        Some(&PropertyValue::ModuloCond {
+            tag_name,
+            divisor,
+            remainder,
+            if_true,
+            if_false,
+        }) => {
+            let tag_value = osm_entity.tags().get_by_key(tag_name).map(str::parse::<f64>);
+            // Elevations such as `ele=100.0` should still count as a multiple of 100.
+            let is_true = matches!(tag_value, Some(Ok(x)) if (x.rem_euclid(*divisor) - remainder).abs() < 1e-9);
+            Some(if is_true { *if_true } else { *if_false })
+        }
         _ => {
             warn(prop_map, prop_name, "expected a number");
             None
@@ -347,6 +613,20 @@ where
         }
     };
 
+    let get_line_join = |prop_name| match get_id(prop_name) {
+        Some("round") => Some(LineJoin::Round),
+        Some("bevel") => Some(LineJoin::Bevel),
+        Some("miter") => Some(LineJoin::Miter(
+            get_num(current_layer_map, "miter-limit")
+                .filter(|limit| *limit >= 1.0)
+                .unwrap_or(DEFAULT_MITER_LIMIT),
+        )),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown line join value");
+            None
+        }
+    };
+
     let get_text_position = |prop_name| match get_id(prop_name) {
         Some("center") => Some(TextPosition::Center),
         Some("line") => Some(TextPosition::Line),
@@ -364,10 +644,38 @@ where
         }
     };
 
-    let layer = osm_entity
-        .tags()
-        .get_by_key("layer")
-        .and_then(|x| x.parse::<i64>().ok());
+    let get_tick_side = |prop_name| match get_id(prop_name) {
+        Some("left") => Some(TickSide::Left),
+        Some("right") => Some(TickSide::Right),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown tick side");
+            None
+        }
+    };
+
+    let get_hatch_pattern = |prop_name| match get_id(prop_name) {
+        Some("horizontal") => Some(HatchPattern::Horizontal),
+        Some("vertical") => Some(HatchPattern::Vertical),
+        Some("diagonal") => Some(HatchPattern::Diagonal),
+        Some("backdiagonal") => Some(HatchPattern::BackDiagonal),
+        Some("cross") => Some(HatchPattern::Cross),
+        Some("dots") => Some(HatchPattern::Dots),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown hatch pattern");
+            None
+        }
+    };
+
+    let get_flag = |prop_name| match get_id(prop_name) {
+        Some("true") | Some("yes") => true,
+        Some("false") | Some("no") | None => false,
+        _ => {
+            warn(current_layer_map, prop_name, "expected true or false");
+            false
+        }
+    };
+
+    let layer = osm_entity.tags().tag_i32("layer").map(i64::from);
     let z_index = get_num(current_layer_map, "z-index").unwrap_or(default_z_index);
 
     let is_foreground_fill =
@@ -391,7 +699,63 @@ where
         }
     };
     let full_casing_width = casing_only_width.map(|w| base_width_for_casing + casing_width_multiplier * w);
-    let text = get_string("text");
+    // Labels and icons can be limited to a narrower zoom range than the rest of the rule, e.g. so that
+    // a road is drawn long before its name appears.
+    let is_visible_at_zoom = |min_zoom_prop, max_zoom_prop| {
+        let zoom = f64::from(zoom);
+        get_num(current_layer_map, min_zoom_prop).is_none_or(|min_zoom| zoom >= min_zoom)
+            && get_num(current_layer_map, max_zoom_prop).is_none_or(|max_zoom| zoom <= max_zoom)
+    };
+    let text = get_string("text").filter(|_| is_visible_at_zoom("text-min-zoom", "text-max-zoom"));
+    let icon_image = get_string("icon-image").filter(|_| is_visible_at_zoom("icon-min-zoom", "icon-max-zoom"));
+
+    // By OSM convention, the lower side of a cliff is to the right of the way direction.
+    let line_decoration = get_num(current_layer_map, "tick-spacing")
+        .filter(|spacing| *spacing > 0.0)
+        .map(|spacing| LineDecoration {
+            spacing,
+            length: get_num(current_layer_map, "tick-length").unwrap_or(3.0),
+            side: get_tick_side("tick-side").unwrap_or(TickSide::Right),
+        });
+
+    let get_positive_num = |prop_name, default| match get_num(current_layer_map, prop_name) {
+        Some(num) if num <= 0.0 => {
+            warn(current_layer_map, prop_name, "expected a positive number");
+            default
+        }
+        num => num.unwrap_or(default),
+    };
+
+    let flow_arrows = get_flag("flow-arrows").then(|| FlowArrows {
+        spacing: get_num(current_layer_map, "flow-arrow-spacing").unwrap_or(120.0),
+        size: get_num(current_layer_map, "flow-arrow-size").unwrap_or(4.0),
+        color: get_color("flow-arrow-color"),
+    });
+
+    let fill_hatch = get_hatch_pattern("fill-hatch").map(|pattern| FillHatch {
+        pattern,
+        color: get_color("hatch-color").unwrap_or(Color { r: 0, g: 0, b: 0 }),
+        spacing: get_positive_num("hatch-spacing", 8.0),
+        width: get_positive_num("hatch-width", 1.0),
+    });
+
+    let symbol = match get_id("symbol-shape") {
+        Some("circle") => {
+            let (fill_color, stroke_color) = (get_color("symbol-fill-color"), get_color("symbol-stroke-color"));
+            let default_fill = Color { r: 255, g: 0, b: 0 };
+            Some(NodeSymbol {
+                size: get_positive_num("symbol-size", 10.0),
+                fill_color: fill_color.or_else(|| stroke_color.is_none().then_some(default_fill)),
+                stroke_color,
+                stroke_width: get_positive_num("symbol-stroke-width", 1.0),
+            })
+        }
+        Some(_) => {
+            warn(current_layer_map, "symbol-shape", "unsupported symbol shape");
+            None
+        }
+        None => None,
+    };
 
     let font_size = get_num(current_layer_map, "font-size").map(|x| x * font_size_multiplier.unwrap_or(1.0));
 
@@ -400,34 +764,69 @@ where
         text_color: get_color("text-color"),
         text_position: get_text_position("text-position"),
         font_size,
+        allow_overlap: get_flag("text-allow-overlap"),
+        smoothing: get_flag("text-smoothing"),
     });
 
     Style {
         layer,
         z_index,
+        render_layer: get_string("render-layer"),
 
         color: get_color("color"),
         fill_color: get_color("fill-color"),
         is_foreground_fill,
         background_color: get_color("background-color"),
-        opacity: get_num(current_layer_map, "opacity"),
-        fill_opacity: get_num(current_layer_map, "fill-opacity"),
+        opacity: get_num(current_layer_map, "opacity").map(clamp_opacity),
+        fill_opacity: get_num(current_layer_map, "fill-opacity").map(clamp_opacity),
 
         width,
         dashes: get_dashes("dashes"),
         line_cap: get_line_cap("linecap"),
+        line_join: get_line_join("linejoin"),
 
         casing_color: get_color("casing-color"),
         casing_width: full_casing_width,
         casing_dashes: get_dashes("casing-dashes"),
         casing_line_cap: get_line_cap("casing-linecap"),
 
-        icon_image: get_string("icon-image"),
+        line_decoration,
+        flow_arrows,
+
+        icon_image,
+        icon_allow_overlap: get_flag("icon-allow-overlap"),
         fill_image: get_string("fill-image"),
+        fill_hatch,
+        symbol,
         text_style,
     }
 }
 
+// Same as in SVG.
+const DEFAULT_MITER_LIMIT: f64 = 4.0;
+
+// Linearly interpolates between the two stops surrounding the zoom level. Zoom levels outside
+// of the stop range use the value of the nearest stop.
+fn interpolate_stops(stops: &[(f64, f64)], zoom: u8) -> f64 {
+    let zoom = f64::from(zoom);
+    let (first_zoom, first_value) = stops[0];
+    if zoom <= first_zoom {
+        return first_value;
+    }
+    for window in stops.windows(2) {
+        let ((from_zoom, from_value), (to_zoom, to_value)) = (window[0], window[1]);
+        if zoom <= to_zoom {
+            let ratio = (zoom - from_zoom) / (to_zoom - from_zoom);
+            return from_value + (to_value - from_value) * ratio;
+        }
+    }
+    stops[stops.len() - 1].1
+}
+
+fn clamp_opacity(opacity: f64) -> f64 {
+    opacity.clamp(0.0, 1.0)
+}
+
 fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<Color> {
     let color_prop = match *style_type {
         StyleType::Josm => "fill-color",
@@ -447,6 +846,49 @@ fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<C
     None
 }
 
+fn extract_render_layers(rules: &[Rule]) -> Vec<RenderLayer> {
+    // Later declarations override earlier ones, as with the properties of features.
+    let mut layer_props = IndexMap::<&str, PropertyMap<'_>>::new();
+    for r in rules {
+        for selector in &r.selectors {
+            match (&selector.object_type, &selector.layer_id) {
+                (ObjectType::Canvas, Some(name)) if name != BASE_LAYER_NAME => {
+                    let props = layer_props.entry(name.as_str()).or_default();
+                    for prop in r.properties.iter() {
+                        props.insert(prop.name.clone(), &prop.value);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut render_layers = layer_props
+        .into_iter()
+        .map(|(name, props)| {
+            let get_num = |prop_name| match props.get(prop_name) {
+                Some(&PropertyValue::Numbers(nums)) if nums.len() == 1 => Some(nums[0]),
+                _ => None,
+            };
+            let scrim_color = match props.get("scrim-color") {
+                Some(&PropertyValue::Color(color)) => Some(color.clone()),
+                Some(&PropertyValue::Identifier(id)) => from_color_name(id),
+                _ => None,
+            };
+            RenderLayer {
+                name: name.to_string(),
+                z_index: get_num("z-index").unwrap_or_default(),
+                scrim: scrim_color.map(|color| Scrim {
+                    color,
+                    opacity: get_num("scrim-opacity").map_or(1.0, clamp_opacity),
+                }),
+            }
+        })
+        .collect::<Vec<_>>();
+    render_layers.sort_by(|a, b| a.z_index.total_cmp(&b.z_index));
+    render_layers
+}
+
 fn matches_by_tags<'e, E>(entity: &E, test: &Test) -> bool
 where
     E: OsmEntity<'e>,
@@ -534,13 +976,13 @@ impl StyleableEntity for Node<'_> {
     }
 
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
-        matches!(*object_type, ObjectType::Node)
+        matches!(*object_type, ObjectType::All | ObjectType::Node)
     }
 }
 
 impl<A: OsmArea> StyleableEntity for A {
     fn default_z_index(&self) -> f64 {
-        if self.is_closed() {
+        if self.is_area() {
             1.0
         } else {
             3.0
@@ -549,13 +991,23 @@ impl<A: OsmArea> StyleableEntity for A {
 
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
         match *object_type {
-            ObjectType::Way => true,
-            ObjectType::Area => self.is_closed(),
+            ObjectType::All | ObjectType::Way => true,
+            ObjectType::Area => self.is_area(),
             _ => false,
         }
     }
 }
 
+impl StyleableEntity for Route<'_> {
+    fn default_z_index(&self) -> f64 {
+        3.0
+    }
+
+    fn matches_object_type(&self, object_type: &ObjectType) -> bool {
+        matches!(*object_type, ObjectType::All | ObjectType::Relation)
+    }
+}
+
 impl CacheableEntity for Node<'_> {
     fn cache_slot(&self) -> usize {
         0
@@ -564,7 +1016,7 @@ impl CacheableEntity for Node<'_> {
 
 impl CacheableEntity for Way<'_> {
     fn cache_slot(&self) -> usize {
-        if self.is_closed() {
+        if self.is_area() {
             1
         } else {
             2
@@ -577,3 +1029,9 @@ impl CacheableEntity for Multipolygon<'_> {
         3
     }
 }
+
+impl CacheableEntity for Route<'_> {
+    fn cache_slot(&self) -> usize {
+        4
+    }
+}
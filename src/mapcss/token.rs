@@ -510,7 +510,7 @@ fn with_pos(token: Token<'_>, position: InputPosition) -> TokenWithPosition<'_>
 mod tests {
     use super::*;
 
-    fn tokenize(s: &str) -> Vec<TokenWithPosition> {
+    fn tokenize(s: &str) -> Vec<TokenWithPosition<'_>> {
         Tokenizer::new(s)
             .map(|x| x.expect("Unexpected lexer error"))
             .collect::<Vec<_>>()
@@ -24,14 +24,16 @@ impl PerfStatsElement {
 
 pub struct Measurer {
     start_time: Instant,
-    element: PerfStatsElementRef,
-    element_stack: PerfStatsElementStackRef,
+    // `None` when no tile is being measured, so that there is nothing to record.
+    element: Option<(PerfStatsElementRef, PerfStatsElementStackRef)>,
 }
 
 impl Drop for Measurer {
     fn drop(&mut self) {
-        self.element.borrow_mut().duration += Instant::now() - self.start_time;
-        self.element_stack.borrow_mut().pop();
+        if let Some((element, element_stack)) = &self.element {
+            element.borrow_mut().duration += Instant::now() - self.start_time;
+            element_stack.borrow_mut().pop();
+        }
     }
 }
 
@@ -73,8 +75,7 @@ impl TilePerfStats {
 
         Measurer {
             start_time: Instant::now(),
-            element: Rc::clone(&new_element),
-            element_stack: Rc::clone(&self.element_stack),
+            element: Some((Rc::clone(&new_element), Rc::clone(&self.element_stack))),
         }
     }
 
@@ -102,6 +103,17 @@ impl SummedPerfStatsElement {
             }
         }
     }
+
+    fn merge(&mut self, other: SummedPerfStatsElement) {
+        self.duration_sum += other.duration_sum;
+        for (other_child_name, other_child) in other.children {
+            if let Some(our_child) = self.children.get_mut(&other_child_name) {
+                our_child.merge(*other_child);
+            } else {
+                self.children.insert(other_child_name, other_child);
+            }
+        }
+    }
 }
 
 #[derive(Default)]
@@ -122,6 +134,15 @@ impl PerfStats {
         zoom_stats.count += 1;
     }
 
+    /// Adds the tiles of `other` to these stats.
+    pub fn merge(&mut self, other: PerfStats) {
+        for (zoom, other_stats) in other.stats_by_zoom {
+            let zoom_stats = self.stats_by_zoom.entry(zoom).or_default();
+            zoom_stats.root_element.merge(other_stats.root_element);
+            zoom_stats.count += other_stats.count;
+        }
+    }
+
     pub fn to_html(&self) -> String {
         let html_template = include_str!("perf_stats.html");
         let mut html_dump = String::new();
@@ -202,6 +223,13 @@ pub fn finish_tile(total_stats: &mut PerfStats) {
     });
 }
 
+/// Measures until the returned value is dropped. Does nothing outside of `start_tile` and `finish_tile`.
 pub fn measure(name: impl Into<String>) -> Measurer {
-    TLS_PERF_STATS.with(|stats| stats.borrow_mut().as_mut().unwrap().measure(name))
+    TLS_PERF_STATS.with(|stats| match stats.borrow_mut().as_mut() {
+        Some(tile_stats) => tile_stats.measure(name),
+        None => Measurer {
+            start_time: Instant::now(),
+            element: None,
+        },
+    })
 }
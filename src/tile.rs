@@ -1,17 +1,95 @@
 use crate::coords::Coords;
 
+use anyhow::{bail, Result};
 use std::f64::consts::PI;
 
 pub const MAX_ZOOM: u8 = 18;
 pub const TILE_SIZE: u32 = 256;
 
-#[derive(Eq, PartialEq, Debug)]
+/// Which way tile rows are numbered. `Tile` always uses XYZ, as do the HTTP server and the batch renderer
+/// unless configured otherwise.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TileScheme {
+    /// Row 0 is the northernmost one, as in OSM, Google and most web maps.
+    #[default]
+    Xyz,
+    /// Row 0 is the southernmost one, as in TMS and MBTiles.
+    Tms,
+}
+
+impl TileScheme {
+    /// Converts a row number between XYZ and this scheme. The conversion goes both ways, as flipping the
+    /// rows twice leaves them unchanged. Rows that are off the map for the zoom are errors, whatever the
+    /// scheme.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::TileScheme;
+    /// assert_eq!(TileScheme::Tms.flip_y(81954, 18).unwrap(), 180189);
+    /// assert_eq!(TileScheme::Tms.flip_y(180189, 18).unwrap(), 81954);
+    /// assert_eq!(TileScheme::Tms.flip_y(0, 0).unwrap(), 0);
+    /// assert_eq!(TileScheme::Xyz.flip_y(81954, 18).unwrap(), 81954);
+    /// assert!(TileScheme::Tms.flip_y(1, 0).is_err());
+    /// assert!(TileScheme::Xyz.flip_y(262144, 18).is_err());
+    /// assert!(TileScheme::Tms.flip_y(0, 32).is_err());
+    /// ```
+    pub fn flip_y(&self, y: u32, zoom: u8) -> Result<u32> {
+        let Some(tiles_per_side) = 1u32.checked_shl(u32::from(zoom)) else {
+            bail!("Zoom {} is too large for tile indices", zoom);
+        };
+        if y >= tiles_per_side {
+            bail!(
+                "Row {} is out of range for zoom {} (expected at most {})",
+                y,
+                zoom,
+                tiles_per_side - 1
+            );
+        }
+        Ok(match self {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => tiles_per_side - 1 - y,
+        })
+    }
+}
+
+#[derive(Clone, Eq, Hash, PartialEq, Debug)]
 pub struct Tile {
     pub zoom: u8,
     pub x: u32,
     pub y: u32,
 }
 
+impl Tile {
+    /// Wraps `x` around the antimeridian, as the map repeats itself to the east and to the west. Zooms above
+    /// `MAX_ZOOM` and rows off the map (there's nothing to the north or the south of it) are errors.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Tile;
+    /// assert_eq!(Tile { zoom: 0, x: 0, y: 0 }.normalized().unwrap(), Tile { zoom: 0, x: 0, y: 0 });
+    /// assert_eq!(Tile { zoom: 3, x: 9, y: 7 }.normalized().unwrap(), Tile { zoom: 3, x: 1, y: 7 });
+    /// assert!(Tile { zoom: 3, x: 5, y: 8 }.normalized().is_err());
+    /// assert!(Tile { zoom: 19, x: 0, y: 0 }.normalized().is_err());
+    /// ```
+    pub fn normalized(&self) -> Result<Tile> {
+        if self.zoom > MAX_ZOOM {
+            bail!("Zoom {} is above the maximum zoom of {}", self.zoom, MAX_ZOOM);
+        }
+        let tiles_per_side = 1u32 << self.zoom;
+        if self.y >= tiles_per_side {
+            bail!(
+                "Row {} is out of range for zoom {} (expected at most {})",
+                self.y,
+                self.zoom,
+                tiles_per_side - 1
+            );
+        }
+        Ok(Tile {
+            zoom: self.zoom,
+            x: self.x % tiles_per_side,
+            y: self.y,
+        })
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct TileRange {
     pub min_x: u32,
@@ -72,6 +150,24 @@ pub fn tile_to_max_zoom_tile_range(tile: &Tile) -> TileRange {
     }
 }
 
+/// Return the tile at a lower (or the same) zoom level that contains a given tile.
+/// # Examples
+/// ```
+/// use renderer::tile::{tile_to_ancestor,Tile};
+/// assert_eq!(tile_to_ancestor(&Tile { zoom: 18, x: 158458, y: 81954 }, 15), Tile { zoom: 15, x: 19807, y: 10244 });
+/// assert_eq!(tile_to_ancestor(&Tile { zoom: 3, x: 5, y: 2 }, 0), Tile { zoom: 0, x: 0, y: 0 });
+/// assert_eq!(tile_to_ancestor(&Tile { zoom: 3, x: 5, y: 2 }, 3), Tile { zoom: 3, x: 5, y: 2 });
+/// ```
+pub fn tile_to_ancestor(tile: &Tile, zoom: u8) -> Tile {
+    assert!(zoom <= tile.zoom);
+    let shift = tile.zoom - zoom;
+    Tile {
+        zoom,
+        x: tile.x >> shift,
+        y: tile.y >> shift,
+    }
+}
+
 /// Projects a given geopoint to Web Mercator coordinates for a given zoom level.
 /// # Examples
 /// ```
@@ -100,6 +196,51 @@ pub fn coords_to_xy<C: Coords>(coords: &C, zoom: u8) -> (f64, f64) {
     (rescale(x), rescale(y))
 }
 
+/// Returns the `(x, y)` indices in the given scheme of the tile that contains a geopoint. Zooms above
+/// `MAX_ZOOM` are errors.
+/// # Examples
+/// ```
+/// use renderer::tile::{coords_to_tile, TileScheme};
+/// let kremlin = (55.7500f64, 37.6091f64);
+/// assert_eq!(coords_to_tile(&kremlin, 18, TileScheme::Xyz).unwrap(), (158458, 81954));
+/// assert_eq!(coords_to_tile(&kremlin, 18, TileScheme::Tms).unwrap(), (158458, 180189));
+/// assert_eq!(coords_to_tile(&kremlin, 0, TileScheme::Tms).unwrap(), (0, 0));
+/// assert!(coords_to_tile(&kremlin, 19, TileScheme::Xyz).is_err());
+/// ```
+pub fn coords_to_tile<C: Coords>(coords: &C, zoom: u8, scheme: TileScheme) -> Result<(u32, u32)> {
+    if zoom > MAX_ZOOM {
+        bail!("Zoom {} is above the maximum zoom of {}", zoom, MAX_ZOOM);
+    }
+    let (x, y) = coords_to_xy(coords, zoom);
+    let max_index = (1u32 << zoom) - 1;
+    let tile_index = |t: f64| ((t / f64::from(TILE_SIZE)).floor().max(0.0) as u32).min(max_index);
+    Ok((tile_index(x), scheme.flip_y(tile_index(y), zoom)?))
+}
+
+/// Returns the `(lat, lon)` of the north-west corner of a tile, given its indices in the given scheme.
+/// # Examples
+/// ```
+/// use renderer::tile::{tile_to_coords, TileScheme};
+/// fn assert_close((lat, lon): (f64, f64), (expected_lat, expected_lon): (f64, f64)) {
+///     assert!((lat - expected_lat).abs() < 1e-6 && (lon - expected_lon).abs() < 1e-6);
+/// }
+/// assert_close(tile_to_coords(0, 0, 0, TileScheme::Xyz).unwrap(), (85.051129, -180.0));
+/// assert_close(tile_to_coords(1, 1, 0, TileScheme::Tms).unwrap(), (0.0, 0.0));
+/// assert_close(tile_to_coords(18, 158458, 81954, TileScheme::Xyz).unwrap(), (55.750304, 37.608948));
+/// assert_close(tile_to_coords(18, 158458, 180189, TileScheme::Tms).unwrap(), (55.750304, 37.608948));
+/// assert!(tile_to_coords(1, 0, 2, TileScheme::Tms).is_err());
+/// ```
+pub fn tile_to_coords(zoom: u8, x: u32, y: u32, scheme: TileScheme) -> Result<(f64, f64)> {
+    let y = scheme.flip_y(y, zoom)?;
+    let tile_count = f64::from(1u32 << zoom);
+    let lon = f64::from(x) / tile_count * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * f64::from(y) / tile_count))
+        .sinh()
+        .atan()
+        .to_degrees();
+    Ok((lat, lon))
+}
+
 pub fn coords_to_xy_tile_relative<C: Coords>(coords: &C, tile: &Tile) -> (f64, f64) {
     let (x, y) = coords_to_xy(coords, tile.zoom);
     (x - f64::from(tile.x * TILE_SIZE), y - f64::from(tile.y * TILE_SIZE))
@@ -0,0 +1,143 @@
+use crate::tile::Tile;
+#[cfg(feature = "mbtiles")]
+use crate::tile::TileScheme;
+use anyhow::{anyhow, Result};
+#[cfg(feature = "mbtiles")]
+use anyhow::{bail, Context};
+#[cfg(feature = "mbtiles")]
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Where `TileWriter` puts the rendered tiles. Every batch should be stored at once, e.g. in a single
+/// database transaction.
+pub trait TileStore: Send + 'static {
+    fn write_batch(&mut self, tiles: &[(Tile, Vec<u8>)]) -> Result<()>;
+}
+
+pub struct TileWriterOptions {
+    pub batch_size: usize,
+    /// How many tiles can wait for the writer before render workers start blocking.
+    pub queue_size: usize,
+}
+
+impl Default for TileWriterOptions {
+    fn default() -> TileWriterOptions {
+        TileWriterOptions {
+            batch_size: 256,
+            queue_size: 1024,
+        }
+    }
+}
+
+/// Stores tiles from a dedicated thread, so that render workers only have to push them to a channel
+/// and never wait for each other's writes.
+pub struct TileWriter {
+    sender: SyncSender<(Tile, Vec<u8>)>,
+    thread: JoinHandle<Result<usize>>,
+}
+
+impl TileWriter {
+    pub fn new<S: TileStore>(mut store: S, options: TileWriterOptions) -> TileWriter {
+        let batch_size = options.batch_size.max(1);
+        let (sender, receiver) = sync_channel(options.queue_size);
+
+        let thread = thread::spawn(move || {
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut written = 0;
+            for tile in receiver {
+                batch.push(tile);
+                if batch.len() == batch_size {
+                    store.write_batch(&batch)?;
+                    written += batch.len();
+                    batch.clear();
+                }
+            }
+            if !batch.is_empty() {
+                store.write_batch(&batch)?;
+                written += batch.len();
+            }
+            Ok(written)
+        });
+
+        TileWriter { sender, thread }
+    }
+
+    /// Can be called from any number of threads at once.
+    pub fn write(&self, tile: Tile, bytes: Vec<u8>) -> Result<()> {
+        self.sender
+            .send((tile, bytes))
+            .map_err(|_| anyhow!("The tile writer has stopped, finish() returns the reason"))
+    }
+
+    /// Writes the last incomplete batch and returns the total number of written tiles.
+    pub fn finish(self) -> Result<usize> {
+        drop(self.sender);
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("The tile writer thread panicked"))?
+    }
+}
+
+/// An MBTiles file (a SQLite database with `metadata` and `tiles` tables) that stores every batch in
+/// one transaction. The database is switched to WAL mode, so the tiles can be read while it's being
+/// written to.
+#[cfg(feature = "mbtiles")]
+pub struct MbtilesStore {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "mbtiles")]
+impl MbtilesStore {
+    /// Opens the file at `path`, creating it and the tables if needed, and sets the `metadata` entries,
+    /// e.g. `("name", "Moscow")` and `("format", "png")`. Existing tiles are overwritten when rendered again.
+    pub fn open<P: AsRef<Path>>(path: P, metadata: &[(&str, &str)]) -> Result<MbtilesStore> {
+        let path = path.as_ref();
+        let connection =
+            rusqlite::Connection::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let journal_mode: String = connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            bail!(
+                "Failed to enable WAL mode for {} (got {})",
+                path.display(),
+                journal_mode
+            );
+        }
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+            CREATE UNIQUE INDEX IF NOT EXISTS metadata_name ON metadata (name);
+            CREATE TABLE IF NOT EXISTS tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+            CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles (zoom_level, tile_column, tile_row);",
+        )?;
+        for (name, value) in metadata {
+            connection.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                (name, value),
+            )?;
+        }
+
+        Ok(MbtilesStore { connection })
+    }
+}
+
+#[cfg(feature = "mbtiles")]
+impl TileStore for MbtilesStore {
+    fn write_batch(&mut self, tiles: &[(Tile, Vec<u8>)]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (tile, bytes) in tiles {
+                // MBTiles numbers the rows from the south, like TMS.
+                let row = TileScheme::Tms.flip_y(tile.y, tile.zoom)?;
+                insert.execute((tile.zoom, tile.x, row, bytes))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,21 @@
-use std::path::PathBuf;
+#![allow(dead_code)]
+
+use renderer::draw::drawer::{Drawer, RenderOptions, TileRenderedPixels};
+use renderer::draw::tile_pixels::TilePixels;
+use renderer::geodata::importer::ImportOptions;
+use renderer::geodata::reader::{GeodataReader, OsmEntities};
+use renderer::mapcss::parser::{parse_file, Rule};
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::tile::Tile;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The z18 tile in the center of Moscow that drawing tests put their features on.
+pub const TEST_TILE: Tile = Tile {
+    zoom: 18,
+    x: 158_458,
+    y: 81_954,
+};
 
 pub fn get_test_path(relative_path: &[&str]) -> String {
     let mut test_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -9,3 +26,71 @@ pub fn get_test_path(relative_path: &[&str]) -> String {
 
     test_path.to_str().unwrap().to_string()
 }
+
+pub fn get_tmp_path(file_name: &str) -> PathBuf {
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("osm_renderer_tests");
+    fs::create_dir_all(&tmp_path).unwrap();
+    tmp_path.push(file_name);
+    tmp_path
+}
+
+pub fn import_osm_str<'a>(name: &str, osm_xml: &str) -> GeodataReader<'a> {
+    import_osm_str_with_options(name, osm_xml, &ImportOptions::default())
+}
+
+pub fn import_osm_str_with_options<'a>(name: &str, osm_xml: &str, options: &ImportOptions) -> GeodataReader<'a> {
+    let osm_file = get_tmp_path(&format!("{}.osm", name));
+    let bin_file = get_tmp_path(&format!("{}.bin", name));
+    fs::write(&osm_file, osm_xml).unwrap();
+    renderer::geodata::importer::import_with_options(&osm_file, &bin_file, options).unwrap();
+    GeodataReader::load(bin_file.to_str().unwrap()).unwrap()
+}
+
+pub fn parse_style_str(name: &str, mapcss: &str) -> Vec<Rule> {
+    let file_name = format!("{}.mapcss", name);
+    let style_file = get_tmp_path(&file_name);
+    fs::write(&style_file, mapcss).unwrap();
+    parse_file(style_file.parent().unwrap(), &file_name).unwrap()
+}
+
+/// OSM data and a JOSM style to draw `TEST_TILE` from.
+pub struct TestTile<'a> {
+    pub reader: GeodataReader<'a>,
+    pub styler: Styler,
+}
+
+impl<'a> TestTile<'a> {
+    /// Imports `osm_xml` and parses `mapcss`, both saved under `name`.
+    pub fn new(name: &str, osm_xml: &str, mapcss: &str) -> TestTile<'a> {
+        TestTile::with_import_options(name, osm_xml, mapcss, &ImportOptions::default())
+    }
+
+    pub fn with_import_options(name: &str, osm_xml: &str, mapcss: &str, options: &ImportOptions) -> TestTile<'a> {
+        TestTile {
+            reader: import_osm_str_with_options(name, osm_xml, options),
+            styler: Styler::new(parse_style_str(name, mapcss), &StyleType::Josm, None),
+        }
+    }
+
+    pub fn with_styler(name: &str, osm_xml: &str, styler: Styler) -> TestTile<'a> {
+        TestTile {
+            reader: import_osm_str(name, osm_xml),
+            styler,
+        }
+    }
+
+    pub fn entities(&self) -> OsmEntities<'_> {
+        self.reader.get_entities_in_tile_with_neighbors(&TEST_TILE, &None)
+    }
+
+    /// The drawer `draw` uses. Icons and images are looked up in `tests/mapcss`.
+    pub fn drawer(options: RenderOptions) -> Drawer {
+        Drawer::with_options(Path::new(&get_test_path(&["mapcss"])), options)
+    }
+
+    /// Draws the tile at scale 1.
+    pub fn draw(&self, options: RenderOptions) -> TileRenderedPixels {
+        TestTile::drawer(options).draw_to_pixels(&self.entities(), &TEST_TILE, &mut TilePixels::new(1), 1, &self.styler)
+    }
+}
@@ -0,0 +1,407 @@
+mod common;
+
+use anyhow::Result;
+use renderer::batch::{BatchOptions, BatchRenderer, MAX_OVERZOOM_LEVELS};
+use renderer::coords::{tiles_for_bbox, BoundingBox};
+use renderer::draw::drawer::Drawer;
+use renderer::draw::tile_pixels::TilePixels;
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::tile::{coords_to_tile, Tile, TileScheme};
+use renderer::tile_writer::{TileStore, TileWriter, TileWriterOptions};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn test_overzoom_resamples_instead_of_rasterizing() {
+    let reader = common::import_osm_str(
+        "batch_overzoom",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "batch_overzoom",
+        "canvas { fill-color: #ffffff; } way[highway] { color: #000000; width: 3; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+
+    let batch = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            max_zoom: 16,
+            overzoom: true,
+            ..Default::default()
+        },
+    );
+
+    let z16_tile = Tile {
+        zoom: 16,
+        x: 39_614,
+        y: 20_488,
+    };
+    let z17_tile = Tile {
+        zoom: 17,
+        x: 79_229,
+        y: 40_977,
+    };
+
+    let mut pixels = TilePixels::new(1);
+    let parent = batch.render_tile(&z16_tile, &mut pixels).unwrap();
+    assert_eq!(batch.stats().rasterized(), 1);
+
+    let child = batch.render_tile(&z17_tile, &mut pixels).unwrap();
+    assert_eq!(batch.stats().rasterized(), 2);
+    assert_eq!(batch.stats().resampled(), 1);
+    assert_eq!(child.dimension, 256);
+    assert!(!child.is_blank);
+
+    // The child is the bottom-right quadrant of the parent blown up 2x.
+    for (y, x) in [(0, 0), (17, 42), (255, 255)] {
+        let parent_pixel = parent.triples[(128 + y / 2) * 256 + 128 + x / 2];
+        assert_eq!(child.triples[y * 256 + x], parent_pixel);
+    }
+
+    let mut rendered_zooms = Vec::new();
+    batch
+        .render_tiles(vec![z17_tile.clone(), z17_tile.clone()], |tile, _| {
+            rendered_zooms.push(tile.zoom);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(rendered_zooms, vec![17, 17]);
+    assert_eq!(batch.stats().rasterized(), 3);
+    assert_eq!(batch.stats().resampled(), 3);
+
+    // Upscaling stops where a single pixel of the ancestor covers the whole tile.
+    let descendant = |zoom: u8| Tile {
+        zoom,
+        x: z16_tile.x << (zoom - 16),
+        y: z16_tile.y << (zoom - 16),
+    };
+    let deepest = batch
+        .render_tile(&descendant(16 + MAX_OVERZOOM_LEVELS), &mut pixels)
+        .unwrap();
+    assert!(deepest.triples.iter().all(|pixel| *pixel == parent.triples[0]));
+    assert!(batch
+        .render_tile(&descendant(17 + MAX_OVERZOOM_LEVELS), &mut pixels)
+        .is_none());
+    assert_eq!(batch.stats().skipped(), 1);
+
+    // Only the rasterized ancestors are timed, each at its own zoom.
+    let perf_stats = batch.take_perf_stats();
+    if cfg!(feature = "perf-stats") {
+        let perf_stats_html = perf_stats.to_html();
+        assert!(perf_stats_html.contains("<h1>Zoom 16 (4 tiles)</h1>"));
+        assert!(!perf_stats_html.contains("Zoom 17"));
+    }
+
+    let no_overzoom = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            max_zoom: 16,
+            ..Default::default()
+        },
+    );
+    assert!(no_overzoom.render_tile(&z17_tile, &mut pixels).is_none());
+    assert_eq!(no_overzoom.stats().rasterized(), 0);
+    assert_eq!(no_overzoom.stats().skipped(), 1);
+}
+
+#[test]
+fn test_region_iter_yields_tiles_in_order() {
+    let reader = common::import_osm_str(
+        "batch_region",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7508" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6105"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "batch_region",
+        "canvas { fill-color: #ffffff; } way[highway] { color: #000000; width: 3; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+    let batch = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            threads: 3,
+            ..Default::default()
+        },
+    );
+
+    let bbox = BoundingBox {
+        min_lat: 55.7496,
+        max_lat: 55.7508,
+        min_lon: 37.6091,
+        max_lon: 37.6105,
+    };
+    let mut tiles = Vec::new();
+    std::thread::scope(|scope| {
+        for rendered in batch.render_region_iter(scope, &bbox, 18) {
+            let (tile, png_bytes) = rendered.unwrap();
+            let png_info = png::Decoder::new(png_bytes.as_slice())
+                .read_info()
+                .unwrap()
+                .info()
+                .clone();
+            assert_eq!((png_info.width, png_info.height), (256, 256));
+            tiles.push((tile.zoom, tile.x, tile.y));
+        }
+    });
+
+    assert_eq!(
+        tiles,
+        [
+            (18, 158_458, 81_953),
+            (18, 158_458, 81_954),
+            (18, 158_459, 81_953),
+            (18, 158_459, 81_954),
+        ]
+    );
+    assert_eq!(batch.stats().rasterized(), 4);
+}
+
+#[test]
+fn test_region_iter_renders_a_bounded_number_of_tiles_ahead() {
+    let reader = common::import_osm_str("batch_region_ahead", r#"<osm version="0.6"></osm>"#);
+    let styler = Styler::new(Vec::new(), &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+    let batch = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            threads: 1,
+            ..Default::default()
+        },
+    );
+
+    let bbox = BoundingBox {
+        min_lat: 55.7440,
+        max_lat: 55.7508,
+        min_lon: 37.6091,
+        max_lon: 37.6160,
+    };
+    let tile_count = tiles_for_bbox(&bbox, 18, TileScheme::Xyz).count();
+    assert!(tile_count > 40);
+
+    std::thread::scope(|scope| {
+        let mut tiles = batch.render_region_iter(scope, &bbox, 18);
+        tiles.next().unwrap().unwrap();
+        // The only worker has at most two runs of eight tiles to render before the caller asks for more.
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(batch.stats().rasterized() <= 16);
+        assert_eq!(tiles.count(), tile_count - 1);
+    });
+    assert_eq!(batch.stats().rasterized(), tile_count);
+}
+
+#[test]
+fn test_tile_writer_batches_tiles_from_many_threads() {
+    #[derive(Clone, Default)]
+    struct RecordingStore {
+        batches: Arc<Mutex<Vec<Vec<Tile>>>>,
+    }
+
+    impl TileStore for RecordingStore {
+        fn write_batch(&mut self, tiles: &[(Tile, Vec<u8>)]) -> Result<()> {
+            assert!(tiles.iter().all(|(tile, bytes)| bytes == &[tile.zoom]));
+            let tiles = tiles.iter().map(|(tile, _)| tile.clone()).collect();
+            self.batches.lock().unwrap().push(tiles);
+            Ok(())
+        }
+    }
+
+    let store = RecordingStore::default();
+    let options = TileWriterOptions {
+        batch_size: 10,
+        queue_size: 4,
+    };
+    let writer = TileWriter::new(store.clone(), options);
+
+    std::thread::scope(|scope| {
+        for worker in 0..4 {
+            let writer = &writer;
+            scope.spawn(move || {
+                for x in 0..25 {
+                    let tile = Tile { zoom: 10, x, y: worker };
+                    writer.write(tile, vec![10]).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(writer.finish().unwrap(), 100);
+
+    let batches = store.batches.lock().unwrap();
+    assert_eq!(batches.len(), 10);
+    assert!(batches.iter().all(|batch| batch.len() == 10));
+    let unique_tiles = batches
+        .iter()
+        .flatten()
+        .map(|tile| (tile.x, tile.y))
+        .collect::<HashSet<_>>();
+    assert_eq!(unique_tiles.len(), 100);
+}
+
+#[cfg(feature = "mbtiles")]
+#[test]
+fn test_tile_writer_fills_mbtiles() {
+    use renderer::tile_writer::MbtilesStore;
+
+    let path = common::get_tmp_path("writer.mbtiles");
+    let _ = std::fs::remove_file(&path);
+    let store = MbtilesStore::open(&path, &[("name", "test"), ("format", "png")]).unwrap();
+    let options = TileWriterOptions {
+        batch_size: 10,
+        queue_size: 4,
+    };
+    let writer = TileWriter::new(store, options);
+    for x in 0..100 {
+        writer.write(Tile { zoom: 7, x, y: 1 }, vec![x as u8]).unwrap();
+    }
+    assert_eq!(writer.finish().unwrap(), 100);
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let journal_mode: String = connection
+        .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(journal_mode, "wal");
+    let format: String = connection
+        .query_row("SELECT value FROM metadata WHERE name = 'format'", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(format, "png");
+
+    let count: u32 = connection
+        .query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 100);
+    let (row, data): (u32, Vec<u8>) = connection
+        .query_row(
+            "SELECT tile_row, tile_data FROM tiles WHERE zoom_level = 7 AND tile_column = 42",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((row, data), (126, vec![42]));
+}
+
+#[test]
+fn test_render_to_dir_writes_sidecars() {
+    let reader = common::import_osm_str(
+        "batch_sidecars",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <node id="3" lat="55.7499" lon="37.6095"><tag k="amenity" v="bench"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str("batch_sidecars", "way[highway] { color: #000000; width: 3; }");
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+    let batch = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            write_sidecars: true,
+            ..Default::default()
+        },
+    );
+
+    let dir = common::get_tmp_path("batch_sidecars");
+    let _ = std::fs::remove_dir_all(&dir);
+    let drawn_tile = Tile {
+        zoom: 18,
+        x: 158_458,
+        y: 81_954,
+    };
+    let empty_tile = Tile { zoom: 18, x: 0, y: 0 };
+    batch.render_to_dir(vec![drawn_tile, empty_tile], &dir).unwrap();
+
+    let read_sidecar = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+    // Three nodes and a way.
+    let drawn = read_sidecar("18/158458/81954.json");
+    assert!(drawn.starts_with(r#"{"feature_count":4,"render_time_ms":"#));
+    assert!(drawn.ends_with(r#","is_blank":false}"#));
+    let empty = read_sidecar("18/0/0.json");
+    assert!(empty.starts_with(r#"{"feature_count":0,"#));
+    assert!(empty.ends_with(r#","is_blank":true}"#));
+    assert!(dir.join("18/158458/81954.png").exists());
+}
+
+#[test]
+fn test_tms_rows_are_complementary_to_xyz() {
+    let point = (55.7500, 37.6091);
+    for zoom in [0, 1, 7, 18] {
+        let (xyz_x, xyz_y) = coords_to_tile(&point, zoom, TileScheme::Xyz).unwrap();
+        let (tms_x, tms_y) = coords_to_tile(&point, zoom, TileScheme::Tms).unwrap();
+        assert_eq!(xyz_x, tms_x);
+        assert_eq!(xyz_y + tms_y, (1 << zoom) - 1);
+    }
+
+    let reader = common::import_osm_str("batch_tms", r#"<osm version="0.6"></osm>"#);
+    let styler = Styler::new(Vec::new(), &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+    let batch = BatchRenderer::new(
+        &reader,
+        &styler,
+        &drawer,
+        BatchOptions {
+            tile_scheme: TileScheme::Tms,
+            ..Default::default()
+        },
+    );
+    let dir = common::get_tmp_path("batch_tms");
+    let _ = std::fs::remove_dir_all(&dir);
+    batch.render_to_dir(vec![Tile { zoom: 2, x: 1, y: 1 }], &dir).unwrap();
+    assert!(dir.join("2/1/2.png").exists());
+}
+
+#[test]
+fn test_columns_past_the_antimeridian_repeat_the_map() {
+    let reader = common::import_osm_str(
+        "batch_antimeridian",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "batch_antimeridian",
+        "canvas { fill-color: #ffffff; } way[highway] { color: #000000; width: 3; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let drawer = Drawer::new(Path::new("."));
+    let batch = BatchRenderer::new(&reader, &styler, &drawer, Default::default());
+
+    let tile = common::TEST_TILE;
+    let tiles_per_side = 1 << tile.zoom;
+    let mut pixels = TilePixels::new(1);
+    let expected = batch.render_tile(&tile, &mut pixels).unwrap();
+    assert!(!expected.is_blank);
+    for x in [tile.x + tiles_per_side, tile.x + 3 * tiles_per_side] {
+        let wrapped = batch.render_tile(&Tile { x, ..tile.clone() }, &mut pixels).unwrap();
+        assert_eq!(wrapped.triples, expected.triples);
+    }
+
+    let off_the_map = Tile {
+        y: tiles_per_side,
+        ..tile
+    };
+    assert!(batch.render_tile(&off_the_map, &mut pixels).is_none());
+    assert_eq!(batch.stats().skipped(), 1);
+}
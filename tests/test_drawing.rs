@@ -0,0 +1,2234 @@
+mod common;
+
+use anyhow::Result;
+use renderer::draw::backend::{DrawCommand, DrawType, OutputBackend};
+use renderer::draw::circle::draw_circle;
+use renderer::draw::clip::{clip_contour, clip_segment};
+use renderer::draw::debug_colors::{debug_color, DebugColorMode};
+use renderer::draw::decoration::{compute_flow_arrows, compute_ticks};
+use renderer::draw::dem::Dem;
+use renderer::draw::drawer::{Drawer, RenderOptions, RenderTimedOut};
+use renderer::draw::feature_index::FeatureId;
+use renderer::draw::fill::{
+    compute_fill_spans, fill_contour, fill_contour_antialiased, is_on_hatch, to_closed_contour, ContourKind, Filler,
+};
+use renderer::draw::geojson_writer::GeoJsonOptions;
+use renderer::draw::graticule::{compute_graticule, GraticuleOptions, GraticuleSpacing};
+use renderer::draw::hillshade::{compute_hillshade, DemSource};
+use renderer::draw::icon::{EdgeWrap, Icon, ImageSampling, SamplingFilter};
+use renderer::draw::jpeg_writer::{ChromaSubsampling, JpegOptions};
+use renderer::draw::labelable::Labelable;
+use renderer::draw::labeler::LabelThinning;
+use renderer::draw::line::draw_lines;
+use renderer::draw::mask::Mask;
+use renderer::draw::png_writer::{ColorProfile, PngBitDepth, PngOptions};
+use renderer::draw::point::Point;
+use renderer::draw::point_pairs::{PointPairCollection, PointPairIter};
+use renderer::draw::tile_pixels::{RgbaColor, TilePixels};
+use renderer::geodata::importer::{EntityKind, ImportOptions};
+use renderer::geodata::reader::{OsmArea, OsmEntity};
+use renderer::mapcss::color::Color;
+use renderer::mapcss::quick_style::{to_mapcss_rules, StyleRule, TagMatch};
+use renderer::mapcss::styler::{LineDecoration, OrderKey, StyleType, Styler, TickSide};
+use renderer::tile::{coords_to_xy_tile_relative, Tile};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn contour(points: &[(i32, i32)]) -> PointPairIter<'static> {
+    let points = points.iter().map(|&(x, y)| Point { x, y }).collect::<Vec<_>>();
+    Box::new((1..points.len()).map(move |idx| (points[idx - 1].clone(), points[idx].clone())))
+}
+
+const TRIANGLE: &[(i32, i32)] = &[(10, 10), (200, 30), (60, 180), (10, 10)];
+
+#[test]
+fn test_transparent_fill_keeps_tile_blank() {
+    let red = Color { r: 255, g: 0, b: 0 };
+    let mut pixels = TilePixels::new(1);
+
+    fill_contour(contour(TRIANGLE), &Filler::Color(&red), 0.0, &mut pixels);
+    pixels.blend_unfinished_pixels(false);
+    assert!(pixels.is_blank());
+
+    fill_contour(contour(TRIANGLE), &Filler::Color(&red), 0.5, &mut pixels);
+    pixels.blend_unfinished_pixels(false);
+    assert!(!pixels.is_blank());
+
+    pixels.reset(&None);
+    assert!(pixels.is_blank());
+}
+
+#[test]
+fn test_antialiased_fill_only_blends_the_outline() {
+    let white = Color { r: 255, g: 255, b: 255 };
+    let black = Color { r: 0, g: 0, b: 0 };
+    let fill = |antialias: bool| {
+        let mut pixels = TilePixels::new(1);
+        pixels.reset(&Some(white.clone()));
+        if antialias {
+            fill_contour_antialiased(contour(TRIANGLE), &Filler::Color(&black), 1.0, &mut pixels);
+        } else {
+            fill_contour(contour(TRIANGLE), &Filler::Color(&black), 1.0, &mut pixels);
+        }
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgb_triples()
+    };
+    let is_partial = |&(r, _, _): &(u8, u8, u8)| r != 0 && r != 255;
+
+    let hard = fill(false);
+    assert!(!hard.iter().any(is_partial));
+
+    let smooth = fill(true);
+    let spans = compute_fill_spans(contour(TRIANGLE), TilePixels::new(1).bb());
+    let mut partial_count = 0;
+    for span in &spans {
+        for x in span.from_x..=span.to_x {
+            let pixel = &smooth[span.y as usize * 256 + x as usize];
+            let is_interior = spans
+                .iter()
+                .any(|other| other.y == span.y - 1 && other.from_x < x && x < other.to_x)
+                && spans
+                    .iter()
+                    .any(|other| other.y == span.y + 1 && other.from_x < x && x < other.to_x)
+                && span.from_x + 1 < x
+                && x + 1 < span.to_x;
+            if is_interior {
+                assert_eq!(*pixel, (0, 0, 0));
+            }
+            if is_partial(pixel) {
+                partial_count += 1;
+            }
+        }
+    }
+    assert!(partial_count > 100);
+    // Nothing is drawn outside of the hard fill.
+    for (hard_pixel, smooth_pixel) in hard.iter().zip(&smooth) {
+        if *hard_pixel == (255, 255, 255) {
+            assert_eq!(*smooth_pixel, (255, 255, 255));
+        }
+    }
+}
+
+#[test]
+fn test_fill_through_circular_mask() {
+    let white = Color { r: 255, g: 255, b: 255 };
+    let black = Color { r: 0, g: 0, b: 0 };
+    let (center, radius) = (128.0, 80.0);
+    let circle = (0..=64)
+        .map(|idx| {
+            let angle = f64::from(idx) * std::f64::consts::TAU / 64.0;
+            let (x, y) = (center + radius * angle.cos(), center + radius * angle.sin());
+            (x.round() as i32, y.round() as i32)
+        })
+        .collect::<Vec<_>>();
+    let whole_tile = &[(-10, -10), (265, -10), (265, 265), (-10, 265), (-10, -10)];
+
+    let mut mask = Mask::new(256);
+    mask.fill_contour(contour(&circle), true);
+
+    let mut pixels = TilePixels::new(1);
+    pixels.reset(&Some(white.clone()));
+    pixels.apply_mask(&mask);
+    fill_contour(contour(whole_tile), &Filler::Color(&black), 1.0, &mut pixels);
+    pixels.blend_unfinished_pixels(false);
+
+    let triples = pixels.to_rgb_triples();
+    let mut partial_count = 0;
+    for (idx, pixel) in triples.iter().enumerate() {
+        let (x, y) = ((idx % 256) as f64, (idx / 256) as f64);
+        let dist = ((x - center).powi(2) + (y - center).powi(2)).sqrt();
+        if dist < radius - 3.0 {
+            assert_eq!(*pixel, (0, 0, 0));
+        } else if dist > radius + 3.0 {
+            assert_eq!(*pixel, (255, 255, 255));
+        } else if pixel.0 != 0 && pixel.0 != 255 {
+            partial_count += 1;
+        }
+    }
+    assert!(partial_count > 100);
+
+    // Without the mask, the whole tile gets painted.
+    pixels.clear_mask();
+    fill_contour(contour(whole_tile), &Filler::Color(&black), 1.0, &mut pixels);
+    pixels.blend_unfinished_pixels(false);
+    assert!(pixels.to_rgb_triples().iter().all(|pixel| *pixel == (0, 0, 0)));
+}
+
+#[test]
+fn test_bilinear_pattern_sampling() {
+    // A black-to-white pattern, drawn 8 times larger.
+    let pattern = Icon::from_pixels(
+        2,
+        1,
+        vec![
+            RgbaColor::from_components(0, 0, 0, 255),
+            RgbaColor::from_components(255, 255, 255, 255),
+        ],
+    );
+    let whole_tile = &[(-10, -10), (265, -10), (265, 265), (-10, 265), (-10, -10)];
+    let first_row = |filter, wrap| {
+        let sampling = ImageSampling { filter, wrap };
+        let filler = Filler::Image {
+            icon: &pattern,
+            scale: 8.0,
+            sampling: &sampling,
+        };
+        let mut pixels = TilePixels::new(1);
+        pixels.reset(&None);
+        fill_contour(contour(whole_tile), &filler, 1.0, &mut pixels);
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgb_triples()[..32]
+            .iter()
+            .map(|pixel| pixel.0)
+            .collect::<Vec<_>>()
+    };
+    let is_intermediate = |value: &u8| *value != 0 && *value != 255;
+
+    let nearest = first_row(SamplingFilter::Nearest, EdgeWrap::Repeat);
+    assert_eq!(&nearest[..16], &[[0; 8], [255; 8]].concat()[..]);
+    assert_eq!(nearest[..16], nearest[16..]);
+
+    let bilinear = first_row(SamplingFilter::Bilinear, EdgeWrap::Repeat);
+    assert!(bilinear[4..12].iter().all(is_intermediate));
+    assert!(bilinear[4..12].windows(2).all(|pair| pair[0] < pair[1]));
+    // Repeating blends the white edge back into the black one, clamping doesn't.
+    assert!(is_intermediate(&bilinear[0]));
+    assert_eq!(bilinear[..16], bilinear[16..]);
+    let clamped = first_row(SamplingFilter::Bilinear, EdgeWrap::Clamp);
+    assert_eq!((clamped[0], clamped[15]), (0, 255));
+}
+
+#[test]
+fn test_fill_spans_are_deterministic() {
+    let pixels = TilePixels::new(1);
+    let concave = &[(10, 10), (120, 200), (240, 15), (120, 90), (10, 10)];
+
+    let first_run = compute_fill_spans(contour(concave), pixels.bb());
+    let second_run = compute_fill_spans(contour(concave), pixels.bb());
+    assert!(!first_run.is_empty());
+    assert_eq!(first_run, second_run);
+
+    for pair in first_run.windows(2) {
+        assert!((pair[0].y, pair[0].from_x) < (pair[1].y, pair[1].from_x));
+    }
+}
+
+#[test]
+fn test_open_contour_is_auto_closed() {
+    let pixels = TilePixels::new(1);
+    let closed_spans = compute_fill_spans(contour(TRIANGLE), pixels.bb());
+    let open_triangle = &TRIANGLE[..TRIANGLE.len() - 1];
+
+    let open_spans = compute_fill_spans(contour(open_triangle), pixels.bb());
+    assert_ne!(open_spans, closed_spans);
+
+    let auto_closed = to_closed_contour(contour(open_triangle), ContourKind::Open);
+    assert_eq!(compute_fill_spans(auto_closed, pixels.bb()), closed_spans);
+
+    // Closed contours are left alone, and every ring of a multi-ring contour is closed on its own.
+    let already_closed = to_closed_contour(contour(TRIANGLE), ContourKind::Open);
+    assert_eq!(compute_fill_spans(already_closed, pixels.bb()), closed_spans);
+
+    let second_triangle = TRIANGLE.iter().map(|&(x, y)| (x + 30, y + 60)).collect::<Vec<_>>();
+    let two_rings = contour(open_triangle).chain(contour(&second_triangle[..second_triangle.len() - 1]));
+    let two_closed_rings = contour(TRIANGLE).chain(contour(&second_triangle));
+    assert_eq!(
+        compute_fill_spans(to_closed_contour(Box::new(two_rings), ContourKind::Open), pixels.bb()),
+        compute_fill_spans(Box::new(two_closed_rings), pixels.bb())
+    );
+}
+
+#[test]
+fn test_supersampling_smooths_diagonal_line() {
+    let test_tile = common::TestTile::new(
+        "supersample",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } way[highway] { color: #000000; width: 1; }",
+    );
+
+    let count_gray_pixels = |supersample| {
+        let rendered = test_tile.draw(RenderOptions {
+            supersample,
+            ..Default::default()
+        });
+        assert_eq!(rendered.dimension, 256);
+        rendered.triples.iter().filter(|&&(r, _, _)| r > 0 && r < 255).count()
+    };
+
+    let plain_count = count_gray_pixels(1);
+    let supersampled_count = count_gray_pixels(2);
+    assert!(plain_count > 0);
+    assert!(
+        supersampled_count > plain_count,
+        "{} gray pixels with supersampling vs {} without",
+        supersampled_count,
+        plain_count
+    );
+}
+
+#[test]
+fn test_clipped_line_matches_unclipped() {
+    let crossing_line = &[(-5_000, -1_234), (3_000, 900)];
+    let (start, end) = (Point { x: -5_000, y: -1_234 }, Point { x: 3_000, y: 900 });
+    let pixels = TilePixels::new(1);
+    let (t_from, t_to) = clip_segment(&start, &end, pixels.bb(), 0.0).unwrap();
+    let point_at = |t: f64| (-5_000.0 + t * 8_000.0, -1_234.0 + t * 2_134.0);
+    let (from_x, _) = point_at(t_from);
+    let (to_x, to_y) = point_at(t_to);
+    assert!(from_x >= -0.001 && to_x <= 255.001 && to_y <= 255.001);
+
+    let black = Color { r: 0, g: 0, b: 0 };
+    let draw = |clip| {
+        let mut pixels = TilePixels::new(1);
+        pixels.reset(&Some(Color { r: 255, g: 255, b: 255 }));
+        let dashes = Some(vec![7.0, 3.0]);
+        draw_lines(
+            contour(crossing_line),
+            3.5,
+            &black,
+            0.8,
+            &dashes,
+            &None,
+            &None,
+            false,
+            clip,
+            &mut pixels,
+        );
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgb_triples()
+    };
+
+    let clipped = draw(true);
+    assert!(clipped.iter().any(|&(r, _, _)| r < 255));
+    assert!(clipped == draw(false));
+}
+
+#[test]
+fn test_clipped_contour_fills_the_same_spans() {
+    let tall_contour = &[
+        (20, -9_000),
+        (240, -8_000),
+        (250, -500),
+        (200, 120),
+        (230, 9_000),
+        (100, 9_500),
+        (-40, 8_500),
+        (30, 60),
+        (20, -9_000),
+    ];
+    let pixels = TilePixels::new(1);
+
+    let clipped = clip_contour(contour(tall_contour), pixels.bb()).collect::<Vec<_>>();
+    assert!(clipped.len() < tall_contour.len() - 1);
+
+    let spans = compute_fill_spans(contour(tall_contour), pixels.bb());
+    assert!(!spans.is_empty());
+    assert_eq!(
+        compute_fill_spans(clip_contour(contour(tall_contour), pixels.bb()), pixels.bb()),
+        spans
+    );
+}
+
+#[test]
+fn test_quick_style_rules() {
+    let osm_xml = r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6095"/>
+            <node id="3" lat="55.7499" lon="37.6095"/>
+            <node id="4" lat="55.7499" lon="37.6091"/>
+            <node id="5" lat="55.7497" lon="37.6085"/>
+            <node id="6" lat="55.7497" lon="37.6105"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="building" v="yes"/>
+            </way>
+            <way id="11"><nd ref="5"/><nd ref="6"/><tag k="highway" v="primary"/></way>
+        </osm>"#;
+
+    let red = Color { r: 255, g: 0, b: 0 };
+    let blue = Color { r: 0, g: 0, b: 255 };
+    let style_rules = vec![
+        StyleRule {
+            tag_match: TagMatch::Equals("building".to_string(), "yes".to_string()),
+            fill: Some(red.clone()),
+            stroke: None,
+            width: 0.0,
+        },
+        StyleRule {
+            tag_match: TagMatch::Exists("highway".to_string()),
+            fill: None,
+            stroke: Some(blue.clone()),
+            width: 4.0,
+        },
+    ];
+    let white = Color { r: 255, g: 255, b: 255 };
+    let styler = Styler::new(to_mapcss_rules(&style_rules, Some(white)), &StyleType::Josm, None);
+    let rendered = common::TestTile::with_styler("quick_style", osm_xml, styler).draw(Default::default());
+
+    let pixel_at = |x: usize, y: usize| rendered.triples[y * rendered.dimension + x];
+    assert_eq!(pixel_at(65, 83), (255, 0, 0));
+    assert_eq!(pixel_at(200, 199), (0, 0, 255));
+    assert_eq!(pixel_at(10, 10), (255, 255, 255));
+}
+
+#[test]
+fn test_line_decoration_ticks() {
+    let eastward_line = &[(10, 100), (60, 100), (110, 100)];
+    let decoration = |side| LineDecoration {
+        spacing: 20.0,
+        length: 5.0,
+        side,
+    };
+
+    let left_ticks = compute_ticks(contour(eastward_line), &decoration(TickSide::Left), 1.0);
+    let expected_left = [20, 40, 60, 80, 100]
+        .iter()
+        .map(|&x| (Point { x, y: 100 }, Point { x, y: 95 }))
+        .collect::<Vec<_>>();
+    assert_eq!(left_ticks, expected_left);
+
+    let right_ticks = compute_ticks(contour(eastward_line), &decoration(TickSide::Right), 2.0);
+    let expected_right = [30, 70, 110]
+        .iter()
+        .map(|&x| (Point { x, y: 100 }, Point { x, y: 110 }))
+        .collect::<Vec<_>>();
+    assert_eq!(right_ticks, expected_right);
+}
+
+#[test]
+fn test_flow_arrows_follow_way_direction() {
+    let test_tile = common::TestTile::new(
+        "flow_arrows",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6091"/>
+            <node id="2" lat="55.7500" lon="37.6097"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="waterway" v="stream"/></way>
+            <way id="11"><nd ref="2"/><nd ref="1"/><tag k="waterway" v="stream"/><tag k="name" v="Back"/></way>
+            <way id="12"><nd ref="1"/><nd ref="2"/><tag k="highway" v="footway"/></way>
+        </osm>"#,
+        "way { color: #0000ff; width: 2; } way[waterway] { flow-arrows: true; flow-arrow-spacing: 40; }",
+    );
+    let (entities, tile) = (test_tile.entities(), common::TEST_TILE);
+    let styled_ways = test_tile.styler.style_entities(entities.ways.iter(), 18, false);
+    assert_eq!(styled_ways.len(), 3);
+
+    for (way, style) in styled_ways {
+        let tags = way.tags();
+        let Some(arrows) = style.flow_arrows.as_ref() else {
+            assert!(tags.get_by_key("waterway").is_none());
+            continue;
+        };
+        assert_eq!(arrows.spacing, 40.0);
+
+        let flow_arrows = compute_flow_arrows(way.to_point_pairs(&tile, 1.0), arrows, 1.0);
+        assert_eq!(flow_arrows.len(), 3);
+        let points_east = tags.get_by_key("name").is_none();
+        for arrow in flow_arrows {
+            for wing in [&arrow.left_wing, &arrow.right_wing] {
+                assert_eq!(arrow.tip.x > wing.x, points_east);
+            }
+            assert!(arrow.left_wing.y != arrow.right_wing.y);
+        }
+    }
+}
+
+#[test]
+fn test_icon_allow_overlap() {
+    let count_icon_pixels = |allow_overlap: &str| {
+        let css = format!(
+            r#"canvas {{ fill-color: #ffffff; }}
+            node[aeroway=aerodrome] {{ icon-image: "symbols/aerodrome.p.16.png"; icon-allow-overlap: {}; }}"#,
+            allow_overlap
+        );
+        let test_tile = common::TestTile::new(
+            &format!("icon_allow_overlap_{}", allow_overlap),
+            r#"<osm version="0.6">
+                <node id="1" lat="55.7499" lon="37.60950"><tag k="aeroway" v="aerodrome"/></node>
+                <node id="2" lat="55.7499" lon="37.60955"><tag k="aeroway" v="aerodrome"/></node>
+            </osm>"#,
+            &css,
+        );
+        let rendered = test_tile.draw(Default::default());
+        rendered.triples.iter().filter(|&&p| p != (255, 255, 255)).count()
+    };
+
+    let single_icon = count_icon_pixels("false");
+    let both_icons = count_icon_pixels("true");
+    assert!(single_icon > 0);
+    assert!(
+        both_icons > single_icon,
+        "{} pixels with overlap vs {} without",
+        both_icons,
+        single_icon
+    );
+}
+
+#[test]
+fn test_multipolygon_holes_without_roles() {
+    let donut = r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6102"/>
+            <node id="3" lat="55.7496" lon="37.6102"/>
+            <node id="4" lat="55.7496" lon="37.6091"/>
+            <node id="5" lat="55.7500" lon="37.6094"/>
+            <node id="6" lat="55.7500" lon="37.6099"/>
+            <node id="7" lat="55.7498" lon="37.6099"/>
+            <node id="8" lat="55.7498" lon="37.6094"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <way id="12"><nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="inner"/>
+                <member type="way" ref="12" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="building" v="yes"/>
+            </relation>
+        </osm>"#;
+    let render = |name: &str, ignore_multipolygon_roles| {
+        let options = ImportOptions {
+            ignore_multipolygon_roles,
+            ..Default::default()
+        };
+        let mapcss = "canvas { fill-color: #ffffff; } area[building=yes] { fill-color: #ff0000; }";
+        let rendered = common::TestTile::with_import_options(name, donut, mapcss, &options).draw(Default::default());
+        move |x: usize, y: usize| rendered.triples[y * rendered.dimension + x]
+    };
+
+    // The outer ring is split between an `outer` and an `inner` way, so it can't be assembled by roles.
+    let by_roles = render("multipolygon_by_roles", false);
+    assert_eq!(by_roles(50, 60), (255, 255, 255));
+
+    let by_containment = render("multipolygon_by_containment", true);
+    assert_eq!(by_containment(50, 60), (255, 0, 0));
+    assert_eq!(by_containment(130, 133), (255, 255, 255));
+}
+
+#[test]
+fn test_jpeg_quality() {
+    let test_tile = common::TestTile::new(
+        "jpeg_quality",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <node id="3" lat="55.7502" lon="37.6102"/>
+            <node id="4" lat="55.7496" lon="37.6091"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="highway" v="secondary"/></way>
+        </osm>"#,
+        r#"canvas { fill-color: #f0f0e0; }
+        way[highway=primary] { color: #e04020; width: 5; }
+        way[highway=secondary] { color: #2040e0; width: 2; }"#,
+    );
+    let entities = test_tile.entities();
+
+    let encode = |quality, chroma_subsampling| {
+        let options = RenderOptions {
+            jpeg: JpegOptions {
+                quality,
+                chroma_subsampling,
+            },
+            ..Default::default()
+        };
+        common::TestTile::drawer(options)
+            .draw_tile_jpeg(
+                &entities,
+                &common::TEST_TILE,
+                &mut TilePixels::new(1),
+                1,
+                &test_tile.styler,
+            )
+            .unwrap()
+    };
+
+    let original = test_tile.draw(Default::default()).triples;
+    // The mean difference of all color components from the image before it was encoded.
+    let decoding_error = |jpeg: &[u8]| {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        let decoded = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!((info.width, info.height), (256, 256));
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::RGB24);
+        // A canvas pixel away from both roads keeps its color.
+        let canvas_pixel = &decoded[3 * (5 * 256 + 128)..3 * (5 * 256 + 128) + 3];
+        for (decoded, expected) in canvas_pixel.iter().zip([0xF0, 0xF0, 0xE0]) {
+            assert!(decoded.abs_diff(expected) <= 4, "{:?}", canvas_pixel);
+        }
+        let original = original.iter().flat_map(|&(r, g, b)| [r, g, b]);
+        let total_error = decoded
+            .iter()
+            .zip(original)
+            .map(|(&decoded, original)| u32::from(decoded.abs_diff(original)))
+            .sum::<u32>();
+        f64::from(total_error) / decoded.len() as f64
+    };
+
+    for chroma_subsampling in [ChromaSubsampling::Yuv420, ChromaSubsampling::Yuv444] {
+        let low = encode(50, chroma_subsampling);
+        let high = encode(90, chroma_subsampling);
+        assert!(low.len() < high.len(), "{} vs {} bytes", low.len(), high.len());
+        let (low_error, high_error) = (decoding_error(&low), decoding_error(&high));
+        assert!(high_error < low_error, "{} vs {}", high_error, low_error);
+        assert!(low_error < 4.0, "{}", low_error);
+    }
+    assert!(encode(90, ChromaSubsampling::Yuv420).len() < encode(90, ChromaSubsampling::Yuv444).len());
+}
+
+#[test]
+fn test_png_bit_depth_and_color_profile() {
+    let test_tile = common::TestTile::new(
+        "png_bit_depth",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+        r#"canvas { fill-color: #336699; }
+        way[highway=primary] { color: #e04020; width: 5; opacity: 0.7; }"#,
+    );
+    let entities = test_tile.entities();
+
+    let encode = |bit_depth, color_profile| {
+        let options = RenderOptions {
+            png: PngOptions {
+                bit_depth,
+                color_profile,
+            },
+            ..Default::default()
+        };
+        let png_bytes = common::TestTile::drawer(options)
+            .draw_tile(
+                &entities,
+                &common::TEST_TILE,
+                &mut TilePixels::new(1),
+                1,
+                &test_tile.styler,
+            )
+            .unwrap();
+
+        let mut png_reader = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+        let mut image = vec![0; png_reader.output_buffer_size()];
+        png_reader.next_frame(&mut image).unwrap();
+        let info = png_reader.info();
+        assert_eq!((info.width, info.height), (256, 256));
+        (
+            info.bit_depth,
+            info.srgb,
+            info.icc_profile.clone().map(|p| p.into_owned()),
+            image,
+        )
+    };
+
+    let (bit_depth, srgb, icc_profile, image8) = encode(PngBitDepth::Eight, ColorProfile::None);
+    assert_eq!((bit_depth, srgb, icc_profile), (png::BitDepth::Eight, None, None));
+
+    let (bit_depth, srgb, icc_profile, image16) = encode(PngBitDepth::Sixteen, ColorProfile::Srgb);
+    assert_eq!(bit_depth, png::BitDepth::Sixteen);
+    assert!(srgb.is_some());
+    assert_eq!(icc_profile, None);
+    assert_eq!(image16.len(), 2 * image8.len());
+
+    // The canvas color is scaled exactly, and the blended line pixels match the 8-bit ones.
+    let sample16 = |idx: usize| u16::from_be_bytes([image16[2 * idx], image16[2 * idx + 1]]);
+    assert_eq!((sample16(0), sample16(1), sample16(2)), (0x3333, 0x6666, 0x9999));
+    assert!(image8.chunks(3).any(|rgb| rgb != [0x33, 0x66, 0x99]));
+    for (idx, &sample8) in image8.iter().enumerate() {
+        let difference = i32::from(sample16(idx)) - 257 * i32::from(sample8);
+        assert!((0..=257).contains(&difference), "{} vs {}", sample16(idx), sample8);
+    }
+
+    let profile: Arc<[u8]> = Arc::from(&b"not really an ICC profile"[..]);
+    let (bit_depth, srgb, icc_profile, _) = encode(PngBitDepth::Eight, ColorProfile::Icc(profile.clone()));
+    assert_eq!((bit_depth, srgb), (png::BitDepth::Eight, None));
+    assert_eq!(icc_profile.as_deref(), Some(&profile[..]));
+}
+
+#[test]
+fn test_graticule() {
+    let tile = Tile { x: 154, y: 80, zoom: 8 };
+
+    // The tile spans 36.5625°E to 37.96875°E and 54.16°N to 55.03°N, so exactly one meridian
+    // and one parallel cross it.
+    let graticule = compute_graticule(&GraticuleSpacing::Degrees(1.0), &tile, 1.0);
+    assert_eq!(
+        graticule.lines,
+        vec![
+            (Point { x: 80, y: 0 }, Point { x: 80, y: 256 }),
+            (Point { x: 0, y: 249 }, Point { x: 256, y: 249 }),
+        ]
+    );
+    assert_eq!(graticule.labels.len(), 1);
+    assert_eq!(graticule.labels[0].0, "55°N 37°E");
+
+    // Steps that aren't positive or are too fine to tell the lines apart draw nothing.
+    for step in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e-9] {
+        let graticule = compute_graticule(&GraticuleSpacing::Degrees(step), &tile, 1.0);
+        assert!(graticule.lines.is_empty() && graticule.labels.is_empty(), "{}", step);
+    }
+
+    let reader = common::import_osm_str(
+        "graticule",
+        r#"<osm version="0.6"><node id="1" lat="10.0" lon="10.0"/></osm>"#,
+    );
+    let styler = Styler::new(
+        common::parse_style_str("graticule", "canvas { fill-color: #ffffff; }"),
+        &StyleType::Josm,
+        None,
+    );
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let render = |graticule| {
+        let options = RenderOptions {
+            graticule,
+            ..Default::default()
+        };
+        let drawer = Drawer::with_options(Path::new("."), options);
+        let mut pixels = TilePixels::new(1);
+        let rendered = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        move |x: usize, y: usize| rendered.triples[y * rendered.dimension + x]
+    };
+
+    let white = (255, 255, 255);
+    let without_graticule = render(None);
+    assert_eq!(without_graticule(80, 100), white);
+
+    let with_graticule = render(Some(GraticuleOptions {
+        spacing: GraticuleSpacing::Degrees(1.0),
+        color: Color { r: 255, g: 0, b: 255 },
+        labels: false,
+        ..Default::default()
+    }));
+    assert_eq!(with_graticule(80, 100), (255, 0, 255));
+    assert_eq!(with_graticule(200, 249), (255, 0, 255));
+    assert_eq!(with_graticule(150, 100), white);
+    assert_eq!(with_graticule(84, 100), white);
+
+    let with_tile_border = render(Some(GraticuleOptions::default()));
+    assert_ne!(with_tile_border(0, 100), white);
+    assert_ne!(with_tile_border(100, 0), white);
+    let label_pixels = (118..138)
+        .flat_map(|y| (100..156).map(move |x| (x, y)))
+        .filter(|&(x, y)| with_tile_border(x, y) != white)
+        .count();
+    assert!(label_pixels > 0);
+}
+
+#[test]
+fn test_custom_output_backend() {
+    #[derive(Default)]
+    struct CountingBackend {
+        tiles: Vec<Tile>,
+        commands: Vec<&'static str>,
+        refuses_tiles: bool,
+    }
+
+    impl OutputBackend for CountingBackend {
+        fn begin_tile(&mut self, tile: &Tile, _: usize, _: &Styler) -> Result<()> {
+            if self.refuses_tiles {
+                anyhow::bail!("Can't draw {:?}", tile);
+            }
+            self.tiles.push(tile.clone());
+            Ok(())
+        }
+
+        fn draw_command(&mut self, command: &DrawCommand<'_, '_>) {
+            self.commands.push(match command {
+                DrawCommand::Area {
+                    draw_type: DrawType::Fill,
+                    ..
+                } => "fill",
+                DrawCommand::Area {
+                    draw_type: DrawType::Casing,
+                    ..
+                } => "casing",
+                DrawCommand::Area {
+                    draw_type: DrawType::Stroke,
+                    ..
+                } => "stroke",
+                DrawCommand::AreaLabel { .. } => "area label",
+                DrawCommand::NodeSymbol { .. } => "node symbol",
+                DrawCommand::NodeLabel { .. } => "node label",
+                DrawCommand::Graticule(_) => "graticule",
+                DrawCommand::Hillshade(_) => "hillshade",
+                DrawCommand::Scrim(_) => "scrim",
+            });
+        }
+
+        fn finish(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![self.commands.len() as u8])
+        }
+    }
+
+    let test_tile = common::TestTile::new(
+        "custom_backend",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <node id="3" lat="55.7499" lon="37.6095"><tag k="amenity" v="cafe"/><tag k="name" v="Cafe"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="1"/><nd ref="2"/><tag k="waterway" v="stream"/></way>
+        </osm>"#,
+        "way[highway] { color: #000000; width: 1; } way[waterway] { color: #0000ff; width: 1; } \
+         node[amenity] { text: name; }",
+    );
+    let (entities, tile, styler) = (test_tile.entities(), common::TEST_TILE, &test_tile.styler);
+    let drawer = common::TestTile::drawer(RenderOptions {
+        graticule: Some(GraticuleOptions::default()),
+        ..Default::default()
+    });
+
+    let mut backend = CountingBackend::default();
+    let output = drawer.render(&entities, &tile, 1, styler, &mut backend).unwrap();
+
+    assert_eq!(backend.tiles, vec![tile.clone()]);
+    assert_eq!(
+        backend.commands,
+        vec![
+            "fill",
+            "fill",
+            "casing",
+            "casing",
+            "stroke",
+            "stroke",
+            "area label",
+            "area label",
+            "node label",
+            "graticule",
+        ]
+    );
+    assert_eq!(output, vec![10]);
+
+    let mut refusing_backend = CountingBackend {
+        refuses_tiles: true,
+        ..Default::default()
+    };
+    let error = drawer
+        .render(&entities, &tile, 1, styler, &mut refusing_backend)
+        .unwrap_err();
+    assert!(error.to_string().starts_with("Can't draw"));
+    assert!(refusing_backend.commands.is_empty());
+}
+
+#[test]
+fn test_text_smoothing_softens_label_edges() {
+    let render = |smoothing: &str| {
+        let mapcss = format!(
+            "canvas {{ fill-color: #ffffff; }} \
+             node[amenity] {{ text: name; font-size: 9; text-color: #000000; text-smoothing: {}; }}",
+            smoothing
+        );
+        let test_tile = common::TestTile::new(
+            &format!("text_smoothing_{}", smoothing),
+            r#"<osm version="0.6">
+                <node id="1" lat="55.7499" lon="37.6095"><tag k="amenity" v="cafe"/><tag k="name" v="Moscow Cafe"/></node>
+            </osm>"#,
+            &mapcss,
+        );
+        test_tile.draw(Default::default()).triples
+    };
+    let intermediate_pixel_count = |triples: &[(u8, u8, u8)]| {
+        triples
+            .iter()
+            .filter(|&&pixel| pixel != (255, 255, 255) && pixel != (0, 0, 0))
+            .count()
+    };
+
+    let rough = render("false");
+    let smooth = render("true");
+    assert!(intermediate_pixel_count(&rough) > 0);
+    assert!(intermediate_pixel_count(&smooth) > intermediate_pixel_count(&rough));
+
+    // The text only gets softer, it doesn't spread beyond the pixels right next to the glyphs.
+    let is_text = |triples: &[(u8, u8, u8)], x: usize, y: usize| triples[y * 256 + x] != (255, 255, 255);
+    for y in 1..255 {
+        for x in 1..255 {
+            if is_text(&smooth, x, y) {
+                assert!((y - 1..=y + 1).any(|ny| (x - 1..=x + 1).any(|nx| is_text(&rough, nx, ny))));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_label_thinning_keeps_one_label_per_cell() {
+    #[derive(Default)]
+    struct NodeLabelBackend {
+        labeled_ids: Vec<u64>,
+    }
+
+    impl OutputBackend for NodeLabelBackend {
+        fn begin_tile(&mut self, _: &Tile, _: usize, _: &Styler) -> Result<()> {
+            Ok(())
+        }
+
+        fn draw_command(&mut self, command: &DrawCommand<'_, '_>) {
+            if let DrawCommand::NodeLabel { node, .. } = command {
+                self.labeled_ids.push(node.global_id());
+            }
+        }
+
+        fn finish(&mut self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+    }
+
+    // Nodes 1-3 are in the same 64x64 pixel cell of the tile, node 4 is in another one.
+    let test_tile = common::TestTile::new(
+        "label_thinning",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7499" lon="37.6098"><tag k="place" v="village"/><tag k="name" v="A"/></node>
+            <node id="2" lat="55.7498" lon="37.6097"><tag k="place" v="village"/><tag k="name" v="B"/></node>
+            <node id="3" lat="55.7498" lon="37.6098"><tag k="place" v="village"/><tag k="name" v="C"/></node>
+            <node id="4" lat="55.7500" lon="37.6091"><tag k="place" v="village"/><tag k="name" v="D"/></node>
+        </osm>"#,
+        "node[place] { text: name; font-size: 10; }",
+    );
+    let entities = test_tile.entities();
+    let labeled_ids = |label_thinning| {
+        let options = RenderOptions {
+            label_thinning,
+            ..Default::default()
+        };
+        let mut backend = NodeLabelBackend::default();
+        common::TestTile::drawer(options)
+            .render(&entities, &common::TEST_TILE, 1, &test_tile.styler, &mut backend)
+            .unwrap();
+        backend.labeled_ids
+    };
+
+    assert_eq!(labeled_ids(None), [1, 2, 3, 4]);
+    let thinning = |max_zoom| Some(LabelThinning::new(64.0, max_zoom).unwrap());
+    assert_eq!(labeled_ids(thinning(18)), [1, 4]);
+    assert_eq!(labeled_ids(thinning(17)), [1, 2, 3, 4]);
+
+    for cell_size in [0.0, -64.0, f64::NAN, f64::INFINITY] {
+        assert!(LabelThinning::new(cell_size, 18).is_err());
+    }
+}
+
+#[test]
+fn test_label_position_avoids_concavities_and_holes() {
+    // A C-shaped way opening to the east, whose centroid is in the concavity, and a multipolygon
+    // with a small outer ring and a large ring around an island.
+    let reader = common::import_osm_str(
+        "label_position",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6097"/>
+            <node id="3" lat="55.7501" lon="37.6097"/>
+            <node id="4" lat="55.7501" lon="37.60925"/>
+            <node id="5" lat="55.7497" lon="37.60925"/>
+            <node id="6" lat="55.7497" lon="37.6097"/>
+            <node id="7" lat="55.7496" lon="37.6097"/>
+            <node id="8" lat="55.7496" lon="37.6091"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/>
+                <nd ref="1"/>
+                <tag k="landuse" v="grass"/>
+            </way>
+
+            <node id="11" lat="55.7502" lon="37.6100"/>
+            <node id="12" lat="55.7502" lon="37.6101"/>
+            <node id="13" lat="55.7501" lon="37.6101"/>
+            <node id="14" lat="55.7500" lon="37.6100"/>
+            <node id="15" lat="55.7500" lon="37.6106"/>
+            <node id="16" lat="55.7494" lon="37.6106"/>
+            <node id="17" lat="55.7494" lon="37.6100"/>
+            <node id="18" lat="55.7498" lon="37.6102"/>
+            <node id="19" lat="55.7498" lon="37.6104"/>
+            <node id="20" lat="55.7496" lon="37.6104"/>
+            <node id="21" lat="55.7496" lon="37.6102"/>
+            <way id="30"><nd ref="11"/><nd ref="12"/><nd ref="13"/><nd ref="11"/></way>
+            <way id="31"><nd ref="14"/><nd ref="15"/><nd ref="16"/><nd ref="17"/><nd ref="14"/></way>
+            <way id="32"><nd ref="18"/><nd ref="19"/><nd ref="20"/><nd ref="21"/><nd ref="18"/></way>
+            <relation id="40">
+                <member type="way" ref="30" role="outer"/>
+                <member type="way" ref="31" role="outer"/>
+                <member type="way" ref="32" role="inner"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="natural" v="water"/>
+            </relation>
+        </osm>"#,
+    );
+
+    let tile = common::TEST_TILE;
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let way = entities
+        .ways
+        .iter()
+        .find(|w| w.tags().get_by_key("landuse").is_some())
+        .unwrap();
+    let ring = way.get_waypoints(&tile, 1.0).unwrap();
+
+    let (x, y) = way.get_label_position(&tile, 1.0).unwrap();
+    let (inner_x, top_arm_y, bottom_arm_y) = (ring[3].x, ring[2].y, ring[4].y);
+    assert!(ring[0].x < inner_x && ring[0].y < top_arm_y && top_arm_y < bottom_arm_y);
+    let in_spine = f64::from(ring[0].x) < x && x < f64::from(inner_x);
+    let in_arm = f64::from(top_arm_y) > y || y > f64::from(bottom_arm_y);
+    assert!(in_spine || in_arm, "({}, {}) is in the concavity", x, y);
+    assert!(x < f64::from(ring[1].x) && f64::from(ring[0].y) < y && y < f64::from(ring[7].y));
+
+    let lake = &entities.multipolygons[0];
+    let waypoints = |way_id| {
+        let way = entities.ways.iter().find(|w| w.global_id() == way_id).unwrap();
+        way.get_waypoints(&tile, 1.0).unwrap()
+    };
+    let (lake_ring, island) = (waypoints(31), waypoints(32));
+    let (x, y) = lake.get_label_position(&tile, 1.0).unwrap();
+    let inside = |ring: &[Point]| {
+        f64::from(ring[0].x) < x && x < f64::from(ring[1].x) && f64::from(ring[0].y) < y && y < f64::from(ring[2].y)
+    };
+    assert!(inside(&lake_ring), "({}, {}) is outside of the largest ring", x, y);
+    assert!(!inside(&island), "({}, {}) is on the island", x, y);
+}
+
+#[test]
+fn test_label_node_of_boundary_relation() {
+    // A square administrative area with a `label` node near its north-eastern corner and an `admin_centre`
+    // node near the south-western one, and the same square with just the `admin_centre`.
+    let reader = common::import_osm_str(
+        "label_node",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6101"/>
+            <node id="3" lat="55.7494" lon="37.6101"/>
+            <node id="4" lat="55.7494" lon="37.6091"/>
+            <node id="5" lat="55.7501" lon="37.6099"/>
+            <node id="6" lat="55.7495" lon="37.6092"><tag k="place" v="town"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="node" ref="6" role="admin_centre"/>
+                <member type="way" ref="10" role="outer"/>
+                <member type="node" ref="5" role="label"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="boundary" v="administrative"/>
+                <tag k="name" v="Town"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="10" role="outer"/>
+                <member type="node" ref="6" role="admin_centre"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="boundary" v="administrative"/>
+            </relation>
+        </osm>"#,
+    );
+
+    let tile = common::TEST_TILE;
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let boundary = |relation_id| {
+        entities
+            .multipolygons
+            .iter()
+            .find(|mp| mp.global_id() == relation_id)
+            .unwrap()
+    };
+    let label_node_position = |relation_id, node_id| {
+        let node = boundary(relation_id).label_node().unwrap();
+        assert_eq!(node.global_id(), node_id);
+        node.get_label_position(&tile, 1.0).unwrap()
+    };
+
+    let (x, y) = boundary(20).get_label_position(&tile, 1.0).unwrap();
+    assert_eq!((x, y), label_node_position(20, 5));
+    let square = entities.ways[0].get_waypoints(&tile, 1.0).unwrap();
+    let center_x = f64::from(square[0].x + square[1].x) / 2.0;
+    let center_y = f64::from(square[0].y + square[2].y) / 2.0;
+    assert!(x > center_x && y < center_y, "({}, {}) is not at the label node", x, y);
+
+    let admin_centre_position = boundary(21).get_label_position(&tile, 1.0).unwrap();
+    assert_eq!(admin_centre_position, label_node_position(21, 6));
+}
+
+#[test]
+fn test_fill_hatch_depends_on_tags() {
+    let test_tile = common::TestTile::new(
+        "fill_hatch",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6094"/>
+            <node id="3" lat="55.7498" lon="37.6094"/>
+            <node id="4" lat="55.7498" lon="37.6091"/>
+            <node id="5" lat="55.7502" lon="37.6096"/>
+            <node id="6" lat="55.7502" lon="37.6099"/>
+            <node id="7" lat="55.7498" lon="37.6099"/>
+            <node id="8" lat="55.7498" lon="37.6096"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="landuse" v="cemetery"/>
+            </way>
+            <way id="11">
+                <nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/>
+                <tag k="landuse" v="orchard"/>
+            </way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } \
+         area[landuse=cemetery] { fill-hatch: cross; hatch-color: #000000; hatch-spacing: 8; } \
+         area[landuse=orchard] { fill-hatch: dots; hatch-color: #000000; hatch-spacing: 4; hatch-width: 2; }",
+    );
+    let (entities, tile) = (test_tile.entities(), common::TEST_TILE);
+    let styles = test_tile.styler.style_entities(entities.ways.iter(), 18, false);
+    let hatch_for = |landuse| {
+        let (_, style) = styles
+            .iter()
+            .find(|(way, _)| way.tags().get_by_key("landuse") == Some(landuse))
+            .unwrap();
+        style.fill_hatch.clone().unwrap()
+    };
+    let (cemetery_hatch, orchard_hatch) = (hatch_for("cemetery"), hatch_for("orchard"));
+    assert_ne!(cemetery_hatch.pattern, orchard_hatch.pattern);
+
+    let rendered = test_tile.draw(Default::default());
+
+    let black_pixels_in = |from_x: i32| {
+        let mut black_pixels = Vec::new();
+        for y in 80..120 {
+            for x in from_x..from_x + 32 {
+                if rendered.triples[y as usize * 256 + x as usize] == (0, 0, 0) {
+                    black_pixels.push((x, y));
+                }
+            }
+        }
+        black_pixels
+    };
+    let expected_pixels_in = |from_x: i32, hatch| {
+        let mut expected = Vec::new();
+        for y in 80..120 {
+            for x in from_x..from_x + 32 {
+                let (global_x, global_y) = (
+                    f64::from(tile.x * 256) + f64::from(x),
+                    f64::from(tile.y * 256) + f64::from(y),
+                );
+                if is_on_hatch(hatch, 1.0, global_x, global_y) {
+                    expected.push((x, y));
+                }
+            }
+        }
+        expected
+    };
+
+    let (cemetery_pixels, orchard_pixels) = (black_pixels_in(40), black_pixels_in(130));
+    assert!(!cemetery_pixels.is_empty() && !orchard_pixels.is_empty());
+    assert_eq!(cemetery_pixels, expected_pixels_in(40, &cemetery_hatch));
+    assert_eq!(orchard_pixels, expected_pixels_in(130, &orchard_hatch));
+    // Only the cross-hatch has solid lines.
+    let full_rows = |pixels: &[(i32, i32)]| {
+        (80..120)
+            .filter(|&y| pixels.iter().filter(|p| p.1 == y).count() == 32)
+            .count()
+    };
+    assert!(full_rows(&cemetery_pixels) > 0);
+    assert_eq!(full_rows(&orchard_pixels), 0);
+}
+
+#[test]
+fn test_fill_hatch_continues_across_tiles() {
+    // The area covers two neighbouring tiles, and the spacing doesn't divide the tile size.
+    let test_tile = common::TestTile::new(
+        "fill_hatch_seam",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7506" lon="37.6085"/>
+            <node id="2" lat="55.7506" lon="37.6120"/>
+            <node id="3" lat="55.7492" lon="37.6120"/>
+            <node id="4" lat="55.7492" lon="37.6085"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="landuse" v="cemetery"/>
+            </way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } \
+         area[landuse=cemetery] { fill-hatch: vertical; hatch-color: #000000; hatch-spacing: 10; }",
+    );
+    let drawer = common::TestTile::drawer(Default::default());
+
+    let mut hatch_columns = Vec::new();
+    for (tile_idx, x) in [common::TEST_TILE.x, common::TEST_TILE.x + 1].into_iter().enumerate() {
+        let tile = Tile { x, ..common::TEST_TILE };
+        let entities = test_tile.reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let rendered = drawer.draw_to_pixels(&entities, &tile, &mut TilePixels::new(1), 1, &test_tile.styler);
+        for column in 0..256 {
+            if rendered.triples[128 * 256 + column] == (0, 0, 0) {
+                hatch_columns.push(tile_idx * 256 + column);
+            }
+        }
+    }
+
+    assert!(hatch_columns.len() > 40);
+    let gaps = hatch_columns
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .collect::<HashSet<_>>();
+    assert_eq!(gaps, HashSet::from([10]));
+}
+
+#[test]
+fn test_fill_hatch_needs_positive_spacing_and_width() {
+    let test_tile = common::TestTile::new(
+        "fill_hatch_invalid",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6094"/>
+            <node id="3" lat="55.7498" lon="37.6094"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/><tag k="landuse" v="cemetery"/></way>
+            <way id="11"><nd ref="1"/><nd ref="3"/><nd ref="2"/><nd ref="1"/><tag k="landuse" v="orchard"/></way>
+        </osm>"#,
+        "area[landuse=cemetery] { fill-hatch: cross; hatch-spacing: 0; hatch-width: -2; } \
+         area[landuse=orchard] { fill-hatch: dots; hatch-spacing: 0.5; hatch-width: 0.25; }",
+    );
+    let entities = test_tile.entities();
+    let styles = test_tile.styler.style_entities(entities.ways.iter(), 18, false);
+    let hatches = styles
+        .iter()
+        .map(|(_, style)| {
+            let hatch = style.fill_hatch.as_ref().unwrap();
+            (hatch.spacing, hatch.width)
+        })
+        .collect::<Vec<_>>();
+    // Non-positive values fall back to the defaults, small positive ones are kept.
+    assert_eq!(hatches, [(8.0, 1.0), (0.5, 0.25)]);
+}
+
+// A 20x20 grid of 0.001 degree cells around the test tile.
+fn test_dem(elevation: impl Fn(usize, usize) -> f32) -> Dem {
+    let elevations = (0..20)
+        .flat_map(|row| (0..20).map(move |col| (row, col)))
+        .map(|(row, col)| elevation(row, col));
+    Dem::new(20, 20, (55.76, 37.60), (0.001, 0.001), elevations.collect()).unwrap()
+}
+
+#[test]
+fn test_hillshade_of_constant_slope_and_flat_terrain() {
+    let tile = common::TEST_TILE;
+    let shades = |dem| compute_hillshade(&DemSource::new(dem), &tile, 1);
+
+    let flat = shades(test_dem(|_, _| 150.0));
+    assert_eq!(flat.len(), 256 * 256);
+    assert!(flat.iter().all(|shade| *shade == Some(0.0)));
+
+    // With the default sun in the north-west, a slope rising to the east faces it.
+    let rising_east = shades(test_dem(|_, col| 10.0 * col as f32))
+        .into_iter()
+        .map(Option::unwrap)
+        .collect::<Vec<_>>();
+    let (min, max) = rising_east
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(min, max), &s| (min.min(s), max.max(s)));
+    assert!(min > 0.0);
+    assert!(max - min < 1e-3);
+
+    let rising_west = shades(test_dem(|_, col| 10.0 * (20 - col) as f32));
+    assert!(rising_west.iter().all(|shade| shade.unwrap() < 0.0));
+}
+
+#[test]
+fn test_hillshade_is_transparent_outside_of_dem() {
+    let tile = common::TEST_TILE;
+    // Covers the tile up to lon 37.6091, which is x=28.
+    let dem = Dem::new(1, 1, (55.76, 37.59), (0.02, 0.0191), vec![0.0]).unwrap();
+    let shades = compute_hillshade(&DemSource::new(dem), &tile, 1);
+    for y in 0..256 {
+        assert!(shades[y * 256 + 20].is_some());
+        assert!(shades[y * 256 + 40].is_none());
+    }
+
+    let test_tile = common::TestTile::new(
+        "hillshade",
+        r#"<osm version="0.6"></osm>"#,
+        "canvas { fill-color: #ffffff; }",
+    );
+    let rendered = test_tile.draw(RenderOptions {
+        hillshade: Some(DemSource::new(test_dem(|_, col| 10.0 * (20 - col) as f32))),
+        ..Default::default()
+    });
+    assert!(rendered.triples.iter().all(|&(r, g, b)| r == g && g == b && r < 255));
+}
+
+// A 3x2 float GeoTIFF with a cell without data, except for the declared bits per sample.
+fn geotiff_bytes(bits_per_sample: u16) -> Vec<u8> {
+    let values: [f32; 6] = [1.0, 2.0, 3.0, 4.0, -9999.0, 6.0];
+    let nodata = b"-9999\0";
+    let header_size = 8;
+    let entries: Vec<(u16, u16, u32, Vec<u8>)> = {
+        let short = |v: u16| v.to_le_bytes().to_vec();
+        let doubles = |v: &[f64]| v.iter().flat_map(|d| d.to_le_bytes()).collect::<Vec<_>>();
+        vec![
+            (256, 3, 1, short(3)),
+            (257, 3, 1, short(2)),
+            (258, 3, 1, short(bits_per_sample)),
+            (259, 3, 1, short(1)),
+            (273, 4, 1, vec![]),
+            (277, 3, 1, short(1)),
+            (279, 4, 1, (values.len() as u32 * 4).to_le_bytes().to_vec()),
+            (339, 3, 1, short(3)),
+            (33550, 12, 3, doubles(&[0.5, 0.25, 0.0])),
+            (33922, 12, 6, doubles(&[0.0, 0.0, 0.0, 37.0, 56.0, 0.0])),
+            (42113, 2, nodata.len() as u32, nodata.to_vec()),
+        ]
+    };
+
+    let ifd_size = 2 + entries.len() * 12 + 4;
+    let mut extra = Vec::new();
+    let mut bytes = b"II".to_vec();
+    bytes.extend(42u16.to_le_bytes());
+    bytes.extend((header_size as u32).to_le_bytes());
+    bytes.extend((entries.len() as u16).to_le_bytes());
+    let extra_offset = header_size + ifd_size;
+    let strip_offset = extra_offset
+        + entries
+            .iter()
+            .filter(|e| e.3.len() > 4)
+            .map(|e| e.3.len())
+            .sum::<usize>();
+    for (tag, field_type, count, mut data) in entries {
+        bytes.extend(tag.to_le_bytes());
+        bytes.extend(field_type.to_le_bytes());
+        bytes.extend(count.to_le_bytes());
+        if tag == 273 {
+            data = (strip_offset as u32).to_le_bytes().to_vec();
+        }
+        if data.len() > 4 {
+            bytes.extend(((extra_offset + extra.len()) as u32).to_le_bytes());
+            extra.extend(data);
+        } else {
+            data.resize(4, 0);
+            bytes.extend(data);
+        }
+    }
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend(extra);
+    bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    bytes
+}
+
+#[test]
+fn test_dem_from_geotiff() {
+    let path = common::get_tmp_path("dem.tif");
+    std::fs::write(&path, geotiff_bytes(32)).unwrap();
+    let dem = Dem::load_geotiff(&path).unwrap();
+
+    // Cell centers.
+    assert_eq!(dem.elevation_at(55.875, 37.25), Some(1.0));
+    assert_eq!(dem.elevation_at(55.875, 37.75), Some(2.0));
+    assert_eq!(dem.elevation_at(55.875, 37.5), Some(1.5));
+    // Next to the cell without data.
+    assert_eq!(dem.elevation_at(55.625, 37.25), Some(4.0));
+    assert_eq!(dem.elevation_at(55.625, 37.5), None);
+    assert_eq!(dem.elevation_at(55.875, 38.6), None);
+    assert_eq!(dem.elevation_at(55.4, 37.25), None);
+}
+
+#[test]
+fn test_dem_with_unsupported_sample_size_is_rejected() {
+    for bits_per_sample in [0, 1, 12, 24] {
+        let path = common::get_tmp_path(&format!("dem_{}_bits.tif", bits_per_sample));
+        std::fs::write(&path, geotiff_bytes(bits_per_sample)).unwrap();
+        let error = Dem::load_geotiff(&path).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains(&format!("Unsupported sample format 3 with {} bits", bits_per_sample)),
+            "{:#}",
+            error
+        );
+    }
+}
+
+#[test]
+fn test_debug_colors_are_derived_from_ids() {
+    assert_eq!(debug_color(42), debug_color(42));
+    for id in 0..1000 {
+        assert_ne!(debug_color(id), debug_color(id + 1));
+    }
+
+    let test_tile = common::TestTile::new(
+        "debug_colors",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7501" lon="37.6088"/>
+            <node id="2" lat="55.7501" lon="37.6100"/>
+            <node id="3" lat="55.7499" lon="37.6088"/>
+            <node id="4" lat="55.7499" lon="37.6100"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="barrier" v="fence"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="barrier" v="fence"/></way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; }",
+    );
+    let entities = test_tile.entities();
+    let render = |debug_colors| {
+        test_tile.draw(RenderOptions {
+            debug_colors,
+            ..Default::default()
+        })
+    };
+
+    // The style doesn't draw fences at all.
+    assert!(render(DebugColorMode::Off)
+        .triples
+        .iter()
+        .all(|&p| p == (255, 255, 255)));
+
+    // Each fence is drawn in the color of its own ID.
+    let rendered = render(DebugColorMode::ById);
+    let fence_colors = entities
+        .ways
+        .iter()
+        .map(|way| {
+            let start = &way.get_waypoints(&common::TEST_TILE, 1.0).unwrap()[0];
+            let color = debug_color(way.global_id());
+            (
+                (color.r, color.g, color.b),
+                rendered.triples[start.y as usize * 256 + 128],
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(fence_colors.len(), 2);
+    assert_ne!(fence_colors[0].0, fence_colors[1].0);
+    for (expected, drawn) in fence_colors {
+        assert_eq!(drawn, expected);
+    }
+}
+
+#[test]
+fn test_feature_index_reports_topmost_feature() {
+    // A wide road across a building, and a relation with the same ID as the road.
+    let test_tile = common::TestTile::new(
+        "feature_index",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6085"/>
+            <node id="2" lat="55.7500" lon="37.6110"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+
+            <node id="3" lat="55.7502" lon="37.6093"/>
+            <node id="4" lat="55.7502" lon="37.6099"/>
+            <node id="5" lat="55.7498" lon="37.6099"/>
+            <node id="6" lat="55.7498" lon="37.6093"/>
+            <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="5"/><nd ref="6"/><nd ref="3"/></way>
+            <relation id="10">
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="building" v="yes"/>
+            </relation>
+        </osm>"#,
+        r#"canvas { fill-color: #ffffff; }
+        area[building] { fill-color: #c0a080; }
+        way[highway] { color: #404040; width: 9; }"#,
+    );
+    let (entities, tile) = (test_tile.entities(), common::TEST_TILE);
+
+    let render = |feature_index| {
+        test_tile.draw(RenderOptions {
+            feature_index,
+            ..Default::default()
+        })
+    };
+    assert!(render(false).feature_index.is_none());
+    let index = render(true).feature_index.unwrap();
+    assert_eq!(index.dimension(), 256);
+
+    let waypoints = |way_id| {
+        let way = entities.ways.iter().find(|w| w.global_id() == way_id).unwrap();
+        way.get_waypoints(&tile, 1.0).unwrap()
+    };
+    let (road, building) = (waypoints(10), waypoints(11));
+    let road_y = road[0].y as usize;
+    let building_x = ((building[0].x + building[1].x) / 2) as usize;
+    let (building_top, building_bottom) = (building[0].y as usize, building[2].y as usize);
+    assert!(building_top + 10 < road_y && road_y + 10 < building_bottom);
+
+    let road_id = Some(FeatureId {
+        kind: EntityKind::Way,
+        global_id: 10,
+    });
+    let building_id = Some(FeatureId {
+        kind: EntityKind::Relation,
+        global_id: 10,
+    });
+    // The road is drawn on top of the building, and its whole width counts.
+    assert_eq!(index.get(building_x, road_y), road_id);
+    assert_eq!(index.get(building_x, road_y + 3), road_id);
+    assert_eq!(index.get(10, road_y - 3), road_id);
+    assert_eq!(index.get(building_x, road_y + 8), building_id);
+    assert_eq!(index.get(building_x, building_bottom - 2), building_id);
+    assert_eq!(index.get(building_x, building_bottom + 5), None);
+    assert_eq!(index.get(250, 250), None);
+}
+
+#[test]
+fn test_edge_snapping_closes_gaps_at_tile_seams() {
+    // Two farmlands meet at the seam between two tiles, but their edges land 0.6 pixels away from it.
+    let east_tile = Tile {
+        x: common::TEST_TILE.x + 1,
+        ..common::TEST_TILE
+    };
+    let seam_lon = f64::from(east_tile.x) / f64::from(1 << 18) * 360.0 - 180.0;
+    let pixel_lon = 360.0 / f64::from(1 << 26);
+    let (west_lon, east_lon) = (seam_lon - 0.6 * pixel_lon, seam_lon + 0.6 * pixel_lon);
+    let test_tile = common::TestTile::new(
+        "edge_snapping",
+        &format!(
+            r#"<osm version="0.6">
+                <node id="1" lat="55.7503" lon="37.6080"/>
+                <node id="2" lat="55.7503" lon="{west_lon}"/>
+                <node id="3" lat="55.7497" lon="{west_lon}"/>
+                <node id="4" lat="55.7497" lon="37.6080"/>
+                <node id="5" lat="55.7503" lon="{east_lon}"/>
+                <node id="6" lat="55.7503" lon="37.6110"/>
+                <node id="7" lat="55.7497" lon="37.6110"/>
+                <node id="8" lat="55.7497" lon="{east_lon}"/>
+                <way id="10">
+                    <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                    <tag k="landuse" v="farmland"/>
+                </way>
+                <way id="11">
+                    <nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/>
+                    <tag k="landuse" v="farmland"/>
+                </way>
+            </osm>"#
+        ),
+        r#"canvas { fill-color: #ffffff; }
+        area[landuse=farmland] { fill-color: #40a040; }"#,
+    );
+    let farmland = (0x40, 0xa0, 0x40);
+
+    // The last column of the western tile and the first column of the eastern one, on row 101.
+    let seam_pixels = |edge_snap_epsilon| {
+        let options = RenderOptions {
+            edge_snap_epsilon,
+            ..Default::default()
+        };
+        let drawer = common::TestTile::drawer(options);
+        let render = |tile: &Tile| {
+            let entities = test_tile.reader.get_entities_in_tile_with_neighbors(tile, &None);
+            drawer
+                .draw_to_pixels(&entities, tile, &mut TilePixels::new(1), 1, &test_tile.styler)
+                .triples
+        };
+        (
+            render(&common::TEST_TILE)[101 * 256 + 255],
+            render(&east_tile)[101 * 256],
+        )
+    };
+
+    assert_eq!(seam_pixels(0.0), (farmland, (0xff, 0xff, 0xff)));
+    assert_eq!(seam_pixels(1.0), (farmland, farmland));
+}
+
+#[test]
+fn test_route_relation_is_drawn_with_its_colour() {
+    // A bus route along two ways, listed in reverse order and with the second one running backwards.
+    let osm_xml = r##"<osm version="0.6">
+        <node id="1" lat="55.7500" lon="37.6080"/>
+        <node id="2" lat="55.7500" lon="37.6100"/>
+        <node id="3" lat="55.7490" lon="37.6100"/>
+        <way id="10">
+            <nd ref="1"/><nd ref="2"/>
+            <tag k="highway" v="residential"/>
+        </way>
+        <way id="11">
+            <nd ref="3"/><nd ref="2"/>
+            <tag k="highway" v="residential"/>
+        </way>
+        <relation id="20">
+            <member type="way" ref="11" role=""/>
+            <member type="way" ref="10" role=""/>
+            <tag k="type" v="route"/>
+            <tag k="route" v="bus"/>
+            <tag k="colour" v="#e01010"/>
+        </relation>
+    </osm>"##;
+    let mapcss = r#"canvas { fill-color: #ffffff; }
+        relation[type=route] { color: tag("colour"); width: 4; }"#;
+    let render = |options: &ImportOptions| {
+        let test_tile = common::TestTile::with_import_options("route_relation", osm_xml, mapcss, options);
+        (
+            test_tile.entities().routes.len(),
+            test_tile.draw(Default::default()).triples,
+        )
+    };
+
+    let (route_count, triples) = render(&ImportOptions {
+        import_routes: true,
+        ..Default::default()
+    });
+    assert_eq!(route_count, 1);
+    // On the horizontal way and on the vertical one.
+    let route_color = (0xe0, 0x10, 0x10);
+    assert_eq!(triples[101 * 256 + 100], route_color);
+    assert_eq!(triples[200 * 256 + 196], route_color);
+
+    let (route_count, triples) = render(&ImportOptions::default());
+    assert_eq!(route_count, 0);
+    assert!(triples.iter().all(|&pixel| pixel == (0xff, 0xff, 0xff)));
+}
+
+#[test]
+fn test_way_is_drawn_with_its_colour_tag() {
+    let test_tile = common::TestTile::new(
+        "colour_tag",
+        r##"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6080"/>
+            <node id="2" lat="55.7502" lon="37.6110"/>
+            <node id="3" lat="55.7500" lon="37.6080"/>
+            <node id="4" lat="55.7500" lon="37.6110"/>
+            <node id="5" lat="55.7498" lon="37.6080"/>
+            <node id="6" lat="55.7498" lon="37.6110"/>
+            <node id="7" lat="55.7496" lon="37.6080"/>
+            <node id="8" lat="55.7496" lon="37.6110"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/><tag k="colour" v="#ff0000"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="highway" v="path"/><tag k="colour" v="rgb(0, 128, 0)"/></way>
+            <way id="12"><nd ref="5"/><nd ref="6"/><tag k="highway" v="path"/><tag k="colour" v="rainbow"/></way>
+            <way id="13"><nd ref="7"/><nd ref="8"/><tag k="highway" v="path"/></way>
+        </osm>"##,
+        r#"canvas { fill-color: #ffffff; }
+        way[highway] { color: #0000ff; width: 4; }
+        way[highway] { color: eval(tag("colour")); }"#,
+    );
+    let triples = test_tile.draw(Default::default()).triples;
+    let is_drawn_with = |y: usize, color: (u8, u8, u8)| (y - 1..=y + 1).any(|y| triples[y * 256 + 100] == color);
+
+    assert!(is_drawn_with(35, (0xff, 0, 0)));
+    assert!(is_drawn_with(101, (0, 0x80, 0)));
+    // A value that isn't a color and a missing tag both leave the color of the rule that comes before.
+    assert!(is_drawn_with(168, (0, 0, 0xff)));
+    assert!(is_drawn_with(235, (0, 0, 0xff)));
+}
+
+#[test]
+fn test_knockout_fills_dont_darken_overlaps() {
+    let test_tile = common::TestTile::new(
+        "knockout_fills",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7505" lon="37.6085"/>
+            <node id="2" lat="55.7505" lon="37.6100"/>
+            <node id="3" lat="55.7495" lon="37.6100"/>
+            <node id="4" lat="55.7495" lon="37.6085"/>
+            <node id="5" lat="55.7505" lon="37.6095"/>
+            <node id="6" lat="55.7505" lon="37.6110"/>
+            <node id="7" lat="55.7495" lon="37.6110"/>
+            <node id="8" lat="55.7495" lon="37.6095"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="leisure" v="park"/>
+            </way>
+            <way id="11">
+                <nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/>
+                <tag k="leisure" v="park"/>
+            </way>
+        </osm>"#,
+        r#"canvas { fill-color: #ffffff; }
+        area[leisure=park] { fill-color: #00ff00; fill-opacity: 0.5; }"#,
+    );
+    // Only the first park, both of them and only the second one, on row 101.
+    let row_pixels = |knockout_fills| {
+        let triples = test_tile
+            .draw(RenderOptions {
+                knockout_fills,
+                ..Default::default()
+            })
+            .triples;
+        [60, 150, 230].map(|x| triples[101 * 256 + x])
+    };
+
+    let [first, overlap, second] = row_pixels(false);
+    assert_eq!(first, second);
+    assert!(overlap.0 < first.0);
+
+    let [first, overlap, second] = row_pixels(true);
+    assert_eq!(first, second);
+    assert_eq!(overlap, first);
+}
+
+#[test]
+fn test_index_contours_are_thicker() {
+    let test_tile = common::TestTile::new(
+        "contours",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6093"/>
+            <node id="2" lat="55.7496" lon="37.6093"/>
+            <node id="3" lat="55.7502" lon="37.6098"/>
+            <node id="4" lat="55.7496" lon="37.6098"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="contour" v="elevation"/><tag k="ele" v="100"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="contour" v="elevation"/><tag k="ele" v="120"/></way>
+        </osm>"#,
+        r#"canvas { fill-color: #ffffff; }
+        way[contour=elevation] { color: #804000; width: eval(cond(mod(tag("ele"), 100) == 0, 4, 1)); }"#,
+    );
+    let triples = test_tile.draw(Default::default()).triples;
+    // The index contour is around x=65 and the intermediate one around x=158.
+    let row = &triples[101 * 256..102 * 256];
+    let painted = |from: usize, to: usize| row[from..to].iter().filter(|&&p| p != (255, 255, 255)).count();
+
+    let index_width = painted(40, 110);
+    let intermediate_width = painted(130, 190);
+    assert!(intermediate_width > 0);
+    assert!(
+        index_width > intermediate_width,
+        "{} pixels for the index contour vs {} for the intermediate one",
+        index_width,
+        intermediate_width
+    );
+}
+
+#[test]
+fn test_scrim_between_render_layers() {
+    // The layers are declared out of order, their z-indices decide.
+    let test_tile = common::TestTile::new(
+        "render_layers",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6091"/>
+            <node id="2" lat="55.7500" lon="37.6096"/>
+            <node id="3" lat="55.7502" lon="37.6098"/>
+            <node id="4" lat="55.7496" lon="37.6098"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/><tag k="tunnel" v="yes"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+        r##"canvas { fill-color: #ffffff; }
+        canvas::roads { z-index: 1; scrim-color: #000000; scrim-opacity: 0.5; }
+        canvas::tunnels { z-index: -1; }
+        way[highway][!tunnel] { color: #0000ff; width: 5; render-layer: roads; }
+        way[highway][tunnel] { color: #ff0000; width: 5; render-layer: tunnels; }"##,
+    );
+    assert_eq!(
+        test_tile
+            .styler
+            .render_layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["tunnels", "roads"]
+    );
+
+    let triples = test_tile.draw(Default::default()).triples;
+    let pixel = |x: usize, y: usize| triples[y * 256 + x];
+
+    let is_half = |component: u8| (127..=128).contains(&component);
+    let (background, tunnel, road) = (pixel(20, 200), pixel(60, 101), pixel(159, 60));
+    assert!(
+        is_half(background.0) && is_half(background.1) && is_half(background.2),
+        "{:?}",
+        background
+    );
+    assert!(is_half(tunnel.0) && tunnel.1 == 0 && tunnel.2 == 0, "{:?}", tunnel);
+    assert_eq!(road, (0, 0, 255));
+}
+
+#[test]
+fn test_render_deadline_cuts_the_tile_short() {
+    // Takes its time on every command, like a tile with huge relations would.
+    struct SlowBackend {
+        commands: u8,
+    }
+
+    impl OutputBackend for SlowBackend {
+        fn begin_tile(&mut self, _: &Tile, _: usize, _: &Styler) -> Result<()> {
+            self.commands = 0;
+            Ok(())
+        }
+
+        fn draw_command(&mut self, _: &DrawCommand<'_, '_>) {
+            std::thread::sleep(Duration::from_millis(20));
+            self.commands += 1;
+        }
+
+        fn finish(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![self.commands])
+        }
+    }
+
+    let test_tile = common::TestTile::new(
+        "render_deadline",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="12"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="13"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } way[highway] { color: #000000; width: 1; }",
+    );
+    let (entities, tile, styler) = (test_tile.entities(), common::TEST_TILE, &test_tile.styler);
+    let with_deadline = |max_render_time| RenderOptions {
+        max_render_time: Some(max_render_time),
+        ..Default::default()
+    };
+
+    // A fill, a casing, a stroke and a label for every way.
+    let mut backend = SlowBackend { commands: 0 };
+    let output = common::TestTile::drawer(Default::default())
+        .render(&entities, &tile, 1, styler, &mut backend)
+        .unwrap();
+    assert_eq!(output, vec![16]);
+
+    let error = common::TestTile::drawer(with_deadline(Duration::from_millis(50)))
+        .render(&entities, &tile, 1, styler, &mut backend)
+        .unwrap_err();
+    let partial_tile = &error.downcast_ref::<RenderTimedOut>().unwrap().partial_tile;
+    assert!(partial_tile[0] > 0 && partial_tile[0] < 16, "{:?}", partial_tile);
+
+    // Nothing makes it before a deadline that has already passed.
+    let rendered = test_tile.draw(with_deadline(Duration::ZERO));
+    assert!(rendered.timed_out);
+    assert!(rendered.triples.iter().all(|&pixel| pixel == (255, 255, 255)));
+
+    let rendered = test_tile.draw(Default::default());
+    assert!(!rendered.timed_out);
+    assert!(rendered.triples.contains(&(0, 0, 0)));
+}
+
+#[test]
+fn test_world_tile_and_tiles_off_the_map() {
+    // Just a short way at the equator, as every max zoom tile in the bounding box of a way indexes it.
+    let reader = common::import_osm_str(
+        "world_tile",
+        r#"<osm version="0.6">
+            <node id="1" lat="0.0" lon="0.0"/>
+            <node id="2" lat="0.0" lon="10.0"/>
+            <way id="3"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+    );
+    let styler = Styler::new(
+        common::parse_style_str(
+            "world_tile",
+            "canvas { fill-color: #ffffff; } way[highway] { color: #ff0000; width: 6; }",
+        ),
+        &StyleType::Josm,
+        None,
+    );
+    let drawer = Drawer::new(Path::new("."));
+    let draw = |tile: &Tile| {
+        let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+        drawer.draw_tile(&entities, tile, &mut TilePixels::new(1), 1, &styler)
+    };
+
+    let world = Tile { zoom: 0, x: 0, y: 0 };
+    let entities = reader.get_entities_in_tile_with_neighbors(&world, &None);
+    let pixels = drawer
+        .draw_to_pixels(&entities, &world, &mut TilePixels::new(1), 1, &styler)
+        .triples;
+    assert_eq!(pixels[128 * 256 + 130], (255, 0, 0));
+
+    // The map repeats itself past the antimeridian, but there's nothing to the north or the south of it.
+    let world_png = draw(&world).unwrap();
+    assert_eq!(draw(&Tile { zoom: 0, x: 1, y: 0 }).unwrap(), world_png);
+    let off_the_map = draw(&Tile { zoom: 0, x: 0, y: 1 }).unwrap_err();
+    assert_eq!(
+        off_the_map.to_string(),
+        "Row 1 is out of range for zoom 0 (expected at most 0)"
+    );
+}
+
+#[test]
+fn test_area_tag_overrides_whether_a_way_is_filled() {
+    // Two closed pedestrian ways, on the west and on the east side of the tile, and an open one with
+    // `area=yes` inside the western one.
+    let osm_xml = r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6094"/>
+            <node id="3" lat="55.7496" lon="37.6094"/>
+            <node id="4" lat="55.7496" lon="37.6091"/>
+            <node id="5" lat="55.7502" lon="37.6097"/>
+            <node id="6" lat="55.7502" lon="37.6100"/>
+            <node id="7" lat="55.7496" lon="37.6100"/>
+            <node id="8" lat="55.7496" lon="37.6097"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="highway" v="pedestrian"/><tag k="area" v="yes"/>
+            </way>
+            <way id="11">
+                <nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/>
+                <tag k="highway" v="pedestrian"/><tag k="area" v="no"/>
+            </way>
+            <way id="12">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/>
+                <tag k="highway" v="pedestrian"/><tag k="area" v="yes"/>
+            </way>
+        </osm>"#;
+
+    // `area=no` keeps the way out of `area` selectors, and it isn't filled even when a `way` selector matches.
+    for selector in ["area[highway=pedestrian]", "way[highway=pedestrian]"] {
+        let mapcss = format!(
+            "canvas {{ fill-color: #ffffff; }} {} {{ fill-color: #ff0000; }}",
+            selector
+        );
+        let pixels = common::TestTile::new("area_tag", osm_xml, &mapcss)
+            .draw(Default::default())
+            .triples;
+        assert_eq!(pixels[128 * 256 + 60], (255, 0, 0), "{}", selector);
+        assert_eq!(pixels[128 * 256 + 170], (255, 255, 255), "{}", selector);
+    }
+
+    // `area=yes` doesn't turn an open way into a polygon.
+    let reader = common::import_osm_str("area_tag", osm_xml);
+    let entities = reader.get_entities_in_tile_with_neighbors(&common::TEST_TILE, &None);
+    let is_area = |way_id| {
+        entities
+            .ways
+            .iter()
+            .find(|way| way.global_id() == way_id)
+            .unwrap()
+            .is_area()
+    };
+    assert_eq!((is_area(10), is_area(11), is_area(12)), (true, false, false));
+}
+
+#[test]
+fn test_geojson_coordinates_are_rounded_to_the_precision() {
+    let test_tile = common::TestTile::new(
+        "geojson_precision",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.74981234567" lon="37.60911234567"/>
+            <node id="2" lat="55.74961234567" lon="37.60991234567"/>
+            <node id="3" lat="55.75001234567" lon="37.60951234567"><tag k="name" v="Big &quot;O&quot;"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+        "way[highway] { color: #000000; width: 2; } node[name] { text: name; font-size: 10; }",
+    );
+    let entities = test_tile.entities();
+    let geojson = |precision| {
+        let options = RenderOptions {
+            geojson: GeoJsonOptions { precision },
+            ..Default::default()
+        };
+        let bytes = common::TestTile::drawer(options)
+            .draw_tile_geojson(&entities, &common::TEST_TILE, &test_tile.styler)
+            .unwrap();
+        String::from_utf8(bytes).unwrap()
+    };
+
+    let full = geojson(None);
+    let rounded = geojson(Some(6));
+    assert!(full.contains("[37.60911234567,55.74981234567]"));
+    assert_eq!(
+        rounded,
+        concat!(
+            r#"{"type":"FeatureCollection","features":["#,
+            r#"{"type":"Feature","id":"way/10","geometry":{"type":"LineString","coordinates":"#,
+            r#"[[37.609112,55.749812],[37.609912,55.749612]]},"properties":{"highway":"primary"}},"#,
+            r#"{"type":"Feature","id":"node/3","geometry":{"type":"Point","coordinates":[37.609512,55.750012]},"#,
+            r#""properties":{"name":"Big \"O\""}}]}"#
+        )
+    );
+    let feature_count = |geojson: &str| geojson.matches(r#""type":"Feature","#).count();
+    assert_eq!(feature_count(&full), feature_count(&rounded));
+
+    // At two decimals all the nodes land on the same point, but no feature is dropped.
+    let coarse = geojson(Some(2));
+    assert_eq!(coarse.matches("[37.61,55.75]").count(), 3);
+    assert_eq!(feature_count(&coarse), feature_count(&full));
+}
+
+#[test]
+fn test_features_are_composited_over_the_underlay() {
+    // A half-transparent white square in the middle of the tile.
+    let test_tile = common::TestTile::new(
+        "underlay",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7502" lon="37.6098"/>
+            <node id="3" lat="55.7496" lon="37.6098"/>
+            <node id="4" lat="55.7496" lon="37.6091"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/><tag k="leisure" v="park"/></way>
+        </osm>"#,
+        "canvas { fill-color: #000000; } area[leisure] { fill-color: #ffffff; fill-opacity: 0.5; }",
+    );
+    let (entities, tile, styler) = (test_tile.entities(), common::TEST_TILE, &test_tile.styler);
+    let drawer = common::TestTile::drawer(Default::default());
+
+    // Blue on the left, red on the right.
+    let underlay = |dimension: usize| {
+        let pixels = (0..dimension * dimension)
+            .map(|idx| match idx % dimension < dimension / 2 {
+                true => RgbaColor::from_components(0, 0, 200, 255),
+                false => RgbaColor::from_components(200, 0, 0, 255),
+            })
+            .collect();
+        Icon::from_pixels(dimension, dimension, pixels)
+    };
+    let png_bytes = drawer
+        .draw_tile_with_underlay(&entities, &tile, &mut TilePixels::new(1), 1, styler, &underlay(256))
+        .unwrap();
+    let mut png_reader = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+    let mut image = vec![0; png_reader.output_buffer_size()];
+    png_reader.next_frame(&mut image).unwrap();
+    let pixel = |x: usize, y: usize| {
+        let idx = 3 * (y * 256 + x);
+        (image[idx], image[idx + 1], image[idx + 2])
+    };
+
+    assert_eq!(pixel(10, 250), (0, 0, 200));
+    assert_eq!(pixel(245, 250), (200, 0, 0));
+    assert_eq!(pixel(60, 100), (127, 127, 227));
+    assert_eq!(pixel(140, 100), (227, 127, 127));
+
+    let wrong_size =
+        drawer.draw_tile_with_underlay(&entities, &tile, &mut TilePixels::new(2), 2, styler, &underlay(256));
+    assert_eq!(
+        wrong_size.unwrap_err().to_string(),
+        "The underlay is 256x256 pixels, but the tile is 512x512"
+    );
+}
+
+#[test]
+fn test_circle_is_drawn_with_smooth_edges() {
+    let white = Color { r: 255, g: 255, b: 255 };
+    let red = Color { r: 255, g: 0, b: 0 };
+    let blue = Color { r: 0, g: 0, b: 255 };
+    // The center of the pixel (100, 100).
+    let center = (100.5, 100.5);
+    let draw = |stroke: Option<&Color>, scale: f64| {
+        let mut pixels = TilePixels::new(1);
+        pixels.reset(&Some(white.clone()));
+        draw_circle(center, 5.0, Some(&red), stroke, 2.0, 1.0, scale, &mut pixels);
+        pixels.blend_unfinished_pixels(false);
+        pixels.to_rgb_triples()
+    };
+    let pixel = |triples: &[(u8, u8, u8)], x: usize, y: usize| triples[y * 256 + x];
+
+    let disc = draw(None, 1.0);
+    for y in 90..=110 {
+        for x in 90..=110 {
+            let distance = f64::from(x - 100).hypot(f64::from(y - 100));
+            let expected = if distance <= 4.5 {
+                Some((255, 0, 0))
+            } else if distance >= 5.5 {
+                Some((255, 255, 255))
+            } else {
+                None
+            };
+            let actual = pixel(&disc, x as usize, y as usize);
+            match expected {
+                Some(expected) => assert_eq!(actual, expected, "({}, {})", x, y),
+                // Pixels on the edge are blended with the canvas.
+                None => assert!(actual.0 == 255 && actual.1 > 0 && actual.1 < 255, "({}, {})", x, y),
+            }
+        }
+    }
+    assert_eq!(pixel(&disc, 105, 100), (255, 127, 127));
+
+    // The stroke is centered on the edge, from 4 to 6 pixels away.
+    let stroked = draw(Some(&blue), 1.0);
+    assert_eq!(pixel(&stroked, 100, 100), (255, 0, 0));
+    assert_eq!(pixel(&stroked, 104, 100), (127, 0, 127));
+    assert_eq!(pixel(&stroked, 105, 100), (0, 0, 255));
+    assert_eq!(pixel(&stroked, 106, 100), (127, 127, 255));
+    assert_eq!(pixel(&stroked, 107, 100), (255, 255, 255));
+
+    // Everything is twice as large on a scaled tile.
+    let scaled = draw(None, 2.0);
+    assert_eq!(pixel(&scaled, 109, 100), (255, 0, 0));
+    assert_eq!(pixel(&scaled, 110, 100), (255, 127, 127));
+    assert_eq!(pixel(&scaled, 111, 100), (255, 255, 255));
+}
+
+#[test]
+fn test_node_symbols_are_drawn_as_circles() {
+    let test_tile = common::TestTile::new(
+        "node_symbols",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6094"><tag k="place" v="town"/></node>
+            <node id="2" lat="55.7500" lon="37.6100"><tag k="railway" v="station"/></node>
+            <node id="3" lat="55.7502" lon="37.6097"><tag k="amenity" v="bench"/></node>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } \
+         node[place] { symbol-shape: circle; symbol-size: 12; symbol-fill-color: #0000ff; \
+                       symbol-stroke-color: #000000; symbol-stroke-width: 2; } \
+         node[railway] { symbol-shape: circle; symbol-size: 6; } \
+         node[amenity] { symbol-shape: triangle; }",
+    );
+    let (entities, tile) = (test_tile.entities(), common::TEST_TILE);
+    let rendered = test_tile.draw(Default::default());
+
+    let center_of = |node_idx: usize| {
+        let (x, y) = coords_to_xy_tile_relative(&entities.nodes[node_idx], &tile);
+        (x.floor() as usize, y.floor() as usize)
+    };
+    let pixel = |(x, y): (usize, usize)| rendered.triples[y * 256 + x];
+    let node_idx = |id| entities.nodes.iter().position(|node| node.global_id() == id).unwrap();
+
+    // The town has a 12 pixel disc with a 2 pixel stroke from 5 to 7 pixels away from the center.
+    let (x, y) = center_of(node_idx(1));
+    assert_eq!(pixel((x, y)), (0, 0, 255));
+    assert_eq!(pixel((x + 3, y)), (0, 0, 255));
+    assert_eq!(pixel((x, y + 6)), (0, 0, 0));
+    assert_eq!(pixel((x - 9, y)), (255, 255, 255));
+
+    // Without any colors, the symbol is filled with red.
+    let (x, y) = center_of(node_idx(2));
+    assert_eq!(pixel((x, y)), (255, 0, 0));
+    assert_eq!(pixel((x + 5, y)), (255, 255, 255));
+
+    // Other shapes aren't drawn.
+    let (x, y) = center_of(node_idx(3));
+    assert_eq!(pixel((x, y)), (255, 255, 255));
+}
+
+#[test]
+fn test_unstyled_ways_are_drawn_only_when_asked_for() {
+    // A styled road on the west side of the tile. On the east side, an unstyled fence under a styled park,
+    // and below them an unstyled multipolygon whose ring is styled to draw nothing.
+    let test_tile = common::TestTile::new(
+        "render_unstyled",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6091"/>
+            <node id="3" lat="55.7500" lon="37.6097"/>
+            <node id="4" lat="55.7500" lon="37.6100"/>
+            <node id="5" lat="55.7501" lon="37.6096"/>
+            <node id="6" lat="55.7501" lon="37.6101"/>
+            <node id="7" lat="55.7499" lon="37.6101"/>
+            <node id="8" lat="55.7499" lon="37.6096"/>
+            <node id="9" lat="55.7498" lon="37.6097"/>
+            <node id="10" lat="55.7498" lon="37.6100"/>
+            <node id="11" lat="55.7496" lon="37.6100"/>
+            <node id="12" lat="55.7496" lon="37.6097"/>
+            <way id="20"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            <way id="21"><nd ref="3"/><nd ref="4"/><tag k="barrier" v="fence"/></way>
+            <way id="22"><nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/><tag k="leisure" v="park"/></way>
+            <way id="23"><nd ref="9"/><nd ref="10"/><nd ref="11"/><nd ref="12"/><nd ref="9"/><tag k="building" v="yes"/></way>
+            <relation id="30">
+                <member type="way" ref="23" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="landuse" v="construction"/>
+            </relation>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } way[highway] { color: #ff0000; width: 4; }
+        area[leisure=park] { fill-color: #00ff00; } way[building] { z-index: 1; }",
+    );
+    let draw = |render_unstyled| {
+        test_tile
+            .draw(RenderOptions {
+                render_unstyled,
+                ..Default::default()
+            })
+            .triples
+    };
+    let east_column =
+        |pixels: &[(u8, u8, u8)], rows: std::ops::Range<usize>| rows.map(|y| pixels[y * 256 + 170]).collect::<Vec<_>>();
+    let is_gray = |&(r, g, b): &(u8, u8, u8)| r == g && g == b && (150..255).contains(&r);
+
+    let without_fallback = draw(false);
+    assert!(!east_column(&without_fallback, 0..256).iter().any(is_gray));
+
+    let with_fallback = draw(true);
+    // The outline of the multipolygon.
+    assert!(east_column(&with_fallback, 150..250).iter().any(is_gray));
+    // The fence goes under the park, and the styled road is drawn just like without the fallback.
+    assert_eq!(
+        east_column(&with_fallback, 70..130),
+        east_column(&without_fallback, 70..130)
+    );
+    assert!(east_column(&with_fallback, 70..130).iter().all(|p| *p == (0, 255, 0)));
+    for y in 0..256 {
+        assert_eq!(
+            with_fallback[y * 256..y * 256 + 128],
+            without_fallback[y * 256..y * 256 + 128]
+        );
+    }
+}
+
+#[test]
+fn test_label_anchors_are_returned_instead_of_rasterized() {
+    // A named road across the tile and a named cafe below it.
+    let test_tile = common::TestTile::new(
+        "label_anchors",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6091"/>
+            <node id="2" lat="55.7500" lon="37.6100"/>
+            <node id="3" lat="55.7497" lon="37.6094"><tag k="amenity" v="cafe"/><tag k="name" v="Cafe"/></node>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/>
+                <tag k="highway" v="primary"/><tag k="layer" v="1"/><tag k="name" v="Main Street"/>
+            </way>
+        </osm>"#,
+        "canvas { fill-color: #ffffff; } \
+         way[highway] { text: name; font-size: 10; z-index: 3; } \
+         node[amenity] { text: name; font-size: 12; z-index: 5; }",
+    );
+    let (entities, tile) = (test_tile.entities(), common::TEST_TILE);
+    let draw = |label_anchors| {
+        let options = RenderOptions {
+            label_anchors,
+            ..Default::default()
+        };
+        let output = common::TestTile::drawer(options)
+            .draw_tile_with_labels(&entities, &tile, &mut TilePixels::new(1), 1, &test_tile.styler)
+            .unwrap();
+        let mut png_reader = png::Decoder::new(output.image.as_slice()).read_info().unwrap();
+        let mut image = vec![0; png_reader.output_buffer_size()];
+        png_reader.next_frame(&mut image).unwrap();
+        (image, output.labels)
+    };
+
+    let (image, labels) = draw(false);
+    assert!(labels.is_empty());
+    assert!(image.iter().any(|&component| component != 255));
+
+    let (image, labels) = draw(true);
+    assert!(image.iter().all(|&component| component == 255));
+    let texts = labels.iter().map(|label| label.text.as_str()).collect::<Vec<_>>();
+    assert_eq!(texts, ["Main Street", "Cafe"]);
+    assert_eq!(labels.iter().map(|label| label.priority).collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(
+        labels.iter().map(|label| label.font_size).collect::<Vec<_>>(),
+        [10.0, 12.0]
+    );
+    assert_eq!(labels.iter().map(|label| label.z_index).collect::<Vec<_>>(), [3.0, 5.0]);
+    assert_eq!(
+        labels.iter().map(|label| label.order_key).collect::<Vec<_>>(),
+        [OrderKey([1.0, 0.0, 3.0]), OrderKey([0.0, 0.0, 5.0])]
+    );
+
+    // The road label is centered on the middle of the road, the cafe label on the cafe.
+    let way = entities.ways.iter().find(|way| way.global_id() == 10).unwrap();
+    let (start, end) = (way.get_node(0), way.get_node(1));
+    let (start_x, y) = Labelable::get_label_position(&start, &tile, 1.0).unwrap();
+    let (end_x, _) = Labelable::get_label_position(&end, &tile, 1.0).unwrap();
+    let (x, _) = labels[0].position;
+    assert!(
+        (x - (start_x + end_x) / 2.0).abs() <= 1.0,
+        "{} {} {}",
+        x,
+        start_x,
+        end_x
+    );
+    assert!((labels[0].position.1 - y).abs() <= 1.0);
+
+    let cafe = entities.nodes.iter().find(|node| node.global_id() == 3).unwrap();
+    let (cafe_x, cafe_y) = cafe.get_label_position(&tile, 1.0).unwrap();
+    assert!((labels[1].position.0 - cafe_x).abs() < 1e-9);
+    assert!((labels[1].position.1 - cafe_y).abs() < 1e-9);
+}
+
+#[test]
+fn test_label_anchors_still_collide() {
+    // Two shops on the same spot, so only the label placed first (the lower z-index) fits.
+    let test_tile = common::TestTile::new(
+        "label_anchors_collide",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7497" lon="37.6094"><tag k="amenity" v="cafe"/><tag k="name" v="Cafe"/></node>
+            <node id="2" lat="55.7497" lon="37.6094"><tag k="shop" v="bakery"/><tag k="name" v="Bakery"/></node>
+        </osm>"#,
+        "node[amenity] { text: name; font-size: 12; z-index: 5; } \
+         node[shop] { text: name; font-size: 12; z-index: 4; }",
+    );
+    let options = RenderOptions {
+        label_anchors: true,
+        ..Default::default()
+    };
+    let labels = common::TestTile::drawer(options)
+        .draw_tile_with_labels(
+            &test_tile.entities(),
+            &common::TEST_TILE,
+            &mut TilePixels::new(1),
+            1,
+            &test_tile.styler,
+        )
+        .unwrap()
+        .labels;
+
+    let texts = labels.iter().map(|label| label.text.as_str()).collect::<Vec<_>>();
+    assert_eq!(texts, ["Bakery"]);
+    assert_eq!(labels[0].priority, 0);
+}
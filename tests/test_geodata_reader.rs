@@ -0,0 +1,288 @@
+mod common;
+
+use renderer::geodata::importer::{ImportOptions, SimplificationLevel};
+use renderer::geodata::reader::{GeodataReader, OsmEntity};
+use renderer::tile::{coords_to_max_zoom_tile, tile_to_ancestor, Tile};
+
+#[test]
+fn test_typed_tag_accessors() {
+    let reader = common::import_osm_str(
+        "typed_tags",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/>
+                <tag k="highway" v="residential"/>
+                <tag k="oneway" v="yes"/>
+                <tag k="layer" v="-2"/>
+                <tag k="lanes" v="abc"/>
+                <tag k="width" v="3.5"/>
+            </way>
+        </osm>"#,
+    );
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    assert_eq!(entities.ways.len(), 1);
+    let tags = entities.ways[0].tags();
+
+    assert_eq!(tags.tag_bool("oneway"), Some(true));
+    assert_eq!(tags.tag_bool("highway"), None);
+    assert_eq!(tags.tag_i32("layer"), Some(-2));
+    assert_eq!(tags.tag_num("layer"), Some(-2.0));
+    assert_eq!(tags.tag_i32("lanes"), None);
+    assert_eq!(tags.tag_num("lanes"), None);
+    assert_eq!(tags.tag_num("width"), Some(3.5));
+    assert_eq!(tags.tag_i32("width"), None);
+}
+
+#[test]
+fn test_simplified_geometry_for_low_zooms() {
+    // A wiggly road that is also a bus route, a wiggly square lake and a tiny building, all passing through
+    // (55.75, 37.61).
+    let mut osm = String::from(r#"<osm version="0.6">"#);
+    let mut add_way = |way_id: u64, points: &[(f64, f64)], closed: bool, tags: &str| {
+        let mut refs = String::new();
+        for (idx, (lat, lon)) in points.iter().enumerate() {
+            let node_id = way_id * 1000 + idx as u64;
+            osm += &format!(r#"<node id="{}" lat="{}" lon="{}"/>"#, node_id, lat, lon);
+            refs += &format!(r#"<nd ref="{}"/>"#, node_id);
+        }
+        if closed {
+            refs += &format!(r#"<nd ref="{}"/>"#, way_id * 1000);
+        }
+        osm += &format!(r#"<way id="{}">{}{}</way>"#, way_id, refs, tags);
+    };
+    let wiggle = |idx: usize| if idx.is_multiple_of(2) { 0.00001 } else { -0.00001 };
+    let step = |idx: usize| 0.0005 * idx as f64;
+
+    let road = (0..50)
+        .map(|idx| (55.75 + wiggle(idx), 37.605 + step(idx) / 2.5))
+        .collect::<Vec<_>>();
+    add_way(1, &road, false, r#"<tag k="highway" v="primary"/>"#);
+
+    let lake = (0..20)
+        .map(|idx| (55.745 + step(idx), 37.61 + wiggle(idx)))
+        .chain((0..20).map(|idx| (55.755 + wiggle(idx), 37.61 + step(idx))))
+        .chain((0..20).map(|idx| (55.755 - step(idx), 37.62 + wiggle(idx))))
+        .chain((0..20).map(|idx| (55.745 + wiggle(idx), 37.62 - step(idx))))
+        .collect::<Vec<_>>();
+    add_way(2, &lake, true, "");
+
+    let building = [(55.75, 37.61), (55.75, 37.61001), (55.74999, 37.61001)];
+    add_way(3, &building, true, r#"<tag k="building" v="yes"/>"#);
+
+    osm += r#"<relation id="4">
+            <member type="way" ref="2" role="outer"/>
+            <tag k="type" v="multipolygon"/>
+            <tag k="natural" v="water"/>
+        </relation>
+        <relation id="5">
+            <member type="way" ref="1" role=""/>
+            <tag k="type" v="route"/>
+            <tag k="route" v="bus"/>
+        </relation>
+    </osm>"#;
+
+    let options = ImportOptions {
+        simplification_levels: vec![SimplificationLevel {
+            max_zoom: 12,
+            tolerance: 1.0,
+        }],
+        import_routes: true,
+        ..Default::default()
+    };
+    let reader = common::import_osm_str_with_options("simplified_geometry", &osm, &options);
+
+    let node_counts = |zoom| {
+        let tile = tile_to_ancestor(&coords_to_max_zoom_tile(&(55.75, 37.61)), zoom);
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let way_node_count = |way_id| {
+            let way = entities.ways.iter().find(|w| w.global_id() == way_id).unwrap();
+            way.node_count()
+        };
+        let lake_node_count = entities.multipolygons[0].get_polygon(0).node_count();
+        let route_node_count = entities.routes[0].get_polyline(0).node_count();
+        (way_node_count(1), way_node_count(3), lake_node_count, route_node_count)
+    };
+
+    // Rings are never simplified to less than a triangle, the tiny building is kept as is.
+    assert_eq!(node_counts(12), (2, 4, 5, 2));
+    assert_eq!(node_counts(13), (50, 4, 81, 50));
+    assert_eq!(node_counts(18), (50, 4, 81, 50));
+}
+
+#[test]
+fn test_distinct_tag_keys_and_values() {
+    let reader = common::import_osm_str(
+        "distinct_tags",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61">
+                <tag k="amenity" v="cafe"/>
+                <tag k="name" v="Coffee"/>
+            </node>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <node id="3" lat="55.751" lon="37.61"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/>
+                <tag k="highway" v="residential"/>
+                <tag k="name" v="Main Street"/>
+            </way>
+            <way id="11">
+                <nd ref="2"/><nd ref="3"/>
+                <tag k="highway" v="residential"/>
+            </way>
+            <way id="12">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/>
+            </way>
+            <relation id="20">
+                <member type="way" ref="12" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="landuse" v="grass"/>
+                <tag k="highway" v="pedestrian"/>
+            </relation>
+        </osm>"#,
+    );
+
+    let to_owned = |pairs: &[(&str, usize)]| {
+        pairs
+            .iter()
+            .map(|&(s, count)| (s.to_string(), count))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        reader.distinct_keys(),
+        to_owned(&[("amenity", 1), ("highway", 3), ("landuse", 1), ("name", 2), ("type", 1)]),
+    );
+    assert_eq!(
+        reader.distinct_values("highway"),
+        to_owned(&[("pedestrian", 1), ("residential", 2)]),
+    );
+    assert_eq!(
+        reader.distinct_values("name"),
+        to_owned(&[("Coffee", 1), ("Main Street", 1)])
+    );
+    assert!(reader.distinct_values("building").is_empty());
+}
+
+#[test]
+fn test_features_at_point() {
+    let reader = common::import_osm_str(
+        "features_at",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6090"/>
+            <node id="2" lat="55.7504" lon="37.6090"/>
+            <node id="3" lat="55.7504" lon="37.6096"/>
+            <node id="4" lat="55.7500" lon="37.6096"/>
+            <node id="5" lat="55.7498" lon="37.6090"/>
+            <node id="6" lat="55.7498" lon="37.6100"/>
+            <node id="7" lat="55.7505" lon="37.6100"><tag k="amenity" v="cafe"/></node>
+            <node id="11" lat="55.7490" lon="37.6090"/>
+            <node id="12" lat="55.7494" lon="37.6090"/>
+            <node id="13" lat="55.7494" lon="37.6100"/>
+            <node id="14" lat="55.7490" lon="37.6100"/>
+            <node id="15" lat="55.7491" lon="37.6093"/>
+            <node id="16" lat="55.7493" lon="37.6093"/>
+            <node id="17" lat="55.7493" lon="37.6097"/>
+            <node id="18" lat="55.7491" lon="37.6097"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/>
+                <tag k="building" v="yes"/>
+            </way>
+            <way id="11"><nd ref="5"/><nd ref="6"/><tag k="highway" v="residential"/></way>
+            <way id="12"><nd ref="11"/><nd ref="12"/><nd ref="13"/><nd ref="14"/><nd ref="11"/></way>
+            <way id="13"><nd ref="15"/><nd ref="16"/><nd ref="17"/><nd ref="18"/><nd ref="15"/></way>
+            <relation id="20">
+                <member type="way" ref="12" role="outer"/>
+                <member type="way" ref="13" role="inner"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="landuse" v="grass"/>
+            </relation>
+        </osm>"#,
+    );
+
+    let features_at = |lat, lon, tolerance| {
+        reader
+            .features_at(lat, lon, tolerance)
+            .iter()
+            .map(|feature| format!("{}/{}", feature.kind(), feature.global_id()))
+            .collect::<Vec<_>>()
+    };
+
+    // Just over 6 meters east of the building.
+    assert_eq!(features_at(55.7502, 37.6093, 1.0), vec!["way/10"]);
+    assert!(features_at(55.7502, 37.6097, 1.0).is_empty());
+    assert_eq!(features_at(55.7502, 37.6097, 10.0), vec!["way/10"]);
+
+    // Just over 5 meters north of the road.
+    assert!(features_at(55.74985, 37.6095, 2.0).is_empty());
+    assert_eq!(features_at(55.74985, 37.6095, 10.0), vec!["way/11"]);
+
+    assert_eq!(features_at(55.7505, 37.6100, 1.0), vec!["node/7"]);
+
+    // In the hole of the multipolygon and then between its rings.
+    assert_eq!(features_at(55.7492, 37.6095, 1.0), Vec::<String>::new());
+    assert_eq!(features_at(55.7492, 37.6091, 1.0), vec!["relation/20"]);
+}
+
+#[test]
+fn test_tile_columns_wrap_around_the_antimeridian() {
+    let reader = common::import_osm_str(
+        "antimeridian_neighbors",
+        r#"<osm version="0.6">
+            <node id="1" lat="10.0" lon="179.9"><tag k="place" v="island"/></node>
+            <node id="2" lat="10.0" lon="-179.9"><tag k="place" v="island"/></node>
+            <node id="3" lat="10.0" lon="0.0"><tag k="place" v="island"/></node>
+        </osm>"#,
+    );
+    let node_ids = |x| {
+        let tile = Tile { zoom: 3, x, y: 3 };
+        let mut ids = reader
+            .get_entities_in_tile_with_neighbors(&tile, &None)
+            .nodes
+            .iter()
+            .map(|node| node.global_id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    };
+
+    // The neighbors across the antimeridian are left out.
+    assert_eq!(node_ids(0), [2]);
+    assert_eq!(node_ids(7), [1]);
+    // Columns past the antimeridian repeat the ones of the map.
+    assert_eq!(node_ids(8), [2]);
+    assert_eq!(node_ids(20), [3]);
+}
+
+#[test]
+fn test_files_of_other_format_versions_are_rejected() {
+    let _ = common::import_osm_str(
+        "format_version",
+        r#"<osm version="0.6"><node id="1" lat="55.75" lon="37.61"/></osm>"#,
+    );
+    let bin_file = common::get_tmp_path("format_version.bin");
+    let bytes = std::fs::read(&bin_file).unwrap();
+    assert_eq!(&bytes[..4], b"OSMR");
+
+    let load_error = |name: &str, bytes: &[u8]| {
+        let path = common::get_tmp_path(name);
+        std::fs::write(&path, bytes).unwrap();
+        match GeodataReader::load(path.to_str().unwrap()) {
+            Ok(_) => panic!("{} was loaded", name),
+            Err(e) => format!("{:#}", e),
+        }
+    };
+
+    let mut newer = bytes.clone();
+    newer[4..8].copy_from_slice(&99u32.to_le_bytes());
+    assert!(load_error("format_version_newer.bin", &newer).contains("format version 99"));
+    // Files of the versions before the header was introduced start right away with the node count.
+    assert!(load_error("format_version_old.bin", &bytes[8..]).contains("older version"));
+    assert!(load_error("format_version_truncated.bin", &bytes[..3]).contains("older version"));
+}
@@ -0,0 +1,1082 @@
+mod common;
+
+use crate::common::get_test_path;
+use renderer::geodata::clip::ClipPolygon;
+use renderer::geodata::importer::{
+    import_with_options, parse_input, parse_input_with_options, parse_input_with_progress, save, validate, EntityKind,
+    EntityStorages, ImportOptions, ImportProblem, ImportStats, ProgressSink,
+};
+use renderer::geodata::reader::GeodataReader;
+use renderer::tile::Tile;
+use std::collections::BTreeMap;
+
+#[test]
+fn test_parse_input_before_saving() {
+    let mut parsed = parse_input(get_test_path(&["osm", "nano_moscow.osm"])).unwrap();
+    assert_eq!(parsed.nodes().len(), 19184);
+    assert_eq!(parsed.ways().len(), 6117);
+    assert!(!parsed.multipolygons().is_empty());
+
+    for way in parsed.ways_mut() {
+        way.tags.remove("name");
+    }
+    assert!(parsed.ways().iter().all(|way| !way.tags.contains_key("name")));
+
+    let mut output = Vec::new();
+    save(&parsed, &mut output).unwrap();
+    assert!(!output.is_empty());
+}
+
+#[test]
+fn test_escaped_tag_values_are_decoded() {
+    let osm_file = common::get_tmp_path("escaped_tags.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61">
+                <tag k="name" v="Caf&#233; &amp; Bar"/>
+                <tag k="name:en" v="Joe&#x2019;s &lt;Diner&gt;"/>
+            </node>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    let tags = &parsed.nodes()[0].tags;
+    assert_eq!(tags["name"], "Café & Bar");
+    assert_eq!(tags["name:en"], "Joe\u{2019}s <Diner>");
+}
+
+#[test]
+fn test_stray_elements_are_skipped() {
+    let osm_file = common::get_tmp_path("stray_elements.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <tag k="name" v="Nowhere"/>
+            <node id="1" lat="55.75" lon="37.61"/>
+            </way>
+            <nd ref="1"/>
+            <node id="2" lat="55.751" lon="37.612"><tag k="amenity" v="cafe"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    assert_eq!(parsed.nodes().len(), 2);
+    assert!(parsed.nodes()[0].tags.is_empty());
+    assert_eq!(parsed.nodes()[1].tags["amenity"], "cafe");
+    assert_eq!(parsed.ways().len(), 1);
+    assert_eq!(parsed.ways()[0].node_ids, vec![0, 1]);
+}
+
+#[derive(Default)]
+struct CollectedStats {
+    reports: Vec<ImportStats>,
+}
+
+impl ProgressSink for CollectedStats {
+    fn report(&mut self, stats: &ImportStats) {
+        self.reports.push(stats.clone());
+    }
+}
+
+#[test]
+fn test_memory_estimate_grows_with_nodes() {
+    let final_stats = |name: &str, node_count: usize| {
+        let nodes = (1..=node_count)
+            .map(|id| format!(r#"<node id="{}" lat="55.75" lon="37.61"/>"#, id))
+            .collect::<String>();
+        let osm_file = common::get_tmp_path(&format!("{}.osm", name));
+        std::fs::write(&osm_file, format!(r#"<osm version="0.6">{}</osm>"#, nodes)).unwrap();
+
+        let mut collected = CollectedStats::default();
+        let parsed = parse_input_with_progress(&osm_file, &mut collected).unwrap();
+        let last = collected.reports.pop().unwrap();
+        assert_eq!(last.node_count, node_count);
+        assert_eq!(last.estimated_memory, parsed.estimated_memory_usage());
+        assert!(last.peak_estimated_memory >= last.estimated_memory);
+        last
+    };
+
+    let few = final_stats("few_nodes", 10);
+    let many = final_stats("many_nodes", 200_000);
+    assert!(many.estimated_memory > few.estimated_memory);
+    assert!(many.estimated_memory > 200_000 * std::mem::size_of::<f64>() * 2);
+}
+
+#[test]
+fn test_degenerate_rings_are_dropped() {
+    // Relation 20 has a real ring and a flat one, relation 21 only the flat one.
+    let osm_file = common::get_tmp_path("degenerate_rings.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="55.751" lon="37.611"/>
+            <node id="3" lat="55.752" lon="37.612"/>
+            <node id="4" lat="55.750" lon="37.620"/>
+            <node id="5" lat="55.751" lon="37.622"/>
+            <node id="6" lat="55.750" lon="37.624"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/></way>
+            <way id="11"><nd ref="4"/><nd ref="5"/><nd ref="6"/><nd ref="4"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="10" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    assert_eq!(parsed.multipolygons().len(), 1);
+    assert_eq!(parsed.multipolygons()[0].global_id, 20);
+    assert_eq!(parsed.multipolygons()[0].polygon_ids.len(), 1);
+    assert_eq!(
+        parsed.problems(),
+        [ImportProblem::DegenerateMultipolygon { relation_id: 21 }]
+    );
+}
+
+#[test]
+fn test_gzipped_file_loads_like_raw() {
+    let input = get_test_path(&["osm", "nano_moscow.osm"]);
+    let import = |file_name, gzip_output| {
+        let output = common::get_tmp_path(file_name);
+        let options = ImportOptions {
+            gzip_output,
+            ..Default::default()
+        };
+        import_with_options(input.as_ref(), output.as_path(), &options).unwrap();
+        output
+    };
+    let raw_file = import("gzip_raw.bin", false);
+    let gzipped_file = import("gzip_compressed.bin.gz", true);
+
+    let gzipped_bytes = std::fs::read(&gzipped_file).unwrap();
+    assert_eq!(gzipped_bytes[..2], [0x1f, 0x8b]);
+    assert!(gzipped_bytes.len() < std::fs::metadata(&raw_file).unwrap().len() as usize);
+
+    let tile = Tile {
+        x: 9903,
+        y: 5122,
+        zoom: 14,
+    };
+    let entity_counts = |file: &std::path::Path| {
+        let reader = GeodataReader::load(file.to_str().unwrap()).unwrap();
+        let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        (entities.nodes.len(), entities.ways.len(), entities.multipolygons.len())
+    };
+    let raw_counts = entity_counts(&raw_file);
+    assert!(raw_counts.1 > 0);
+    assert_eq!(entity_counts(&gzipped_file), raw_counts);
+}
+
+#[test]
+fn test_validation_reports_problems_without_writing_output() {
+    let osm_file = common::get_tmp_path("validation.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="55.751" lon="37.610"/>
+            <node id="3" lat="55.751" lon="37.611"/>
+            <node id="3" lat="55.751" lon="37.611"/>
+            <node id="4" lat="95.0" lon="37.611"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="5"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let problems = validate(&osm_file, &ImportOptions::default()).unwrap();
+    assert_eq!(
+        problems,
+        vec![
+            ImportProblem::DuplicateId {
+                kind: EntityKind::Node,
+                id: 3,
+            },
+            ImportProblem::CoordsOutOfRange {
+                node_id: 4,
+                lat: 95.0,
+                lon: 37.611,
+            },
+            ImportProblem::UnresolvedRef {
+                kind: EntityKind::Way,
+                id: 10,
+                ref_kind: EntityKind::Node,
+                ref_id: 5,
+            },
+            ImportProblem::UnresolvedRef {
+                kind: EntityKind::Relation,
+                id: 20,
+                ref_kind: EntityKind::Way,
+                ref_id: 11,
+            },
+            ImportProblem::BrokenMultipolygon { relation_id: 20 },
+        ]
+    );
+
+    let run_importer = |max_problems: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_importer"))
+            .args(["--validate", "--max-problems", max_problems])
+            .arg(&osm_file)
+            .output()
+            .unwrap()
+    };
+    let failed = run_importer("4");
+    assert!(!failed.status.success());
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("Relation #20 is not a valid multipolygon"));
+    assert!(run_importer("5").status.success());
+
+    let written_files = std::fs::read_dir(osm_file.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.file_stem() == osm_file.file_stem() && *path != osm_file)
+        .collect::<Vec<_>>();
+    assert_eq!(written_files, Vec::<std::path::PathBuf>::new());
+}
+
+#[test]
+fn test_clip_to_polygon() {
+    // A 5x5 grid of nodes with the IDs 100 + 5 * row + col.
+    let mut osm = String::from(r#"<osm version="0.6">"#);
+    for row in 0..5 {
+        for col in 0..5 {
+            osm += &format!(
+                r#"<node id="{}" lat="{}" lon="{}"/>"#,
+                100 + 5 * row + col,
+                55.750 + 0.001 * f64::from(row),
+                37.610 + 0.001 * f64::from(col)
+            );
+        }
+    }
+    osm += r#"<way id="10"><nd ref="100"/><nd ref="124"/></way>"#;
+    osm += r#"<way id="11"><nd ref="119"/><nd ref="123"/></way>"#;
+    osm += "</osm>";
+    let osm_file = common::get_tmp_path("clip_polygon.osm");
+    std::fs::write(&osm_file, osm).unwrap();
+
+    // Contains the nodes with row + col <= 4.
+    let triangle = ClipPolygon::from_geojson(
+        r#"{
+            "type": "Feature",
+            "properties": {"name": "Triangle", "admin_level": 8, "boundary": true},
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[[37.6095, 55.7495], [37.615, 55.7495], [37.6095, 55.755], [37.6095, 55.7495]]]
+            }
+        }"#,
+    )
+    .unwrap();
+    let options = ImportOptions {
+        clip_polygon: Some(triangle),
+        ..Default::default()
+    };
+    let parsed = parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap();
+
+    let mut expected_node_ids = (0..5)
+        .flat_map(|row| (0..5 - row).map(move |col| 100 + 5 * row + col))
+        .collect::<Vec<_>>();
+    // The way crossing the boundary keeps its outside node.
+    expected_node_ids.push(124);
+    let node_ids = parsed.nodes().iter().map(|node| node.global_id).collect::<Vec<_>>();
+    assert_eq!(node_ids, expected_node_ids);
+
+    assert_eq!(parsed.ways().len(), 1);
+    let way = &parsed.ways()[0];
+    assert_eq!(way.global_id, 10);
+    let way_node_ids = way
+        .node_ids
+        .iter()
+        .map(|&id| parsed.nodes()[id].global_id)
+        .collect::<Vec<_>>();
+    assert_eq!(way_node_ids, [100, 124]);
+}
+
+#[test]
+fn test_deeply_nested_clip_polygon_is_rejected() {
+    let nested = |depth| {
+        format!(
+            r#"{{"type": "Polygon", "coordinates": {}{}}}"#,
+            "[".repeat(depth),
+            "]".repeat(depth)
+        )
+    };
+    let error = ClipPolygon::from_geojson(&nested(100_000)).err().unwrap();
+    assert!(
+        error.to_string().starts_with("JSON nested deeper than 128 levels"),
+        "{}",
+        error
+    );
+    // Nesting within the limit only fails because there are no coordinates.
+    let error = ClipPolygon::from_geojson(&nested(100)).err().unwrap();
+    assert!(!error.to_string().contains("nested"), "{}", error);
+}
+
+#[test]
+fn test_negative_ids_from_editors() {
+    let osm_file = common::get_tmp_path("negative_ids.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<?xml version='1.0' encoding='UTF-8'?>
+        <osm version='0.6' upload='never' generator='JOSM'>
+            <node id='-101' action='modify' visible='true' lat='55.75' lon='37.61'/>
+            <node id='-102' action='modify' visible='true' lat='55.751' lon='37.612'/>
+            <node id='5' visible='true' version='1' lat='55.752' lon='37.613'/>
+            <way id='-201' action='modify' visible='true'>
+                <nd ref='-101'/><nd ref='-102'/><nd ref='5'/><nd ref='-103'/>
+                <tag k='highway' v='residential'/>
+            </way>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    let node_ids = parsed
+        .nodes()
+        .iter()
+        .map(|node| node.global_id as i64)
+        .collect::<Vec<_>>();
+    assert_eq!(node_ids, [-101, -102, 5]);
+
+    assert_eq!(parsed.ways().len(), 1);
+    let way = &parsed.ways()[0];
+    assert_eq!(way.global_id as i64, -201);
+    let way_node_ids = way
+        .node_ids
+        .iter()
+        .map(|&id| parsed.nodes()[id].global_id as i64)
+        .collect::<Vec<_>>();
+    assert_eq!(way_node_ids, [-101, -102, 5]);
+
+    let problems = parsed.problems().iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(problems, ["way #-201 refers to a missing node #-103"]);
+}
+
+#[test]
+fn test_nodes_with_non_finite_coords_are_skipped() {
+    let osm_file = common::get_tmp_path("non_finite_coords.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="NaN" lon="37.610"/>
+            <node id="3" lat="55.751" lon="inf"/>
+            <node id="4" lat="55.751" lon="37.611"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><tag k="highway" v="path"/></way>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    let node_ids = parsed.nodes().iter().map(|node| node.global_id).collect::<Vec<_>>();
+    assert_eq!(node_ids, [1, 4]);
+    assert!(parsed
+        .nodes()
+        .iter()
+        .all(|node| node.lat.is_finite() && node.lon.is_finite()));
+    assert_eq!(parsed.ways()[0].node_ids.len(), 2);
+
+    let problems = parsed.problems().iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(
+        problems,
+        [
+            "Node #2 has non-finite coordinates",
+            "Node #3 has non-finite coordinates",
+            "way #10 refers to a missing node #2",
+            "way #10 refers to a missing node #3",
+        ]
+    );
+}
+
+#[test]
+fn test_way_shared_between_relations() {
+    // Way 10 is the border between two areas and twice traverses a spike out to node 5 and back.
+    let osm_file = common::get_tmp_path("shared_way.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="55.751" lon="37.610"/>
+            <node id="3" lat="55.752" lon="37.610"/>
+            <node id="4" lat="55.751" lon="37.605"/>
+            <node id="5" lat="55.751" lon="37.611"/>
+            <node id="6" lat="55.751" lon="37.615"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="5"/><nd ref="2"/><nd ref="3"/></way>
+            <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <way id="12"><nd ref="3"/><nd ref="6"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="12" role="outer"/>
+                <member type="way" ref="10" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    assert!(parsed.problems().is_empty());
+    let global_ids = |refs: &[usize]| refs.iter().map(|&id| parsed.nodes()[id].global_id).collect::<Vec<_>>();
+    assert_eq!(global_ids(&parsed.ways()[0].node_ids), [1, 2, 3]);
+
+    let rings = parsed
+        .multipolygons()
+        .iter()
+        .map(|mp| {
+            assert_eq!(mp.polygon_ids.len(), 1, "relation #{}", mp.global_id);
+            let mut ring = global_ids(&parsed.polygons()[mp.polygon_ids[0]]);
+            assert_eq!(ring.first(), ring.last());
+            ring.pop();
+            ring.sort();
+            ring
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rings, [vec![1, 2, 3, 4], vec![1, 2, 3, 6]]);
+}
+
+#[test]
+fn test_multipolygon_way_with_repeated_segments() {
+    // Way 10 goes around a square, retraces its first side there and back, and then goes around a
+    // triangle that touches the square at node 1.
+    let osm_file = common::get_tmp_path("repeated_segments.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="55.751" lon="37.610"/>
+            <node id="3" lat="55.751" lon="37.611"/>
+            <node id="4" lat="55.750" lon="37.611"/>
+            <node id="6" lat="55.749" lon="37.610"/>
+            <node id="7" lat="55.749" lon="37.609"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/><nd ref="2"/><nd ref="1"/>
+                <nd ref="6"/><nd ref="7"/><nd ref="1"/>
+            </way>
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let parsed = parse_input(&osm_file).unwrap();
+    assert!(parsed.problems().is_empty());
+    let global_ids = |refs: &[usize]| refs.iter().map(|&id| parsed.nodes()[id].global_id).collect::<Vec<_>>();
+    assert_eq!(global_ids(&parsed.ways()[0].node_ids), [1, 2, 3, 4, 1, 6, 7, 1]);
+
+    // Each segment is only fed to the assembly once, so the relation has exactly the two rings.
+    let multipolygon = &parsed.multipolygons()[0];
+    let mut rings = multipolygon
+        .polygon_ids
+        .iter()
+        .map(|&polygon_id| {
+            let mut ring = global_ids(&parsed.polygons()[polygon_id]);
+            assert_eq!(ring.first(), ring.last());
+            ring.pop();
+            ring.sort();
+            ring
+        })
+        .collect::<Vec<_>>();
+    rings.sort();
+    assert_eq!(rings, [vec![1, 2, 3, 4], vec![1, 6, 7]]);
+}
+
+#[test]
+fn test_forward_refs_are_resolved_in_two_passes() {
+    // Everything is listed in reverse, so all references point further down the file.
+    let osm_file = common::get_tmp_path("forward_refs.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <relation id="20">
+                <member type="way" ref="10" role="outer"/>
+                <member type="way" ref="11" role="outer"/>
+                <tag k="type" v="multipolygon"/>
+                <tag k="landuse" v="grass"/>
+            </relation>
+            <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+            <node id="4" lat="55.751" lon="37.605"/>
+            <node id="4" lat="55.751" lon="37.605"/>
+            <node id="3" lat="55.752" lon="37.610"/>
+            <node id="2" lat="55.751" lon="37.611"/>
+            <node id="1" lat="55.750" lon="37.610"/>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let problems = validate(&osm_file, &ImportOptions::default()).unwrap();
+    assert!(problems.contains(&ImportProblem::UnresolvedRef {
+        kind: EntityKind::Way,
+        id: 10,
+        ref_kind: EntityKind::Node,
+        ref_id: 1,
+    }));
+
+    let options = ImportOptions {
+        resolve_forward_refs: true,
+        ..Default::default()
+    };
+    let parsed = parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap();
+    assert_eq!(
+        parsed.problems(),
+        [ImportProblem::DuplicateId {
+            kind: EntityKind::Node,
+            id: 4,
+        }]
+    );
+    let global_ids = |refs: &[usize]| refs.iter().map(|&id| parsed.nodes()[id].global_id).collect::<Vec<_>>();
+    let way_node_ids = parsed
+        .ways()
+        .iter()
+        .map(|way| global_ids(&way.node_ids))
+        .collect::<Vec<_>>();
+    assert_eq!(way_node_ids, [vec![3, 4, 1], vec![1, 2, 3]]);
+
+    assert_eq!(parsed.multipolygons().len(), 1);
+    let mut ring = global_ids(&parsed.polygons()[parsed.multipolygons()[0].polygon_ids[0]]);
+    ring.pop();
+    ring.sort();
+    assert_eq!(ring, [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_forward_refs_skip_nodes_with_non_finite_coords() {
+    let osm_file = common::get_tmp_path("forward_refs_non_finite.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="path"/></way>
+            <node id="1" lat="55.750" lon="37.610"/>
+            <node id="2" lat="NaN" lon="37.610"/>
+            <node id="3" lat="55.751" lon="37.611"/>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let options = ImportOptions {
+        resolve_forward_refs: true,
+        ..Default::default()
+    };
+    let parsed = parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap();
+    let way_node_ids = parsed.ways()[0]
+        .node_ids
+        .iter()
+        .map(|&id| parsed.nodes()[id].global_id)
+        .collect::<Vec<_>>();
+    assert_eq!(way_node_ids, [1, 3]);
+
+    let problems = parsed.problems().iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(
+        problems,
+        [
+            "way #10 refers to a missing node #2",
+            "Node #2 has non-finite coordinates"
+        ]
+    );
+
+    let mut output = Vec::new();
+    save(&parsed, &mut output).unwrap();
+}
+
+// A minimal PBF writer: just enough protobuf for a header block and blocks of dense nodes, ways and relations.
+#[cfg(feature = "pbf")]
+mod pbf {
+    pub fn varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    pub fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+        varint(buf, field << 3 | 2);
+        varint(buf, bytes.len() as u64);
+        buf.extend_from_slice(bytes);
+    }
+
+    pub fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+        varint(buf, field << 3);
+        varint(buf, value);
+    }
+
+    pub fn zigzag(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    pub fn sint_field(buf: &mut Vec<u8>, field: u64, value: i64) {
+        varint_field(buf, field, zigzag(value));
+    }
+
+    pub fn packed_field(buf: &mut Vec<u8>, field: u64, values: impl Iterator<Item = u64>) {
+        let mut packed = Vec::new();
+        for value in values {
+            varint(&mut packed, value);
+        }
+        bytes_field(buf, field, &packed);
+    }
+
+    pub fn packed_deltas(buf: &mut Vec<u8>, field: u64, values: &[i64]) {
+        let deltas = values
+            .iter()
+            .scan(0, |prev, &value| Some(zigzag(value - std::mem::replace(prev, value))));
+        packed_field(buf, field, deltas);
+    }
+
+    // Wraps an uncompressed block into a blob the way it's laid out in a file.
+    pub fn blob(blob_type: &[u8], block: &[u8]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        bytes_field(&mut blob, 1, block);
+        let mut blob_header = Vec::new();
+        bytes_field(&mut blob_header, 1, blob_type);
+        varint_field(&mut blob_header, 3, blob.len() as u64);
+
+        let mut result = (blob_header.len() as u32).to_be_bytes().to_vec();
+        result.extend(blob_header);
+        result.extend(blob);
+        result
+    }
+
+    pub fn header_blob(bbox: Option<[i64; 4]>) -> Vec<u8> {
+        let mut header_block = Vec::new();
+        if let Some(bbox) = bbox {
+            let mut header_bbox = Vec::new();
+            for (idx, value) in bbox.iter().enumerate() {
+                sint_field(&mut header_bbox, idx as u64 + 1, *value);
+            }
+            bytes_field(&mut header_block, 1, &header_bbox);
+        }
+        bytes_field(&mut header_block, 4, b"OsmSchema-V0.6");
+        bytes_field(&mut header_block, 4, b"DenseNodes");
+        blob(b"OSMHeader", &header_block)
+    }
+
+    /// A data block with a string table and a single primitive group, see `dense_nodes`, `way` and so on.
+    pub fn data_blob(strings: &[&str], group: &[u8]) -> Vec<u8> {
+        let mut string_table = Vec::new();
+        bytes_field(&mut string_table, 1, b"");
+        for string in strings {
+            bytes_field(&mut string_table, 1, string.as_bytes());
+        }
+        let mut block = Vec::new();
+        bytes_field(&mut block, 1, &string_table);
+        bytes_field(&mut block, 2, group);
+        blob(b"OSMData", &block)
+    }
+
+    /// `(id, lat, lon)` of untagged nodes, the coordinates in 100 nanodegrees.
+    pub fn dense_nodes(nodes: &[(i64, i64, i64)]) -> Vec<u8> {
+        let column = |f: fn(&(i64, i64, i64)) -> i64| nodes.iter().map(f).collect::<Vec<_>>();
+        let mut dense = Vec::new();
+        packed_deltas(&mut dense, 1, &column(|n| n.0));
+        packed_deltas(&mut dense, 8, &column(|n| n.1));
+        packed_deltas(&mut dense, 9, &column(|n| n.2));
+        let mut group = Vec::new();
+        bytes_field(&mut group, 2, &dense);
+        group
+    }
+
+    /// Tags are `(key, value)` indices into the string table of the block.
+    pub fn way(id: i64, tags: &[(u64, u64)], refs: &[i64]) -> Vec<u8> {
+        let mut way = Vec::new();
+        varint_field(&mut way, 1, id as u64);
+        packed_field(&mut way, 2, tags.iter().map(|t| t.0));
+        packed_field(&mut way, 3, tags.iter().map(|t| t.1));
+        packed_deltas(&mut way, 8, refs);
+        way
+    }
+
+    /// Way members with their roles as indices into the string table.
+    pub fn relation(id: i64, tags: &[(u64, u64)], way_members: &[(i64, u64)]) -> Vec<u8> {
+        let mut relation = Vec::new();
+        varint_field(&mut relation, 1, id as u64);
+        packed_field(&mut relation, 2, tags.iter().map(|t| t.0));
+        packed_field(&mut relation, 3, tags.iter().map(|t| t.1));
+        packed_field(&mut relation, 8, way_members.iter().map(|m| m.1));
+        packed_deltas(&mut relation, 9, &way_members.iter().map(|m| m.0).collect::<Vec<_>>());
+        packed_field(&mut relation, 10, way_members.iter().map(|_| 1));
+        relation
+    }
+}
+
+#[cfg(feature = "pbf")]
+#[test]
+fn test_header_only_pbf() {
+    let parse = |name: &str, bbox| {
+        let path = common::get_tmp_path(name);
+        std::fs::write(&path, pbf::header_blob(bbox)).unwrap();
+        parse_input(&path).unwrap()
+    };
+
+    let without_bbox = parse("header_only.pbf", None);
+    assert!(without_bbox.nodes().is_empty());
+    assert!(without_bbox.ways().is_empty());
+    assert!(without_bbox.multipolygons().is_empty());
+    assert!(without_bbox.routes().is_empty());
+    assert!(without_bbox.problems().is_empty());
+    assert_eq!(without_bbox.extent(), None);
+
+    // Left, right, top and bottom, in nanodegrees.
+    let with_bbox = parse(
+        "header_only_bbox.pbf",
+        Some([37_600_000_000, 37_620_000_000, 55_760_000_000, 55_740_000_000]),
+    );
+    assert!(with_bbox.nodes().is_empty());
+    let extent = with_bbox.extent().unwrap();
+    let is_close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(is_close(extent.min_lat, 55.74), "{:?}", extent);
+    assert!(is_close(extent.max_lat, 55.76), "{:?}", extent);
+    assert!(is_close(extent.min_lon, 37.6), "{:?}", extent);
+    assert!(is_close(extent.max_lon, 37.62), "{:?}", extent);
+}
+
+#[cfg(feature = "pbf")]
+#[test]
+fn test_parallel_pbf_import_matches_serial() {
+    // A 10x10 grid of nodes in four blocks, rows of the grid as ways in a fifth one and a multipolygon made
+    // of the outline of the grid in a sixth one.
+    let node_id = |row: i64, col: i64| 1 + row * 10 + col;
+    let mut file = pbf::header_blob(None);
+    for block_idx in 0..4 {
+        let nodes = (block_idx * 25..(block_idx + 1) * 25)
+            .map(|idx| (1 + idx, 557_500_000 + idx / 10 * 1_000, 376_100_000 + idx % 10 * 1_000))
+            .collect::<Vec<_>>();
+        file.extend(pbf::data_blob(&[], &pbf::dense_nodes(&nodes)));
+    }
+    let mut ways = Vec::new();
+    for row in 0..10 {
+        let refs = (0..10).map(|col| node_id(row, col)).collect::<Vec<_>>();
+        pbf::bytes_field(&mut ways, 3, &pbf::way(100 + row, &[(1, 2)], &refs));
+    }
+    let outline = [
+        node_id(0, 0),
+        node_id(0, 9),
+        node_id(9, 9),
+        node_id(9, 0),
+        node_id(0, 0),
+    ];
+    pbf::bytes_field(&mut ways, 3, &pbf::way(200, &[], &outline));
+    file.extend(pbf::data_blob(&["highway", "residential"], &ways));
+    let mut relations = Vec::new();
+    let tags = [(1, 2), (3, 4)];
+    pbf::bytes_field(&mut relations, 4, &pbf::relation(300, &tags, &[(200, 5)]));
+    file.extend(pbf::data_blob(
+        &["type", "multipolygon", "landuse", "forest", "outer"],
+        &relations,
+    ));
+
+    let path = common::get_tmp_path("parallel_import.pbf");
+    std::fs::write(&path, file).unwrap();
+    let import = |thread_count| {
+        let options = ImportOptions {
+            thread_count: Some(thread_count),
+            ..Default::default()
+        };
+        let parsed = parse_input_with_options(&path, &options, &mut CollectedStats::default()).unwrap();
+        assert_eq!(parsed.nodes().len(), 100);
+        assert_eq!(parsed.ways().len(), 11);
+        assert_eq!(parsed.multipolygons().len(), 1);
+        assert!(parsed.problems().is_empty());
+        let mut output = Vec::new();
+        save(&parsed, &mut output).unwrap();
+        output
+    };
+
+    let serial = import(1);
+    for thread_count in [2, 3, 4, 8] {
+        assert!(import(thread_count) == serial, "{} threads", thread_count);
+    }
+}
+
+#[cfg(feature = "pbf")]
+#[test]
+fn test_pbf_duplicates_are_reported_and_skipped() {
+    let mut file = pbf::header_blob(None);
+    let nodes = [
+        (1, 557_500_000, 376_100_000),
+        (2, 557_510_000, 376_100_000),
+        (2, 557_520_000, 376_120_000),
+        (3, 557_510_000, 376_110_000),
+    ];
+    file.extend(pbf::data_blob(&[], &pbf::dense_nodes(&nodes)));
+    let mut ways = Vec::new();
+    pbf::bytes_field(&mut ways, 3, &pbf::way(10, &[(1, 2)], &[1, 2, 3]));
+    pbf::bytes_field(&mut ways, 3, &pbf::way(10, &[], &[3, 1]));
+    file.extend(pbf::data_blob(&["highway", "residential"], &ways));
+    let path = common::get_tmp_path("pbf_duplicates.pbf");
+    std::fs::write(&path, file).unwrap();
+
+    for resolve_forward_refs in [false, true] {
+        let options = ImportOptions {
+            resolve_forward_refs,
+            ..Default::default()
+        };
+        let parsed = parse_input_with_options(&path, &options, &mut CollectedStats::default()).unwrap();
+        assert_eq!(
+            parsed.problems(),
+            [
+                ImportProblem::DuplicateId {
+                    kind: EntityKind::Node,
+                    id: 2,
+                },
+                ImportProblem::DuplicateId {
+                    kind: EntityKind::Way,
+                    id: 10,
+                },
+            ]
+        );
+        let node_ids = parsed.nodes().iter().map(|node| node.global_id).collect::<Vec<_>>();
+        assert_eq!(node_ids, [1, 2, 3]);
+        assert!((parsed.nodes()[1].lat - 55.751).abs() < 1e-9);
+        assert_eq!(parsed.ways().len(), 1);
+        let way_node_ids = parsed.ways()[0]
+            .node_ids
+            .iter()
+            .map(|&id| parsed.nodes()[id].global_id)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            way_node_ids,
+            [1, 2, 3],
+            "resolve_forward_refs: {}",
+            resolve_forward_refs
+        );
+    }
+}
+
+#[cfg(feature = "pbf")]
+#[test]
+fn test_bundled_pbf_fixture_matches_xml() {
+    // The PBF fixture used by the benchmarks is a conversion of the XML one, so they must have the same
+    // entities. Only the coordinates may differ in the last bits, since PBF stores them as integers.
+    let pbf = parse_input(common::get_test_path(&["osm", "nano_moscow.osm.pbf"])).unwrap();
+    let xml = parse_input(common::get_test_path(&["osm", "nano_moscow.osm"])).unwrap();
+
+    assert_eq!(pbf.nodes().len(), xml.nodes().len());
+    for (pbf_node, xml_node) in pbf.nodes().iter().zip(xml.nodes()) {
+        assert_eq!(pbf_node.global_id, xml_node.global_id);
+        assert!((pbf_node.lat - xml_node.lat).abs() < 1e-9);
+        assert!((pbf_node.lon - xml_node.lon).abs() < 1e-9);
+        assert_eq!(pbf_node.tags, xml_node.tags);
+    }
+    assert_eq!(pbf.ways().len(), xml.ways().len());
+    for (pbf_way, xml_way) in pbf.ways().iter().zip(xml.ways()) {
+        assert_eq!(pbf_way.global_id, xml_way.global_id);
+        assert_eq!(pbf_way.node_ids, xml_way.node_ids);
+        assert_eq!(pbf_way.tags, xml_way.tags);
+    }
+    assert_eq!(pbf.polygons(), xml.polygons());
+    assert_eq!(pbf.multipolygons().len(), xml.multipolygons().len());
+    assert_eq!(pbf.problems().len(), xml.problems().len());
+}
+
+#[test]
+fn test_multipolygons_with_too_many_segments_are_skipped() {
+    // Relation 20 is a ring of 100 ways with 1000 segments, relation 21 a square with 4 segments.
+    let ring_nodes = (0..1000)
+        .map(|idx| {
+            let angle = f64::from(idx) / 1000.0 * std::f64::consts::TAU;
+            format!(
+                r#"<node id="{}" lat="{}" lon="{}"/>"#,
+                1000 + idx,
+                55.75 + 0.001 * angle.sin(),
+                37.61 + 0.001 * angle.cos()
+            )
+        })
+        .collect::<String>();
+    let ring_ways = (0..100)
+        .map(|way_idx| {
+            let node_refs = (0..=10)
+                .map(|idx| format!(r#"<nd ref="{}"/>"#, 1000 + (way_idx * 10 + idx) % 1000))
+                .collect::<String>();
+            format!(r#"<way id="{}">{}</way>"#, 100 + way_idx, node_refs)
+        })
+        .collect::<String>();
+    let ring_members = (0..100)
+        .map(|way_idx| format!(r#"<member type="way" ref="{}" role="outer"/>"#, 100 + way_idx))
+        .collect::<String>();
+    let osm_file = common::get_tmp_path("too_many_segments.osm");
+    std::fs::write(
+        &osm_file,
+        format!(
+            r#"<osm version="0.6">
+                {}
+                <node id="1" lat="55.76" lon="37.62"/>
+                <node id="2" lat="55.76" lon="37.621"/>
+                <node id="3" lat="55.761" lon="37.621"/>
+                <node id="4" lat="55.761" lon="37.62"/>
+                {}
+                <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+                <relation id="20">{}<tag k="type" v="multipolygon"/></relation>
+                <relation id="21">
+                    <member type="way" ref="10" role="outer"/>
+                    <tag k="type" v="multipolygon"/>
+                </relation>
+            </osm>"#,
+            ring_nodes, ring_ways, ring_members
+        ),
+    )
+    .unwrap();
+
+    let parse = |max_multipolygon_segments| {
+        let options = ImportOptions {
+            max_multipolygon_segments,
+            ..Default::default()
+        };
+        parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap()
+    };
+    let multipolygon_ids =
+        |parsed: &EntityStorages| parsed.multipolygons().iter().map(|mp| mp.global_id).collect::<Vec<_>>();
+
+    let parsed = parse(999);
+    assert_eq!(multipolygon_ids(&parsed), [21]);
+    assert_eq!(
+        parsed.problems(),
+        [ImportProblem::TooManySegments {
+            relation_id: 20,
+            segment_count: 1000,
+        }]
+    );
+    assert_eq!(
+        parsed.problems()[0].to_string(),
+        "Relation #20 has too many segments (1000) to be assembled"
+    );
+
+    let parsed = parse(1000);
+    assert_eq!(multipolygon_ids(&parsed), [20, 21]);
+    assert!(parsed.problems().is_empty());
+}
+
+#[test]
+fn test_member_ways_inherit_relation_tags() {
+    // Ways 10 and 11 are in route 20, way 12 only in a multipolygon, which doesn't pass its tags on, and
+    // way 13 in boundary 22, which isn't assembled into an area.
+    let osm_file = common::get_tmp_path("inherited_relation_tags.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.75" lon="37.62"/>
+            <node id="3" lat="55.76" lon="37.62"/>
+            <node id="4" lat="55.76" lon="37.61"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+            <way id="11"><nd ref="2"/><nd ref="3"/><tag k="ref" v="M1"/></way>
+            <way id="12"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/></way>
+            <way id="13"><nd ref="1"/><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role=""/>
+                <member type="way" ref="11" role=""/>
+                <tag k="type" v="route"/><tag k="route" v="bus"/><tag k="ref" v="42"/><tag k="colour" v="red"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="12" role="outer"/>
+                <tag k="type" v="multipolygon"/><tag k="ref" v="7"/>
+            </relation>
+            <relation id="22">
+                <member type="way" ref="13" role="outer"/>
+                <tag k="type" v="boundary"/><tag k="boundary" v="administrative"/><tag k="ref" v="B"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let way_tags = |inherited_relation_tags: Vec<String>| {
+        let options = ImportOptions {
+            inherited_relation_tags,
+            ..Default::default()
+        };
+        let parsed = parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap();
+        let multipolygon_ids = parsed.multipolygons().iter().map(|mp| mp.global_id).collect::<Vec<_>>();
+        assert_eq!(multipolygon_ids, [21]);
+        parsed.ways().iter().map(|way| way.tags.clone()).collect::<Vec<_>>()
+    };
+    let tag = |tags: &BTreeMap<String, String>, key: &str| tags.get(key).cloned();
+
+    let tags = way_tags(Vec::new());
+    assert_eq!(tag(&tags[0], "ref"), None);
+
+    let tags = way_tags(vec!["ref".to_string()]);
+    assert_eq!(tag(&tags[0], "ref"), Some("42".to_string()));
+    assert_eq!(tag(&tags[0], "highway"), Some("primary".to_string()));
+    assert_eq!(tag(&tags[0], "colour"), None);
+    // Tags of the way itself win over the inherited ones.
+    assert_eq!(tag(&tags[1], "ref"), Some("M1".to_string()));
+    assert_eq!(tag(&tags[2], "ref"), None);
+    assert_eq!(tag(&tags[3], "ref"), Some("B".to_string()));
+}
+
+#[test]
+fn test_route_relations_are_imported_on_request() {
+    let osm_file = common::get_tmp_path("import_routes.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.75" lon="37.62"/>
+            <node id="3" lat="55.76" lon="37.62"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/></way>
+            <way id="11"><nd ref="2"/><nd ref="3"/></way>
+            <way id="12"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/></way>
+            <relation id="20">
+                <member type="way" ref="10" role=""/>
+                <member type="way" ref="11" role=""/>
+                <tag k="type" v="route"/><tag k="route" v="bus"/>
+            </relation>
+            <relation id="21">
+                <member type="way" ref="12" role="outer"/>
+                <tag k="type" v="multipolygon"/><tag k="landuse" v="grass"/>
+            </relation>
+        </osm>"#,
+    )
+    .unwrap();
+
+    let options = ImportOptions {
+        import_routes: true,
+        ..Default::default()
+    };
+    let parsed = parse_input_with_options(&osm_file, &options, &mut CollectedStats::default()).unwrap();
+    assert_eq!(parsed.routes().len(), 1);
+    assert_eq!(parsed.routes()[0].polyline_ids, [0]);
+    assert_eq!(parsed.polylines(), [vec![0, 1, 2]]);
+    // The ring of the multipolygon is the only polygon, routes have a storage of their own.
+    assert_eq!(parsed.polygons().len(), 1);
+
+    let tile = Tile {
+        zoom: 15,
+        x: 19_807,
+        y: 10_244,
+    };
+    let route_count = |flags: &[&str], bin_name: &str| {
+        let bin_file = common::get_tmp_path(bin_name);
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_importer"))
+            .args(flags)
+            .arg(&osm_file)
+            .arg(&bin_file)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+        let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+        reader.get_entities_in_tile_with_neighbors(&tile, &None).routes.len()
+    };
+    assert_eq!(route_count(&[], "without_routes.bin"), 0);
+    assert_eq!(route_count(&["--import-routes"], "with_routes.bin"), 1);
+}
@@ -1,7 +1,11 @@
 mod common;
 
 use crate::common::get_test_path;
+use renderer::draw::drawer::Drawer;
+use renderer::draw::tile_pixels::TilePixels;
 use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::tile::Tile;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -44,3 +48,68 @@ fn test_parsing_is_idempotent() {
     let rules_str = rules.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("\n\n");
     assert_eq!(rules_str, canonize_newlines(&canonical));
 }
+
+#[test]
+fn test_empty_and_comment_only_styles() {
+    let tile = Tile {
+        zoom: 18,
+        x: 158_458,
+        y: 81_954,
+    };
+    let reader = common::import_osm_str(
+        "empty_style",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7502" lon="37.6091"/>
+            <node id="2" lat="55.7496" lon="37.6102"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let drawer = Drawer::new(Path::new("."));
+
+    for (name, mapcss) in [
+        ("empty", ""),
+        ("whitespace", " \n\t\r\n  "),
+        ("comments", "/* Nothing to see here. */\n// Not even this.\n/**/"),
+        ("unterminated_comment", "// No newline at the end"),
+        ("byte_order_mark", "\u{feff}/* Saved by a picky editor. */\n"),
+    ] {
+        let rules = common::parse_style_str(&format!("empty_style_{}", name), mapcss);
+        assert!(rules.is_empty(), "{}", name);
+
+        let styler = Styler::new(rules, &StyleType::Josm, None);
+        assert!(styler.canvas_fill_color.is_none(), "{}", name);
+        let rendered = drawer.draw_to_pixels(&entities, &tile, &mut TilePixels::new(1), 1, &styler);
+        assert!(rendered.is_blank, "{}", name);
+    }
+
+    // The canvas is all there is to draw.
+    let rules = common::parse_style_str(
+        "empty_style_canvas",
+        "/* Only a canvas. */ canvas { fill-color: #102030; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let rendered = drawer.draw_to_pixels(&entities, &tile, &mut TilePixels::new(1), 1, &styler);
+    assert!(rendered.triples.iter().all(|pixel| *pixel == (0x10, 0x20, 0x30)));
+}
+
+#[test]
+fn test_modulo_cond_eval() {
+    let mapcss = r#"way[contour] { width: eval(cond(mod(tag("ele"), 100) == 0, 4, 1)); }"#;
+    let rules = common::parse_style_str("modulo_cond", mapcss);
+    let rules_str = rules.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("\n\n");
+    assert!(
+        rules_str.contains(r#"width: eval(cond(mod(tag("ele"), 100) == 0, 4, 1));"#),
+        "{}",
+        rules_str
+    );
+
+    let style_file = common::get_tmp_path("modulo_cond_by_zero.mapcss");
+    std::fs::write(
+        &style_file,
+        r#"way[contour] { width: eval(cond(mod(tag("ele"), 0) == 0, 4, 1)); }"#,
+    )
+    .unwrap();
+    let error = parse_file(style_file.parent().unwrap(), "modulo_cond_by_zero.mapcss").unwrap_err();
+    assert!(format!("{:#}", error).contains("Division by zero"), "{:#}", error);
+}
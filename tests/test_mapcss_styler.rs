@@ -1,13 +1,19 @@
 mod common;
 
 use crate::common::get_test_path;
+use renderer::draw::drawer::Drawer;
+use renderer::draw::tile_pixels::TilePixels;
 use renderer::geodata::reader::OsmEntity;
 use renderer::mapcss::color::{from_color_name, Color};
 use renderer::mapcss::parser::parse_file;
-use renderer::mapcss::styler::{LineCap, Style, StyleType, Styler};
+use renderer::mapcss::styler::{
+    default_order_key, z_index_first_order_key, LineCap, LineDecoration, LineJoin, OrderKey, Style, StyleType, Styler,
+    TickSide,
+};
 use renderer::tile::Tile;
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
 
 #[test]
 fn test_styling() {
@@ -96,6 +102,194 @@ fn test_styling() {
     }
 }
 
+#[test]
+fn test_opacity_stops() {
+    let reader = common::import_osm_str(
+        "opacity_stops",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "opacity_stops",
+        "way[highway] { color: red; opacity: 10:0, 12:1; width: 10:2, 12:4, 14:8; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let style_at = |zoom| {
+        let styles = styler.style_entities(entities.ways.iter(), zoom, false);
+        assert_eq!(styles.len(), 1);
+        (styles[0].1.opacity, styles[0].1.width)
+    };
+
+    assert_eq!(style_at(9), (Some(0.0), Some(2.0)));
+    assert_eq!(style_at(11), (Some(0.5), Some(3.0)));
+    assert_eq!(style_at(13), (Some(1.0), Some(6.0)));
+    assert_eq!(style_at(18), (Some(1.0), Some(8.0)));
+}
+
+#[test]
+fn test_tick_decoration() {
+    let reader = common::import_osm_str(
+        "tick_decoration",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="natural" v="cliff"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "tick_decoration",
+        "way[natural=cliff] { color: #808080; tick-spacing: 6; tick-side: left; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let styles = styler.style_entities(entities.ways.iter(), 18, false);
+    assert_eq!(
+        styles[0].1.line_decoration,
+        Some(LineDecoration {
+            spacing: 6.0,
+            length: 3.0,
+            side: TickSide::Left,
+        })
+    );
+}
+
+#[test]
+fn test_custom_order_key() {
+    let reader = common::import_osm_str(
+        "custom_order_key",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="1"/><nd ref="2"/><tag k="waterway" v="river"/></way>
+        </osm>"#,
+    );
+    let rules = || {
+        common::parse_style_str(
+            "custom_order_key",
+            "way[highway] { color: #ffffff; width: 4; z-index: 2; } way[waterway] { color: #0000ff; width: 8; z-index: 1; }",
+        )
+    };
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let drawn_features = |styler: &Styler| {
+        styler
+            .style_entities(entities.ways.iter(), 18, false)
+            .iter()
+            .map(|(way, _)| way.tags().get_by_key("waterway").unwrap_or("road"))
+            .collect::<Vec<_>>()
+    };
+
+    let mut styler = Styler::new(rules(), &StyleType::Josm, None);
+    assert_eq!(drawn_features(&styler), vec!["river", "road"]);
+
+    styler.set_order_key(|tags, style, for_labels| {
+        let OrderKey([layer, class, z_index]) = default_order_key(tags, style, for_labels);
+        let is_water = tags.get_by_key("waterway").is_some();
+        OrderKey([layer, if is_water { 1.0 } else { 0.0 }, class + z_index])
+    });
+    assert_eq!(drawn_features(&styler), vec!["road", "river"]);
+}
+
+#[test]
+fn test_z_index_first_order_key() {
+    // The river is on a higher layer than the road, but has a lower z-index.
+    let reader = common::import_osm_str(
+        "z_index_first_order_key",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="1"/><nd ref="2"/><tag k="waterway" v="river"/><tag k="layer" v="1"/></way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "z_index_first_order_key",
+        "way[highway] { color: #ffffff; width: 4; z-index: 2; } way[waterway] { color: #0000ff; width: 8; z-index: 1; }",
+    );
+    let mut styler = Styler::new(rules, &StyleType::Josm, None);
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let drawn_features = |styler: &Styler| {
+        styler
+            .style_entities(entities.ways.iter(), 18, false)
+            .iter()
+            .map(|(way, _)| way.tags().get_by_key("waterway").unwrap_or("road"))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(drawn_features(&styler), vec!["road", "river"]);
+    styler.set_order_key(z_index_first_order_key);
+    assert_eq!(drawn_features(&styler), vec!["river", "road"]);
+}
+
+#[test]
+fn test_label_zoom_range() {
+    let reader = common::import_osm_str(
+        "label_zoom_range",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.75" lon="37.61"/>
+            <node id="2" lat="55.751" lon="37.612"/>
+            <way id="10">
+                <nd ref="1"/><nd ref="2"/>
+                <tag k="highway" v="residential"/><tag k="name" v="Tverskaya"/>
+            </way>
+        </osm>"#,
+    );
+    let rules = common::parse_style_str(
+        "label_zoom_range",
+        "way|z10-[highway] { color: #ffffff; width: 2; text: name; text-min-zoom: 14; }",
+    );
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let style_at = |zoom| {
+        let styles = styler.style_entities(entities.ways.iter(), zoom, false);
+        assert_eq!(styles.len(), 1);
+        Arc::clone(&styles[0].1)
+    };
+
+    for zoom in [10, 12] {
+        let style = style_at(zoom);
+        assert!(style.color.is_some());
+        assert!(style.text_style.is_none());
+    }
+    let style = style_at(14);
+    assert!(style.color.is_some());
+    assert_eq!(style.text_style.as_ref().map(|text| text.text.as_str()), Some("name"));
+}
+
 fn compare_with_josm_style(our_style: &Style, way_is_closed: bool, josm_style_str: &str) {
     let josm_style = from_josm_style(way_is_closed, josm_style_str);
     assert_styles_eq(our_style, &josm_style);
@@ -110,6 +304,7 @@ fn assert_styles_eq(our_style: &Style, josm_style: &Style) {
     assert_eq!(our_style.width, josm_style.width);
     assert_eq!(our_style.dashes, josm_style.dashes);
     assert_eq!(our_style.line_cap, josm_style.line_cap);
+    assert_eq!(our_style.line_join, josm_style.line_join);
 }
 
 fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
@@ -143,6 +338,7 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
 
     Style {
         layer: None,
+        render_layer: None,
         z_index: parse_num("z-index").unwrap_or(if way_is_closed { 1.0 } else { 3.0 }),
 
         color: parse_color("color"),
@@ -169,14 +365,151 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
                 })
                 .unwrap_or(LineCap::Butt),
         ),
+        line_join: props.get("linejoin").map(|x| match *x {
+            "Keyword{round}" => LineJoin::Round,
+            "Keyword{bevel}" => LineJoin::Bevel,
+            "Keyword{miter}" => LineJoin::Miter(4.0),
+            _ => unreachable!(),
+        }),
 
         casing_color: None,
         casing_width: None,
         casing_dashes: None,
         casing_line_cap: None,
 
+        line_decoration: None,
+        flow_arrows: None,
+
         icon_image: None,
+        icon_allow_overlap: false,
         fill_image: None,
+        fill_hatch: None,
+        symbol: None,
         text_style: None,
     }
 }
+
+#[test]
+fn test_line_cap_and_join() {
+    let reader = common::import_osm_str(
+        "line_cap_and_join",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6091"/>
+            <node id="2" lat="55.7500" lon="37.6097"/>
+            <node id="3" lat="55.7496" lon="37.6097"/>
+            <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+    );
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+    let render = |name: &str, props: &str| {
+        let css = format!(
+            "canvas {{ fill-color: #ffffff; }} way[highway=primary] {{ color: #000000; width: 10; {} }}",
+            props
+        );
+        let styler = Styler::new(common::parse_style_str(name, &css), &StyleType::Josm, None);
+        let style = Arc::clone(&styler.style_entities(entities.ways.iter(), 18, false)[0].1);
+        let drawer = Drawer::new(Path::new("."));
+        let mut pixels = TilePixels::new(1);
+        let rendered = drawer.draw_to_pixels(&entities, &tile, &mut pixels, 1, &styler);
+        (style, move |x: usize, y: usize| {
+            rendered.triples[y * rendered.dimension + x]
+        })
+    };
+
+    let (round_style, round) = render("line_cap_round", "linecap: round; linejoin: miter; miter-limit: 2;");
+    assert_eq!(round_style.line_cap, Some(LineCap::Round));
+    assert_eq!(round_style.line_join, Some(LineJoin::Miter(2.0)));
+
+    let (butt_style, butt) = render("line_cap_butt", "");
+    assert_eq!(butt_style.line_cap, None);
+    assert_eq!(butt_style.line_join, None);
+
+    // The way starts at (28, 101): only the round cap reaches past its start.
+    let white = (255, 255, 255);
+    assert_eq!(butt(24, 101), white);
+    assert_ne!(round(24, 101), white);
+    // The outer side of the corner at (140, 101) is only covered by the miter join.
+    assert_eq!(butt(143, 98), white);
+    assert_ne!(round(143, 98), white);
+}
+
+#[test]
+fn test_universal_selector_and_value_wildcard() {
+    let reader = common::import_osm_str(
+        "universal_selector",
+        r#"<osm version="0.6">
+            <node id="1" lat="55.7500" lon="37.6091"/>
+            <node id="2" lat="55.7500" lon="37.6097"/>
+            <node id="3" lat="55.7498" lon="37.6094"><tag k="amenity" v="cafe"/></node>
+            <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+            <way id="11"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+        </osm>"#,
+    );
+    let tile = Tile {
+        x: 158_458,
+        y: 81_954,
+        zoom: 18,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+    // The universal rule comes last, but it's still the least specific one.
+    let rules = common::parse_style_str(
+        "universal_selector",
+        "way[highway=primary] { color: #ff0000; } node[amenity=*] { text: amenity; } * { color: grey; width: 2; }",
+    );
+    assert_eq!(rules[1].selectors[0].to_string(), "node[amenity]");
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+
+    let way_style = |id| {
+        let styled = styler.style_entities(entities.ways.iter(), 18, false);
+        let (_, style) = styled.iter().find(|(way, _)| way.global_id() == id).unwrap();
+        Arc::clone(style)
+    };
+    let residential = way_style(10);
+    assert_eq!(residential.color, from_color_name("grey"));
+    assert_eq!(residential.width, Some(2.0));
+    let primary = way_style(11);
+    assert_eq!(primary.color, Some(Color { r: 255, g: 0, b: 0 }));
+    assert_eq!(primary.width, Some(2.0));
+
+    let styled_nodes = styler.style_entities(entities.nodes.iter(), 18, true);
+    let node_text = |id| {
+        let (_, style) = styled_nodes.iter().find(|(node, _)| node.global_id() == id).unwrap();
+        style.text_style.as_ref().map(|text_style| text_style.text.clone())
+    };
+    assert_eq!(node_text(3), Some("amenity".to_string()));
+    assert_eq!(node_text(1), None);
+}
+
+#[test]
+fn test_debug_dump_resolves_colors_and_imports() {
+    std::fs::write(
+        common::get_tmp_path("debug_dump_included.mapcss"),
+        "way[highway] { color: #ff0000; z-index: 2; }",
+    )
+    .unwrap();
+    let rules = common::parse_style_str(
+        "debug_dump",
+        r#"@import "debug_dump_included.mapcss";
+        @water: #aabbcc;
+        area[natural=water] { fill-color: @water; }
+        * { opacity: 0.5; }"#,
+    );
+    let dump = Styler::new(rules, &StyleType::Josm, None).debug_dump();
+    // `*` selectors come first in the cascade, even though the rule is the last one.
+    assert_eq!(
+        dump,
+        "/* cascade position: 1; z-index: 2 */\n\
+         way[highway] {\ncolor: #ff0000;\nz-index: 2;\n}\n\n\
+         /* cascade position: 2 */\n\
+         area[natural=water] {\nfill-color: #aabbcc;\n}\n\n\
+         /* cascade position: 0 */\n\
+         * {\nopacity: 0.5;\n}"
+    );
+}
@@ -107,7 +107,7 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
             for sub_y in 0..tile_dimension {
                 for rendered in x_rendered.values() {
                     if sub_y == 0 {
-                        rgb.extend(std::iter::repeat(RED_PIXEL).take(tile_dimension));
+                        rgb.extend(std::iter::repeat_n(RED_PIXEL, tile_dimension));
                     } else {
                         rgb.extend(&rendered[sub_y * tile_dimension..(sub_y + 1) * tile_dimension - 1]);
                         rgb.push(RED_PIXEL);